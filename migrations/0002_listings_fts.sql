@@ -0,0 +1,26 @@
+-- Full-text index over listing titles and descriptions.
+-- listings uses a TEXT primary key, so rather than an external-content table keyed on
+-- the (unstable) implicit rowid, the index keeps its own copy and carries listing_id.
+
+CREATE VIRTUAL TABLE listings_fts USING fts5(
+    listing_id UNINDEXED,
+    title,
+    description,
+    tokenize = 'porter unicode61 remove_diacritics 2'
+);
+
+INSERT INTO listings_fts (listing_id, title, description)
+SELECT id, title, description FROM listings;
+
+CREATE TRIGGER listings_fts_insert AFTER INSERT ON listings BEGIN
+    INSERT INTO listings_fts (listing_id, title, description) VALUES (new.id, new.title, new.description);
+END;
+
+CREATE TRIGGER listings_fts_delete AFTER DELETE ON listings BEGIN
+    DELETE FROM listings_fts WHERE listing_id = old.id;
+END;
+
+CREATE TRIGGER listings_fts_update AFTER UPDATE OF title, description ON listings BEGIN
+    DELETE FROM listings_fts WHERE listing_id = old.id;
+    INSERT INTO listings_fts (listing_id, title, description) VALUES (new.id, new.title, new.description);
+END;
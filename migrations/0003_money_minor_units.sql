@@ -0,0 +1,11 @@
+-- Prices move from REAL dollars to INTEGER minor units plus an ISO 4217 currency code.
+
+ALTER TABLE listings ADD COLUMN price_cents INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE listings ADD COLUMN currency TEXT NOT NULL DEFAULT 'USD';
+UPDATE listings SET price_cents = CAST(ROUND(price * 100) AS INTEGER);
+ALTER TABLE listings DROP COLUMN price;
+
+ALTER TABLE offers ADD COLUMN amount_cents INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE offers ADD COLUMN currency TEXT NOT NULL DEFAULT 'USD';
+UPDATE offers SET amount_cents = CAST(ROUND(amount * 100) AS INTEGER);
+ALTER TABLE offers DROP COLUMN amount;
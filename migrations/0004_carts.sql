@@ -0,0 +1,18 @@
+-- Shopping carts keyed by the forge_cart cookie token.
+
+CREATE TABLE carts (
+    id TEXT PRIMARY KEY,
+    token TEXT UNIQUE,
+    created_at TEXT NOT NULL DEFAULT (datetime('now')),
+    updated_at TEXT NOT NULL DEFAULT (datetime('now'))
+);
+
+-- listing_id is deliberately not a foreign key: deleting a listing must not fail because
+-- it sits in somebody's cart. Items whose listing is gone are skipped when reading.
+CREATE TABLE cart_items (
+    cart_id TEXT NOT NULL REFERENCES carts(id) ON DELETE CASCADE,
+    listing_id TEXT NOT NULL,
+    quantity INTEGER NOT NULL DEFAULT 1 CHECK (quantity > 0),
+    added_at TEXT NOT NULL DEFAULT (datetime('now')),
+    PRIMARY KEY (cart_id, listing_id)
+);
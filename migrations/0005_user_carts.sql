@@ -0,0 +1,6 @@
+-- Carts can belong to a signed-in user instead of a guest cookie token.
+-- A guest cart is merged into the user's cart (and deleted) when they log in or register.
+
+ALTER TABLE carts ADD COLUMN user_id TEXT REFERENCES users(id) ON DELETE CASCADE;
+
+CREATE UNIQUE INDEX idx_carts_user ON carts(user_id) WHERE user_id IS NOT NULL;
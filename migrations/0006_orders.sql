@@ -0,0 +1,35 @@
+-- Orders record a sale. Each order has exactly one seller; checking out a cart with
+-- items from several sellers creates one order per seller.
+
+CREATE TABLE orders (
+    id TEXT PRIMARY KEY,
+    buyer_id TEXT NOT NULL REFERENCES users(id),
+    seller_id TEXT NOT NULL REFERENCES users(id),
+    -- Set when the order came from an accepted offer; at most one order per offer
+    offer_id TEXT REFERENCES offers(id),
+    status TEXT NOT NULL DEFAULT 'pending_payment'
+        CHECK (status IN ('pending_payment', 'paid', 'handed_over', 'shipped', 'completed', 'cancelled')),
+    total_cents INTEGER NOT NULL,
+    currency TEXT NOT NULL DEFAULT 'USD',
+    -- Seller's payment instructions as they were at checkout
+    payment_info TEXT NOT NULL DEFAULT '',
+    created_at TEXT NOT NULL DEFAULT (datetime('now')),
+    updated_at TEXT NOT NULL DEFAULT (datetime('now'))
+);
+
+CREATE UNIQUE INDEX idx_orders_offer ON orders(offer_id) WHERE offer_id IS NOT NULL;
+CREATE INDEX idx_orders_buyer ON orders(buyer_id, created_at);
+CREATE INDEX idx_orders_seller ON orders(seller_id, created_at);
+
+-- Title, image and price are copied from the listing so order history survives edits
+-- and deletion. listing_id is not a foreign key for the same reason.
+CREATE TABLE order_items (
+    order_id TEXT NOT NULL REFERENCES orders(id) ON DELETE CASCADE,
+    listing_id TEXT NOT NULL,
+    title TEXT NOT NULL,
+    image_url TEXT NOT NULL,
+    unit_price_cents INTEGER NOT NULL,
+    currency TEXT NOT NULL DEFAULT 'USD',
+    quantity INTEGER NOT NULL DEFAULT 1 CHECK (quantity > 0),
+    PRIMARY KEY (order_id, listing_id)
+);
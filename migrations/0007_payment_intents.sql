@@ -0,0 +1,20 @@
+-- A payment intent is one attempt to pay for an order through a payment provider.
+-- `provider_ref` is the provider's own id for the payment (e.g. a gateway charge id).
+
+CREATE TABLE payment_intents (
+    id TEXT PRIMARY KEY,
+    order_id TEXT NOT NULL REFERENCES orders(id) ON DELETE CASCADE,
+    provider TEXT NOT NULL,
+    provider_ref TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'requires_action'
+        CHECK (status IN ('requires_action', 'succeeded', 'failed', 'refunded')),
+    amount_cents INTEGER NOT NULL,
+    currency TEXT NOT NULL DEFAULT 'USD',
+    -- What the buyer needs to see to pay, e.g. the seller's Venmo handle
+    instructions TEXT NOT NULL DEFAULT '',
+    created_at TEXT NOT NULL DEFAULT (datetime('now')),
+    updated_at TEXT NOT NULL DEFAULT (datetime('now'))
+);
+
+CREATE INDEX idx_payment_intents_order ON payment_intents(order_id);
+CREATE UNIQUE INDEX idx_payment_intents_ref ON payment_intents(provider, provider_ref);
@@ -0,0 +1,10 @@
+-- Counter-offers. Each round of a negotiation is its own row pointing at the offer it
+-- answers; the answered offer is marked 'countered'. `created_by` is whoever named the
+-- amount (the buyer for opening offers, either party for counters).
+
+ALTER TABLE offers ADD COLUMN parent_id TEXT REFERENCES offers(id);
+ALTER TABLE offers ADD COLUMN created_by TEXT REFERENCES users(id);
+
+UPDATE offers SET created_by = buyer_id WHERE created_by IS NULL;
+
+CREATE INDEX idx_offers_conversation ON offers(conversation_id, created_at);
@@ -0,0 +1,10 @@
+-- Offers expire if the other party doesn't answer in time. Each seller picks how long
+-- offers on their listings stay open; existing offers get the default from when they were made.
+
+ALTER TABLE users ADD COLUMN offer_ttl_hours INTEGER NOT NULL DEFAULT 48;
+
+ALTER TABLE offers ADD COLUMN expires_at TEXT;
+
+UPDATE offers SET expires_at = datetime(created_at, '+48 hours') WHERE expires_at IS NULL;
+
+CREATE INDEX idx_offers_pending_expiry ON offers(expires_at) WHERE status = 'pending';
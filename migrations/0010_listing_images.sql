@@ -0,0 +1,18 @@
+-- Listings can have several photos. `position` orders them; position 0 is the cover,
+-- which is mirrored into listings.image_url so feeds, carts and orders keep working unchanged.
+
+CREATE TABLE listing_images (
+    id TEXT PRIMARY KEY,
+    listing_id TEXT NOT NULL REFERENCES listings(id) ON DELETE CASCADE,
+    url TEXT NOT NULL,
+    alt TEXT NOT NULL DEFAULT '',
+    position INTEGER NOT NULL,
+    created_at TEXT NOT NULL DEFAULT (datetime('now'))
+);
+
+CREATE INDEX idx_listing_images_listing ON listing_images(listing_id, position);
+
+INSERT INTO listing_images (id, listing_id, url, position)
+SELECT lower(hex(randomblob(16))), id, image_url, 0
+FROM listings
+WHERE image_url != '/static/images/placeholder.svg';
@@ -0,0 +1,7 @@
+-- Uploaded photos are stored in two sizes. Thumbnails are used wherever listings are shown
+-- as cards; the cover's thumbnail is mirrored onto listings like the cover itself.
+-- Photos uploaded before this have no thumbnail and fall back to the full image.
+
+ALTER TABLE listing_images ADD COLUMN thumb_url TEXT;
+
+ALTER TABLE listings ADD COLUMN thumb_url TEXT;
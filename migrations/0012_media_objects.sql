@@ -0,0 +1,33 @@
+-- Stored media is content-addressed: one row per stored object, keyed by its hash. Triggers
+-- keep `refcount` in step with the rows that point at an object's URL, so nothing in the
+-- application has to remember to count. Objects at zero are removed by `sweep-media`.
+-- Photos uploaded before this live in static/images and are not tracked.
+
+CREATE TABLE media_objects (
+    key TEXT PRIMARY KEY,
+    url TEXT NOT NULL UNIQUE,
+    content_type TEXT NOT NULL,
+    size_bytes INTEGER NOT NULL,
+    refcount INTEGER NOT NULL DEFAULT 0,
+    touched_at TEXT NOT NULL DEFAULT (datetime('now'))
+);
+
+CREATE INDEX idx_media_unreferenced ON media_objects(touched_at) WHERE refcount <= 0;
+
+CREATE TRIGGER listing_images_media_ref AFTER INSERT ON listing_images BEGIN
+    UPDATE media_objects SET refcount = refcount + 1 WHERE url IN (NEW.url, NEW.thumb_url);
+END;
+
+CREATE TRIGGER listing_images_media_unref AFTER DELETE ON listing_images BEGIN
+    UPDATE media_objects SET refcount = refcount - 1, touched_at = datetime('now')
+    WHERE url IN (OLD.url, OLD.thumb_url);
+END;
+
+CREATE TRIGGER order_items_media_ref AFTER INSERT ON order_items BEGIN
+    UPDATE media_objects SET refcount = refcount + 1 WHERE url = NEW.image_url;
+END;
+
+CREATE TRIGGER order_items_media_unref AFTER DELETE ON order_items BEGIN
+    UPDATE media_objects SET refcount = refcount - 1, touched_at = datetime('now')
+    WHERE url = OLD.image_url;
+END;
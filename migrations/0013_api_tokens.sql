@@ -0,0 +1,16 @@
+-- Personal API tokens for scripts and other non-browser clients. Only a SHA-256 of the
+-- token is kept; `prefix` is the start of the token so people can tell theirs apart.
+-- `scopes` is a space-separated list such as "listings:write messages:read".
+
+CREATE TABLE api_tokens (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    prefix TEXT NOT NULL,
+    scopes TEXT NOT NULL,
+    created_at TEXT NOT NULL DEFAULT (datetime('now')),
+    last_used_at TEXT
+);
+
+CREATE INDEX idx_api_tokens_user ON api_tokens(user_id);
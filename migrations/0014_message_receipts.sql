@@ -0,0 +1,19 @@
+-- Read state per message instead of one `last_read_at` per user and conversation, which
+-- compared timestamps at one-second resolution and miscounted messages sent in the same
+-- second as a read. A row means `user_id` has seen `message_id`.
+
+CREATE TABLE message_receipts (
+    message_id TEXT NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    read_at TEXT NOT NULL DEFAULT (datetime('now')),
+    PRIMARY KEY (message_id, user_id)
+);
+
+-- Carry over what the old timestamps said was read
+INSERT OR IGNORE INTO message_receipts (message_id, user_id, read_at)
+SELECT m.id, r.user_id, r.last_read_at
+FROM message_reads r
+JOIN messages m ON m.conversation_id = r.conversation_id
+WHERE m.sender_id != r.user_id AND m.created_at <= r.last_read_at;
+
+DROP TABLE message_reads;
@@ -0,0 +1,14 @@
+-- A per-conversation message number, 1, 2, 3, ... in the order messages were sent. Cursors
+-- for polling, live streams and paging used `created_at`, which has one-second resolution
+-- and skipped messages sent in the same second as the cursor.
+
+ALTER TABLE messages ADD COLUMN seq INTEGER NOT NULL DEFAULT 0;
+
+-- Number existing messages by time, breaking same-second ties by insertion order
+UPDATE messages SET seq = (
+    SELECT COUNT(*) FROM messages m
+    WHERE m.conversation_id = messages.conversation_id
+    AND (m.created_at < messages.created_at OR (m.created_at = messages.created_at AND m.rowid <= messages.rowid))
+);
+
+CREATE UNIQUE INDEX idx_messages_conversation_seq ON messages(conversation_id, seq);
@@ -0,0 +1,6 @@
+-- Each session carries the CSRF token its forms must echo back. Sessions created from now on
+-- get one from the OS random generator; existing sessions get SQLite's.
+
+ALTER TABLE sessions ADD COLUMN csrf_token TEXT NOT NULL DEFAULT '';
+
+UPDATE sessions SET csrf_token = lower(hex(randomblob(32)));
@@ -0,0 +1,22 @@
+-- Rate limit counters, used when FORGE_RATE_LIMIT_STORE=sqlite (they're kept in memory
+-- otherwise). `key` names the limit and who it applies to, e.g. "login-email:alice@example.com".
+-- Times are unix seconds.
+
+CREATE TABLE rate_limits (
+    key TEXT PRIMARY KEY,
+    count INTEGER NOT NULL,
+    reset_at INTEGER NOT NULL,
+    blocked_until INTEGER NOT NULL
+);
+
+-- Audit trail of every lockout, whichever store holds the counters
+CREATE TABLE lockouts (
+    id TEXT PRIMARY KEY,
+    key TEXT NOT NULL,
+    ip TEXT NOT NULL,
+    failures INTEGER NOT NULL,
+    locked_until TEXT NOT NULL,
+    created_at TEXT NOT NULL DEFAULT (datetime('now'))
+);
+
+CREATE INDEX idx_lockouts_key ON lockouts(key);
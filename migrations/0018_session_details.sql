@@ -0,0 +1,12 @@
+-- What the profile page shows about each signed-in device. `public_id` names the session in
+-- revoke forms, since `id` is the cookie value itself. `last_seen_at` is refreshed at most
+-- once a minute. Sessions from before this migration have no user agent or address.
+
+ALTER TABLE sessions ADD COLUMN public_id TEXT NOT NULL DEFAULT '';
+ALTER TABLE sessions ADD COLUMN last_seen_at TEXT NOT NULL DEFAULT '';
+ALTER TABLE sessions ADD COLUMN user_agent TEXT NOT NULL DEFAULT '';
+ALTER TABLE sessions ADD COLUMN ip TEXT NOT NULL DEFAULT '';
+
+UPDATE sessions SET public_id = lower(hex(randomblob(16))), last_seen_at = created_at;
+
+CREATE INDEX idx_sessions_expires ON sessions(expires_at);
@@ -0,0 +1,25 @@
+-- Password reset links. Only a SHA-256 of the token in the link is kept. A token works once,
+-- until `expires_at`; `used_at` is set when it's redeemed.
+
+CREATE TABLE password_resets (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    token_hash TEXT NOT NULL UNIQUE,
+    created_at TEXT NOT NULL DEFAULT (datetime('now')),
+    expires_at TEXT NOT NULL,
+    used_at TEXT
+);
+
+CREATE INDEX idx_password_resets_user ON password_resets(user_id);
+
+-- Mail the `outbox` mailer "sent", for development and tests. `message` is the full RFC 5322
+-- text, the same as an .eml file.
+
+CREATE TABLE outbox (
+    id TEXT PRIMARY KEY,
+    to_address TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    body TEXT NOT NULL,
+    message TEXT NOT NULL,
+    created_at TEXT NOT NULL DEFAULT (datetime('now'))
+);
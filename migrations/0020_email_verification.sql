@@ -0,0 +1,15 @@
+-- Accounts confirm their email address by following a signed link before they can list,
+-- message sellers or make offers. Accounts from before this migration count as verified.
+-- `secrets` holds keys generated once per database, such as the one verification links are
+-- signed with, so links keep working across restarts and between servers.
+
+ALTER TABLE users ADD COLUMN email_verified_at TEXT;
+
+UPDATE users SET email_verified_at = created_at;
+
+CREATE TABLE secrets (
+    name TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+
+INSERT INTO secrets (name, value) VALUES ('email_verification', lower(hex(randomblob(32))));
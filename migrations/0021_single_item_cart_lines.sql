@@ -0,0 +1,4 @@
+-- Each listing is one item, so a cart line is never more than one of it. Lines saved when
+-- the cart still had a quantity control are brought back to one.
+
+UPDATE cart_items SET quantity = 1 WHERE quantity > 1;
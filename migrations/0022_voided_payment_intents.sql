@@ -0,0 +1,33 @@
+-- Intents still open when their order is cancelled or paid another way are now voided,
+-- so a payment that succeeds on one later is refunded instead of paying the order. SQLite
+-- can't change a CHECK constraint in place, so the table is rebuilt with the new status.
+
+CREATE TABLE payment_intents_new (
+    id TEXT PRIMARY KEY,
+    order_id TEXT NOT NULL REFERENCES orders(id) ON DELETE CASCADE,
+    provider TEXT NOT NULL,
+    provider_ref TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'requires_action'
+        CHECK (status IN ('requires_action', 'succeeded', 'failed', 'refunded', 'voided')),
+    amount_cents INTEGER NOT NULL,
+    currency TEXT NOT NULL DEFAULT 'USD',
+    -- What the buyer needs to see to pay, e.g. the seller's Venmo handle
+    instructions TEXT NOT NULL DEFAULT '',
+    created_at TEXT NOT NULL DEFAULT (datetime('now')),
+    updated_at TEXT NOT NULL DEFAULT (datetime('now'))
+);
+
+INSERT INTO payment_intents_new
+SELECT id, order_id, provider, provider_ref, status, amount_cents, currency, instructions, created_at, updated_at
+FROM payment_intents;
+
+DROP TABLE payment_intents;
+ALTER TABLE payment_intents_new RENAME TO payment_intents;
+
+CREATE INDEX idx_payment_intents_order ON payment_intents(order_id);
+CREATE UNIQUE INDEX idx_payment_intents_ref ON payment_intents(provider, provider_ref);
+
+-- Open intents on orders that already stopped waiting for payment
+UPDATE payment_intents SET status = 'voided'
+WHERE status = 'requires_action'
+  AND order_id IN (SELECT id FROM orders WHERE status != 'pending_payment');
@@ -1,9 +1,13 @@
 use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
 use argon2::password_hash::SaltString;
+use hmac::{Hmac, Mac};
+use axum::http::{header, HeaderMap};
 use axum_extra::extract::cookie::{Cookie, CookieJar};
 use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
 use crate::db::{self, Db};
-use crate::models::User;
+use crate::models::{CartMerge, CartOwner, User};
 
 pub const SESSION_COOKIE: &str = "forge_session";
 pub const CART_COOKIE: &str = "forge_cart";
@@ -24,11 +28,129 @@ pub fn verify_password(password: &str, hash: &str) -> bool {
     Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok()
 }
 
+/// 32 random bytes, hex-encoded. Used for CSRF tokens, reset links and API tokens.
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// SHA-256 of a token, hex-encoded, for tokens whose plain value is never stored.
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Longest `User-Agent` kept on a session
+const MAX_USER_AGENT_CHARS: usize = 256;
+
+/// The browser or client named by the request's `User-Agent`, for the sessions list.
+pub fn user_agent(headers: &HeaderMap) -> String {
+    headers.get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .chars()
+        .take(MAX_USER_AGENT_CHARS)
+        .collect()
+}
+
+// === Password resets ===
+
+/// How long a reset link works. Its token is a `random_token`; only the `hash_token` is stored.
+pub const RESET_TOKEN_MINUTES: i64 = 60;
+
+// === Email verification ===
+
+/// How long a verification link works
+pub const VERIFY_LINK_HOURS: i64 = 48;
+
+/// Whether `email` looks deliverable enough to send a verification link to.
+pub fn valid_email(email: &str) -> bool {
+    email.parse::<lettre::Address>().is_ok_and(|a| a.domain().contains('.'))
+}
+
+// Signs the user and address together, so a link stops working if the address changes
+fn verification_mac(db: &Db, user: &User, expires: i64) -> Hmac<Sha256> {
+    let key = db::get_secret(db, "email_verification");
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(format!("{}\n{}\n{}", user.id, user.email, expires).as_bytes());
+    mac
+}
+
+/// The path and query of a link that verifies the user's address for `VERIFY_LINK_HOURS`.
+pub fn email_verification_path(db: &Db, user: &User) -> String {
+    let expires = chrono::Utc::now().timestamp() + VERIFY_LINK_HOURS * 60 * 60;
+    let sig = hex::encode(verification_mac(db, user, expires).finalize().into_bytes());
+    format!("/verify-email?user={}&expires={}&sig={}", user.id, expires, sig)
+}
+
+/// The user a verification link is for, if its signature holds and it hasn't expired.
+pub fn check_email_verification(db: &Db, user_id: &str, expires: i64, sig: &str) -> Result<User, &'static str> {
+    let invalid = "This verification link isn't valid. Make sure you copied all of it.";
+    let user = db::get_user_by_id(db, user_id).ok_or(invalid)?;
+    let sig = hex::decode(sig).map_err(|_| invalid)?;
+    verification_mac(db, &user, expires).verify_slice(&sig).map_err(|_| invalid)?;
+    if expires < chrono::Utc::now().timestamp() {
+        return Err("This verification link has expired. Log in and send yourself a new one from your profile.");
+    }
+    Ok(user)
+}
+
+// === API tokens ===
+
+/// Personal tokens start with this, which is how bearer auth tells them from session tokens.
+pub const API_TOKEN_PREFIX: &str = "fpat_";
+
+/// Scopes a personal token can be granted, with the label shown on the profile page.
+/// Reading listings needs no token at all.
+pub const API_SCOPES: &[(&str, &str)] = &[
+    ("listings:write", "Create, edit and delete your listings"),
+    ("messages:read", "Read your conversations"),
+    ("messages:write", "Start conversations and send messages"),
+    ("offers:write", "Make and answer offers"),
+    ("profile:read", "Read your profile"),
+    ("profile:write", "Edit your profile"),
+];
+
+/// A fresh personal token: the prefix plus a `random_token`. Only its `hash_token` is stored.
+pub fn generate_api_token() -> String {
+    format!("{}{}", API_TOKEN_PREFIX, random_token())
+}
+
+/// Who a bearer token belongs to. Session tokens (from the API login) carry every scope,
+/// so `scopes` is `None`; personal tokens carry only what they were granted.
+pub fn get_bearer_user(db: &Db, token: &str) -> Option<(User, Option<Vec<String>>)> {
+    if token.starts_with(API_TOKEN_PREFIX) {
+        db::get_api_token_user(db, &hash_token(token)).map(|(user, scopes)| (user, Some(scopes)))
+    } else {
+        db::get_session_user(db, token).map(|user| (user, None))
+    }
+}
+
 pub fn get_current_user(db: &Db, jar: &CookieJar) -> Option<User> {
     let session_id = jar.get(SESSION_COOKIE)?.value().to_string();
     db::get_session_user(db, &session_id)
 }
 
+/// The cart the current visitor is shopping with: their own once signed in, otherwise the
+/// guest cart named by the `forge_cart` cookie (if any).
+pub fn get_cart_owner(db: &Db, jar: &CookieJar) -> Option<CartOwner> {
+    if let Some(user) = get_current_user(db, jar) {
+        return Some(CartOwner::User(user.id));
+    }
+    jar.get(CART_COOKIE).map(|c| CartOwner::Guest(c.value().to_string()))
+}
+
+/// Moves a guest cart into the user's cart after login or registration and forgets the guest token.
+pub fn claim_guest_cart(db: &Db, jar: CookieJar, user_id: &str) -> (CookieJar, CartMerge) {
+    match jar.get(CART_COOKIE).map(|c| c.value().to_string()) {
+        Some(token) => {
+            let merge = db::merge_guest_cart(db, &token, user_id);
+            (jar.remove(Cookie::build(CART_COOKIE).path("/")), merge)
+        }
+        None => (jar, CartMerge::default()),
+    }
+}
+
 pub fn get_cart_token(jar: &CookieJar) -> (String, Option<CookieJar>) {
     if let Some(c) = jar.get(CART_COOKIE) {
         (c.value().to_string(), None)
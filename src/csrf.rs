@@ -0,0 +1,216 @@
+use axum::body::{to_bytes, Body, Bytes};
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderMap, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use futures_util::{stream, StreamExt};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tera::Tera;
+use crate::auth::{self, SESSION_COOKIE};
+use crate::db::{self, Db};
+
+// === CSRF protection ===
+//
+// Every POST (or other unsafe method) from a browser has to echo back a token that a
+// cross-site page can't read. Signed-in visitors use the token stored on their session row;
+// guests get one in the `forge_csrf` cookie. `protect` makes the token available to
+// templates as `csrf_token()` while the request is handled, so forms include it with
+//
+//     <input type="hidden" name="csrf_token" value="{{ csrf_token() }}">
+//
+// Scripts can send it in an `X-CSRF-Token` header instead. Multipart forms must make the
+// token their first field: only that much of the body is read here, and the rest streams
+// on to the handler untouched.
+//
+// WebSocket upgrades are GETs, so instead of a token they must come from a page with this
+// site's `Origin` (`same_origin`).
+//
+// The JSON API authenticates with bearer tokens rather than cookies, and payment webhooks
+// come from the provider, so neither is checked.
+
+pub const CSRF_COOKIE: &str = "forge_csrf";
+pub const CSRF_FIELD: &str = "csrf_token";
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+/// Paths that aren't cookie-authenticated and render no forms, so get no token at all.
+const EXEMPT_PREFIXES: &[&str] = &["/api/", "/payments/webhook/"];
+
+/// Largest urlencoded form body read to find the token, the same as axum's `Form` default.
+const MAX_FORM_BYTES: usize = 2 * 1024 * 1024;
+
+/// How much of a multipart body is read looking for the token field at its start.
+const MAX_MULTIPART_PEEK_BYTES: usize = 8 * 1024;
+
+type AppState = (Db, Arc<Tera>);
+
+tokio::task_local! {
+    static TOKEN: String;
+}
+
+/// Adds the `csrf_token()` template function. Call once on the `Tera` passed to
+/// `build_router`.
+pub fn register(tera: &mut Tera) {
+    tera.register_function("csrf_token", |_: &HashMap<String, tera::Value>| {
+        TOKEN.try_with(|token| tera::Value::String(token.clone()))
+            .map_err(|_| tera::Error::msg("csrf_token() used outside a request"))
+    });
+}
+
+pub async fn protect(
+    State((db, _tera)): State<AppState>,
+    jar: CookieJar,
+    request: Request,
+    next: Next,
+) -> Response {
+    if is_exempt(request.uri().path()) {
+        return next.run(request).await;
+    }
+    let session_token = jar.get(SESSION_COOKIE).and_then(|c| db::get_session_csrf_token(&db, c.value()));
+    let (token, jar) = match session_token {
+        Some(token) => (token, jar),
+        None => guest_token(jar),
+    };
+
+    let request = if needs_check(&request) {
+        let (provided, request) = match provided_token(request).await {
+            Ok(found) => found,
+            Err(response) => return response,
+        };
+        if !provided.is_some_and(|p| tokens_match(&p, &token)) {
+            return (StatusCode::FORBIDDEN, "This form has expired. Go back, reload the page and try again.").into_response();
+        }
+        request
+    } else {
+        request
+    };
+
+    let response = TOKEN.scope(token, next.run(request)).await;
+    (jar, response).into_response()
+}
+
+/// The guest's token, setting the cookie if they don't have one yet.
+fn guest_token(jar: CookieJar) -> (String, CookieJar) {
+    if let Some(c) = jar.get(CSRF_COOKIE) {
+        return (c.value().to_string(), jar);
+    }
+    let token = auth::random_token();
+    let cookie = Cookie::build((CSRF_COOKIE, token.clone()))
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .build();
+    (token, jar.add(cookie))
+}
+
+/// Whether the request's `Origin` is this site: `FORGE_BASE_URL`, or the host the request was
+/// sent to. For WebSocket handshakes, which carry the session cookie but no token; browsers
+/// always send `Origin` on them, so a missing one fails.
+pub fn same_origin(headers: &HeaderMap) -> bool {
+    let Some(origin) = headers.get(header::ORIGIN).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    if origin == crate::mailer::base_url() {
+        return true;
+    }
+    let origin_host = origin.strip_prefix("https://").or_else(|| origin.strip_prefix("http://"));
+    let host = headers.get(header::HOST).and_then(|v| v.to_str().ok());
+    matches!((origin_host, host), (Some(o), Some(h)) if o.eq_ignore_ascii_case(h))
+}
+
+fn is_exempt(path: &str) -> bool {
+    EXEMPT_PREFIXES.iter().any(|prefix| path.starts_with(prefix))
+}
+
+fn needs_check(request: &Request) -> bool {
+    !matches!(*request.method(), Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE)
+}
+
+/// The token sent with the request, from the header, the urlencoded body or the first field
+/// of a multipart body. Whatever was read of the body is put back for the handler.
+async fn provided_token(request: Request) -> Result<(Option<String>, Request), Response> {
+    if let Some(token) = request.headers().get(CSRF_HEADER).and_then(|v| v.to_str().ok()) {
+        return Ok((Some(token.to_string()), request));
+    }
+    let content_type = request.headers().get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+
+    if content_type.starts_with("application/x-www-form-urlencoded") {
+        let (parts, body) = request.into_parts();
+        let bytes = to_bytes(body, MAX_FORM_BYTES).await
+            .map_err(|_| StatusCode::PAYLOAD_TOO_LARGE.into_response())?;
+        let token = field(&bytes);
+        Ok((token, Request::from_parts(parts, Body::from(bytes))))
+    } else if content_type.starts_with("multipart/form-data") {
+        let Some(boundary) = multipart_boundary(&content_type) else {
+            return Ok((None, request));
+        };
+        let (parts, body) = request.into_parts();
+        let mut stream = body.into_data_stream();
+        let mut head = Vec::new();
+        let token = loop {
+            let peek = &head[..head.len().min(MAX_MULTIPART_PEEK_BYTES)];
+            if let Some(found) = first_part_token(peek, &boundary) {
+                break found;
+            }
+            if peek.len() == MAX_MULTIPART_PEEK_BYTES {
+                break None;
+            }
+            match stream.next().await {
+                Some(Ok(chunk)) => head.extend_from_slice(&chunk),
+                Some(Err(_)) => return Err(StatusCode::BAD_REQUEST.into_response()),
+                None => break None,
+            }
+        };
+        let head = stream::once(async move { Ok::<_, axum::Error>(Bytes::from(head)) });
+        Ok((token, Request::from_parts(parts, Body::from_stream(head.chain(stream)))))
+    } else {
+        Ok((None, request))
+    }
+}
+
+fn field(urlencoded: &[u8]) -> Option<String> {
+    form_urlencoded::parse(urlencoded)
+        .find(|(name, _)| name == CSRF_FIELD)
+        .map(|(_, value)| value.into_owned())
+}
+
+fn multipart_boundary(content_type: &str) -> Option<String> {
+    content_type.split(';')
+        .find_map(|param| param.trim().strip_prefix("boundary="))
+        .map(|b| b.trim_matches('"').to_string())
+        .filter(|b| !b.is_empty())
+}
+
+/// The token, if the first part of a multipart body is the token field. `None` while `head`
+/// doesn't yet hold the whole first part.
+fn first_part_token(head: &[u8], boundary: &str) -> Option<Option<String>> {
+    let opening = format!("--{}\r\n", boundary);
+    if head.len() < opening.len() {
+        return None;
+    }
+    let Some(part) = head.strip_prefix(opening.as_bytes()) else {
+        return Some(None);
+    };
+    let headers_end = find(part, b"\r\n\r\n")?;
+    let value = &part[headers_end + 4..];
+    let value_end = find(value, format!("\r\n--{}", boundary).as_bytes())?;
+    let name = format!("; name=\"{}\"", CSRF_FIELD);
+    let is_token = String::from_utf8_lossy(&part[..headers_end]).lines().any(|line| {
+        line.to_ascii_lowercase().starts_with("content-disposition: form-data") && line.contains(&name)
+    });
+    Some(is_token.then(|| String::from_utf8_lossy(&value[..value_end]).into_owned()))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+// Compares every byte so the time taken doesn't hint at how much matched
+fn tokens_match(provided: &str, expected: &str) -> bool {
+    provided.len() == expected.len()
+        && provided.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
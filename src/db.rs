@@ -1,6 +1,8 @@
 use rusqlite::{Connection, params};
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use crate::models::*;
+use crate::money::{Currency, Money};
 
 pub type Db = Arc<Mutex<Connection>>;
 
@@ -22,76 +24,15 @@ pub fn init_db_with_path(path: &str) -> Db {
 }
 
 pub fn run_migrations(db: &Db) {
-    let conn = db.lock().unwrap();
-    conn.execute_batch("
-        CREATE TABLE IF NOT EXISTS users (
-            id TEXT PRIMARY KEY,
-            email TEXT UNIQUE NOT NULL,
-            name TEXT NOT NULL,
-            password_hash TEXT NOT NULL,
-            location TEXT NOT NULL DEFAULT '',
-            avatar_url TEXT NOT NULL DEFAULT '',
-            payment_info TEXT NOT NULL DEFAULT '',
-            bio TEXT NOT NULL DEFAULT '',
-            created_at TEXT NOT NULL DEFAULT (datetime('now'))
-        );
-
-        CREATE TABLE IF NOT EXISTS listings (
-            id TEXT PRIMARY KEY,
-            seller_id TEXT NOT NULL REFERENCES users(id),
-            title TEXT NOT NULL,
-            description TEXT NOT NULL,
-            price REAL NOT NULL,
-            category TEXT NOT NULL,
-            condition TEXT NOT NULL DEFAULT 'Good',
-            location TEXT NOT NULL DEFAULT '',
-            image_url TEXT NOT NULL DEFAULT '/static/images/placeholder.svg',
-            status TEXT NOT NULL DEFAULT 'active',
-            created_at TEXT NOT NULL DEFAULT (datetime('now'))
-        );
-
-        CREATE TABLE IF NOT EXISTS conversations (
-            id TEXT PRIMARY KEY,
-            listing_id TEXT NOT NULL REFERENCES listings(id),
-            buyer_id TEXT NOT NULL REFERENCES users(id),
-            seller_id TEXT NOT NULL REFERENCES users(id),
-            created_at TEXT NOT NULL DEFAULT (datetime('now')),
-            UNIQUE(listing_id, buyer_id)
-        );
-
-        CREATE TABLE IF NOT EXISTS messages (
-            id TEXT PRIMARY KEY,
-            conversation_id TEXT NOT NULL REFERENCES conversations(id),
-            sender_id TEXT NOT NULL REFERENCES users(id),
-            content TEXT NOT NULL,
-            created_at TEXT NOT NULL DEFAULT (datetime('now'))
-        );
-
-        CREATE TABLE IF NOT EXISTS offers (
-            id TEXT PRIMARY KEY,
-            listing_id TEXT NOT NULL REFERENCES listings(id),
-            conversation_id TEXT NOT NULL REFERENCES conversations(id),
-            buyer_id TEXT NOT NULL REFERENCES users(id),
-            amount REAL NOT NULL,
-            status TEXT NOT NULL DEFAULT 'pending',
-            created_at TEXT NOT NULL DEFAULT (datetime('now'))
-        );
-
-        CREATE TABLE IF NOT EXISTS message_reads (
-            user_id TEXT NOT NULL REFERENCES users(id),
-            conversation_id TEXT NOT NULL REFERENCES conversations(id),
-            last_read_at TEXT NOT NULL DEFAULT (datetime('now')),
-            PRIMARY KEY (user_id, conversation_id)
-        );
-
-        CREATE INDEX IF NOT EXISTS idx_listings_seller ON listings(seller_id);
-        CREATE INDEX IF NOT EXISTS idx_listings_category ON listings(category);
-        CREATE INDEX IF NOT EXISTS idx_listings_status ON listings(status);
-        CREATE INDEX IF NOT EXISTS idx_conversations_buyer ON conversations(buyer_id);
-        CREATE INDEX IF NOT EXISTS idx_conversations_seller ON conversations(seller_id);
-        CREATE INDEX IF NOT EXISTS idx_messages_conversation ON messages(conversation_id);
-        CREATE INDEX IF NOT EXISTS idx_offers_listing ON offers(listing_id);
-    ").expect("Failed to run migrations");
+    let mut conn = db.lock().unwrap();
+    match crate::migrations::run(&mut conn) {
+        Ok(applied) => {
+            for m in applied {
+                println!("📦 Applied migration {:04}_{}", m.version, m.name);
+            }
+        }
+        Err(e) => panic!("Failed to run migrations: {}", e),
+    }
 }
 
 fn seed_data(db: &Db) {
@@ -111,7 +52,8 @@ fn seed_data(db: &Db) {
         let id = uuid::Uuid::new_v4().to_string();
         let hash = crate::auth::hash_password("password123");
         conn.execute(
-            "INSERT INTO users (id, email, name, password_hash, location, payment_info, bio) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            "INSERT INTO users (id, email, name, password_hash, location, payment_info, bio, email_verified_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, datetime('now'))",
             params![id, email, name, hash, location, payment, bio],
         ).unwrap();
         user_ids.push(id);
@@ -119,45 +61,57 @@ fn seed_data(db: &Db) {
 
     // Create demo listings
     let listings = vec![
-        (&user_ids[0], "Vintage Le Creuset Dutch Oven", "5.5 qt in flame orange. Some patina on the exterior but the enamel interior is perfect. These last forever.", 85.00, "Home & Kitchen", "Good", "Austin, TX"),
-        (&user_ids[0], "Handmade Ceramic Mug Set (4)", "Hand-thrown stoneware mugs with a reactive glaze. Each one is unique. Holds about 12oz.", 48.00, "Home & Kitchen", "Like New", "Austin, TX"),
-        (&user_ids[1], "Lie-Nielsen No. 4 Smoothing Plane", "Bronze body, A2 steel blade. Used on maybe 3 projects. Incredible tool, just downsizing the shop.", 295.00, "Tools", "Like New", "Portland, OR"),
-        (&user_ids[1], "Japanese Pull Saw Set", "Ryoba and Dozuki pair. Razor sharp, barely used. Great for fine joinery work.", 65.00, "Tools", "Like New", "Portland, OR"),
-        (&user_ids[1], "Waxed Canvas Shop Apron", "Heavy-duty waxed cotton with leather straps. Has character — some wax wear and a few stains.", 35.00, "Apparel", "Good", "Portland, OR"),
-        (&user_ids[2], "Herman Miller Aeron Chair", "Size B, fully loaded with PostureFit. Some wear on the mesh but fully functional. Pickup only.", 450.00, "Furniture", "Good", "Denver, CO"),
-        (&user_ids[2], "Wool Pendleton Blanket", "Queen size, Rob Roy tartan pattern. 100% virgin wool. Washed once, stored in cedar chest.", 120.00, "Home & Kitchen", "Like New", "Denver, CO"),
-        (&user_ids[0], "1960s Brass Desk Lamp", "Adjustable arm, original patina. Rewired with a new cloth cord for safety. Works perfectly.", 75.00, "Lighting", "Good", "Austin, TX"),
-        (&user_ids[2], "Leuchtturm1917 Notebooks (5 pack)", "A5 dot grid, assorted colors. Bought too many. Still sealed in plastic.", 55.00, "Stationery", "New", "Denver, CO"),
-        (&user_ids[1], "Cast Iron Skillet 12\" — Lodge", "Freshly re-seasoned. Smooth cooking surface from years of use. Better than anything new.", 40.00, "Home & Kitchen", "Good", "Portland, OR"),
-        (&user_ids[0], "Merino Wool Beanie — Hand Knit", "100% merino, charcoal gray. Made this myself. Fits most heads comfortably.", 28.00, "Apparel", "New", "Austin, TX"),
-        (&user_ids[2], "Mid-Century Teak Bookshelf", "Danish modern style, 5 shelves. Some minor scratches on top. Solid teak, not veneer.", 280.00, "Furniture", "Good", "Denver, CO"),
+        (&user_ids[0], "Vintage Le Creuset Dutch Oven", "5.5 qt in flame orange. Some patina on the exterior but the enamel interior is perfect. These last forever.", 8500, "Home & Kitchen", "Good", "Austin, TX"),
+        (&user_ids[0], "Handmade Ceramic Mug Set (4)", "Hand-thrown stoneware mugs with a reactive glaze. Each one is unique. Holds about 12oz.", 4800, "Home & Kitchen", "Like New", "Austin, TX"),
+        (&user_ids[1], "Lie-Nielsen No. 4 Smoothing Plane", "Bronze body, A2 steel blade. Used on maybe 3 projects. Incredible tool, just downsizing the shop.", 29500, "Tools", "Like New", "Portland, OR"),
+        (&user_ids[1], "Japanese Pull Saw Set", "Ryoba and Dozuki pair. Razor sharp, barely used. Great for fine joinery work.", 6500, "Tools", "Like New", "Portland, OR"),
+        (&user_ids[1], "Waxed Canvas Shop Apron", "Heavy-duty waxed cotton with leather straps. Has character — some wax wear and a few stains.", 3500, "Apparel", "Good", "Portland, OR"),
+        (&user_ids[2], "Herman Miller Aeron Chair", "Size B, fully loaded with PostureFit. Some wear on the mesh but fully functional. Pickup only.", 45000, "Furniture", "Good", "Denver, CO"),
+        (&user_ids[2], "Wool Pendleton Blanket", "Queen size, Rob Roy tartan pattern. 100% virgin wool. Washed once, stored in cedar chest.", 12000, "Home & Kitchen", "Like New", "Denver, CO"),
+        (&user_ids[0], "1960s Brass Desk Lamp", "Adjustable arm, original patina. Rewired with a new cloth cord for safety. Works perfectly.", 7500, "Lighting", "Good", "Austin, TX"),
+        (&user_ids[2], "Leuchtturm1917 Notebooks (5 pack)", "A5 dot grid, assorted colors. Bought too many. Still sealed in plastic.", 5500, "Stationery", "New", "Denver, CO"),
+        (&user_ids[1], "Cast Iron Skillet 12\" — Lodge", "Freshly re-seasoned. Smooth cooking surface from years of use. Better than anything new.", 4000, "Home & Kitchen", "Good", "Portland, OR"),
+        (&user_ids[0], "Merino Wool Beanie — Hand Knit", "100% merino, charcoal gray. Made this myself. Fits most heads comfortably.", 2800, "Apparel", "New", "Austin, TX"),
+        (&user_ids[2], "Mid-Century Teak Bookshelf", "Danish modern style, 5 shelves. Some minor scratches on top. Solid teak, not veneer.", 28000, "Furniture", "Good", "Denver, CO"),
     ];
 
-    for (seller_id, title, desc, price, category, condition, location) in listings {
+    for (seller_id, title, desc, price_cents, category, condition, location) in listings {
         let id = uuid::Uuid::new_v4().to_string();
         conn.execute(
-            "INSERT INTO listings (id, seller_id, title, description, price, category, condition, location, image_url) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
-            params![id, seller_id, title, desc, price, category, condition, location, "/static/images/placeholder.svg"],
+            "INSERT INTO listings (id, seller_id, title, description, price_cents, category, condition, location, image_url) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![id, seller_id, title, desc, price_cents, category, condition, location, "/static/images/placeholder.svg"],
         ).unwrap();
     }
 }
 
 // === Listing queries ===
 
+const LISTING_COLUMNS: &str = "l.id, l.seller_id, u.name, l.title, l.description, l.price_cents, l.currency, l.category, l.condition, l.location, l.image_url, l.status, l.created_at, COALESCE(l.thumb_url, l.image_url)";
+
+fn listing_from_row(row: &rusqlite::Row) -> rusqlite::Result<Listing> {
+    Ok(Listing {
+        id: row.get(0)?, seller_id: row.get(1)?, seller_name: row.get(2)?,
+        title: row.get(3)?, description: row.get(4)?, price: Money::new(row.get(5)?, row.get(6)?),
+        category: row.get(7)?, condition: row.get(8)?, location: row.get(9)?,
+        image_url: row.get(10)?, status: row.get(11)?, created_at: row.get(12)?,
+        thumb_url: row.get(13)?,
+    })
+}
+
 pub fn get_listings(db: &Db, query: &SearchQuery) -> Vec<Listing> {
     let conn = db.lock().unwrap();
-    let mut sql = String::from(
-        "SELECT l.id, l.seller_id, u.name, l.title, l.description, l.price, l.category, l.condition, l.location, l.image_url, l.status, l.created_at
-         FROM listings l JOIN users u ON l.seller_id = u.id WHERE l.status = 'active'"
-    );
+    let fts = query.q.as_deref().and_then(fts_match_query);
+    let mut sql = format!("SELECT {} FROM listings l JOIN users u ON l.seller_id = u.id", LISTING_COLUMNS);
+    if fts.is_some() {
+        sql.push_str(" JOIN listings_fts ON listings_fts.listing_id = l.id");
+    }
+    sql.push_str(" WHERE l.status = 'active'");
     let mut param_values: Vec<String> = Vec::new();
 
-    if let Some(q) = &query.q {
-        if !q.is_empty() {
-            let idx = param_values.len() + 1;
-            sql.push_str(&format!(" AND (l.title LIKE '%' || ?{} || '%' OR l.description LIKE '%' || ?{} || '%')", idx, idx));
-            param_values.push(q.clone());
-        }
+    if let Some(m) = &fts {
+        let idx = param_values.len() + 1;
+        sql.push_str(&format!(" AND listings_fts MATCH ?{}", idx));
+        param_values.push(m.clone());
     }
     if let Some(cat) = &query.category {
         if !cat.is_empty() {
@@ -174,24 +128,26 @@ pub fn get_listings(db: &Db, query: &SearchQuery) -> Vec<Listing> {
         }
     }
     if let Some(min) = &query.min_price {
-        if let Ok(v) = min.parse::<f64>() {
+        if let Ok(v) = Money::parse(min, Currency::USD) {
             let idx = param_values.len() + 1;
-            sql.push_str(&format!(" AND l.price >= ?{}", idx));
-            param_values.push(v.to_string());
+            sql.push_str(&format!(" AND l.price_cents >= ?{}", idx));
+            param_values.push(v.cents.to_string());
         }
     }
     if let Some(max) = &query.max_price {
-        if let Ok(v) = max.parse::<f64>() {
+        if let Ok(v) = Money::parse(max, Currency::USD) {
             let idx = param_values.len() + 1;
-            sql.push_str(&format!(" AND l.price <= ?{}", idx));
-            param_values.push(v.to_string());
+            sql.push_str(&format!(" AND l.price_cents <= ?{}", idx));
+            param_values.push(v.cents.to_string());
         }
     }
 
     let order = match query.sort.as_deref() {
-        Some("price_asc") => "l.price ASC",
-        Some("price_desc") => "l.price DESC",
+        Some("price_asc") => "l.price_cents ASC",
+        Some("price_desc") => "l.price_cents DESC",
         Some("oldest") => "l.created_at ASC",
+        // Title hits count four times as much as description hits; listing_id is unindexed
+        Some("relevance") if fts.is_some() => "bm25(listings_fts, 0.0, 4.0, 1.0) ASC, l.created_at DESC",
         _ => "l.created_at DESC",
     };
     sql.push_str(&format!(" ORDER BY {}", order));
@@ -199,72 +155,248 @@ pub fn get_listings(db: &Db, query: &SearchQuery) -> Vec<Listing> {
 
     let mut stmt = conn.prepare(&sql).unwrap();
     let params_refs: Vec<&dyn rusqlite::types::ToSql> = param_values.iter().map(|s| s as &dyn rusqlite::types::ToSql).collect();
-    stmt.query_map(params_refs.as_slice(), |row| {
-        Ok(Listing {
-            id: row.get(0)?, seller_id: row.get(1)?, seller_name: row.get(2)?,
-            title: row.get(3)?, description: row.get(4)?, price: row.get(5)?,
-            category: row.get(6)?, condition: row.get(7)?, location: row.get(8)?,
-            image_url: row.get(9)?, status: row.get(10)?, created_at: row.get(11)?,
-        })
-    }).unwrap().filter_map(|r| r.ok()).collect()
+    stmt.query_map(params_refs.as_slice(), listing_from_row).unwrap().filter_map(|r| r.ok()).collect()
+}
+
+/// Turns free-text search input into an FTS5 MATCH expression.
+///
+/// Every bare word must match (`oak desk`), `"double quotes"` match an exact phrase and a
+/// trailing `*` matches a prefix (`teak*`). Each term is re-quoted, so FTS5 operators and
+/// punctuation typed by the user are treated as plain text rather than query syntax.
+fn fts_match_query(input: &str) -> Option<String> {
+    fn quote(term: &str) -> Option<String> {
+        if !term.chars().any(char::is_alphanumeric) {
+            return None;
+        }
+        Some(format!("\"{}\"", term.replace('"', "")))
+    }
+
+    let mut terms = Vec::new();
+    for (i, chunk) in input.split('"').enumerate() {
+        if i % 2 == 1 {
+            // Inside a pair of quotes: one phrase
+            terms.extend(quote(chunk.trim()));
+            continue;
+        }
+        for word in chunk.split_whitespace() {
+            match word.strip_suffix('*') {
+                Some(stem) => terms.extend(quote(stem.trim_end_matches('*')).map(|t| t + "*")),
+                None => terms.extend(quote(word)),
+            }
+        }
+    }
+    if terms.is_empty() { None } else { Some(terms.join(" ")) }
 }
 
 pub fn get_listing(db: &Db, id: &str) -> Option<Listing> {
     let conn = db.lock().unwrap();
     conn.query_row(
-        "SELECT l.id, l.seller_id, u.name, l.title, l.description, l.price, l.category, l.condition, l.location, l.image_url, l.status, l.created_at
-         FROM listings l JOIN users u ON l.seller_id = u.id WHERE l.id = ?1",
+        &format!("SELECT {} FROM listings l JOIN users u ON l.seller_id = u.id WHERE l.id = ?1", LISTING_COLUMNS),
         params![id],
-        |row| Ok(Listing {
-            id: row.get(0)?, seller_id: row.get(1)?, seller_name: row.get(2)?,
-            title: row.get(3)?, description: row.get(4)?, price: row.get(5)?,
-            category: row.get(6)?, condition: row.get(7)?, location: row.get(8)?,
-            image_url: row.get(9)?, status: row.get(10)?, created_at: row.get(11)?,
-        })
+        listing_from_row,
     ).ok()
 }
 
+/// The currency a listing is priced in, which offers on it are made in too.
+pub fn get_listing_currency(db: &Db, id: &str) -> Option<Currency> {
+    let conn = db.lock().unwrap();
+    conn.query_row("SELECT currency FROM listings WHERE id = ?1", params![id], |row| row.get(0)).ok()
+}
+
 pub fn get_user_listings(db: &Db, user_id: &str) -> Vec<Listing> {
     let conn = db.lock().unwrap();
-    let mut stmt = conn.prepare(
-        "SELECT l.id, l.seller_id, u.name, l.title, l.description, l.price, l.category, l.condition, l.location, l.image_url, l.status, l.created_at
-         FROM listings l JOIN users u ON l.seller_id = u.id WHERE l.seller_id = ?1 ORDER BY l.created_at DESC"
-    ).unwrap();
-    stmt.query_map(params![user_id], |row| {
-        Ok(Listing {
-            id: row.get(0)?, seller_id: row.get(1)?, seller_name: row.get(2)?,
-            title: row.get(3)?, description: row.get(4)?, price: row.get(5)?,
-            category: row.get(6)?, condition: row.get(7)?, location: row.get(8)?,
-            image_url: row.get(9)?, status: row.get(10)?, created_at: row.get(11)?,
-        })
-    }).unwrap().filter_map(|r| r.ok()).collect()
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM listings l JOIN users u ON l.seller_id = u.id WHERE l.seller_id = ?1 ORDER BY l.created_at DESC",
+        LISTING_COLUMNS
+    )).unwrap();
+    stmt.query_map(params![user_id], listing_from_row).unwrap().filter_map(|r| r.ok()).collect()
 }
 
-pub fn create_listing(db: &Db, seller_id: &str, form: &ListingForm, image_url: &str) -> String {
+pub fn create_listing(db: &Db, seller_id: &str, form: &ListingForm, price: Money, images: &[UploadedImage]) -> String {
     let conn = db.lock().unwrap();
     let id = uuid::Uuid::new_v4().to_string();
-    let price: f64 = form.price.parse().unwrap_or(0.0);
     conn.execute(
-        "INSERT INTO listings (id, seller_id, title, description, price, category, condition, location, image_url) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
-        params![id, seller_id, form.title, form.description, price, form.category, form.condition, form.location, image_url],
+        "INSERT INTO listings (id, seller_id, title, description, price_cents, currency, category, condition, location, image_url) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        params![id, seller_id, form.title, form.description, price.cents, price.currency, form.category, form.condition, form.location, PLACEHOLDER_IMAGE],
     ).unwrap();
+    append_listing_images(&conn, &id, images);
     id
 }
 
-pub fn update_listing(db: &Db, id: &str, seller_id: &str, form: &ListingForm, image_url: Option<&str>) -> bool {
+/// Updates the listing's details. New photos are appended after the existing ones.
+pub fn update_listing(db: &Db, id: &str, seller_id: &str, form: &ListingForm, price: Money, new_images: &[UploadedImage]) -> Result<(), ListingError> {
     let conn = db.lock().unwrap();
-    let price: f64 = form.price.parse().unwrap_or(0.0);
-    let rows = if let Some(url) = image_url {
+    let rows = conn.execute(
+        "UPDATE listings SET title=?1, description=?2, price_cents=?3, currency=?4, category=?5, condition=?6, location=?7 WHERE id=?8 AND seller_id=?9",
+        params![form.title, form.description, price.cents, price.currency, form.category, form.condition, form.location, id, seller_id],
+    ).map_err(|e| ListingError::Failed(e.to_string()))?;
+    if rows == 0 {
+        return Err(ListingError::NotFound);
+    }
+    append_listing_images(&conn, id, new_images);
+    Ok(())
+}
+
+// === Listing images ===
+
+pub const PLACEHOLDER_IMAGE: &str = "/static/images/placeholder.svg";
+pub const MAX_LISTING_IMAGES: usize = 10;
+
+fn append_listing_images(conn: &Connection, listing_id: &str, images: &[UploadedImage]) {
+    if images.is_empty() { return; }
+    let next: i64 = conn.query_row(
+        "SELECT COALESCE(MAX(position) + 1, 0) FROM listing_images WHERE listing_id = ?1",
+        params![listing_id], |r| r.get(0),
+    ).unwrap();
+    for (i, image) in images.iter().enumerate() {
         conn.execute(
-            "UPDATE listings SET title=?1, description=?2, price=?3, category=?4, condition=?5, location=?6, image_url=?7 WHERE id=?8 AND seller_id=?9",
-            params![form.title, form.description, price, form.category, form.condition, form.location, url, id, seller_id],
-        ).unwrap_or(0)
-    } else {
+            "INSERT INTO listing_images (id, listing_id, url, thumb_url, position) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![uuid::Uuid::new_v4().to_string(), listing_id, image.url, image.thumb_url, next + i as i64],
+        ).unwrap();
+    }
+    sync_cover_image(conn, listing_id);
+}
+
+/// Mirrors the first photo into `listings.image_url`/`thumb_url`, which feeds, carts and orders read.
+fn sync_cover_image(conn: &Connection, listing_id: &str) {
+    conn.execute(
+        "UPDATE listings SET
+            image_url = COALESCE((SELECT url FROM listing_images WHERE listing_id = ?1 ORDER BY position LIMIT 1), ?2),
+            thumb_url = (SELECT thumb_url FROM listing_images WHERE listing_id = ?1 ORDER BY position LIMIT 1)
+         WHERE id = ?1",
+        params![listing_id, PLACEHOLDER_IMAGE],
+    ).unwrap();
+}
+
+fn list_listing_images(conn: &Connection, listing_id: &str) -> Vec<ListingImage> {
+    let mut stmt = conn.prepare(
+        "SELECT id, listing_id, url, COALESCE(thumb_url, url), alt, position FROM listing_images WHERE listing_id = ?1 ORDER BY position"
+    ).unwrap();
+    stmt.query_map(params![listing_id], |row| {
+        Ok(ListingImage {
+            id: row.get(0)?, listing_id: row.get(1)?, url: row.get(2)?, thumb_url: row.get(3)?,
+            alt: row.get(4)?, position: row.get(5)?,
+        })
+    }).unwrap().filter_map(|r| r.ok()).collect()
+}
+
+pub fn get_listing_images(db: &Db, listing_id: &str) -> Vec<ListingImage> {
+    let conn = db.lock().unwrap();
+    list_listing_images(&conn, listing_id)
+}
+
+fn owns_listing(conn: &Connection, listing_id: &str, seller_id: &str) -> bool {
+    conn.query_row(
+        "SELECT 1 FROM listings WHERE id = ?1 AND seller_id = ?2",
+        params![listing_id, seller_id], |_| Ok(()),
+    ).is_ok()
+}
+
+/// Rewrites positions as 0..n in the given order and refreshes the cover.
+fn store_image_order(conn: &Connection, listing_id: &str, images: &[ListingImage]) {
+    for (i, image) in images.iter().enumerate() {
         conn.execute(
-            "UPDATE listings SET title=?1, description=?2, price=?3, category=?4, condition=?5, location=?6 WHERE id=?7 AND seller_id=?8",
-            params![form.title, form.description, price, form.category, form.condition, form.location, id, seller_id],
-        ).unwrap_or(0)
+            "UPDATE listing_images SET position = ?1 WHERE id = ?2",
+            params![i as i64, image.id],
+        ).unwrap();
+    }
+    sync_cover_image(conn, listing_id);
+}
+
+/// Moves a photo one step "up" or "down", or to the front ("first") to make it the cover.
+pub fn move_listing_image(db: &Db, listing_id: &str, image_id: &str, seller_id: &str, direction: &str) -> bool {
+    let mut conn = db.lock().unwrap();
+    let tx = conn.transaction().unwrap();
+    if !owns_listing(&tx, listing_id, seller_id) { return false; }
+    let mut images = list_listing_images(&tx, listing_id);
+    let Some(from) = images.iter().position(|i| i.id == image_id) else { return false };
+    let to = match direction {
+        "up" => from.saturating_sub(1),
+        "down" => (from + 1).min(images.len() - 1),
+        "first" => 0,
+        _ => return false,
+    };
+    let image = images.remove(from);
+    images.insert(to, image);
+    store_image_order(&tx, listing_id, &images);
+    tx.commit().unwrap();
+    true
+}
+
+/// Removes a photo. Its files are left for the media sweep once nothing else points at them.
+pub fn delete_listing_image(db: &Db, listing_id: &str, image_id: &str, seller_id: &str) -> bool {
+    let mut conn = db.lock().unwrap();
+    let tx = conn.transaction().unwrap();
+    if !owns_listing(&tx, listing_id, seller_id) { return false; }
+    let mut images = list_listing_images(&tx, listing_id);
+    let Some(index) = images.iter().position(|i| i.id == image_id) else { return false };
+    let removed = images.remove(index);
+    tx.execute("DELETE FROM listing_images WHERE id = ?1", params![removed.id]).unwrap();
+    store_image_order(&tx, listing_id, &images);
+    tx.commit().unwrap();
+    true
+}
+
+// === Media objects ===
+
+/// Marks a stored object as just used. False if there's no such object.
+pub fn touch_media_object(db: &Db, key: &str) -> bool {
+    let conn = db.lock().unwrap();
+    let rows = conn.execute(
+        "UPDATE media_objects SET touched_at = datetime('now') WHERE key = ?1",
+        params![key],
+    ).unwrap_or(0);
+    rows > 0
+}
+
+pub fn insert_media_object(db: &Db, key: &str, url: &str, content_type: &str, size_bytes: i64) {
+    let conn = db.lock().unwrap();
+    conn.execute(
+        "INSERT INTO media_objects (key, url, content_type, size_bytes) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT(key) DO UPDATE SET touched_at = datetime('now')",
+        params![key, url, content_type, size_bytes],
+    ).unwrap();
+}
+
+/// Removes objects that have been unreferenced for `grace_minutes`, calling `delete` for each.
+/// Each row is removed in the same write transaction as its object, so an upload of the same
+/// content waits and then stores the object afresh. A failed delete leaves the row in place
+/// and stops the sweep.
+pub fn sweep_unreferenced_media(
+    db: &Db,
+    grace_minutes: i64,
+    mut delete: impl FnMut(&str) -> Result<(), String>,
+) -> Result<usize, String> {
+    let mut conn = db.lock().unwrap();
+    let cutoff = format!("-{} minutes", grace_minutes);
+    let keys: Vec<String> = {
+        let mut stmt = conn.prepare(
+            "SELECT key FROM media_objects WHERE refcount <= 0 AND touched_at < datetime('now', ?1)"
+        ).unwrap();
+        stmt.query_map(params![cutoff], |r| r.get(0)).unwrap().filter_map(|r| r.ok()).collect()
     };
+    let mut removed = 0;
+    for key in keys {
+        let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate).unwrap();
+        let claimed = tx.execute(
+            "DELETE FROM media_objects WHERE key = ?1 AND refcount <= 0 AND touched_at < datetime('now', ?2)",
+            params![key, cutoff],
+        ).unwrap();
+        if claimed == 0 { continue; }
+        delete(&key)?;
+        tx.commit().unwrap();
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+pub fn set_listing_image_alt(db: &Db, listing_id: &str, image_id: &str, seller_id: &str, alt: &str) -> bool {
+    let conn = db.lock().unwrap();
+    let rows = conn.execute(
+        "UPDATE listing_images SET alt = ?1
+         WHERE id = ?2 AND listing_id = ?3 AND listing_id IN (SELECT id FROM listings WHERE seller_id = ?4)",
+        params![alt.trim(), image_id, listing_id, seller_id],
+    ).unwrap_or(0);
     rows > 0
 }
 
@@ -277,10 +409,50 @@ pub fn update_listing_status(db: &Db, id: &str, seller_id: &str, status: &str) -
     rows > 0
 }
 
-pub fn delete_listing(db: &Db, id: &str, seller_id: &str) -> bool {
+/// Puts a reserved listing back on the market. The accepted offer that reserved it is
+/// withdrawn and its buyer told. Fails if the listing isn't reserved, e.g. because the buyer
+/// checked out and the order marked it sold.
+pub fn unreserve_listing(db: &Db, id: &str, seller_id: &str) -> Result<(), String> {
+    let mut conn = db.lock().unwrap();
+    let tx = conn.transaction().unwrap();
+    let rows = tx.execute(
+        "UPDATE listings SET status = 'active' WHERE id = ?1 AND seller_id = ?2 AND status = 'reserved'",
+        params![id, seller_id],
+    ).unwrap();
+    if rows == 0 {
+        return Err("This listing isn't reserved".to_string());
+    }
+    let accepted: Vec<(String, String)> = {
+        let mut stmt = tx.prepare(
+            "SELECT o.id, o.conversation_id FROM offers o
+             WHERE o.listing_id = ?1 AND o.status = 'accepted'
+             AND NOT EXISTS (SELECT 1 FROM orders WHERE offer_id = o.id AND status != 'cancelled')"
+        ).unwrap();
+        stmt.query_map(params![id], |row| Ok((row.get(0)?, row.get(1)?))).unwrap().filter_map(|r| r.ok()).collect()
+    };
+    let mut notices = Vec::new();
+    for (offer_id, convo_id) in &accepted {
+        tx.execute("UPDATE offers SET status = 'withdrawn' WHERE id = ?1", params![offer_id]).unwrap();
+        notices.push(insert_message(&tx, convo_id, seller_id, "🔓 The seller put this item back on the market, so the accepted offer was withdrawn."));
+    }
+    tx.commit().unwrap();
+    for id in &notices {
+        publish_message(&conn, id);
+    }
+    Ok(())
+}
+
+/// Fails with a conflict once anyone has messaged about the listing; it can be marked sold instead.
+pub fn delete_listing(db: &Db, id: &str, seller_id: &str) -> Result<(), ListingError> {
     let conn = db.lock().unwrap();
-    let rows = conn.execute("DELETE FROM listings WHERE id = ?1 AND seller_id = ?2", params![id, seller_id]).unwrap_or(0);
-    rows > 0
+    match conn.execute("DELETE FROM listings WHERE id = ?1 AND seller_id = ?2", params![id, seller_id]) {
+        Ok(0) => Err(ListingError::NotFound),
+        Ok(_) => Ok(()),
+        Err(e) if e.sqlite_error_code() == Some(rusqlite::ErrorCode::ConstraintViolation) => {
+            Err(ListingError::Conflict("Buyers have messaged about this listing. Mark it sold instead."))
+        }
+        Err(e) => Err(ListingError::Failed(e.to_string())),
+    }
 }
 
 pub fn get_categories(db: &Db) -> Vec<Category> {
@@ -293,18 +465,11 @@ pub fn get_categories(db: &Db) -> Vec<Category> {
 
 pub fn get_seller_listings(db: &Db, seller_id: &str, exclude_id: &str) -> Vec<Listing> {
     let conn = db.lock().unwrap();
-    let mut stmt = conn.prepare(
-        "SELECT l.id, l.seller_id, u.name, l.title, l.description, l.price, l.category, l.condition, l.location, l.image_url, l.status, l.created_at
-         FROM listings l JOIN users u ON l.seller_id = u.id WHERE l.seller_id = ?1 AND l.id != ?2 AND l.status = 'active' LIMIT 4"
-    ).unwrap();
-    stmt.query_map(params![seller_id, exclude_id], |row| {
-        Ok(Listing {
-            id: row.get(0)?, seller_id: row.get(1)?, seller_name: row.get(2)?,
-            title: row.get(3)?, description: row.get(4)?, price: row.get(5)?,
-            category: row.get(6)?, condition: row.get(7)?, location: row.get(8)?,
-            image_url: row.get(9)?, status: row.get(10)?, created_at: row.get(11)?,
-        })
-    }).unwrap().filter_map(|r| r.ok()).collect()
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM listings l JOIN users u ON l.seller_id = u.id WHERE l.seller_id = ?1 AND l.id != ?2 AND l.status = 'active' LIMIT 4",
+        LISTING_COLUMNS
+    )).unwrap();
+    stmt.query_map(params![seller_id, exclude_id], listing_from_row).unwrap().filter_map(|r| r.ok()).collect()
 }
 
 // === Conversation queries ===
@@ -331,12 +496,11 @@ pub fn get_or_create_conversation(db: &Db, listing_id: &str, buyer_id: &str, sel
 pub fn get_user_conversations(db: &Db, user_id: &str) -> Vec<Conversation> {
     let conn = db.lock().unwrap();
     let mut stmt = conn.prepare(
-        "SELECT c.id, c.listing_id, l.title, l.image_url, c.buyer_id, bu.name, c.seller_id, su.name,
-                COALESCE((SELECT content FROM messages WHERE conversation_id = c.id ORDER BY created_at DESC LIMIT 1), ''),
-                COALESCE((SELECT created_at FROM messages WHERE conversation_id = c.id ORDER BY created_at DESC LIMIT 1), c.created_at),
-                COALESCE((SELECT COUNT(*) FROM messages m WHERE m.conversation_id = c.id
-                    AND m.created_at > COALESCE((SELECT last_read_at FROM message_reads WHERE user_id = ?1 AND conversation_id = c.id), '1970-01-01')
-                    AND m.sender_id != ?1), 0)
+        "SELECT c.id, c.listing_id, l.title, COALESCE(l.thumb_url, l.image_url), c.buyer_id, bu.name, c.seller_id, su.name,
+                COALESCE((SELECT content FROM messages WHERE conversation_id = c.id ORDER BY seq DESC LIMIT 1), ''),
+                COALESCE((SELECT created_at FROM messages WHERE conversation_id = c.id ORDER BY seq DESC LIMIT 1), c.created_at),
+                (SELECT COUNT(*) FROM messages m WHERE m.conversation_id = c.id AND m.sender_id != ?1
+                    AND NOT EXISTS (SELECT 1 FROM message_receipts r WHERE r.message_id = m.id AND r.user_id = ?1))
          FROM conversations c
          JOIN listings l ON c.listing_id = l.id
          JOIN users bu ON c.buyer_id = bu.id
@@ -357,9 +521,9 @@ pub fn get_user_conversations(db: &Db, user_id: &str) -> Vec<Conversation> {
 pub fn get_conversation(db: &Db, id: &str) -> Option<Conversation> {
     let conn = db.lock().unwrap();
     conn.query_row(
-        "SELECT c.id, c.listing_id, l.title, l.image_url, c.buyer_id, bu.name, c.seller_id, su.name,
-                COALESCE((SELECT content FROM messages WHERE conversation_id = c.id ORDER BY created_at DESC LIMIT 1), ''),
-                COALESCE((SELECT created_at FROM messages WHERE conversation_id = c.id ORDER BY created_at DESC LIMIT 1), c.created_at),
+        "SELECT c.id, c.listing_id, l.title, COALESCE(l.thumb_url, l.image_url), c.buyer_id, bu.name, c.seller_id, su.name,
+                COALESCE((SELECT content FROM messages WHERE conversation_id = c.id ORDER BY seq DESC LIMIT 1), ''),
+                COALESCE((SELECT created_at FROM messages WHERE conversation_id = c.id ORDER BY seq DESC LIMIT 1), c.created_at),
                 0
          FROM conversations c
          JOIN listings l ON c.listing_id = l.id
@@ -378,86 +542,143 @@ pub fn get_conversation(db: &Db, id: &str) -> Option<Conversation> {
 
 // === Message queries ===
 
-pub fn get_messages(db: &Db, conversation_id: &str) -> Vec<Message> {
+// Selected FROM messages m JOIN users u ON m.sender_id = u.id. Conversations have two
+// participants, so any receipt from someone other than the sender is the recipient's.
+const MESSAGE_COLUMNS: &str = "m.id, m.conversation_id, m.sender_id, u.name, m.content, m.created_at,
+    m.seq, EXISTS (SELECT 1 FROM message_receipts r WHERE r.message_id = m.id AND r.user_id != m.sender_id)";
+
+fn message_from_row(row: &rusqlite::Row) -> rusqlite::Result<Message> {
+    Ok(Message {
+        id: row.get(0)?, conversation_id: row.get(1)?, sender_id: row.get(2)?,
+        sender_name: row.get(3)?, content: row.get(4)?, created_at: row.get(5)?, seq: row.get(6)?,
+        seen: row.get(7)?,
+    })
+}
+
+/// Messages per page of a conversation's history.
+pub const MESSAGE_PAGE_SIZE: i64 = 50;
+
+/// The latest `MESSAGE_PAGE_SIZE` messages before `before_seq` (or the latest overall), oldest
+/// first.
+pub fn get_messages_before(db: &Db, conversation_id: &str, before_seq: Option<i64>) -> Vec<Message> {
     let conn = db.lock().unwrap();
-    let mut stmt = conn.prepare(
-        "SELECT m.id, m.conversation_id, m.sender_id, u.name, m.content, m.created_at,
-                COALESCE(o.id, '') as offer_id, COALESCE(o.amount, 0) as offer_amount, COALESCE(o.status, '') as offer_status
-         FROM messages m
-         JOIN users u ON m.sender_id = u.id
-         LEFT JOIN offers o ON o.conversation_id = m.conversation_id
-            AND m.content LIKE '%offer%' AND o.amount = CAST(
-                REPLACE(REPLACE(SUBSTR(m.content, INSTR(m.content, '$') + 1), ',', ''), ' ', '') AS REAL
-            )
-         WHERE m.conversation_id = ?1
-         ORDER BY m.created_at ASC"
-    ).unwrap();
-    // Simpler approach — just get messages, we'll handle offers separately
-    drop(stmt);
+    let mut stmt = conn.prepare(&format!(
+        "SELECT * FROM (
+            SELECT {} FROM messages m JOIN users u ON m.sender_id = u.id
+            WHERE m.conversation_id = ?1 AND m.seq < ?2
+            ORDER BY m.seq DESC LIMIT ?3
+         ) ORDER BY seq ASC",
+        MESSAGE_COLUMNS
+    )).unwrap();
+    stmt.query_map(params![conversation_id, before_seq.unwrap_or(i64::MAX), MESSAGE_PAGE_SIZE], message_from_row)
+        .unwrap().filter_map(|r| r.ok()).collect()
+}
 
-    let mut stmt = conn.prepare(
-        "SELECT m.id, m.conversation_id, m.sender_id, u.name, m.content, m.created_at
-         FROM messages m JOIN users u ON m.sender_id = u.id
-         WHERE m.conversation_id = ?1 ORDER BY m.created_at ASC"
-    ).unwrap();
-    stmt.query_map(params![conversation_id], |row| {
-        Ok(Message {
-            id: row.get(0)?, conversation_id: row.get(1)?, sender_id: row.get(2)?,
-            sender_name: row.get(3)?, content: row.get(4)?, is_offer: false,
-            offer_amount: None, offer_status: None, created_at: row.get(5)?,
-        })
-    }).unwrap().filter_map(|r| r.ok()).collect()
+/// Every message after `after_seq`, oldest first.
+pub fn get_messages_after(db: &Db, conversation_id: &str, after_seq: i64) -> Vec<Message> {
+    let conn = db.lock().unwrap();
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM messages m JOIN users u ON m.sender_id = u.id
+         WHERE m.conversation_id = ?1 AND m.seq > ?2
+         ORDER BY m.seq ASC",
+        MESSAGE_COLUMNS
+    )).unwrap();
+    stmt.query_map(params![conversation_id, after_seq], message_from_row).unwrap().filter_map(|r| r.ok()).collect()
 }
 
-pub fn get_messages_after(db: &Db, conversation_id: &str, after_id: &str) -> Vec<Message> {
+pub fn get_message(db: &Db, id: &str) -> Option<Message> {
     let conn = db.lock().unwrap();
-    let mut stmt = conn.prepare(
-        "SELECT m.id, m.conversation_id, m.sender_id, u.name, m.content, m.created_at
-         FROM messages m JOIN users u ON m.sender_id = u.id
-         WHERE m.conversation_id = ?1 AND m.created_at > (SELECT created_at FROM messages WHERE id = ?2)
-         ORDER BY m.created_at ASC"
-    ).unwrap();
-    stmt.query_map(params![conversation_id, after_id], |row| {
-        Ok(Message {
-            id: row.get(0)?, conversation_id: row.get(1)?, sender_id: row.get(2)?,
-            sender_name: row.get(3)?, content: row.get(4)?, is_offer: false,
-            offer_amount: None, offer_status: None, created_at: row.get(5)?,
-        })
-    }).unwrap().filter_map(|r| r.ok()).collect()
+    message_by_id(&conn, id)
+}
+
+fn message_by_id(conn: &Connection, id: &str) -> Option<Message> {
+    conn.query_row(
+        &format!("SELECT {} FROM messages m JOIN users u ON m.sender_id = u.id WHERE m.id = ?1", MESSAGE_COLUMNS),
+        params![id],
+        message_from_row,
+    ).ok()
 }
 
 pub fn send_message(db: &Db, conversation_id: &str, sender_id: &str, content: &str) -> String {
     let conn = db.lock().unwrap();
+    let id = insert_message(&conn, conversation_id, sender_id, content);
+    publish_message(&conn, &id);
+    id
+}
+
+/// Pushes a committed message to live subscribers. Inside a transaction, call this for each
+/// inserted message after the commit, never before.
+fn publish_message(conn: &Connection, id: &str) {
+    let Some(message) = message_by_id(conn, id) else { return };
+    let participants: Option<(String, String)> = conn.query_row(
+        "SELECT buyer_id, seller_id FROM conversations WHERE id = ?1",
+        params![message.conversation_id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ).ok();
+    if let Some((buyer_id, seller_id)) = participants {
+        crate::events::hub().publish_message(message, [&buyer_id, &seller_id]);
+    }
+}
+
+// For callers that already hold the connection, e.g. inside a transaction
+fn insert_message(conn: &Connection, conversation_id: &str, sender_id: &str, content: &str) -> String {
     let id = uuid::Uuid::new_v4().to_string();
     conn.execute(
-        "INSERT INTO messages (id, conversation_id, sender_id, content) VALUES (?1, ?2, ?3, ?4)",
+        "INSERT INTO messages (id, conversation_id, sender_id, content, seq)
+         VALUES (?1, ?2, ?3, ?4, (SELECT COALESCE(MAX(seq), 0) + 1 FROM messages WHERE conversation_id = ?2))",
         params![id, conversation_id, sender_id, content],
     ).unwrap();
     id
 }
 
-pub fn mark_conversation_read(db: &Db, user_id: &str, conversation_id: &str) {
+/// Records that `user_id` has read `messages`, skipping their own and any already read, and
+/// tells live pages in each conversation which ones were newly seen.
+pub fn mark_messages_read(db: &Db, user_id: &str, messages: &[Message]) {
+    let conn = db.lock().unwrap();
+    let mut seen: HashMap<&str, Vec<String>> = HashMap::new();
+    for m in messages.iter().filter(|m| m.sender_id != user_id) {
+        if insert_receipt(&conn, &m.id, user_id) {
+            seen.entry(&m.conversation_id).or_default().push(m.id.clone());
+        }
+    }
+    for (conversation_id, message_ids) in seen {
+        crate::events::hub().publish_seen(conversation_id, user_id, message_ids);
+    }
+}
+
+/// Records that `user_id` has read everything in the conversation up to and including
+/// `seq`, including older messages that were never loaded.
+pub fn mark_read_through(db: &Db, user_id: &str, conversation_id: &str, seq: i64) {
     let conn = db.lock().unwrap();
+    let unread: Vec<String> = conn.prepare(
+        "SELECT m.id FROM messages m
+         WHERE m.conversation_id = ?1 AND m.seq <= ?2 AND m.sender_id != ?3
+         AND NOT EXISTS (SELECT 1 FROM message_receipts r WHERE r.message_id = m.id AND r.user_id = ?3)
+         ORDER BY m.seq ASC"
+    ).unwrap().query_map(params![conversation_id, seq, user_id], |row| row.get(0))
+        .unwrap().filter_map(|r| r.ok()).collect();
+    let seen: Vec<String> = unread.into_iter().filter(|id| insert_receipt(&conn, id, user_id)).collect();
+    if !seen.is_empty() {
+        crate::events::hub().publish_seen(conversation_id, user_id, seen);
+    }
+}
+
+// True if this is the first receipt for the message
+fn insert_receipt(conn: &Connection, message_id: &str, user_id: &str) -> bool {
     conn.execute(
-        "INSERT OR REPLACE INTO message_reads (user_id, conversation_id, last_read_at) VALUES (?1, ?2, datetime('now'))",
-        params![user_id, conversation_id],
-    ).unwrap();
+        "INSERT OR IGNORE INTO message_receipts (message_id, user_id) VALUES (?1, ?2)",
+        params![message_id, user_id],
+    ).unwrap() > 0
 }
 
 pub fn get_unread_count(db: &Db, user_id: &str) -> i64 {
     let conn = db.lock().unwrap();
     conn.query_row(
-        "SELECT COALESCE(SUM(cnt), 0) FROM (
-            SELECT COUNT(*) as cnt FROM messages m
-            JOIN conversations c ON m.conversation_id = c.id
-            WHERE (c.buyer_id = ?1 OR c.seller_id = ?1)
-            AND m.sender_id != ?1
-            AND m.created_at > COALESCE(
-                (SELECT last_read_at FROM message_reads WHERE user_id = ?1 AND conversation_id = c.id),
-                '1970-01-01'
-            )
-            GROUP BY c.id
-        )",
+        "SELECT COUNT(*) FROM messages m
+         JOIN conversations c ON m.conversation_id = c.id
+         WHERE (c.buyer_id = ?1 OR c.seller_id = ?1)
+         AND m.sender_id != ?1
+         AND NOT EXISTS (SELECT 1 FROM message_receipts r WHERE r.message_id = m.id AND r.user_id = ?1)",
         params![user_id],
         |row| row.get(0),
     ).unwrap_or(0)
@@ -465,58 +686,849 @@ pub fn get_unread_count(db: &Db, user_id: &str) -> i64 {
 
 // === Offer queries ===
 
-pub fn create_offer(db: &Db, listing_id: &str, conversation_id: &str, buyer_id: &str, amount: f64) -> String {
-    let conn = db.lock().unwrap();
-    // Cancel any previous pending offers for this listing+buyer
-    conn.execute(
+const OFFER_COLUMNS: &str = "id, listing_id, conversation_id, buyer_id, created_by, parent_id, amount_cents, currency, status, expires_at, created_at";
+
+/// Upper bound for a seller's offer window: 30 days
+pub const MAX_OFFER_TTL_HOURS: i64 = 720;
+
+// Expiry for a new offer on `listing_id` (?N), using the seller's offer window
+fn offer_expiry_sql(n: usize) -> String {
+    format!(
+        "datetime('now', '+' || (SELECT u.offer_ttl_hours FROM listings l JOIN users u ON l.seller_id = u.id WHERE l.id = ?{}) || ' hours')",
+        n
+    )
+}
+
+fn offer_from_row(row: &rusqlite::Row) -> rusqlite::Result<Offer> {
+    Ok(Offer {
+        id: row.get(0)?, listing_id: row.get(1)?, conversation_id: row.get(2)?,
+        buyer_id: row.get(3)?, created_by: row.get(4)?, parent_id: row.get(5)?,
+        amount: Money::new(row.get(6)?, row.get(7)?),
+        status: row.get(8)?, expires_at: row.get(9)?, created_at: row.get(10)?,
+    })
+}
+
+/// Makes a buyer's offer on a listing that is still for sale. Returns the new offer's id.
+/// An open counter from the seller is marked `countered` by it, like any other counter.
+pub fn create_offer(db: &Db, listing_id: &str, conversation_id: &str, buyer_id: &str, amount: Money) -> Result<String, OfferError> {
+    let mut conn = db.lock().unwrap();
+    let tx = conn.transaction().unwrap();
+    let status: String = tx.query_row(
+        "SELECT status FROM listings WHERE id = ?1",
+        params![listing_id],
+        |row| row.get(0),
+    ).map_err(|_| OfferError::NotFound)?;
+    // Reserved for another buyer's accepted offer, or sold
+    if status != "active" {
+        return Err(OfferError::Conflict("This item is no longer available"));
+    }
+    // Offering again while the seller's counter is open answers it, so the new offer is
+    // another round of that negotiation rather than a fresh start. Replacing the buyer's own
+    // open offer keeps whatever that one answered.
+    let open: Option<(String, String, Option<String>)> = tx.query_row(
+        "SELECT id, created_by, parent_id FROM offers
+         WHERE listing_id = ?1 AND buyer_id = ?2 AND status = 'pending'
+         AND (expires_at IS NULL OR expires_at > datetime('now'))
+         ORDER BY created_at DESC, rowid DESC LIMIT 1",
+        params![listing_id, buyer_id],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    ).ok();
+    let parent_id = match open {
+        Some((counter_id, created_by, _)) if created_by != buyer_id => {
+            tx.execute("UPDATE offers SET status = 'countered' WHERE id = ?1", params![counter_id]).unwrap();
+            Some(counter_id)
+        }
+        Some((_, _, parent_id)) => parent_id,
+        None => None,
+    };
+    // Cancel any other pending offers for this listing+buyer
+    tx.execute(
         "UPDATE offers SET status = 'cancelled' WHERE listing_id = ?1 AND buyer_id = ?2 AND status = 'pending'",
         params![listing_id, buyer_id],
     ).unwrap();
     let id = uuid::Uuid::new_v4().to_string();
-    conn.execute(
-        "INSERT INTO offers (id, listing_id, conversation_id, buyer_id, amount) VALUES (?1, ?2, ?3, ?4, ?5)",
-        params![id, listing_id, conversation_id, buyer_id, amount],
+    tx.execute(
+        &format!(
+            "INSERT INTO offers (id, listing_id, conversation_id, buyer_id, created_by, parent_id, amount_cents, currency, expires_at)
+             VALUES (?1, ?2, ?3, ?4, ?4, ?5, ?6, ?7, {})",
+            offer_expiry_sql(2)
+        ),
+        params![id, listing_id, conversation_id, buyer_id, parent_id, amount.cents, amount.currency],
     ).unwrap();
-    id
+    tx.commit().unwrap();
+    Ok(id)
 }
 
 pub fn get_pending_offer(db: &Db, conversation_id: &str) -> Option<Offer> {
     let conn = db.lock().unwrap();
     conn.query_row(
-        "SELECT id, listing_id, conversation_id, buyer_id, amount, status, created_at FROM offers WHERE conversation_id = ?1 AND status = 'pending' ORDER BY created_at DESC LIMIT 1",
+        &format!(
+            "SELECT {} FROM offers
+             WHERE conversation_id = ?1 AND status = 'pending' AND (expires_at IS NULL OR expires_at > datetime('now'))
+             ORDER BY created_at DESC LIMIT 1",
+            OFFER_COLUMNS
+        ),
         params![conversation_id],
-        |row| Ok(Offer {
-            id: row.get(0)?, listing_id: row.get(1)?, conversation_id: row.get(2)?,
-            buyer_id: row.get(3)?, amount: row.get(4)?, status: row.get(5)?, created_at: row.get(6)?,
-        })
+        offer_from_row,
+    ).ok()
+}
+
+/// Answers a pending offer on behalf of whoever it was made to: the seller for the buyer's
+/// offers, the buyer for the seller's counters. A counter marks this offer `countered` and
+/// opens a new pending round linked to it, which is returned; otherwise the answered offer is.
+///
+/// Accepting reserves the listing and declines every other pending offer on it, posting a
+/// note into each of those conversations.
+pub fn respond_to_offer(db: &Db, offer_id: &str, user_id: &str, response: OfferResponse) -> Result<Offer, OfferError> {
+    let mut conn = db.lock().unwrap();
+    let tx = conn.transaction().unwrap();
+    let offer = tx.query_row(
+        &format!("SELECT {} FROM offers WHERE id = ?1", OFFER_COLUMNS),
+        params![offer_id],
+        offer_from_row,
+    ).map_err(|_| OfferError::NotFound)?;
+    let seller_id: String = tx.query_row(
+        "SELECT seller_id FROM conversations WHERE id = ?1",
+        params![offer.conversation_id],
+        |row| row.get(0),
+    ).map_err(|_| OfferError::NotFound)?;
+    if user_id != offer.buyer_id && user_id != seller_id {
+        return Err(OfferError::NotFound);
+    }
+    if user_id == offer.created_by {
+        return Err(OfferError::Forbidden);
+    }
+    if offer.status != "pending" || is_expired(&offer) {
+        return Err(OfferError::Conflict("This offer has already been answered"));
+    }
+
+    let mut notices = Vec::new();
+    let (status, counter_id) = match response {
+        OfferResponse::Accept => {
+            let reserved = tx.execute(
+                "UPDATE listings SET status = 'reserved' WHERE id = ?1 AND status = 'active'",
+                params![offer.listing_id],
+            ).unwrap();
+            if reserved == 0 {
+                return Err(OfferError::Conflict("This item is no longer available"));
+            }
+            let competing: Vec<(String, String)> = {
+                let mut stmt = tx.prepare(
+                    "SELECT id, conversation_id FROM offers WHERE listing_id = ?1 AND status = 'pending' AND id != ?2"
+                ).unwrap();
+                stmt.query_map(params![offer.listing_id, offer.id], |row| Ok((row.get(0)?, row.get(1)?)))
+                    .unwrap().filter_map(|r| r.ok()).collect()
+            };
+            let mut notified = std::collections::HashSet::new();
+            for (competing_id, convo_id) in &competing {
+                tx.execute("UPDATE offers SET status = 'rejected' WHERE id = ?1", params![competing_id]).unwrap();
+                if notified.insert(convo_id.clone()) {
+                    notices.push(insert_message(&tx, convo_id, &seller_id, "🔒 This item is now reserved for another buyer, so your offer was declined."));
+                }
+            }
+            ("accepted", None)
+        }
+        OfferResponse::Reject => ("rejected", None),
+        OfferResponse::Counter(amount) => {
+            let id = uuid::Uuid::new_v4().to_string();
+            tx.execute(
+                &format!(
+                    "INSERT INTO offers (id, listing_id, conversation_id, buyer_id, created_by, parent_id, amount_cents, currency, expires_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, {})",
+                    offer_expiry_sql(2)
+                ),
+                params![id, offer.listing_id, offer.conversation_id, offer.buyer_id, user_id, offer.id, amount.cents, amount.currency],
+            ).unwrap();
+            ("countered", Some(id))
+        }
+    };
+    tx.execute("UPDATE offers SET status = ?1 WHERE id = ?2", params![status, offer.id]).unwrap();
+    let current = tx.query_row(
+        &format!("SELECT {} FROM offers WHERE id = ?1", OFFER_COLUMNS),
+        params![counter_id.as_deref().unwrap_or(&offer.id)],
+        offer_from_row,
+    ).unwrap();
+    tx.commit().unwrap();
+    for id in &notices {
+        publish_message(&conn, id);
+    }
+    Ok(current)
+}
+
+// Past its expiry but not yet swept
+fn is_expired(offer: &Offer) -> bool {
+    offer.expires_at.as_deref()
+        .and_then(|at| chrono::NaiveDateTime::parse_from_str(at, "%Y-%m-%d %H:%M:%S").ok())
+        .map(|at| at <= chrono::Utc::now().naive_utc())
+        .unwrap_or(false)
+}
+
+/// Marks every pending offer past its `expires_at` as `expired`, posts a note about each into
+/// its conversation in the same transaction, and returns the expired offers.
+pub fn expire_offers(db: &Db) -> Vec<Offer> {
+    let mut conn = db.lock().unwrap();
+    let tx = conn.transaction().unwrap();
+    let expired: Vec<Offer> = {
+        let mut stmt = tx.prepare(&format!(
+            "UPDATE offers SET status = 'expired'
+             WHERE status = 'pending' AND expires_at <= datetime('now')
+             RETURNING {}",
+            OFFER_COLUMNS
+        )).unwrap();
+        stmt.query_map([], offer_from_row).unwrap().filter_map(|r| r.ok()).collect()
+    };
+    let mut notices = Vec::new();
+    for offer in &expired {
+        // Posted as the offer's author so it reads as their offer lapsing
+        let msg = format!("⌛ Offer of {} expired without an answer.", offer.amount);
+        notices.push(insert_message(&tx, &offer.conversation_id, &offer.created_by, &msg));
+    }
+    tx.commit().unwrap();
+    for id in &notices {
+        publish_message(&conn, id);
+    }
+    expired
+}
+
+/// Every offer and counter-offer in a conversation, oldest first.
+pub fn get_offer_thread(db: &Db, conversation_id: &str) -> Vec<Offer> {
+    let conn = db.lock().unwrap();
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM offers WHERE conversation_id = ?1 ORDER BY created_at ASC, rowid ASC",
+        OFFER_COLUMNS
+    )).unwrap();
+    stmt.query_map(params![conversation_id], offer_from_row).unwrap().filter_map(|r| r.ok()).collect()
+}
+
+/// The most recent accepted offer in a conversation, if the seller has accepted one.
+pub fn get_accepted_offer(db: &Db, conversation_id: &str) -> Option<Offer> {
+    let conn = db.lock().unwrap();
+    conn.query_row(
+        &format!("SELECT {} FROM offers WHERE conversation_id = ?1 AND status = 'accepted' ORDER BY created_at DESC LIMIT 1", OFFER_COLUMNS),
+        params![conversation_id],
+        offer_from_row,
     ).ok()
 }
 
-pub fn respond_to_offer(db: &Db, offer_id: &str, seller_id: &str, accept: bool) -> bool {
+pub fn get_offer(db: &Db, id: &str) -> Option<Offer> {
     let conn = db.lock().unwrap();
-    let status = if accept { "accepted" } else { "rejected" };
-    // Verify the seller owns the listing
+    conn.query_row(
+        &format!("SELECT {} FROM offers WHERE id = ?1", OFFER_COLUMNS),
+        params![id],
+        offer_from_row,
+    ).ok()
+}
+
+// === Cart queries ===
+//
+// Each listing is one item, so a cart holds a listing at most once. `cart_items.quantity`
+// predates that and is always 1.
+
+// Column and value identifying the owner's row in `carts`
+fn cart_key(owner: &CartOwner) -> (&'static str, &str) {
+    match owner {
+        CartOwner::Guest(token) => ("token", token),
+        CartOwner::User(user_id) => ("user_id", user_id),
+    }
+}
+
+fn find_cart(conn: &Connection, owner: &CartOwner) -> Option<String> {
+    let (column, value) = cart_key(owner);
+    conn.query_row(
+        &format!("SELECT id FROM carts WHERE {} = ?1", column),
+        params![value],
+        |row| row.get(0),
+    ).ok()
+}
+
+fn find_or_create_cart(conn: &Connection, owner: &CartOwner) -> String {
+    if let Some(id) = find_cart(conn, owner) {
+        return id;
+    }
+    let (column, value) = cart_key(owner);
+    let id = uuid::Uuid::new_v4().to_string();
+    conn.execute(
+        &format!("INSERT INTO carts (id, {}) VALUES (?1, ?2)", column),
+        params![id, value],
+    ).unwrap();
+    id
+}
+
+// A cart holds one currency so its total means something: that of its oldest available item
+fn cart_currency(conn: &Connection, cart_id: &str) -> Option<Currency> {
+    conn.query_row(
+        "SELECT l.currency FROM cart_items ci JOIN listings l ON ci.listing_id = l.id
+         WHERE ci.cart_id = ?1 AND l.status = 'active'
+         ORDER BY ci.added_at, ci.rowid LIMIT 1",
+        params![cart_id],
+        |row| row.get(0),
+    ).ok()
+}
+
+pub fn add_to_cart(db: &Db, owner: &CartOwner, listing_id: &str) -> Result<(), String> {
+    let conn = db.lock().unwrap();
+    let (status, currency): (String, Currency) = conn.query_row(
+        "SELECT status, currency FROM listings WHERE id = ?1",
+        params![listing_id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ).map_err(|_| "Listing not found".to_string())?;
+    if status != "active" {
+        return Err("This item is no longer available".to_string());
+    }
+    let cart_id = find_or_create_cart(&conn, owner);
+    if let Some(cart) = cart_currency(&conn, &cart_id).filter(|c| *c != currency) {
+        return Err(format!(
+            "Your cart is priced in {}, so this {} item can't be added. Check out or empty your cart first.",
+            cart, currency
+        ));
+    }
+    conn.execute(
+        "INSERT INTO cart_items (cart_id, listing_id) VALUES (?1, ?2)
+         ON CONFLICT (cart_id, listing_id) DO NOTHING",
+        params![cart_id, listing_id],
+    ).unwrap();
+    conn.execute("UPDATE carts SET updated_at = datetime('now') WHERE id = ?1", params![cart_id]).unwrap();
+    Ok(())
+}
+
+pub fn remove_from_cart(db: &Db, owner: &CartOwner, listing_id: &str) -> bool {
+    let conn = db.lock().unwrap();
+    let (column, value) = cart_key(owner);
     let rows = conn.execute(
-        "UPDATE offers SET status = ?1 WHERE id = ?2 AND listing_id IN (SELECT id FROM listings WHERE seller_id = ?3)",
-        params![status, offer_id, seller_id],
+        &format!(
+            "DELETE FROM cart_items WHERE cart_id = (SELECT id FROM carts WHERE {} = ?1) AND listing_id = ?2",
+            column
+        ),
+        params![value, listing_id],
     ).unwrap_or(0);
     rows > 0
 }
 
-pub fn get_offer(db: &Db, id: &str) -> Option<Offer> {
+/// Cart contents grouped by seller. Items whose listing is no longer active are returned
+/// (so the page can say so) but left out of the counts and totals, as are items whose
+/// listing has since been repriced in a currency other than the cart's.
+pub fn get_cart(db: &Db, owner: &CartOwner) -> Cart {
     let conn = db.lock().unwrap();
+    let (column, value) = cart_key(owner);
+    let mut stmt = conn.prepare(&format!(
+        "SELECT ci.listing_id, l.title, COALESCE(l.thumb_url, l.image_url), l.price_cents, l.currency, l.seller_id, u.name, l.status
+         FROM cart_items ci
+         JOIN carts c ON ci.cart_id = c.id
+         JOIN listings l ON ci.listing_id = l.id
+         JOIN users u ON l.seller_id = u.id
+         WHERE c.{} = ?1
+         ORDER BY u.name, l.seller_id, ci.added_at, ci.rowid",
+        column
+    )).unwrap();
+    let items: Vec<CartItem> = stmt.query_map(params![value], |row| {
+        Ok(CartItem {
+            listing_id: row.get(0)?, title: row.get(1)?, image_url: row.get(2)?,
+            price: Money::new(row.get(3)?, row.get(4)?),
+            seller_id: row.get(5)?, seller_name: row.get(6)?, status: row.get(7)?,
+        })
+    }).unwrap().filter_map(|r| r.ok()).collect();
+    let currency = find_cart(&conn, owner).and_then(|id| cart_currency(&conn, &id));
+
+    let mut cart = Cart::default();
+    for item in items {
+        let available = item.status == "active";
+        if cart.groups.last().map(|g| g.seller_id != item.seller_id).unwrap_or(true) {
+            cart.groups.push(CartSellerGroup {
+                seller_id: item.seller_id.clone(),
+                seller_name: item.seller_name.clone(),
+                items: Vec::new(),
+                subtotal: Money::new(0, currency.unwrap_or(item.price.currency)),
+            });
+        }
+        let group = cart.groups.last_mut().unwrap();
+        if available && Some(item.price.currency) == currency {
+            let subtotal = group.subtotal.checked_add(item.price);
+            let total = cart.total.map_or(Some(item.price), |t| t.checked_add(item.price));
+            // Only fails on overflow, since everything summed is in the cart's currency
+            if let (Some(subtotal), Some(total)) = (subtotal, total) {
+                group.subtotal = subtotal;
+                cart.item_count += 1;
+                cart.total = Some(total);
+            }
+        }
+        group.items.push(item);
+    }
+    cart
+}
+
+pub fn get_cart_count(db: &Db, owner: &CartOwner) -> i64 {
+    let conn = db.lock().unwrap();
+    let (column, value) = cart_key(owner);
     conn.query_row(
-        "SELECT id, listing_id, conversation_id, buyer_id, amount, status, created_at FROM offers WHERE id = ?1",
-        params![id],
-        |row| Ok(Offer {
-            id: row.get(0)?, listing_id: row.get(1)?, conversation_id: row.get(2)?,
-            buyer_id: row.get(3)?, amount: row.get(4)?, status: row.get(5)?, created_at: row.get(6)?,
+        &format!(
+            "SELECT COUNT(*)
+             FROM cart_items ci
+             JOIN carts c ON ci.cart_id = c.id
+             JOIN listings l ON ci.listing_id = l.id
+             WHERE c.{} = ?1 AND l.status = 'active'",
+            column
+        ),
+        params![value],
+        |row| row.get(0),
+    ).unwrap_or(0)
+}
+
+pub fn is_in_cart(db: &Db, owner: &CartOwner, listing_id: &str) -> bool {
+    let conn = db.lock().unwrap();
+    let (column, value) = cart_key(owner);
+    conn.query_row(
+        &format!(
+            "SELECT 1 FROM cart_items ci JOIN carts c ON ci.cart_id = c.id WHERE c.{} = ?1 AND ci.listing_id = ?2",
+            column
+        ),
+        params![value, listing_id],
+        |_| Ok(()),
+    ).is_ok()
+}
+
+/// Folds a guest cart into the user's cart and deletes the guest cart, all in one transaction.
+/// Items the user already has are left as they are; listings that are no longer active,
+/// were deleted, belong to the user or are priced in a currency other than the user's cart
+/// are dropped.
+pub fn merge_guest_cart(db: &Db, token: &str, user_id: &str) -> CartMerge {
+    let mut conn = db.lock().unwrap();
+    let tx = conn.transaction().unwrap();
+    let mut merge = CartMerge::default();
+
+    let guest_id = match find_cart(&tx, &CartOwner::Guest(token.to_string())) {
+        Some(id) => id,
+        None => return merge,
+    };
+    // listing id, and the listing's status, seller and currency if it still exists
+    type GuestItem = (String, Option<String>, Option<String>, Option<Currency>);
+    let items: Vec<GuestItem> = {
+        let mut stmt = tx.prepare(
+            "SELECT ci.listing_id, l.status, l.seller_id, l.currency
+             FROM cart_items ci
+             LEFT JOIN listings l ON ci.listing_id = l.id
+             WHERE ci.cart_id = ?1
+             ORDER BY ci.added_at, ci.rowid"
+        ).unwrap();
+        stmt.query_map(params![guest_id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))
+            .unwrap().filter_map(|r| r.ok()).collect()
+    };
+
+    if !items.is_empty() {
+        let user_cart = find_or_create_cart(&tx, &CartOwner::User(user_id.to_string()));
+        let mut currency = cart_currency(&tx, &user_cart);
+        for (listing_id, status, seller_id, item_currency) in items {
+            let available = status.as_deref() == Some("active")
+                && seller_id.as_deref() != Some(user_id)
+                && (currency.is_none() || currency == item_currency);
+            if !available {
+                merge.dropped += 1;
+                continue;
+            }
+            currency = item_currency;
+            let inserted = tx.execute(
+                "INSERT INTO cart_items (cart_id, listing_id) VALUES (?1, ?2)
+                 ON CONFLICT (cart_id, listing_id) DO NOTHING",
+                params![user_cart, listing_id],
+            ).unwrap();
+            if inserted > 0 {
+                merge.added += 1;
+            } else {
+                merge.combined += 1;
+            }
+        }
+        tx.execute("UPDATE carts SET updated_at = datetime('now') WHERE id = ?1", params![user_cart]).unwrap();
+    }
+
+    tx.execute("DELETE FROM carts WHERE id = ?1", params![guest_id]).unwrap();
+    tx.commit().unwrap();
+    merge
+}
+
+// === Order queries ===
+
+const ORDER_COLUMNS: &str = "o.id, o.buyer_id, b.name, o.seller_id, s.name, o.offer_id, o.status, o.total_cents, o.currency, o.payment_info, o.created_at, o.updated_at";
+
+fn order_from_row(row: &rusqlite::Row) -> rusqlite::Result<Order> {
+    let status: String = row.get(6)?;
+    Ok(Order {
+        id: row.get(0)?, buyer_id: row.get(1)?, buyer_name: row.get(2)?,
+        seller_id: row.get(3)?, seller_name: row.get(4)?, offer_id: row.get(5)?,
+        status: OrderStatus::parse(&status).unwrap_or(OrderStatus::Cancelled),
+        total: Money::new(row.get(7)?, row.get(8)?), payment_info: row.get(9)?,
+        items: Vec::new(), created_at: row.get(10)?, updated_at: row.get(11)?,
+    })
+}
+
+fn load_order_items(conn: &Connection, order: &mut Order) {
+    let mut stmt = conn.prepare(
+        "SELECT listing_id, title, image_url, unit_price_cents, currency, quantity
+         FROM order_items WHERE order_id = ?1 ORDER BY title"
+    ).unwrap();
+    order.items = stmt.query_map(params![order.id], |row| {
+        let unit_price = Money::new(row.get(3)?, row.get(4)?);
+        let quantity: i64 = row.get(5)?;
+        Ok(OrderItem {
+            listing_id: row.get(0)?, title: row.get(1)?, image_url: row.get(2)?,
+            unit_price, quantity, line_total: unit_price * quantity,
         })
+    }).unwrap().filter_map(|r| r.ok()).collect();
+}
+
+// Writes one order for a single seller and takes the listings off the market. Each listing
+// is one item, so each is sold and charged once whatever quantity the caller passed.
+fn insert_order(conn: &Connection, buyer_id: &str, seller_id: &str, offer_id: Option<&str>, items: &[OrderItem]) -> Result<String, String> {
+    let id = uuid::Uuid::new_v4().to_string();
+    let total = items.iter()
+        .map(|item| Some(item.unit_price))
+        .reduce(|a, b| a?.checked_add(b?))
+        .ok_or_else(|| "An order needs at least one item".to_string())?
+        .ok_or_else(|| "These items are priced in different currencies".to_string())?;
+    let payment_info: String = conn.query_row(
+        "SELECT payment_info FROM users WHERE id = ?1",
+        params![seller_id],
+        |row| row.get(0),
+    ).unwrap_or_default();
+    conn.execute(
+        "INSERT INTO orders (id, buyer_id, seller_id, offer_id, total_cents, currency, payment_info)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![id, buyer_id, seller_id, offer_id, total.cents, total.currency, payment_info],
+    ).unwrap();
+    for item in items {
+        conn.execute(
+            "INSERT INTO order_items (order_id, listing_id, title, image_url, unit_price_cents, currency, quantity)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, 1)",
+            params![id, item.listing_id, item.title, item.image_url, item.unit_price.cents, item.unit_price.currency],
+        ).unwrap();
+        conn.execute("UPDATE listings SET status = 'sold' WHERE id = ?1", params![item.listing_id]).unwrap();
+    }
+    Ok(id)
+}
+
+/// Turns an accepted offer into an order at the offered price.
+pub fn create_order_from_offer(db: &Db, offer_id: &str, buyer_id: &str) -> Result<String, String> {
+    let mut conn = db.lock().unwrap();
+    let tx = conn.transaction().unwrap();
+    let offer = tx.query_row(
+        &format!("SELECT {} FROM offers WHERE id = ?1 AND buyer_id = ?2", OFFER_COLUMNS),
+        params![offer_id, buyer_id],
+        offer_from_row,
+    ).map_err(|_| "Offer not found".to_string())?;
+    if offer.status != "accepted" {
+        return Err("Only accepted offers can be checked out".to_string());
+    }
+    let existing: Option<String> = tx.query_row(
+        "SELECT id FROM orders WHERE offer_id = ?1 AND status != 'cancelled'",
+        params![offer_id],
+        |row| row.get(0),
+    ).ok();
+    if let Some(order_id) = existing {
+        return Ok(order_id);
+    }
+    let (seller_id, title, image_url, status): (String, String, String, String) = tx.query_row(
+        "SELECT seller_id, title, image_url, status FROM listings WHERE id = ?1",
+        params![offer.listing_id],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+    ).map_err(|_| "This listing no longer exists".to_string())?;
+    // Accepting the offer reserved the listing for this buyer
+    if status != "active" && status != "reserved" {
+        return Err("This item is no longer available".to_string());
+    }
+    let item = OrderItem {
+        listing_id: offer.listing_id.clone(), title, image_url,
+        unit_price: offer.amount, quantity: 1, line_total: offer.amount,
+    };
+    let order_id = insert_order(&tx, buyer_id, &seller_id, Some(offer_id), &[item])?;
+    tx.execute(
+        "DELETE FROM cart_items WHERE listing_id = ?1 AND cart_id IN (SELECT id FROM carts WHERE user_id = ?2)",
+        params![offer.listing_id, buyer_id],
+    ).unwrap();
+    tx.commit().unwrap();
+    Ok(order_id)
+}
+
+/// Checks out everything available in the user's cart, one order per seller, at one of each
+/// listing. Bought items leave the cart; unavailable ones stay so the cart page can explain why.
+pub fn create_orders_from_cart(db: &Db, buyer_id: &str) -> Result<Vec<String>, String> {
+    let mut conn = db.lock().unwrap();
+    let tx = conn.transaction().unwrap();
+    let cart_id = find_cart(&tx, &CartOwner::User(buyer_id.to_string()))
+        .ok_or_else(|| "Your cart is empty".to_string())?;
+    let rows: Vec<(String, OrderItem)> = {
+        let mut stmt = tx.prepare(
+            "SELECT l.seller_id, ci.listing_id, l.title, l.image_url, l.price_cents, l.currency
+             FROM cart_items ci JOIN listings l ON ci.listing_id = l.id
+             WHERE ci.cart_id = ?1 AND l.status = 'active' AND l.seller_id != ?2
+             ORDER BY l.seller_id, ci.added_at"
+        ).unwrap();
+        stmt.query_map(params![cart_id, buyer_id], |row| {
+            let unit_price = Money::new(row.get(4)?, row.get(5)?);
+            Ok((row.get(0)?, OrderItem {
+                listing_id: row.get(1)?, title: row.get(2)?, image_url: row.get(3)?,
+                unit_price, quantity: 1, line_total: unit_price,
+            }))
+        }).unwrap().filter_map(|r| r.ok()).collect()
+    };
+    // Same rule as the cart total: items repriced in another currency stay in the cart
+    let currency = cart_currency(&tx, &cart_id);
+    let rows: Vec<(String, OrderItem)> = rows.into_iter()
+        .filter(|(_, item)| Some(item.unit_price.currency) == currency)
+        .collect();
+    if rows.is_empty() {
+        return Err("Nothing in your cart is available to buy".to_string());
+    }
+
+    let mut by_seller: Vec<(String, Vec<OrderItem>)> = Vec::new();
+    for (seller_id, item) in rows {
+        if by_seller.last().map(|(s, _)| s != &seller_id).unwrap_or(true) {
+            by_seller.push((seller_id, Vec::new()));
+        }
+        by_seller.last_mut().unwrap().1.push(item);
+    }
+
+    let mut order_ids = Vec::new();
+    for (seller_id, items) in &by_seller {
+        order_ids.push(insert_order(&tx, buyer_id, seller_id, None, items)?);
+        for item in items {
+            tx.execute(
+                "DELETE FROM cart_items WHERE cart_id = ?1 AND listing_id = ?2",
+                params![cart_id, item.listing_id],
+            ).unwrap();
+        }
+    }
+    tx.commit().unwrap();
+    Ok(order_ids)
+}
+
+pub fn get_order(db: &Db, id: &str) -> Option<Order> {
+    let conn = db.lock().unwrap();
+    let mut order = conn.query_row(
+        &format!(
+            "SELECT {} FROM orders o JOIN users b ON o.buyer_id = b.id JOIN users s ON o.seller_id = s.id WHERE o.id = ?1",
+            ORDER_COLUMNS
+        ),
+        params![id],
+        order_from_row,
+    ).ok()?;
+    load_order_items(&conn, &mut order);
+    Some(order)
+}
+
+/// The live (not cancelled) order placed from an offer, if any.
+pub fn get_order_for_offer(db: &Db, offer_id: &str) -> Option<String> {
+    let conn = db.lock().unwrap();
+    conn.query_row(
+        "SELECT id FROM orders WHERE offer_id = ?1 AND status != 'cancelled'",
+        params![offer_id],
+        |row| row.get(0),
+    ).ok()
+}
+
+/// Orders the user bought (`as_seller == false`) or sold, newest first.
+pub fn get_user_orders(db: &Db, user_id: &str, as_seller: bool) -> Vec<Order> {
+    let conn = db.lock().unwrap();
+    let column = if as_seller { "seller_id" } else { "buyer_id" };
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM orders o JOIN users b ON o.buyer_id = b.id JOIN users s ON o.seller_id = s.id
+         WHERE o.{} = ?1 ORDER BY o.created_at DESC",
+        ORDER_COLUMNS, column
+    )).unwrap();
+    let mut orders: Vec<Order> = stmt.query_map(params![user_id], order_from_row)
+        .unwrap().filter_map(|r| r.ok()).collect();
+    for order in &mut orders {
+        load_order_items(&conn, order);
+    }
+    orders
+}
+
+/// Gives a paid order's payment back through its provider: the new status of the intent
+/// that succeeded, normally `Refunded`.
+pub type Refund<'a> = &'a dyn Fn(&PaymentIntent) -> Result<IntentStatus, String>;
+
+/// Moves an order along its state machine on behalf of the buyer or seller. A paid order
+/// is cancelled with `cancel_order`, which refunds it.
+pub fn update_order_status(db: &Db, order_id: &str, user_id: &str, next: OrderStatus) -> Result<(), OrderError> {
+    move_order(db, order_id, user_id, next, None)
+}
+
+/// Cancels an order on behalf of the buyer or seller. Its listings go back on the market
+/// and the offer it was bought through is withdrawn, so that offer can't be checked out
+/// again at the agreed price. If it was paid, `refund` is called under the same lock once
+/// the cancellation is known to be allowed, and the refund is recorded with it: a refused
+/// or repeated cancel refunds nothing, and a failed refund cancels nothing.
+pub fn cancel_order(db: &Db, order_id: &str, user_id: &str, refund: Refund) -> Result<(), OrderError> {
+    move_order(db, order_id, user_id, OrderStatus::Cancelled, Some(refund))
+}
+
+fn move_order(db: &Db, order_id: &str, user_id: &str, next: OrderStatus, refund: Option<Refund>) -> Result<(), OrderError> {
+    let mut conn = db.lock().unwrap();
+    let tx = conn.transaction().unwrap();
+    let (buyer_id, seller_id, status): (String, String, String) = tx.query_row(
+        "SELECT buyer_id, seller_id, status FROM orders WHERE id = ?1",
+        params![order_id],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    ).map_err(|_| OrderError::NotFound)?;
+    let is_seller = user_id == seller_id;
+    if !is_seller && user_id != buyer_id {
+        return Err(OrderError::NotFound);
+    }
+    let current = OrderStatus::parse(&status).ok_or(OrderError::NotFound)?;
+    if !current.can_become(next, is_seller) {
+        return Err(OrderError::Illegal(format!("An order that is {} can't be marked {}", current.as_str(), next.as_str())));
+    }
+    if next == OrderStatus::Cancelled {
+        let paid = tx.query_row(
+            &format!("SELECT {} FROM payment_intents WHERE order_id = ?1 AND status = 'succeeded'", INTENT_COLUMNS),
+            params![order_id],
+            intent_from_row,
+        ).ok();
+        if let Some(intent) = paid {
+            let refund = refund.ok_or_else(|| OrderError::Illegal("A paid order is cancelled with a refund".to_string()))?;
+            let refunded = refund(&intent).map_err(OrderError::RefundFailed)?;
+            if !intent.status.can_become(refunded) {
+                return Err(OrderError::RefundFailed(format!("the provider reported the payment {}", refunded.as_str())));
+            }
+            tx.execute(
+                "UPDATE payment_intents SET status = ?1, updated_at = datetime('now') WHERE id = ?2",
+                params![refunded.as_str(), intent.id],
+            ).unwrap();
+        }
+        void_open_intents(&tx, order_id);
+    }
+    tx.execute(
+        "UPDATE orders SET status = ?1, updated_at = datetime('now') WHERE id = ?2",
+        params![next.as_str(), order_id],
+    ).unwrap();
+    let mut notices = Vec::new();
+    if next == OrderStatus::Cancelled {
+        tx.execute(
+            "UPDATE listings SET status = 'active'
+             WHERE status = 'sold' AND id IN (SELECT listing_id FROM order_items WHERE order_id = ?1)",
+            params![order_id],
+        ).unwrap();
+        let withdrawn: Option<String> = tx.query_row(
+            "UPDATE offers SET status = 'withdrawn'
+             WHERE status = 'accepted' AND id = (SELECT offer_id FROM orders WHERE id = ?1)
+             RETURNING conversation_id",
+            params![order_id],
+            |row| row.get(0),
+        ).ok();
+        if let Some(convo_id) = withdrawn {
+            notices.push(insert_message(&tx, &convo_id, user_id, "↩️ The order was cancelled, so the accepted offer was withdrawn."));
+        }
+    }
+    tx.commit().unwrap();
+    for id in &notices {
+        publish_message(&conn, id);
+    }
+    Ok(())
+}
+
+// === Payment queries ===
+
+const INTENT_COLUMNS: &str = "id, order_id, provider, provider_ref, status, amount_cents, currency, instructions, created_at, updated_at";
+
+fn intent_from_row(row: &rusqlite::Row) -> rusqlite::Result<PaymentIntent> {
+    let status: String = row.get(4)?;
+    Ok(PaymentIntent {
+        id: row.get(0)?, order_id: row.get(1)?, provider: row.get(2)?, provider_ref: row.get(3)?,
+        status: IntentStatus::parse(&status).unwrap_or(IntentStatus::Failed),
+        amount: Money::new(row.get(5)?, row.get(6)?), instructions: row.get(7)?,
+        created_at: row.get(8)?, updated_at: row.get(9)?,
+    })
+}
+
+pub fn create_payment_intent(db: &Db, order: &Order, provider: &str, provider_ref: &str, instructions: &str) -> PaymentIntent {
+    let conn = db.lock().unwrap();
+    let id = uuid::Uuid::new_v4().to_string();
+    conn.execute(
+        "INSERT INTO payment_intents (id, order_id, provider, provider_ref, amount_cents, currency, instructions)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![id, order.id, provider, provider_ref, order.total.cents, order.total.currency, instructions],
+    ).unwrap();
+    conn.query_row(
+        &format!("SELECT {} FROM payment_intents WHERE id = ?1", INTENT_COLUMNS),
+        params![id],
+        intent_from_row,
+    ).unwrap()
+}
+
+/// All payment attempts for an order, newest first.
+pub fn get_payment_intents(db: &Db, order_id: &str) -> Vec<PaymentIntent> {
+    let conn = db.lock().unwrap();
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM payment_intents WHERE order_id = ?1 ORDER BY created_at DESC, rowid DESC",
+        INTENT_COLUMNS
+    )).unwrap();
+    stmt.query_map(params![order_id], intent_from_row).unwrap().filter_map(|r| r.ok()).collect()
+}
+
+pub fn get_intent_by_ref(db: &Db, provider: &str, provider_ref: &str) -> Option<PaymentIntent> {
+    let conn = db.lock().unwrap();
+    conn.query_row(
+        &format!("SELECT {} FROM payment_intents WHERE provider = ?1 AND provider_ref = ?2", INTENT_COLUMNS),
+        params![provider, provider_ref],
+        intent_from_row,
     ).ok()
 }
 
+// Once an order stops waiting for payment its other attempts can't be completed
+fn void_open_intents(conn: &Connection, order_id: &str) {
+    conn.execute(
+        "UPDATE payment_intents SET status = 'voided', updated_at = datetime('now')
+         WHERE order_id = ?1 AND status = 'requires_action'",
+        params![order_id],
+    ).unwrap();
+}
+
+/// Records a provider's verdict on an intent, if `IntentStatus::can_become` allows it. A
+/// successful payment moves a pending order to `paid` and voids its other open intents.
+/// A success for an order that isn't waiting for payment any more, on an open or voided
+/// intent, pays nothing: it comes back `Unwanted` for the caller to refund.
+pub fn record_payment(db: &Db, intent_id: &str, status: IntentStatus) -> PaymentRecord {
+    let mut conn = db.lock().unwrap();
+    let tx = conn.transaction().unwrap();
+    let found = tx.query_row(
+        "SELECT i.status, i.order_id, o.status FROM payment_intents i JOIN orders o ON i.order_id = o.id WHERE i.id = ?1",
+        params![intent_id],
+        |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?)),
+    ).ok();
+    let Some((current, order_id, order_status)) = found else { return PaymentRecord::Stale };
+    let current = IntentStatus::parse(&current);
+    let awaiting_payment = order_status == OrderStatus::PendingPayment.as_str();
+    let unwanted = status == IntentStatus::Succeeded
+        && !awaiting_payment
+        && matches!(current, Some(IntentStatus::RequiresAction | IntentStatus::Voided));
+    if !unwanted && !current.is_some_and(|c| c.can_become(status)) {
+        return PaymentRecord::Stale;
+    }
+    tx.execute(
+        "UPDATE payment_intents SET status = ?1, updated_at = datetime('now') WHERE id = ?2",
+        params![status.as_str(), intent_id],
+    ).unwrap();
+    if status == IntentStatus::Succeeded && awaiting_payment {
+        tx.execute(
+            "UPDATE orders SET status = 'paid', updated_at = datetime('now') WHERE id = ?1",
+            params![order_id],
+        ).unwrap();
+        void_open_intents(&tx, &order_id);
+    }
+    tx.commit().unwrap();
+    if unwanted { PaymentRecord::Unwanted } else { PaymentRecord::Recorded }
+}
+
 // === User queries ===
 
+const USER_COLUMNS: &str = "u.id, u.email, u.name, u.password_hash, u.location, u.avatar_url, u.payment_info, u.bio, u.offer_ttl_hours, u.email_verified_at, u.created_at";
+
+fn user_from_row(row: &rusqlite::Row) -> rusqlite::Result<User> {
+    Ok(User {
+        id: row.get(0)?, email: row.get(1)?, name: row.get(2)?, password_hash: row.get(3)?,
+        location: row.get(4)?, avatar_url: row.get(5)?, payment_info: row.get(6)?,
+        bio: row.get(7)?, offer_ttl_hours: row.get(8)?, email_verified_at: row.get(9)?,
+        created_at: row.get(10)?,
+    })
+}
+
 pub fn create_user(db: &Db, name: &str, email: &str, password_hash: &str) -> Result<String, String> {
     let conn = db.lock().unwrap();
     let id = uuid::Uuid::new_v4().to_string();
@@ -533,76 +1545,378 @@ pub fn create_user(db: &Db, name: &str, email: &str, password_hash: &str) -> Res
 pub fn get_user_by_email(db: &Db, email: &str) -> Option<User> {
     let conn = db.lock().unwrap();
     conn.query_row(
-        "SELECT id, email, name, password_hash, location, avatar_url, payment_info, bio, created_at FROM users WHERE email = ?1",
+        &format!("SELECT {} FROM users u WHERE u.email = ?1", USER_COLUMNS),
         params![email],
-        |row| Ok(User {
-            id: row.get(0)?, email: row.get(1)?, name: row.get(2)?, password_hash: row.get(3)?,
-            location: row.get(4)?, avatar_url: row.get(5)?, payment_info: row.get(6)?,
-            bio: row.get(7)?, created_at: row.get(8)?,
-        })
+        user_from_row
     ).ok()
 }
 
 pub fn get_user_by_id(db: &Db, id: &str) -> Option<User> {
     let conn = db.lock().unwrap();
     conn.query_row(
-        "SELECT id, email, name, password_hash, location, avatar_url, payment_info, bio, created_at FROM users WHERE id = ?1",
+        &format!("SELECT {} FROM users u WHERE u.id = ?1", USER_COLUMNS),
         params![id],
-        |row| Ok(User {
-            id: row.get(0)?, email: row.get(1)?, name: row.get(2)?, password_hash: row.get(3)?,
-            location: row.get(4)?, avatar_url: row.get(5)?, payment_info: row.get(6)?,
-            bio: row.get(7)?, created_at: row.get(8)?,
-        })
+        user_from_row
     ).ok()
 }
 
 pub fn update_user_profile(db: &Db, id: &str, form: &ProfileForm) -> bool {
     let conn = db.lock().unwrap();
     let rows = conn.execute(
-        "UPDATE users SET name = ?1, location = ?2, bio = ?3, payment_info = ?4 WHERE id = ?5",
-        params![form.name, form.location, form.bio, form.payment_info, id],
+        "UPDATE users SET name = ?1, location = ?2, bio = ?3, payment_info = ?4,
+             offer_ttl_hours = COALESCE(?5, offer_ttl_hours)
+         WHERE id = ?6",
+        params![form.name, form.location, form.bio, form.payment_info,
+                form.offer_ttl_hours.map(|h| h.clamp(1, MAX_OFFER_TTL_HOURS)), id],
     ).unwrap_or(0);
     rows > 0
 }
 
 // === Session queries ===
 
-pub fn create_session(db: &Db, user_id: &str) -> String {
-    let conn = db.lock().unwrap();
-    // We'll reuse the sessions table concept but inline it
-    // Actually, we need a sessions table
-    conn.execute_batch("
-        CREATE TABLE IF NOT EXISTS sessions (
-            id TEXT PRIMARY KEY,
-            user_id TEXT NOT NULL REFERENCES users(id),
-            created_at TEXT NOT NULL DEFAULT (datetime('now')),
-            expires_at TEXT NOT NULL
-        );
-    ").unwrap();
+pub fn create_session(db: &Db, user_id: &str, user_agent: &str, ip: &str) -> String {
+    let conn = db.lock().unwrap();
     let id = uuid::Uuid::new_v4().to_string();
     conn.execute(
-        "INSERT INTO sessions (id, user_id, expires_at) VALUES (?1, ?2, datetime('now', '+7 days'))",
-        params![id, user_id],
+        "INSERT INTO sessions (id, user_id, csrf_token, public_id, last_seen_at, user_agent, ip, expires_at)
+         VALUES (?1, ?2, ?3, ?4, datetime('now'), ?5, ?6, datetime('now', '+7 days'))",
+        params![id, user_id, crate::auth::random_token(), uuid::Uuid::new_v4().simple().to_string(), user_agent, ip],
     ).unwrap();
     id
 }
 
-pub fn get_session_user(db: &Db, session_id: &str) -> Option<User> {
+/// The CSRF token of a live session.
+pub fn get_session_csrf_token(db: &Db, session_id: &str) -> Option<String> {
     let conn = db.lock().unwrap();
     conn.query_row(
-        "SELECT u.id, u.email, u.name, u.password_hash, u.location, u.avatar_url, u.payment_info, u.bio, u.created_at
-         FROM sessions s JOIN users u ON s.user_id = u.id
-         WHERE s.id = ?1 AND s.expires_at > datetime('now')",
+        "SELECT csrf_token FROM sessions WHERE id = ?1 AND expires_at > datetime('now')",
         params![session_id],
-        |row| Ok(User {
-            id: row.get(0)?, email: row.get(1)?, name: row.get(2)?, password_hash: row.get(3)?,
-            location: row.get(4)?, avatar_url: row.get(5)?, payment_info: row.get(6)?,
-            bio: row.get(7)?, created_at: row.get(8)?,
-        })
+        |row| row.get(0),
     ).ok()
 }
 
+/// Resolves a live session to its user, and records the use.
+pub fn get_session_user(db: &Db, session_id: &str) -> Option<User> {
+    let conn = db.lock().unwrap();
+    let user = conn.query_row(
+        &format!(
+            "SELECT {} FROM sessions s JOIN users u ON s.user_id = u.id
+             WHERE s.id = ?1 AND s.expires_at > datetime('now')",
+            USER_COLUMNS
+        ),
+        params![session_id],
+        user_from_row
+    ).ok()?;
+    // At most once a minute, so page loads don't all write
+    conn.execute(
+        "UPDATE sessions SET last_seen_at = datetime('now')
+         WHERE id = ?1 AND last_seen_at < datetime('now', '-1 minute')",
+        params![session_id],
+    ).unwrap();
+    Some(user)
+}
+
+/// The user's live sessions, most recently used first. `current_session_id` is the cookie or
+/// bearer token of the session asking, so it can be marked.
+pub fn get_sessions(db: &Db, user_id: &str, current_session_id: Option<&str>) -> Vec<Session> {
+    let conn = db.lock().unwrap();
+    let mut stmt = conn.prepare(
+        "SELECT public_id, created_at, last_seen_at, user_agent, ip, id = ?2 FROM sessions
+         WHERE user_id = ?1 AND expires_at > datetime('now')
+         ORDER BY last_seen_at DESC, created_at DESC"
+    ).unwrap();
+    stmt.query_map(params![user_id, current_session_id], |row| {
+        Ok(Session {
+            public_id: row.get(0)?,
+            created_at: row.get(1)?,
+            last_seen_at: row.get(2)?,
+            user_agent: row.get(3)?,
+            ip: row.get(4)?,
+            current: row.get::<_, Option<bool>>(5)?.unwrap_or(false),
+        })
+    }).unwrap().filter_map(|r| r.ok()).collect()
+}
+
 pub fn delete_session(db: &Db, session_id: &str) {
     let conn = db.lock().unwrap();
     conn.execute("DELETE FROM sessions WHERE id = ?1", params![session_id]).unwrap();
 }
+
+/// Ends one of the user's sessions by its public id.
+pub fn revoke_session(db: &Db, user_id: &str, public_id: &str) -> bool {
+    let conn = db.lock().unwrap();
+    let rows = conn.execute(
+        "DELETE FROM sessions WHERE public_id = ?1 AND user_id = ?2",
+        params![public_id, user_id],
+    ).unwrap_or(0);
+    rows > 0
+}
+
+/// Ends every session the user has, this one included.
+pub fn delete_user_sessions(db: &Db, user_id: &str) -> usize {
+    let conn = db.lock().unwrap();
+    conn.execute("DELETE FROM sessions WHERE user_id = ?1", params![user_id]).unwrap()
+}
+
+/// Records that the user confirmed their address. Returns false if they already had.
+pub fn mark_email_verified(db: &Db, user_id: &str) -> bool {
+    let conn = db.lock().unwrap();
+    let rows = conn.execute(
+        "UPDATE users SET email_verified_at = datetime('now') WHERE id = ?1 AND email_verified_at IS NULL",
+        params![user_id],
+    ).unwrap_or(0);
+    rows > 0
+}
+
+/// A key from the `secrets` table. The migrations create every key the app uses.
+pub fn get_secret(db: &Db, name: &str) -> String {
+    let conn = db.lock().unwrap();
+    conn.query_row("SELECT value FROM secrets WHERE name = ?1", params![name], |row| row.get(0))
+        .unwrap_or_else(|_| panic!("secret {} is missing", name))
+}
+
+pub fn update_user_password(db: &Db, user_id: &str, password_hash: &str) -> bool {
+    let conn = db.lock().unwrap();
+    let rows = conn.execute(
+        "UPDATE users SET password_hash = ?1 WHERE id = ?2",
+        params![password_hash, user_id],
+    ).unwrap_or(0);
+    rows > 0
+}
+
+/// Deletes sessions past their expiry. Called by the sweeper.
+pub fn purge_expired_sessions(db: &Db) -> usize {
+    let conn = db.lock().unwrap();
+    conn.execute("DELETE FROM sessions WHERE expires_at <= datetime('now')", []).unwrap()
+}
+
+// === API tokens ===
+
+const API_TOKEN_COLUMNS: &str = "id, name, prefix, scopes, created_at, last_used_at";
+
+fn api_token_from_row(row: &rusqlite::Row) -> rusqlite::Result<ApiToken> {
+    let scopes: String = row.get(3)?;
+    Ok(ApiToken {
+        id: row.get(0)?, name: row.get(1)?, prefix: row.get(2)?,
+        scopes: scopes.split_whitespace().map(str::to_string).collect(),
+        created_at: row.get(4)?, last_used_at: row.get(5)?,
+    })
+}
+
+pub fn create_api_token(db: &Db, user_id: &str, name: &str, token_hash: &str, prefix: &str, scopes: &[&str]) -> ApiToken {
+    let conn = db.lock().unwrap();
+    let id = uuid::Uuid::new_v4().to_string();
+    conn.execute(
+        "INSERT INTO api_tokens (id, user_id, name, token_hash, prefix, scopes) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![id, user_id, name, token_hash, prefix, scopes.join(" ")],
+    ).unwrap();
+    conn.query_row(
+        &format!("SELECT {} FROM api_tokens WHERE id = ?1", API_TOKEN_COLUMNS),
+        params![id],
+        api_token_from_row,
+    ).unwrap()
+}
+
+pub fn get_api_tokens(db: &Db, user_id: &str) -> Vec<ApiToken> {
+    let conn = db.lock().unwrap();
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM api_tokens WHERE user_id = ?1 ORDER BY created_at DESC",
+        API_TOKEN_COLUMNS
+    )).unwrap();
+    stmt.query_map(params![user_id], api_token_from_row).unwrap().filter_map(|r| r.ok()).collect()
+}
+
+/// Revokes every personal token the user has made. Returns how many there were.
+pub fn delete_user_api_tokens(db: &Db, user_id: &str) -> usize {
+    let conn = db.lock().unwrap();
+    conn.execute("DELETE FROM api_tokens WHERE user_id = ?1", params![user_id]).unwrap()
+}
+
+pub fn revoke_api_token(db: &Db, user_id: &str, token_id: &str) -> bool {
+    let conn = db.lock().unwrap();
+    let rows = conn.execute(
+        "DELETE FROM api_tokens WHERE id = ?1 AND user_id = ?2",
+        params![token_id, user_id],
+    ).unwrap_or(0);
+    rows > 0
+}
+
+/// Resolves a token hash to its owner and scopes, and records the use.
+pub fn get_api_token_user(db: &Db, token_hash: &str) -> Option<(User, Vec<String>)> {
+    let conn = db.lock().unwrap();
+    let (token_id, scopes): (String, String) = conn.query_row(
+        "SELECT id, scopes FROM api_tokens WHERE token_hash = ?1",
+        params![token_hash],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ).ok()?;
+    let user = conn.query_row(
+        &format!("SELECT {} FROM api_tokens t JOIN users u ON t.user_id = u.id WHERE t.id = ?1", USER_COLUMNS),
+        params![token_id],
+        user_from_row,
+    ).ok()?;
+    conn.execute("UPDATE api_tokens SET last_used_at = datetime('now') WHERE id = ?1", params![token_id]).unwrap();
+    Some((user, scopes.split_whitespace().map(str::to_string).collect()))
+}
+
+// === Password resets ===
+
+/// Stores a reset token hash for the user, replacing any they hadn't used yet.
+pub fn create_password_reset(db: &Db, user_id: &str, token_hash: &str, minutes: i64) {
+    let conn = db.lock().unwrap();
+    conn.execute("DELETE FROM password_resets WHERE user_id = ?1 AND used_at IS NULL", params![user_id]).unwrap();
+    conn.execute(
+        "INSERT INTO password_resets (id, user_id, token_hash, expires_at)
+         VALUES (?1, ?2, ?3, datetime('now', ?4))",
+        params![uuid::Uuid::new_v4().to_string(), user_id, token_hash, format!("+{} minutes", minutes)],
+    ).unwrap();
+}
+
+/// The user an unused, unexpired reset token belongs to.
+pub fn get_password_reset_user(db: &Db, token_hash: &str) -> Option<User> {
+    let conn = db.lock().unwrap();
+    conn.query_row(
+        &format!(
+            "SELECT {} FROM password_resets r JOIN users u ON r.user_id = u.id
+             WHERE r.token_hash = ?1 AND r.used_at IS NULL AND r.expires_at > datetime('now')",
+            USER_COLUMNS
+        ),
+        params![token_hash],
+        user_from_row,
+    ).ok()
+}
+
+/// Marks a reset token used and returns its user's id, or `None` if it was already used,
+/// has expired or doesn't exist. Only one caller can redeem a token.
+pub fn redeem_password_reset(db: &Db, token_hash: &str) -> Option<String> {
+    let conn = db.lock().unwrap();
+    conn.query_row(
+        "UPDATE password_resets SET used_at = datetime('now')
+         WHERE token_hash = ?1 AND used_at IS NULL AND expires_at > datetime('now')
+         RETURNING user_id",
+        params![token_hash],
+        |row| row.get(0),
+    ).ok()
+}
+
+/// Deletes reset tokens that were used or have expired. Called by the sweeper.
+pub fn purge_password_resets(db: &Db) -> usize {
+    let conn = db.lock().unwrap();
+    conn.execute(
+        "DELETE FROM password_resets WHERE used_at IS NOT NULL OR expires_at <= datetime('now')",
+        [],
+    ).unwrap()
+}
+
+// === Outbox ===
+
+pub fn insert_outbox_message(db: &Db, to: &str, subject: &str, body: &str, message: &str) {
+    let conn = db.lock().unwrap();
+    conn.execute(
+        "INSERT INTO outbox (id, to_address, subject, body, message) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![uuid::Uuid::new_v4().to_string(), to, subject, body, message],
+    ).unwrap();
+}
+
+/// Mail kept by the `outbox` mailer for one address, newest first.
+pub fn get_outbox(db: &Db, to: &str) -> Vec<OutboxMessage> {
+    let conn = db.lock().unwrap();
+    let mut stmt = conn.prepare(
+        "SELECT id, to_address, subject, body, message, created_at FROM outbox
+         WHERE to_address = ?1 ORDER BY created_at DESC, rowid DESC"
+    ).unwrap();
+    stmt.query_map(params![to], |row| {
+        Ok(OutboxMessage {
+            id: row.get(0)?,
+            to_address: row.get(1)?,
+            subject: row.get(2)?,
+            body: row.get(3)?,
+            message: row.get(4)?,
+            created_at: row.get(5)?,
+        })
+    }).unwrap().filter_map(|r| r.ok()).collect()
+}
+
+// === Rate limits ===
+
+pub fn get_rate_limit(db: &Db, key: &str) -> RateCounter {
+    let conn = db.lock().unwrap();
+    rate_limit_by_key(&conn, key)
+}
+
+fn rate_limit_by_key(conn: &Connection, key: &str) -> RateCounter {
+    conn.query_row(
+        "SELECT count, reset_at, blocked_until FROM rate_limits WHERE key = ?1",
+        params![key],
+        |row| Ok(RateCounter { count: row.get(0)?, reset_at: row.get(1)?, blocked_until: row.get(2)? }),
+    ).unwrap_or_default()
+}
+
+/// Applies `f` to the counter for `key` and stores the result, atomically. A counter with
+/// nothing left to remember is deleted.
+pub fn update_rate_limit(db: &Db, key: &str, f: &mut dyn FnMut(RateCounter) -> RateCounter) -> RateCounter {
+    let conn = db.lock().unwrap();
+    let current = rate_limit_by_key(&conn, key);
+    let updated = f(current);
+    if updated.count == 0 && updated.blocked_until == 0 {
+        conn.execute("DELETE FROM rate_limits WHERE key = ?1", params![key]).unwrap();
+    } else {
+        conn.execute(
+            "INSERT INTO rate_limits (key, count, reset_at, blocked_until) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT(key) DO UPDATE SET count = ?2, reset_at = ?3, blocked_until = ?4",
+            params![key, updated.count, updated.reset_at, updated.blocked_until],
+        ).unwrap();
+    }
+    updated
+}
+
+/// Deletes counters that have reset and aren't blocking anything.
+pub fn prune_rate_limits(db: &Db, now: i64) -> usize {
+    let conn = db.lock().unwrap();
+    conn.execute(
+        "DELETE FROM rate_limits WHERE reset_at <= ?1 AND blocked_until <= ?1",
+        params![now],
+    ).unwrap()
+}
+
+pub fn record_lockout(db: &Db, key: &str, ip: &str, failures: u32, locked_until: i64) {
+    let conn = db.lock().unwrap();
+    conn.execute(
+        "INSERT INTO lockouts (id, key, ip, failures, locked_until) VALUES (?1, ?2, ?3, ?4, datetime(?5, 'unixepoch'))",
+        params![uuid::Uuid::new_v4().to_string(), key, ip, failures, locked_until],
+    ).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fts_requires_every_bare_word() {
+        assert_eq!(fts_match_query("oak desk").as_deref(), Some(r#""oak" "desk""#));
+        assert_eq!(fts_match_query("  oak \t desk  ").as_deref(), Some(r#""oak" "desk""#));
+    }
+
+    #[test]
+    fn fts_keeps_quoted_phrases_together() {
+        assert_eq!(fts_match_query(r#""mid century" chair"#).as_deref(), Some(r#""mid century" "chair""#));
+        assert_eq!(fts_match_query(r#"lamp " brass  base ""#).as_deref(), Some(r#""lamp" "brass  base""#));
+    }
+
+    #[test]
+    fn fts_turns_a_trailing_star_into_a_prefix() {
+        assert_eq!(fts_match_query("teak*").as_deref(), Some(r#""teak"*"#));
+        assert_eq!(fts_match_query("teak** desk").as_deref(), Some(r#""teak"* "desk""#));
+        assert_eq!(fts_match_query("*").as_deref(), None);
+    }
+
+    #[test]
+    fn fts_treats_stray_quotes_and_operators_as_text() {
+        // An unmatched quote runs to the end of the input
+        assert_eq!(fts_match_query(r#"oak "desk"#).as_deref(), Some(r#""oak" "desk""#));
+        assert_eq!(fts_match_query(r#"""#).as_deref(), None);
+        assert_eq!(fts_match_query("oak OR desk NOT chair").as_deref(), Some(r#""oak" "OR" "desk" "NOT" "chair""#));
+        assert_eq!(fts_match_query("title:desk (oak) -pine ^lamp").as_deref(), Some(r#""title:desk" "(oak)" "-pine" "^lamp""#));
+        assert_eq!(fts_match_query("- + ( ) : ^").as_deref(), None);
+        assert_eq!(fts_match_query("").as_deref(), None);
+    }
+}
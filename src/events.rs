@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use tokio::sync::broadcast;
+use crate::models::Message;
+
+// === Live events ===
+//
+// In-process pub/sub for pushing new messages to open pages over SSE and WebSockets instead
+// of having them poll. `db` publishes once a write has committed; subscribers are the SSE and
+// WebSocket handlers in `routes::messages`, which also publish typing and presence. Nothing
+// is persisted here: a subscriber that connects late or falls behind catches up from the
+// database.
+
+/// Slow subscribers that fall this far behind get `Lagged` and re-read from the database.
+const CHANNEL_CAPACITY: usize = 64;
+
+/// Something happened in a conversation.
+#[derive(Debug, Clone)]
+pub enum ConversationEvent {
+    Message(Message),
+    Typing { user_id: String, typing: bool },
+    /// A participant opened the conversation or closed their last page of it
+    Presence { user_id: String, online: bool },
+    /// A participant read these messages
+    Seen { user_id: String, message_ids: Vec<String> },
+}
+
+/// Something changed for a user that may affect their unread badge.
+#[derive(Debug, Clone)]
+pub enum UserEvent {
+    /// A message was posted in one of their conversations
+    Message { conversation_id: String },
+    /// They read one of their conversations
+    Read { conversation_id: String },
+}
+
+pub struct Hub {
+    conversations: Topics<ConversationEvent>,
+    users: Topics<UserEvent>,
+    /// Open connections per conversation, per user
+    presence: Mutex<HashMap<String, HashMap<String, usize>>>,
+}
+
+static HUB: OnceLock<Hub> = OnceLock::new();
+
+pub fn hub() -> &'static Hub {
+    HUB.get_or_init(|| Hub { conversations: Topics::new(), users: Topics::new(), presence: Mutex::new(HashMap::new()) })
+}
+
+impl Hub {
+    /// Events in a conversation from now on.
+    pub fn subscribe_conversation(&self, conversation_id: &str) -> broadcast::Receiver<ConversationEvent> {
+        self.conversations.subscribe(conversation_id)
+    }
+
+    pub fn subscribe_user(&self, user_id: &str) -> broadcast::Receiver<UserEvent> {
+        self.users.subscribe(user_id)
+    }
+
+    /// Tells the conversation and both of its participants about a new message.
+    pub fn publish_message(&self, message: Message, participants: [&str; 2]) {
+        for user_id in participants {
+            self.users.publish(user_id, UserEvent::Message { conversation_id: message.conversation_id.clone() });
+        }
+        let conversation_id = message.conversation_id.clone();
+        self.conversations.publish(&conversation_id, ConversationEvent::Message(message));
+    }
+
+    /// Tells the reader's badge and the conversation that messages were read.
+    pub fn publish_seen(&self, conversation_id: &str, user_id: &str, message_ids: Vec<String>) {
+        self.users.publish(user_id, UserEvent::Read { conversation_id: conversation_id.to_string() });
+        self.conversations.publish(conversation_id, ConversationEvent::Seen { user_id: user_id.to_string(), message_ids });
+    }
+
+    pub fn publish_typing(&self, conversation_id: &str, user_id: &str, typing: bool) {
+        self.conversations.publish(conversation_id, ConversationEvent::Typing { user_id: user_id.to_string(), typing });
+    }
+
+    /// Marks the user present in the conversation until the guard is dropped. Opening a second
+    /// page of the same conversation doesn't announce them again.
+    pub fn join(&'static self, conversation_id: &str, user_id: &str) -> Presence {
+        let first = {
+            let mut presence = self.presence.lock().unwrap();
+            let count = presence.entry(conversation_id.to_string()).or_default().entry(user_id.to_string()).or_insert(0);
+            *count += 1;
+            *count == 1
+        };
+        if first {
+            self.publish_presence(conversation_id, user_id, true);
+        }
+        Presence { hub: self, conversation_id: conversation_id.to_string(), user_id: user_id.to_string() }
+    }
+
+    /// Users with the conversation open right now.
+    pub fn present(&self, conversation_id: &str) -> Vec<String> {
+        let presence = self.presence.lock().unwrap();
+        presence.get(conversation_id).map(|users| users.keys().cloned().collect()).unwrap_or_default()
+    }
+
+    fn leave(&self, conversation_id: &str, user_id: &str) {
+        let last = {
+            let mut presence = self.presence.lock().unwrap();
+            let Some(users) = presence.get_mut(conversation_id) else { return };
+            let Some(count) = users.get_mut(user_id) else { return };
+            *count -= 1;
+            let last = *count == 0;
+            if last {
+                users.remove(user_id);
+                if users.is_empty() {
+                    presence.remove(conversation_id);
+                }
+            }
+            last
+        };
+        if last {
+            self.publish_presence(conversation_id, user_id, false);
+        }
+    }
+
+    fn publish_presence(&self, conversation_id: &str, user_id: &str, online: bool) {
+        self.conversations.publish(conversation_id, ConversationEvent::Presence { user_id: user_id.to_string(), online });
+    }
+}
+
+/// A user's open connection to a conversation; see `Hub::join`.
+pub struct Presence {
+    hub: &'static Hub,
+    conversation_id: String,
+    user_id: String,
+}
+
+impl Drop for Presence {
+    fn drop(&mut self) {
+        self.hub.leave(&self.conversation_id, &self.user_id);
+    }
+}
+
+/// One broadcast channel per key, created on first subscribe and dropped once its last
+/// subscriber has gone.
+struct Topics<T> {
+    channels: Mutex<HashMap<String, broadcast::Sender<T>>>,
+}
+
+impl<T: Clone> Topics<T> {
+    fn new() -> Self {
+        Topics { channels: Mutex::new(HashMap::new()) }
+    }
+
+    fn subscribe(&self, key: &str) -> broadcast::Receiver<T> {
+        let mut channels = self.channels.lock().unwrap();
+        channels.retain(|_, tx| tx.receiver_count() > 0);
+        channels.entry(key.to_string())
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe()
+    }
+
+    fn publish(&self, key: &str, event: T) {
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(tx) = channels.get(key) {
+            // Only fails when nobody is listening any more
+            if tx.send(event).is_err() {
+                channels.remove(key);
+            }
+        }
+    }
+}
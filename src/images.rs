@@ -0,0 +1,256 @@
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits};
+use std::io::Cursor;
+use crate::db::Db;
+use crate::media;
+use crate::models::UploadedImage;
+
+// === Image pipeline ===
+//
+// Uploads are never stored as sent. Every photo is sniffed by its magic bytes, decoded,
+// rotated upright according to its EXIF orientation and re-encoded from raw pixels, which
+// drops EXIF (GPS position, camera serials) and anything else riding along in the file.
+// Each upload is stored twice, through `media`: a full size for the listing page and a
+// thumbnail for feeds.
+
+/// Largest single photo we accept, before processing.
+pub const MAX_UPLOAD_BYTES: usize = 10 * 1024 * 1024;
+/// Longest edge of the stored full-size image.
+pub const FULL_MAX_EDGE: u32 = 1600;
+/// Longest edge of the stored thumbnail.
+pub const THUMB_MAX_EDGE: u32 = 400;
+/// Refuse images that would decode to more pixels than this (decompression bombs).
+const MAX_PIXELS: u64 = 50_000_000;
+const JPEG_QUALITY: u8 = 85;
+
+/// Identifies the format from the file's leading bytes. Names and content types sent by the
+/// client are ignored.
+pub fn sniff(data: &[u8]) -> Option<ImageFormat> {
+    if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some(ImageFormat::Jpeg)
+    } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some(ImageFormat::Png)
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some(ImageFormat::Gif)
+    } else if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        Some(ImageFormat::WebP)
+    } else {
+        None
+    }
+}
+
+/// A photo re-encoded for storage, in both sizes.
+pub struct ProcessedImage {
+    /// "jpg", or "png" for images with transparency
+    pub extension: &'static str,
+    pub full: Vec<u8>,
+    pub thumb: Vec<u8>,
+}
+
+pub fn process(data: &[u8]) -> Result<ProcessedImage, String> {
+    if data.len() > MAX_UPLOAD_BYTES {
+        return Err(format!("Photos can be at most {} MB.", MAX_UPLOAD_BYTES / (1024 * 1024)));
+    }
+    let format = sniff(data).ok_or_else(|| "Photos must be JPEG, PNG, WebP or GIF.".to_string())?;
+
+    let mut reader = ImageReader::with_format(Cursor::new(data), format);
+    let mut limits = Limits::default();
+    limits.max_alloc = Some(MAX_PIXELS * 4);
+    reader.limits(limits);
+    let unreadable = |_| "That photo couldn't be read. Try a different file.".to_string();
+    let mut decoder = reader.into_decoder().map_err(unreadable)?;
+    let (width, height) = decoder.dimensions();
+    if u64::from(width) * u64::from(height) > MAX_PIXELS {
+        return Err("That photo is too large to process.".to_string());
+    }
+    let orientation = decoder.orientation().map_err(unreadable)?;
+    let mut img = DynamicImage::from_decoder(decoder).map_err(unreadable)?;
+    img.apply_orientation(orientation);
+
+    let full = fit(&img, FULL_MAX_EDGE, FilterType::Lanczos3);
+    let thumb = fit(&img, THUMB_MAX_EDGE, FilterType::Triangle);
+    let (extension, full, thumb) = if img.color().has_alpha() {
+        ("png", encode_png(&full)?, encode_png(&thumb)?)
+    } else {
+        ("jpg", encode_jpeg(&full)?, encode_jpeg(&thumb)?)
+    };
+    Ok(ProcessedImage { extension, full, thumb })
+}
+
+fn fit(img: &DynamicImage, max_edge: u32, filter: FilterType) -> DynamicImage {
+    if img.width() <= max_edge && img.height() <= max_edge {
+        img.clone()
+    } else {
+        img.resize(max_edge, max_edge, filter)
+    }
+}
+
+fn encode_jpeg(img: &DynamicImage) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    let rgb = DynamicImage::ImageRgb8(img.to_rgb8());
+    rgb.write_with_encoder(JpegEncoder::new_with_quality(&mut out, JPEG_QUALITY))
+        .map_err(|e| format!("Couldn't encode photo: {}", e))?;
+    Ok(out)
+}
+
+fn encode_png(img: &DynamicImage) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    let rgba = DynamicImage::ImageRgba8(img.to_rgba8());
+    rgba.write_with_encoder(PngEncoder::new(&mut out))
+        .map_err(|e| format!("Couldn't encode photo: {}", e))?;
+    Ok(out)
+}
+
+/// Processes an upload and stores both sizes in the media store.
+pub fn store_upload(db: &Db, data: &[u8]) -> Result<UploadedImage, String> {
+    let processed = process(data)?;
+    let content_type = if processed.extension == "png" { "image/png" } else { "image/jpeg" };
+    let save_failed = |e| {
+        eprintln!("⚠️  Media store error: {}", e);
+        "Couldn't save your photo. Please try again.".to_string()
+    };
+    Ok(UploadedImage {
+        url: media::save(db, &processed.full, processed.extension, content_type).map_err(save_failed)?,
+        thumb_url: media::save(db, &processed.thumb, processed.extension, content_type).map_err(save_failed)?,
+    })
+}
+
+/// Stores a batch of uploads, stopping at the first bad one. Anything stored before the
+/// failure is unreferenced and goes with the next media sweep.
+pub fn store_uploads(db: &Db, uploads: &[Vec<u8>]) -> Result<Vec<UploadedImage>, String> {
+    uploads.iter().map(|data| store_upload(db, data)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImageView, RgbImage, RgbaImage};
+
+    fn encode(img: DynamicImage, format: ImageFormat) -> Vec<u8> {
+        let mut out = Cursor::new(Vec::new());
+        img.write_to(&mut out, format).unwrap();
+        out.into_inner()
+    }
+
+    fn rgb(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| image::Rgb([x as u8, y as u8, 90])))
+    }
+
+    // PNG chunk checksum (CRC-32/ISO-HDLC)
+    fn crc32(bytes: &[u8]) -> u32 {
+        let mut crc = 0xFFFF_FFFFu32;
+        for &b in bytes {
+            crc ^= u32::from(b);
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            }
+        }
+        !crc
+    }
+
+    /// Inserts an APP1 EXIF segment right after the JPEG's SOI marker: a big-endian TIFF
+    /// block with one Orientation entry, followed by `extra` as stand-in metadata.
+    fn with_exif(jpeg: &[u8], orientation: u16, extra: &[u8]) -> Vec<u8> {
+        let mut tiff = b"MM\0\x2a\0\0\0\x08".to_vec();
+        tiff.extend_from_slice(&1u16.to_be_bytes());
+        tiff.extend_from_slice(&[0x01, 0x12, 0x00, 0x03, 0, 0, 0, 1]);
+        tiff.extend_from_slice(&orientation.to_be_bytes());
+        tiff.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        tiff.extend_from_slice(extra);
+        let mut app1 = b"Exif\0\0".to_vec();
+        app1.extend_from_slice(&tiff);
+        let mut out = jpeg[..2].to_vec();
+        out.extend_from_slice(&[0xFF, 0xE1]);
+        out.extend_from_slice(&(app1.len() as u16 + 2).to_be_bytes());
+        out.extend_from_slice(&app1);
+        out.extend_from_slice(&jpeg[2..]);
+        out
+    }
+
+    #[test]
+    fn non_images_are_rejected_whatever_they_are_called() {
+        let err = "Photos must be JPEG, PNG, WebP or GIF.".to_string();
+        // What an upload named "photo.jpg" might really hold
+        assert_eq!(process(b"<html><script>alert(1)</script></html>").err(), Some(err.clone()));
+        assert_eq!(process(b"%PDF-1.7\n").err(), Some(err.clone()));
+        assert_eq!(process(b"").err(), Some(err));
+        assert_eq!(sniff(b"RIFF\0\0\0\0WAVE"), None);
+
+        // The right magic bytes on something that isn't really an image
+        let mut fake = vec![0xFF, 0xD8, 0xFF];
+        fake.extend_from_slice(b"not a jpeg at all");
+        assert_eq!(process(&fake).err().as_deref(), Some("That photo couldn't be read. Try a different file."));
+    }
+
+    #[test]
+    fn oversized_uploads_are_rejected() {
+        let mut big = b"\x89PNG\r\n\x1a\n".to_vec();
+        big.resize(MAX_UPLOAD_BYTES + 1, 0);
+        assert_eq!(process(&big).err().as_deref(), Some("Photos can be at most 10 MB."));
+    }
+
+    /// A PNG header claiming `width` × `height` pixels at `bit_depth` and `color_type`, with
+    /// an empty image data chunk.
+    fn png_header(width: u32, height: u32, bit_depth: u8, color_type: u8) -> Vec<u8> {
+        let mut ihdr = b"IHDR".to_vec();
+        ihdr.extend_from_slice(&width.to_be_bytes());
+        ihdr.extend_from_slice(&height.to_be_bytes());
+        ihdr.extend_from_slice(&[bit_depth, color_type, 0, 0, 0]);
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png.extend_from_slice(&13u32.to_be_bytes());
+        png.extend_from_slice(&ihdr);
+        png.extend_from_slice(&crc32(&ihdr).to_be_bytes());
+        for chunk in [b"IDAT", b"IEND"] {
+            png.extend_from_slice(&[0, 0, 0, 0]);
+            png.extend_from_slice(chunk);
+            png.extend_from_slice(&crc32(chunk).to_be_bytes());
+        }
+        png
+    }
+
+    #[test]
+    fn images_over_the_pixel_limit_are_rejected_before_decoding() {
+        // 60 million pixels: the decoder could allocate them, but we won't
+        assert_eq!(process(&png_header(8_000, 7_500, 8, 2)).err().as_deref(), Some("That photo is too large to process."));
+        assert_eq!(process(&png_header(10_000, 10_000, 16, 6)).err().as_deref(), Some("That photo is too large to process."));
+    }
+
+    #[test]
+    fn exif_is_applied_then_stripped() {
+        let secret = b"GPS 51.5072N 0.1276W serial 0042";
+        let jpeg = with_exif(&encode(rgb(64, 32), ImageFormat::Jpeg), 6, secret);
+        assert!(jpeg.windows(secret.len()).any(|w| w == secret));
+
+        let processed = process(&jpeg).unwrap();
+        assert_eq!(processed.extension, "jpg");
+        for out in [&processed.full, &processed.thumb] {
+            assert!(!out.windows(4).any(|w| w == b"Exif"));
+            assert!(!out.windows(secret.len()).any(|w| w == secret));
+        }
+        // Orientation 6 means "rotate 90° clockwise to display"; the pixels are stored upright now
+        let full = image::load_from_memory(&processed.full).unwrap();
+        assert_eq!(full.dimensions(), (32, 64));
+    }
+
+    #[test]
+    fn full_size_and_thumbnail_fit_their_edges() {
+        let processed = process(&encode(rgb(1200, 600), ImageFormat::Png)).unwrap();
+        assert_eq!(processed.extension, "jpg");
+        assert_eq!(image::load_from_memory(&processed.full).unwrap().dimensions(), (1200, 600));
+        let thumb = image::load_from_memory_with_format(&processed.thumb, ImageFormat::Jpeg).unwrap();
+        assert_eq!(thumb.dimensions(), (THUMB_MAX_EDGE, THUMB_MAX_EDGE / 2));
+
+        let tall = process(&encode(rgb(300, 2000), ImageFormat::Jpeg)).unwrap();
+        assert_eq!(image::load_from_memory(&tall.full).unwrap().dimensions(), (240, FULL_MAX_EDGE));
+        assert_eq!(image::load_from_memory(&tall.thumb).unwrap().dimensions(), (60, THUMB_MAX_EDGE));
+
+        // Transparency survives as PNG
+        let clear = DynamicImage::ImageRgba8(RgbaImage::from_pixel(500, 500, image::Rgba([0, 0, 0, 0])));
+        let processed = process(&encode(clear, ImageFormat::Png)).unwrap();
+        assert_eq!(processed.extension, "png");
+        assert_eq!(sniff(&processed.thumb), Some(ImageFormat::Png));
+        assert_eq!(image::load_from_memory(&processed.thumb).unwrap().dimensions(), (THUMB_MAX_EDGE, THUMB_MAX_EDGE));
+    }
+}
@@ -1,42 +1,112 @@
 pub mod auth;
+pub mod csrf;
 pub mod db;
+pub mod events;
+pub mod images;
+pub mod mailer;
+pub mod media;
+pub mod migrations;
 pub mod models;
+pub mod money;
+pub mod payments;
+pub mod ratelimit;
 pub mod routes;
+pub mod sweeper;
 
-use axum::{routing::{get, post}, Router};
+use axum::{extract::DefaultBodyLimit, middleware, routing::{get, post}, Json, Router};
+use routes::api;
 use std::sync::Arc;
 use tera::Tera;
 use tower_http::services::ServeDir;
+use utoipa::OpenApi;
+use utoipa_axum::{router::OpenApiRouter, routes};
 
+/// The whole app. `state.1` needs `csrf::register` applied.
 pub fn build_router(state: (db::Db, Arc<Tera>)) -> Router {
+    // Room for a full set of photos plus the text fields
+    let listing_form_limit = DefaultBodyLimit::max(images::MAX_UPLOAD_BYTES * db::MAX_LISTING_IMAGES + 64 * 1024);
+
+    // JSON API for the mobile app and scripts, mounted under /api/v1. Paths and methods come
+    // from each handler's #[utoipa::path], which also builds the OpenAPI document.
+    let (api_v1, openapi) = OpenApiRouter::with_openapi(api::ApiDoc::openapi())
+        .routes(routes!(api::login))
+        .routes(routes!(api::register))
+        .routes(routes!(api::logout))
+        .routes(routes!(api::me, api::update_me))
+        .routes(routes!(api::list_listings, api::create_listing))
+        .routes(routes!(api::get_listing, api::update_listing, api::delete_listing))
+        .routes(routes!(api::start_conversation))
+        .routes(routes!(api::list_conversations))
+        .routes(routes!(api::get_conversation))
+        .routes(routes!(api::list_messages, api::send_message))
+        .routes(routes!(api::make_offer))
+        .routes(routes!(api::respond_offer))
+        .fallback(api::not_found)
+        .split_for_parts();
+
     Router::new()
         // Marketplace feed
         .route("/", get(routes::listings::feed))
         .route("/search", get(routes::listings::feed_partial))
         // Listings
-        .route("/sell", get(routes::listings::new_listing_page).post(routes::listings::create_listing))
+        .route("/sell", get(routes::listings::new_listing_page).post(routes::listings::create_listing).layer(listing_form_limit))
         .route("/listing/{id}", get(routes::listings::listing_detail))
-        .route("/listing/{id}/edit", get(routes::listings::edit_listing_page).post(routes::listings::update_listing))
+        .route("/listing/{id}/edit", get(routes::listings::edit_listing_page).post(routes::listings::update_listing).layer(listing_form_limit))
         .route("/listing/{id}/sold", post(routes::listings::mark_sold))
+        .route("/listing/{id}/unreserve", post(routes::listings::unreserve))
         .route("/listing/{id}/delete", post(routes::listings::delete_listing))
+        .route("/listing/{id}/images/{image_id}/move", post(routes::listings::move_image))
+        .route("/listing/{id}/images/{image_id}/delete", post(routes::listings::delete_image))
+        .route("/listing/{id}/images/{image_id}/alt", post(routes::listings::update_image_alt))
         // Messages
         .route("/messages", get(routes::messages::inbox))
         .route("/messages/{id}", get(routes::messages::conversation))
         .route("/messages/{id}/send", post(routes::messages::send_message))
         .route("/messages/{id}/offer", post(routes::messages::make_offer))
-        .route("/messages/{convo_id}/offer/{offer_id}/respond", get(routes::messages::respond_offer))
+        .route("/messages/{convo_id}/offer/{offer_id}/respond", post(routes::messages::respond_offer))
+        .route("/messages/{convo_id}/offer/{offer_id}/counter", post(routes::messages::counter_offer))
         .route("/messages/{id}/poll", get(routes::messages::poll_messages))
+        .route("/messages/{id}/older", get(routes::messages::older_messages))
+        .route("/messages/{id}/events", get(routes::messages::conversation_events))
+        .route("/messages/{id}/ws", get(routes::messages::conversation_socket))
+        .route("/messages/events", get(routes::messages::unread_events))
         // Start conversation from listing
-        .route("/listing/{id}/contact", get(routes::messages::start_conversation))
+        .route("/listing/{id}/contact", post(routes::messages::start_conversation))
+        // Cart
+        .route("/cart", get(routes::cart::cart_page))
+        .route("/cart/count", get(routes::cart::cart_count))
+        .route("/cart/add/{listing_id}", post(routes::cart::add_to_cart))
+        .route("/cart/{listing_id}/remove", post(routes::cart::remove_item))
+        // Checkout & orders
+        .route("/checkout/cart", post(routes::orders::checkout_cart))
+        .route("/checkout/offer/{offer_id}", post(routes::orders::checkout_offer))
+        .route("/orders", get(routes::orders::orders_page))
+        .route("/orders/{id}", get(routes::orders::order_detail))
+        .route("/orders/{id}/status", post(routes::orders::update_status))
+        // Payments
+        .route("/orders/{id}/pay", post(routes::payments::start_payment))
+        .route("/orders/{id}/pay/confirm", post(routes::payments::confirm_payment))
+        .route("/payments/webhook/{provider}", post(routes::payments::webhook))
         // Auth
         .route("/login", get(routes::auth::login_page).post(routes::auth::login))
         .route("/register", get(routes::auth::register_page).post(routes::auth::register))
-        .route("/logout", get(routes::auth::logout))
+        .route("/logout", post(routes::auth::logout))
+        .route("/forgot-password", get(routes::auth::forgot_password_page).post(routes::auth::forgot_password))
+        .route("/reset-password", get(routes::auth::reset_password_page).post(routes::auth::reset_password))
+        .route("/verify-email", get(routes::auth::verify_email_page).post(routes::auth::verify_email))
         .route("/profile", get(routes::auth::profile).post(routes::auth::update_profile))
+        .route("/profile/verify-email", post(routes::auth::resend_verification))
+        .route("/profile/tokens", post(routes::auth::create_api_token))
+        .route("/profile/tokens/{id}/revoke", post(routes::auth::revoke_api_token))
+        .route("/profile/sessions/{id}/revoke", post(routes::auth::revoke_session))
+        .route("/profile/sessions/revoke-all", post(routes::auth::revoke_all_sessions))
+        .nest("/api/v1", api_v1)
+        .route("/api/openapi.json", get(move || async move { Json(openapi) }))
         // Health
         .route("/health", get(health))
         // Static files
         .nest_service("/static", ServeDir::new("static"))
+        .layer(middleware::from_fn_with_state(state.clone(), csrf::protect))
         .with_state(state)
 }
 
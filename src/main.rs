@@ -4,7 +4,23 @@ use tera::Tera;
 #[tokio::main]
 async fn main() {
     let database = forge_commerce::db::init_db();
-    let tera = Arc::new(Tera::new("templates/**/*.html").expect("Failed to load templates"));
+
+    if std::env::args().nth(1).as_deref() == Some("sweep-media") {
+        let db = database.clone();
+        match tokio::task::spawn_blocking(move || forge_commerce::media::sweep(&db, forge_commerce::media::store())).await.unwrap() {
+            Ok(removed) => println!("🧹 Removed {} unreferenced media objects", removed),
+            Err(e) => {
+                eprintln!("❌ Media sweep failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    forge_commerce::sweeper::spawn(database.clone());
+    let mut tera = Tera::new("templates/**/*.html").expect("Failed to load templates");
+    forge_commerce::csrf::register(&mut tera);
+    let tera = Arc::new(tera);
 
     let app = forge_commerce::build_router((database, tera));
 
@@ -13,7 +29,8 @@ async fn main() {
     println!("🔨 Forge Commerce running at http://localhost:{}", port);
 
     let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
-    axum::serve(listener, app)
+    // Per-IP rate limits need the peer address
+    axum::serve(listener, app.into_make_service_with_connect_info::<std::net::SocketAddr>())
         .with_graceful_shutdown(shutdown_signal())
         .await
         .unwrap();
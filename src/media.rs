@@ -0,0 +1,238 @@
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use crate::db::{self, Db};
+
+// === Media storage ===
+//
+// Uploaded files are written through a `MediaStore`, keyed by the SHA-256 of their bytes,
+// so the same photo uploaded twice is stored once. Each stored object has a row in
+// `media_objects` whose refcount is maintained by triggers on the tables that point at it;
+// `sweep()` (the `sweep-media` command) deletes objects nobody points at any more.
+//
+// The backend is chosen once at startup by `FORGE_MEDIA_STORE`: `local` (default) or `s3`.
+
+pub trait MediaStore: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn put(&self, key: &str, data: &[u8], content_type: &str) -> Result<(), String>;
+
+    /// Deleting a key that doesn't exist is not an error.
+    fn delete(&self, key: &str) -> Result<(), String>;
+
+    /// Public URL the browser loads the object from
+    fn url(&self, key: &str) -> String;
+}
+
+static STORE: OnceLock<Box<dyn MediaStore>> = OnceLock::new();
+
+pub fn store() -> &'static dyn MediaStore {
+    STORE.get_or_init(|| {
+        match std::env::var("FORGE_MEDIA_STORE").as_deref() {
+            Ok("s3") => Box::new(S3Store::from_env().expect("FORGE_MEDIA_STORE=s3 needs the FORGE_S3_* settings")),
+            _ => Box::new(LocalStore::new("static/media", "/static/media")),
+        }
+    }).as_ref()
+}
+
+/// Content-addressed key: `ab/abcdef….ext`. The two-character prefix keeps directories small.
+pub fn content_key(data: &[u8], extension: &str) -> String {
+    let hash = hex::encode(Sha256::digest(data));
+    format!("{}/{}.{}", &hash[..2], hash, extension)
+}
+
+/// Stores `data` unless an object with the same content is already stored, and returns its URL.
+/// The object starts unreferenced; it's kept once a row points at the URL.
+pub fn save(db: &Db, data: &[u8], extension: &str, content_type: &str) -> Result<String, String> {
+    let store = store();
+    let key = content_key(data, extension);
+    let url = store.url(&key);
+    if !db::touch_media_object(db, &key) {
+        store.put(&key, data, content_type)?;
+        db::insert_media_object(db, &key, &url, content_type, data.len() as i64);
+    }
+    Ok(url)
+}
+
+/// Objects unreferenced for this long are deleted. The delay covers the gap between
+/// storing an upload and inserting the row that points at it.
+pub const SWEEP_GRACE_MINUTES: i64 = 60;
+
+/// Deletes unreferenced objects from `store`. Returns how many were removed.
+pub fn sweep(db: &Db, store: &dyn MediaStore) -> Result<usize, String> {
+    db::sweep_unreferenced_media(db, SWEEP_GRACE_MINUTES, |key| {
+        store.delete(key).map_err(|e| format!("Couldn't delete {}: {}", key, e))
+    })
+}
+
+// === Local filesystem ===
+
+pub struct LocalStore {
+    root: PathBuf,
+    url_prefix: String,
+}
+
+impl LocalStore {
+    pub fn new(root: impl Into<PathBuf>, url_prefix: &str) -> Self {
+        LocalStore { root: root.into(), url_prefix: url_prefix.trim_end_matches('/').to_string() }
+    }
+}
+
+impl MediaStore for LocalStore {
+    fn name(&self) -> &'static str { "local" }
+
+    fn put(&self, key: &str, data: &[u8], _content_type: &str) -> Result<(), String> {
+        let path = self.root.join(key);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        // Write then rename so a reader never sees half a file
+        let tmp = path.with_extension("part");
+        std::fs::write(&tmp, data).map_err(|e| e.to_string())?;
+        std::fs::rename(&tmp, &path).map_err(|e| e.to_string())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), String> {
+        match std::fs::remove_file(self.root.join(key)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("{}/{}", self.url_prefix, key)
+    }
+}
+
+// === S3-compatible object storage ===
+
+/// Any service speaking the S3 API (AWS, MinIO, R2, …), using path-style addressing and
+/// Signature Version 4. Objects must be publicly readable at `public_url`.
+pub struct S3Store {
+    endpoint: String,
+    host: String,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    public_url: String,
+    agent: ureq::Agent,
+}
+
+impl S3Store {
+    pub fn new(endpoint: &str, bucket: &str, region: &str, access_key: &str, secret_key: &str, public_url: Option<&str>) -> Self {
+        let endpoint = endpoint.trim_end_matches('/').to_string();
+        let host = endpoint.split("://").nth(1).unwrap_or(&endpoint).split('/').next().unwrap_or("").to_string();
+        let public_url = public_url
+            .map(|u| u.trim_end_matches('/').to_string())
+            .unwrap_or_else(|| format!("{}/{}", endpoint, bucket));
+        S3Store {
+            endpoint, host,
+            bucket: bucket.to_string(),
+            region: region.to_string(),
+            access_key: access_key.to_string(),
+            secret_key: secret_key.to_string(),
+            public_url,
+            agent: ureq::AgentBuilder::new().timeout(std::time::Duration::from_secs(30)).build(),
+        }
+    }
+
+    /// Reads `FORGE_S3_ENDPOINT`, `FORGE_S3_BUCKET`, `FORGE_S3_ACCESS_KEY`, `FORGE_S3_SECRET_KEY`,
+    /// and optionally `FORGE_S3_REGION` (default us-east-1) and `FORGE_S3_PUBLIC_URL`.
+    pub fn from_env() -> Result<Self, String> {
+        let var = |name: &str| std::env::var(name).map_err(|_| format!("{} is not set", name));
+        let region = std::env::var("FORGE_S3_REGION").unwrap_or_else(|_| "us-east-1".to_string());
+        let public_url = std::env::var("FORGE_S3_PUBLIC_URL").ok();
+        Ok(S3Store::new(
+            &var("FORGE_S3_ENDPOINT")?, &var("FORGE_S3_BUCKET")?, &region,
+            &var("FORGE_S3_ACCESS_KEY")?, &var("FORGE_S3_SECRET_KEY")?, public_url.as_deref(),
+        ))
+    }
+
+    fn path(&self, key: &str) -> String {
+        format!("/{}/{}", uri_encode(&self.bucket, true), uri_encode(key, false))
+    }
+
+    /// Value of the `Authorization` header for a request with no query string.
+    pub fn authorization(&self, method: &str, path: &str, payload_hash: &str, amz_date: &str) -> String {
+        let date = &amz_date[..8];
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, path, self.host, payload_hash, amz_date, signed_headers, payload_hash,
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date, scope, hex::encode(Sha256::digest(canonical_request.as_bytes())),
+        );
+        let mut key = hmac_sha256(format!("AWS4{}", self.secret_key).as_bytes(), date.as_bytes());
+        for part in [self.region.as_str(), "s3", "aws4_request"] {
+            key = hmac_sha256(&key, part.as_bytes());
+        }
+        let signature = hex::encode(hmac_sha256(&key, string_to_sign.as_bytes()));
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key, scope, signed_headers, signature,
+        )
+    }
+
+    fn send(&self, method: &str, key: &str, body: &[u8], content_type: Option<&str>) -> Result<(), String> {
+        let path = self.path(key);
+        let payload_hash = hex::encode(Sha256::digest(body));
+        let amz_date = chrono::Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let mut request = self.agent.request(method, &format!("{}{}", self.endpoint, path))
+            .set("x-amz-content-sha256", &payload_hash)
+            .set("x-amz-date", &amz_date)
+            .set("Authorization", &self.authorization(method, &path, &payload_hash, &amz_date));
+        if let Some(ct) = content_type {
+            request = request.set("Content-Type", ct);
+        }
+        match request.send_bytes(body) {
+            Ok(_) => Ok(()),
+            Err(ureq::Error::Status(code, response)) => {
+                let detail = response.into_string().unwrap_or_default();
+                Err(format!("{} {} returned {}: {}", method, path, code, detail.trim()))
+            }
+            Err(e) => Err(format!("{} {} failed: {}", method, path, e)),
+        }
+    }
+}
+
+impl MediaStore for S3Store {
+    fn name(&self) -> &'static str { "s3" }
+
+    fn put(&self, key: &str, data: &[u8], content_type: &str) -> Result<(), String> {
+        self.send("PUT", key, data, Some(content_type))
+    }
+
+    fn delete(&self, key: &str) -> Result<(), String> {
+        // S3 answers 204 for missing keys too
+        self.send("DELETE", key, &[], None)
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("{}/{}", self.public_url, key)
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// SigV4 URI encoding: everything but unreserved characters is percent-encoded, and `/` too
+/// unless it separates path segments.
+fn uri_encode(s: &str, encode_slash: bool) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => out.push(b as char),
+            b'/' if !encode_slash => out.push('/'),
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
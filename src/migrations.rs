@@ -0,0 +1,127 @@
+use rusqlite::{params, Connection};
+
+// === Versioned schema migrations ===
+//
+// Each migration is a SQL file under `migrations/`, embedded at compile time and
+// applied in order inside its own transaction. Applied versions are recorded in
+// `schema_migrations`. Migrations are append-only: never edit one that has shipped,
+// add a new file instead.
+
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, name: "initial", sql: include_str!("../migrations/0001_initial.sql") },
+    Migration { version: 2, name: "listings_fts", sql: include_str!("../migrations/0002_listings_fts.sql") },
+    Migration { version: 3, name: "money_minor_units", sql: include_str!("../migrations/0003_money_minor_units.sql") },
+    Migration { version: 4, name: "carts", sql: include_str!("../migrations/0004_carts.sql") },
+    Migration { version: 5, name: "user_carts", sql: include_str!("../migrations/0005_user_carts.sql") },
+    Migration { version: 6, name: "orders", sql: include_str!("../migrations/0006_orders.sql") },
+    Migration { version: 7, name: "payment_intents", sql: include_str!("../migrations/0007_payment_intents.sql") },
+    Migration { version: 8, name: "offer_negotiation", sql: include_str!("../migrations/0008_offer_negotiation.sql") },
+    Migration { version: 9, name: "offer_expiry", sql: include_str!("../migrations/0009_offer_expiry.sql") },
+    Migration { version: 10, name: "listing_images", sql: include_str!("../migrations/0010_listing_images.sql") },
+    Migration { version: 11, name: "image_thumbnails", sql: include_str!("../migrations/0011_image_thumbnails.sql") },
+    Migration { version: 12, name: "media_objects", sql: include_str!("../migrations/0012_media_objects.sql") },
+    Migration { version: 13, name: "api_tokens", sql: include_str!("../migrations/0013_api_tokens.sql") },
+    Migration { version: 14, name: "message_receipts", sql: include_str!("../migrations/0014_message_receipts.sql") },
+    Migration { version: 15, name: "message_seq", sql: include_str!("../migrations/0015_message_seq.sql") },
+    Migration { version: 16, name: "session_csrf_tokens", sql: include_str!("../migrations/0016_session_csrf_tokens.sql") },
+    Migration { version: 17, name: "rate_limits", sql: include_str!("../migrations/0017_rate_limits.sql") },
+    Migration { version: 18, name: "session_details", sql: include_str!("../migrations/0018_session_details.sql") },
+    Migration { version: 19, name: "password_resets", sql: include_str!("../migrations/0019_password_resets.sql") },
+    Migration { version: 20, name: "email_verification", sql: include_str!("../migrations/0020_email_verification.sql") },
+    Migration { version: 21, name: "single_item_cart_lines", sql: include_str!("../migrations/0021_single_item_cart_lines.sql") },
+    Migration { version: 22, name: "voided_payment_intents", sql: include_str!("../migrations/0022_voided_payment_intents.sql") },
+];
+
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+pub fn current_version(conn: &Connection) -> rusqlite::Result<i64> {
+    conn.query_row("SELECT COALESCE(MAX(version), 0) FROM schema_migrations", [], |r| r.get(0))
+}
+
+/// Brings the database up to `latest_version()` and returns the migrations that were applied.
+/// Refuses to touch a database whose schema is newer than this binary knows about.
+pub fn run(conn: &mut Connection) -> Result<Vec<&'static Migration>, String> {
+    conn.execute_batch("
+        CREATE TABLE IF NOT EXISTS schema_migrations (
+            version INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            applied_at TEXT NOT NULL DEFAULT (datetime('now'))
+        );
+    ").map_err(|e| format!("Failed to create schema_migrations: {}", e))?;
+
+    let current = current_version(conn).map_err(|e| format!("Failed to read schema version: {}", e))?;
+    let latest = latest_version();
+    if current > latest {
+        return Err(format!(
+            "Database schema is at version {} but this build only knows migrations up to {}. \
+             Upgrade forge-commerce before opening this database.",
+            current, latest
+        ));
+    }
+
+    let mut applied = Vec::new();
+    for m in MIGRATIONS.iter().filter(|m| m.version > current) {
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+        tx.execute_batch(m.sql)
+            .map_err(|e| format!("Migration {:04}_{} failed: {}", m.version, m.name, e))?;
+        tx.execute(
+            "INSERT INTO schema_migrations (version, name) VALUES (?1, ?2)",
+            params![m.version, m.name],
+        ).map_err(|e| e.to_string())?;
+        tx.commit().map_err(|e| format!("Migration {:04}_{} failed: {}", m.version, m.name, e))?;
+        applied.push(m);
+    }
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("PRAGMA foreign_keys=ON;").unwrap();
+        conn
+    }
+
+    #[test]
+    fn versions_count_up_from_one() {
+        for (i, m) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(m.version, i as i64 + 1, "migration {} is out of order", m.name);
+        }
+    }
+
+    #[test]
+    fn run_applies_everything_once() {
+        let mut conn = memory();
+        let applied = run(&mut conn).unwrap();
+        assert_eq!(applied.len(), MIGRATIONS.len());
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+
+        // A second run finds nothing to do and records nothing
+        assert!(run(&mut conn).unwrap().is_empty());
+        let recorded: i64 = conn.query_row("SELECT COUNT(*) FROM schema_migrations", [], |r| r.get(0)).unwrap();
+        assert_eq!(recorded, MIGRATIONS.len() as i64);
+    }
+
+    #[test]
+    fn run_refuses_a_database_newer_than_the_binary() {
+        let mut conn = memory();
+        run(&mut conn).unwrap();
+        let newer = latest_version() + 1;
+        conn.execute("INSERT INTO schema_migrations (version, name) VALUES (?1, 'from_the_future')", params![newer]).unwrap();
+
+        let err = run(&mut conn).err().expect("a newer schema is refused");
+        assert!(err.contains(&format!("version {}", newer)), "{}", err);
+        assert!(err.contains(&format!("up to {}", latest_version())), "{}", err);
+        assert_eq!(current_version(&conn).unwrap(), newer);
+    }
+}
@@ -1,37 +1,118 @@
 use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use crate::money::Money;
 
 // === Domain Models ===
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct User {
     pub id: String,
     pub email: String,
     pub name: String,
+    #[serde(skip_serializing)]
     pub password_hash: String,
     pub location: String,
     pub avatar_url: String,
     pub payment_info: String,
     pub bio: String,
+    /// How long offers on this user's listings stay open before expiring
+    pub offer_ttl_hours: i64,
+    /// When the email address was confirmed. Until then the user can't list items, message
+    /// sellers or make offers.
+    pub email_verified_at: Option<String>,
     pub created_at: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl User {
+    pub fn is_verified(&self) -> bool {
+        self.email_verified_at.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct Listing {
     pub id: String,
     pub seller_id: String,
     pub seller_name: String,
     pub title: String,
     pub description: String,
-    pub price: f64,
+    pub price: Money,
     pub category: String,
     pub condition: String,
     pub location: String,
     pub image_url: String,
+    /// Small version of the cover for cards; same as `image_url` when there's none
+    pub thumb_url: String,
     pub status: String,
     pub created_at: String,
 }
 
+/// A personal API token as shown to its owner. The secret itself is never stored.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub id: String,
+    pub name: String,
+    /// First characters of the token, for telling tokens apart
+    pub prefix: String,
+    pub scopes: Vec<String>,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+}
+
+/// A signed-in browser or API login, as listed on its owner's profile.
+#[derive(Debug, Clone, Serialize)]
+pub struct Session {
+    /// Names the session in revoke forms; not the cookie value
+    pub public_id: String,
+    pub created_at: String,
+    pub last_seen_at: String,
+    pub user_agent: String,
+    pub ip: String,
+    /// The session making the request
+    pub current: bool,
+}
+
+/// A message the `outbox` mailer kept instead of sending.
+#[derive(Debug, Clone, Serialize)]
+pub struct OutboxMessage {
+    pub id: String,
+    pub to_address: String,
+    pub subject: String,
+    pub body: String,
+    /// The whole message as it would have been sent
+    pub message: String,
+    pub created_at: String,
+}
+
+/// Events counted against a rate limit for one key. Times are unix seconds.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RateCounter {
+    pub count: u32,
+    /// When `count` starts over from zero
+    pub reset_at: i64,
+    /// Requests are refused until then
+    pub blocked_until: i64,
+}
+
+/// One photo of a listing. Position 0 is the cover shown in the feed.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ListingImage {
+    pub id: String,
+    pub listing_id: String,
+    pub url: String,
+    pub thumb_url: String,
+    pub alt: String,
+    pub position: i64,
+}
+
+/// Where a processed upload was stored.
+#[derive(Debug, Clone)]
+pub struct UploadedImage {
+    pub url: String,
+    pub thumb_url: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct Conversation {
     pub id: String,
     pub listing_id: String,
@@ -46,28 +127,304 @@ pub struct Conversation {
     pub unread_count: i64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct Message {
     pub id: String,
     pub conversation_id: String,
     pub sender_id: String,
     pub sender_name: String,
     pub content: String,
-    pub is_offer: bool,
-    pub offer_amount: Option<f64>,
-    pub offer_status: Option<String>,
+    /// Position in the conversation, counting from 1
+    pub seq: i64,
     pub created_at: String,
+    /// Whether the other participant has read it
+    pub seen: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct Offer {
     pub id: String,
     pub listing_id: String,
     pub conversation_id: String,
     pub buyer_id: String,
-    pub amount: f64,
+    /// Who named this amount: the buyer, or the seller when countering
+    pub created_by: String,
+    /// The offer this one counters, if any
+    pub parent_id: Option<String>,
+    pub amount: Money,
     pub status: String,
+    pub expires_at: Option<String>,
+    pub created_at: String,
+}
+
+/// How the recipient of a pending offer answers it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OfferResponse {
+    Accept,
+    Reject,
+    Counter(Money),
+}
+
+/// Why `db::respond_to_offer` turned an answer down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OfferError {
+    NotFound,
+    /// Only the party the offer was made to can answer it
+    Forbidden,
+    /// The offer is no longer pending, or the listing is no longer available
+    Conflict(&'static str),
+}
+
+impl std::fmt::Display for OfferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OfferError::NotFound => f.write_str("Offer not found"),
+            OfferError::Forbidden => f.write_str("Only the other party can answer this offer"),
+            OfferError::Conflict(reason) => f.write_str(reason),
+        }
+    }
+}
+
+/// Why `db::update_order_status` or `db::cancel_order` left an order as it was.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OrderError {
+    NotFound,
+    /// The order's state machine doesn't allow the change for this user
+    Illegal(String),
+    /// The payment couldn't be given back, so a paid order stays paid
+    RefundFailed(String),
+}
+
+impl std::fmt::Display for OrderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OrderError::NotFound => f.write_str("Order not found"),
+            OrderError::Illegal(reason) => f.write_str(reason),
+            OrderError::RefundFailed(e) => write!(f, "Couldn't refund the payment: {}", e),
+        }
+    }
+}
+
+/// Why `db::update_listing` or `db::delete_listing` left a listing as it was.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListingError {
+    NotFound,
+    /// Conversations or offers still point at the listing
+    Conflict(&'static str),
+    Failed(String),
+}
+
+impl std::fmt::Display for ListingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListingError::NotFound => f.write_str("Listing not found"),
+            ListingError::Conflict(reason) => f.write_str(reason),
+            ListingError::Failed(e) => write!(f, "Couldn't save the listing: {}", e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CartItem {
+    pub listing_id: String,
+    pub title: String,
+    pub image_url: String,
+    pub price: Money,
+    pub seller_id: String,
+    pub seller_name: String,
+    pub status: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CartSellerGroup {
+    pub seller_id: String,
+    pub seller_name: String,
+    pub items: Vec<CartItem>,
+    pub subtotal: Money,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Cart {
+    pub groups: Vec<CartSellerGroup>,
+    pub item_count: i64,
+    pub total: Option<Money>,
+}
+
+/// Whose cart to read or change: a guest identified by the `forge_cart` cookie, or a signed-in user.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CartOwner {
+    Guest(String),
+    User(String),
+}
+
+/// What happened when a guest cart was folded into a user's cart at login.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CartMerge {
+    /// Items that were new to the user's cart
+    pub added: i64,
+    /// Items the user already had
+    pub combined: i64,
+    /// Items that were sold, deleted, listed by the user themselves or priced in another currency
+    pub dropped: i64,
+}
+
+impl CartMerge {
+    pub fn is_empty(&self) -> bool {
+        self.added == 0 && self.combined == 0 && self.dropped == 0
+    }
+}
+
+/// Order lifecycle:
+///
+/// ```text
+/// pending_payment → paid → handed_over | shipped → completed
+///        ↓           ↓
+///    cancelled   cancelled
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderStatus {
+    PendingPayment,
+    Paid,
+    HandedOver,
+    Shipped,
+    Completed,
+    Cancelled,
+}
+
+impl OrderStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrderStatus::PendingPayment => "pending_payment",
+            OrderStatus::Paid => "paid",
+            OrderStatus::HandedOver => "handed_over",
+            OrderStatus::Shipped => "shipped",
+            OrderStatus::Completed => "completed",
+            OrderStatus::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<OrderStatus> {
+        match s {
+            "pending_payment" => Some(OrderStatus::PendingPayment),
+            "paid" => Some(OrderStatus::Paid),
+            "handed_over" => Some(OrderStatus::HandedOver),
+            "shipped" => Some(OrderStatus::Shipped),
+            "completed" => Some(OrderStatus::Completed),
+            "cancelled" => Some(OrderStatus::Cancelled),
+            _ => None,
+        }
+    }
+
+    /// Statuses the buyer or the seller may move an order to from here. `Paid` is not one of
+    /// them: an order only becomes paid when its payment provider confirms a payment.
+    pub fn next_steps(&self, is_seller: bool) -> &'static [OrderStatus] {
+        use OrderStatus::*;
+        match (self, is_seller) {
+            (PendingPayment, _) => &[Cancelled],
+            (Paid, true) => &[HandedOver, Shipped, Cancelled],
+            (HandedOver | Shipped, false) => &[Completed],
+            _ => &[],
+        }
+    }
+
+    pub fn can_become(&self, next: OrderStatus, is_seller: bool) -> bool {
+        self.next_steps(is_seller).contains(&next)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderItem {
+    pub listing_id: String,
+    pub title: String,
+    pub image_url: String,
+    pub unit_price: Money,
+    pub quantity: i64,
+    pub line_total: Money,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Order {
+    pub id: String,
+    pub buyer_id: String,
+    pub buyer_name: String,
+    pub seller_id: String,
+    pub seller_name: String,
+    pub offer_id: Option<String>,
+    pub status: OrderStatus,
+    pub total: Money,
+    pub payment_info: String,
+    pub items: Vec<OrderItem>,
     pub created_at: String,
+    pub updated_at: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IntentStatus {
+    RequiresAction,
+    Succeeded,
+    Failed,
+    Refunded,
+    /// Left open when its order stopped waiting for payment
+    Voided,
+}
+
+impl IntentStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IntentStatus::RequiresAction => "requires_action",
+            IntentStatus::Succeeded => "succeeded",
+            IntentStatus::Failed => "failed",
+            IntentStatus::Refunded => "refunded",
+            IntentStatus::Voided => "voided",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<IntentStatus> {
+        match s {
+            "requires_action" => Some(IntentStatus::RequiresAction),
+            "succeeded" => Some(IntentStatus::Succeeded),
+            "failed" => Some(IntentStatus::Failed),
+            "refunded" => Some(IntentStatus::Refunded),
+            "voided" => Some(IntentStatus::Voided),
+            _ => None,
+        }
+    }
+
+    /// A provider's verdict only moves an intent forward: an open intent succeeds, fails or
+    /// is voided, and a successful one can be refunded. Anything else is a replay or arrived
+    /// too late.
+    pub fn can_become(&self, next: IntentStatus) -> bool {
+        use IntentStatus::*;
+        matches!((self, next), (RequiresAction, Succeeded | Failed | Voided) | (Succeeded, Refunded))
+    }
+}
+
+/// What `db::record_payment` made of a provider's verdict.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaymentRecord {
+    /// The intent moved on, and a success paid the order
+    Recorded,
+    /// The verdict no longer applies, e.g. a redelivered webhook. Nothing changed.
+    Stale,
+    /// Money was taken for an order that isn't waiting for it: cancelled, or already paid
+    /// through another intent. The intent is marked succeeded and must be refunded.
+    Unwanted,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PaymentIntent {
+    pub id: String,
+    pub order_id: String,
+    pub provider: String,
+    pub provider_ref: String,
+    pub status: IntentStatus,
+    pub amount: Money,
+    pub instructions: String,
+    pub created_at: String,
+    pub updated_at: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -92,6 +449,18 @@ pub struct RegisterForm {
     pub password_confirm: String,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct ForgotPasswordForm {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetPasswordForm {
+    pub token: String,
+    pub password: String,
+    pub password_confirm: String,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct ListingForm {
     pub title: String,
@@ -103,12 +472,28 @@ pub struct ListingForm {
 }
 
 #[derive(Debug, Deserialize)]
+pub struct ImageMoveForm {
+    /// "up", "down" or "first"
+    pub direction: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImageAltForm {
+    pub alt: String,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct SearchQuery {
+    /// Full-text search over titles and descriptions
     pub q: Option<String>,
     pub category: Option<String>,
     pub condition: Option<String>,
+    /// Decimal amount in dollars
     pub min_price: Option<String>,
+    /// Decimal amount in dollars
     pub max_price: Option<String>,
+    /// `newest` (default), `oldest`, `price_asc`, `price_desc`, or `relevance` with `q`
     pub sort: Option<String>,
 }
 
@@ -122,16 +507,29 @@ pub struct MakeOfferForm {
     pub amount: String,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct RespondOfferForm {
+    /// `true` to accept, `false` to decline
+    pub accept: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OrderStatusForm {
+    pub status: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PayForm {
+    pub provider: String,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct ProfileForm {
     pub name: String,
     pub location: String,
     pub bio: String,
     pub payment_info: String,
-}
-
-pub fn format_price(price: f64) -> String {
-    format!("${:.2}", price)
+    pub offer_ttl_hours: Option<i64>,
 }
 
 pub fn time_ago(created_at: &str) -> String {
@@ -152,3 +550,18 @@ pub fn time_ago(created_at: &str) -> String {
     }
     created_at.to_string()
 }
+
+pub fn time_until(expires_at: &str) -> String {
+    let now = chrono::Utc::now();
+    if let Ok(dt) = chrono::NaiveDateTime::parse_from_str(expires_at, "%Y-%m-%d %H:%M:%S") {
+        let expires = chrono::DateTime::<chrono::Utc>::from_naive_utc_and_offset(dt, chrono::Utc);
+        let diff = expires - now;
+        let mins = diff.num_minutes();
+        if mins < 1 { return "any moment".to_string(); }
+        if mins < 60 { return format!("in {}m", mins); }
+        let hours = diff.num_hours();
+        if hours < 48 { return format!("in {}h", hours); }
+        return format!("in {}d", diff.num_days());
+    }
+    expires_at.to_string()
+}
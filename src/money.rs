@@ -0,0 +1,365 @@
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+use utoipa::openapi::schema::{KnownFormat, ObjectBuilder, Schema, SchemaFormat, Type};
+use utoipa::openapi::RefOr;
+
+// === Currency ===
+
+/// ISO 4217 currency code, e.g. `USD`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    pub const USD: Currency = Currency(*b"USD");
+
+    pub fn as_str(&self) -> &str {
+        // Only ever constructed from ASCII letters
+        std::str::from_utf8(&self.0).unwrap_or("???")
+    }
+
+    fn symbol(&self) -> Option<&'static str> {
+        match &self.0 {
+            b"USD" | b"CAD" | b"AUD" => Some("$"),
+            b"EUR" => Some("€"),
+            b"GBP" => Some("£"),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Currency {
+    fn default() -> Self { Currency::USD }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Currency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let b = s.as_bytes();
+        if b.len() == 3 && b.iter().all(|c| c.is_ascii_uppercase()) {
+            Ok(Currency([b[0], b[1], b[2]]))
+        } else {
+            Err(format!("'{}' is not an ISO 4217 currency code", s))
+        }
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(d)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl ToSql for Currency {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for Currency {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value.as_str()?.parse().map_err(|e: String| FromSqlError::Other(e.into()))
+    }
+}
+
+// === Money ===
+
+/// An amount of money stored as integer minor units (cents), never as a float.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Money {
+    pub cents: i64,
+    pub currency: Currency,
+}
+
+impl Money {
+    /// Largest amount accepted from user input: 1,000,000.00
+    pub const MAX_CENTS: i64 = 100_000_000;
+
+    pub fn new(cents: i64, currency: Currency) -> Self {
+        Money { cents, currency }
+    }
+
+    pub fn usd(cents: i64) -> Self {
+        Money::new(cents, Currency::USD)
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.cents == 0
+    }
+
+    /// Strictly parses user input such as `25`, `25.5`, `$1,250.99`.
+    ///
+    /// Rejects negatives, more than two decimal places, misplaced thousands separators and
+    /// anything that is not a number. The error is safe to show to the user as-is.
+    pub fn parse(input: &str, currency: Currency) -> Result<Money, String> {
+        const HINT: &str = "Enter an amount like 25 or 25.99";
+        let mut s = input.trim();
+        if let Some(sym) = currency.symbol() {
+            s = s.strip_prefix(sym).unwrap_or(s).trim_start();
+        }
+        if s.is_empty() {
+            return Err(format!("{}.", HINT));
+        }
+        if s.starts_with('-') {
+            return Err("Amount can't be negative.".to_string());
+        }
+
+        let (whole, frac) = match s.split_once('.') {
+            Some((w, f)) => (w, Some(f)),
+            None => (s, None),
+        };
+        let whole_digits = if whole.contains(',') {
+            let groups: Vec<&str> = whole.split(',').collect();
+            let well_formed = (1..=3).contains(&groups[0].len())
+                && groups[1..].iter().all(|g| g.len() == 3);
+            if !well_formed {
+                return Err(format!("{}.", HINT));
+            }
+            groups.concat()
+        } else {
+            whole.to_string()
+        };
+        if whole_digits.is_empty() && frac.is_none() {
+            return Err(format!("{}.", HINT));
+        }
+        if !whole_digits.chars().all(|c| c.is_ascii_digit()) {
+            return Err(format!("{}.", HINT));
+        }
+
+        let frac_cents = match frac {
+            None => 0,
+            Some(f) if f.is_empty() || !f.chars().all(|c| c.is_ascii_digit()) => {
+                return Err(format!("{}.", HINT));
+            }
+            Some(f) if f.len() > 2 => return Err("Amounts can have at most two decimal places.".to_string()),
+            Some(f) if f.len() == 1 => f.parse::<i64>().unwrap_or(0) * 10,
+            Some(f) => f.parse::<i64>().unwrap_or(0),
+        };
+
+        let whole_units: i64 = if whole_digits.is_empty() {
+            0
+        } else {
+            whole_digits.parse().map_err(|_| "Amount is too large.".to_string())?
+        };
+        let cents = whole_units
+            .checked_mul(100)
+            .and_then(|c| c.checked_add(frac_cents))
+            .filter(|c| *c <= Self::MAX_CENTS)
+            .ok_or_else(|| "Amount is too large.".to_string())?;
+        Ok(Money::new(cents, currency))
+    }
+
+    /// Sum of two amounts, or `None` if they're in different currencies or the sum overflows.
+    /// There is deliberately no `Add`: a mismatch must be handled, not silently summed.
+    pub fn checked_add(self, other: Money) -> Option<Money> {
+        if self.currency != other.currency {
+            return None;
+        }
+        self.cents.checked_add(other.cents).map(|cents| Money::new(cents, self.currency))
+    }
+
+    /// Plain decimal amount without symbol or separators, e.g. `1250.00`. Suitable for form inputs.
+    pub fn amount(&self) -> String {
+        let sign = if self.cents < 0 { "-" } else { "" };
+        let abs = self.cents.unsigned_abs();
+        format!("{}{}.{:02}", sign, abs / 100, abs % 100)
+    }
+
+    /// Like `Display` but drops `.00` for whole amounts, e.g. `$85` — used on listing cards.
+    pub fn display_short(&self) -> String {
+        if self.cents % 100 == 0 {
+            self.format(false)
+        } else {
+            self.format(true)
+        }
+    }
+
+    fn format(&self, with_cents: bool) -> String {
+        let abs = self.cents.unsigned_abs();
+        let whole = (abs / 100).to_string();
+        let mut grouped = String::new();
+        for (i, c) in whole.chars().enumerate() {
+            if i > 0 && (whole.len() - i).is_multiple_of(3) {
+                grouped.push(',');
+            }
+            grouped.push(c);
+        }
+        if with_cents {
+            grouped.push_str(&format!(".{:02}", abs % 100));
+        }
+        let sign = if self.cents < 0 { "-" } else { "" };
+        match self.currency.symbol() {
+            Some(sym) => format!("{}{}{}", sign, sym, grouped),
+            None => format!("{}{} {}", sign, grouped, self.currency),
+        }
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.format(true))
+    }
+}
+
+impl std::ops::Mul<i64> for Money {
+    type Output = Money;
+
+    fn mul(self, qty: i64) -> Money {
+        Money::new(self.cents * qty, self.currency)
+    }
+}
+
+/// Serialized with pre-formatted strings so templates can print prices without filters.
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let mut st = s.serialize_struct("Money", 5)?;
+        st.serialize_field("cents", &self.cents)?;
+        st.serialize_field("currency", &self.currency)?;
+        st.serialize_field("amount", &self.amount())?;
+        st.serialize_field("display", &self.to_string())?;
+        st.serialize_field("display_short", &self.display_short())?;
+        st.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Raw {
+            cents: i64,
+            #[serde(default)]
+            currency: Currency,
+        }
+        let raw = Raw::deserialize(d)?;
+        Ok(Money::new(raw.cents, raw.currency))
+    }
+}
+
+/// Hand-written to match `Serialize` above, which the derive can't see through.
+impl utoipa::PartialSchema for Money {
+    fn schema() -> RefOr<Schema> {
+        let string = |description: &str, example: &str| {
+            ObjectBuilder::new().schema_type(Type::String).description(Some(description)).examples([example])
+        };
+        ObjectBuilder::new()
+            .description(Some("An amount of money in integer minor units, with pre-formatted strings for display."))
+            .property("cents", ObjectBuilder::new().schema_type(Type::Integer).format(Some(SchemaFormat::KnownFormat(KnownFormat::Int64))).examples([4250]))
+            .required("cents")
+            .property("currency", string("ISO 4217 currency code", "USD").pattern(Some("^[A-Z]{3}$")))
+            .required("currency")
+            .property("amount", string("Decimal amount without symbol", "42.50"))
+            .required("amount")
+            .property("display", string("Formatted with symbol and cents", "$42.50"))
+            .required("display")
+            .property("display_short", string("Formatted, dropping cents when they're zero", "$42.50"))
+            .required("display_short")
+            .into()
+    }
+}
+
+impl utoipa::ToSchema for Money {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> Result<i64, String> {
+        Money::parse(input, Currency::USD).map(|m| m.cents)
+    }
+
+    #[test]
+    fn parse_accepts_plain_and_formatted_amounts() {
+        assert_eq!(parse("25"), Ok(2500));
+        assert_eq!(parse("25.5"), Ok(2550));
+        assert_eq!(parse("25.05"), Ok(2505));
+        assert_eq!(parse(".5"), Ok(50));
+        assert_eq!(parse("0"), Ok(0));
+        assert_eq!(parse("  $1,250.99 "), Ok(125099));
+        assert_eq!(parse("$ 12"), Ok(1200));
+        assert_eq!(parse("1,000,000"), Ok(Money::MAX_CENTS));
+        assert_eq!(Money::parse("€12.50", "EUR".parse().unwrap()).map(|m| m.cents), Ok(1250));
+    }
+
+    #[test]
+    fn parse_rejects_negatives() {
+        assert_eq!(parse("-5"), Err("Amount can't be negative.".to_string()));
+        assert_eq!(parse("$-5.00"), Err("Amount can't be negative.".to_string()));
+    }
+
+    #[test]
+    fn parse_rejects_more_than_two_decimals() {
+        assert_eq!(parse("25.999"), Err("Amounts can have at most two decimal places.".to_string()));
+        assert_eq!(parse("0.001"), Err("Amounts can have at most two decimal places.".to_string()));
+    }
+
+    #[test]
+    fn parse_rejects_misplaced_thousands_separators() {
+        for input in ["1,2345", "12,34", ",123", "1,,000", "1234,567", "1,000,00", "1,000."] {
+            assert!(parse(input).is_err(), "{:?} should be rejected", input);
+        }
+    }
+
+    #[test]
+    fn parse_rejects_amounts_above_the_maximum() {
+        assert_eq!(parse("1000000.01"), Err("Amount is too large.".to_string()));
+        assert_eq!(parse("1,000,001"), Err("Amount is too large.".to_string()));
+        assert_eq!(parse("99999999999999999999"), Err("Amount is too large.".to_string()));
+    }
+
+    #[test]
+    fn parse_rejects_things_that_are_not_numbers() {
+        for input in ["", "   ", "$", ".", "5.", "abc", "12a", "1.2.3", "1e5", "+5", "£5"] {
+            assert!(parse(input).is_err(), "{:?} should be rejected", input);
+        }
+    }
+
+    #[test]
+    fn checked_add_refuses_mixed_currencies() {
+        let eur = Money::new(500, "EUR".parse().unwrap());
+        assert_eq!(Money::usd(1250).checked_add(Money::usd(99)), Some(Money::usd(1349)));
+        assert_eq!(eur.checked_add(eur), Some(Money::new(1000, eur.currency)));
+        assert_eq!(Money::usd(1250).checked_add(eur), None);
+        assert_eq!(eur.checked_add(Money::usd(1250)), None);
+        assert_eq!(Money::usd(i64::MAX).checked_add(Money::usd(1)), None);
+    }
+
+    #[test]
+    fn display_formats_with_symbol_and_grouping() {
+        assert_eq!(Money::usd(125099).to_string(), "$1,250.99");
+        assert_eq!(Money::usd(8500).to_string(), "$85.00");
+        assert_eq!(Money::usd(5).to_string(), "$0.05");
+        assert_eq!(Money::usd(-1250).to_string(), "-$12.50");
+        assert_eq!(Money::new(100_000_000, "JPY".parse().unwrap()).to_string(), "1,000,000.00 JPY");
+        assert_eq!(Money::usd(8500).display_short(), "$85");
+        assert_eq!(Money::usd(8550).display_short(), "$85.50");
+        assert_eq!(Money::usd(100_000_000).display_short(), "$1,000,000");
+    }
+
+    #[test]
+    fn display_round_trips_through_parse() {
+        let eur: Currency = "EUR".parse().unwrap();
+        for cents in [0, 5, 50, 99, 100, 2550, 8500, 100_000, 125_099, 9_999_999, Money::MAX_CENTS] {
+            for money in [Money::usd(cents), Money::new(cents, eur)] {
+                assert_eq!(Money::parse(&money.to_string(), money.currency), Ok(money));
+                assert_eq!(Money::parse(&money.display_short(), money.currency), Ok(money));
+                assert_eq!(Money::parse(&money.amount(), money.currency), Ok(money));
+            }
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use axum::http::HeaderMap;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use crate::models::{IntentStatus, Order, PaymentIntent};
+
+// === Payment providers ===
+//
+// A provider knows how to take money for an order. The order flow only talks to the
+// `PaymentProvider` trait; which providers are offered is decided once at startup by
+// `providers()`. Payment state lives in the `payment_intents` table, so providers
+// themselves are stateless.
+
+/// Who confirms that a payment went through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Confirmer {
+    /// The buyer submits payment details (e.g. a card)
+    Buyer,
+    /// The seller confirms money arrived off-platform
+    Seller,
+}
+
+/// What a provider hands back when asked to start a payment.
+pub struct NewIntent {
+    pub provider_ref: String,
+    pub instructions: String,
+}
+
+/// A verified status change pushed by a provider.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebhookEvent {
+    pub provider_ref: String,
+    pub status: IntentStatus,
+}
+
+pub trait PaymentProvider: Send + Sync {
+    /// Stable identifier stored in `payment_intents.provider` and used in URLs
+    fn name(&self) -> &'static str;
+
+    /// Label for the checkout button
+    fn label(&self) -> &'static str;
+
+    fn confirmer(&self) -> Confirmer;
+
+    fn create_intent(&self, order: &Order) -> Result<NewIntent, String>;
+
+    /// Completes an intent with the fields the confirmer submitted. Returns `Failed` (not an
+    /// error) for a payment the provider declined; errors are for input that was never sent.
+    fn confirm(&self, intent: &PaymentIntent, fields: &HashMap<String, String>) -> Result<IntentStatus, String>;
+
+    fn refund(&self, intent: &PaymentIntent) -> Result<IntentStatus, String>;
+
+    fn verify_webhook(&self, headers: &HeaderMap, body: &[u8]) -> Result<WebhookEvent, String>;
+}
+
+static PROVIDERS: OnceLock<Vec<Box<dyn PaymentProvider>>> = OnceLock::new();
+
+/// Providers offered at checkout. The mock card gateway is on in debug builds, or in
+/// release builds when `FORGE_MOCK_PAYMENTS=1` and `FORGE_MOCK_WEBHOOK_SECRET` is set.
+pub fn providers() -> &'static [Box<dyn PaymentProvider>] {
+    PROVIDERS.get_or_init(|| {
+        let mut list: Vec<Box<dyn PaymentProvider>> = vec![Box::new(ManualProvider)];
+        let mock_enabled = cfg!(debug_assertions)
+            || std::env::var("FORGE_MOCK_PAYMENTS").map(|v| v == "1").unwrap_or(false);
+        if mock_enabled {
+            match MockCardGateway::from_env() {
+                Some(gateway) => list.push(Box::new(gateway)),
+                None => eprintln!("⚠️  Mock card gateway left off: set FORGE_MOCK_WEBHOOK_SECRET to enable it in a release build"),
+            }
+        }
+        list
+    })
+}
+
+pub fn provider(name: &str) -> Option<&'static dyn PaymentProvider> {
+    providers().iter().find(|p| p.name() == name).map(|p| p.as_ref())
+}
+
+// === Manual (reveal handle) ===
+
+/// Today's flow: the buyer pays the seller's Venmo/PayPal/etc. handle directly and the
+/// seller confirms when the money arrives. Refunds also happen off-platform.
+pub struct ManualProvider;
+
+impl PaymentProvider for ManualProvider {
+    fn name(&self) -> &'static str { "manual" }
+
+    fn label(&self) -> &'static str { "Pay the seller directly" }
+
+    fn confirmer(&self) -> Confirmer { Confirmer::Seller }
+
+    fn create_intent(&self, order: &Order) -> Result<NewIntent, String> {
+        Ok(NewIntent {
+            provider_ref: format!("manual_{}", uuid::Uuid::new_v4().simple()),
+            instructions: order.payment_info.clone(),
+        })
+    }
+
+    fn confirm(&self, _intent: &PaymentIntent, _fields: &HashMap<String, String>) -> Result<IntentStatus, String> {
+        Ok(IntentStatus::Succeeded)
+    }
+
+    fn refund(&self, _intent: &PaymentIntent) -> Result<IntentStatus, String> {
+        Ok(IntentStatus::Refunded)
+    }
+
+    fn verify_webhook(&self, _headers: &HeaderMap, _body: &[u8]) -> Result<WebhookEvent, String> {
+        Err("Manual payments don't send webhooks".to_string())
+    }
+}
+
+// === Mock card gateway ===
+
+pub const MOCK_SIGNATURE_HEADER: &str = "mock-signature";
+const MOCK_WEBHOOK_TOLERANCE_SECS: i64 = 300;
+
+/// In-process stand-in for a card processor so paid checkout can be exercised end to end.
+///
+/// Test cards: `4242 4242 4242 4242` succeeds, `4000 0000 0000 0002` is declined, any other
+/// Luhn-valid number succeeds. Webhooks are signed like Stripe's: the `Mock-Signature` header
+/// is `t=<unix time>,v1=<hex HMAC-SHA256 of "<t>.<body>">`.
+pub struct MockCardGateway {
+    webhook_secret: String,
+}
+
+impl MockCardGateway {
+    pub fn new(webhook_secret: &str) -> Self {
+        MockCardGateway { webhook_secret: webhook_secret.to_string() }
+    }
+
+    /// Uses `FORGE_MOCK_WEBHOOK_SECRET`. Only debug builds fall back to a fixed development
+    /// secret: it's public, so anyone could sign a webhook with it and mark an order paid.
+    pub fn from_env() -> Option<Self> {
+        let configured = std::env::var("FORGE_MOCK_WEBHOOK_SECRET").ok();
+        webhook_secret(configured, cfg!(debug_assertions)).map(|s| MockCardGateway::new(&s))
+    }
+
+    fn mac(&self, timestamp: i64, body: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.webhook_secret.as_bytes()).expect("HMAC accepts any key length");
+        mac.update(timestamp.to_string().as_bytes());
+        mac.update(b".");
+        mac.update(body);
+        mac
+    }
+
+    /// Signature header value for `body`, as the gateway would send it.
+    pub fn sign(&self, body: &[u8], timestamp: i64) -> String {
+        let sig = self.mac(timestamp, body).finalize().into_bytes();
+        format!("t={},v1={}", timestamp, hex::encode(sig))
+    }
+}
+
+const MOCK_DEV_WEBHOOK_SECRET: &str = "whsec_forge_mock";
+
+fn webhook_secret(configured: Option<String>, debug_build: bool) -> Option<String> {
+    match configured.filter(|s| !s.is_empty()) {
+        Some(secret) => Some(secret),
+        None if debug_build => Some(MOCK_DEV_WEBHOOK_SECRET.to_string()),
+        None => None,
+    }
+}
+
+fn luhn_valid(digits: &str) -> bool {
+    let sum: u32 = digits.chars().rev().enumerate().map(|(i, c)| {
+        let d = c.to_digit(10).unwrap_or(0);
+        if i % 2 == 1 {
+            let dd = d * 2;
+            if dd > 9 { dd - 9 } else { dd }
+        } else {
+            d
+        }
+    }).sum();
+    sum.is_multiple_of(10)
+}
+
+impl PaymentProvider for MockCardGateway {
+    fn name(&self) -> &'static str { "mock_card" }
+
+    fn label(&self) -> &'static str { "Card (test gateway)" }
+
+    fn confirmer(&self) -> Confirmer { Confirmer::Buyer }
+
+    fn create_intent(&self, _order: &Order) -> Result<NewIntent, String> {
+        Ok(NewIntent {
+            provider_ref: format!("mock_pi_{}", uuid::Uuid::new_v4().simple()),
+            instructions: "Test mode: pay with 4242 4242 4242 4242, or 4000 0000 0000 0002 to see a decline.".to_string(),
+        })
+    }
+
+    fn confirm(&self, _intent: &PaymentIntent, fields: &HashMap<String, String>) -> Result<IntentStatus, String> {
+        let number: String = fields.get("card_number")
+            .map(|n| n.chars().filter(|c| !c.is_whitespace() && *c != '-').collect())
+            .unwrap_or_default();
+        if !(12..=19).contains(&number.len()) || !number.chars().all(|c| c.is_ascii_digit()) || !luhn_valid(&number) {
+            return Err("That card number isn't valid.".to_string());
+        }
+        if number == "4000000000000002" {
+            return Ok(IntentStatus::Failed);
+        }
+        Ok(IntentStatus::Succeeded)
+    }
+
+    fn refund(&self, intent: &PaymentIntent) -> Result<IntentStatus, String> {
+        if intent.status != IntentStatus::Succeeded {
+            return Err("Only successful payments can be refunded".to_string());
+        }
+        Ok(IntentStatus::Refunded)
+    }
+
+    fn verify_webhook(&self, headers: &HeaderMap, body: &[u8]) -> Result<WebhookEvent, String> {
+        let header = headers.get(MOCK_SIGNATURE_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .ok_or_else(|| "Missing signature".to_string())?;
+        let mut timestamp = None;
+        let mut signature = None;
+        for part in header.split(',') {
+            match part.trim().split_once('=') {
+                Some(("t", t)) => timestamp = t.parse::<i64>().ok(),
+                Some(("v1", s)) => signature = hex::decode(s).ok(),
+                _ => {}
+            }
+        }
+        let (timestamp, signature) = timestamp.zip(signature).ok_or_else(|| "Malformed signature".to_string())?;
+        if (chrono::Utc::now().timestamp() - timestamp).abs() > MOCK_WEBHOOK_TOLERANCE_SECS {
+            return Err("Signature timestamp outside tolerance".to_string());
+        }
+        self.mac(timestamp, body).verify_slice(&signature).map_err(|_| "Bad signature".to_string())?;
+
+        #[derive(serde::Deserialize)]
+        struct Payload {
+            #[serde(rename = "type")]
+            kind: String,
+            provider_ref: String,
+        }
+        let payload: Payload = serde_json::from_slice(body).map_err(|e| format!("Bad payload: {}", e))?;
+        let status = match payload.kind.as_str() {
+            "payment_intent.succeeded" => IntentStatus::Succeeded,
+            "payment_intent.payment_failed" => IntentStatus::Failed,
+            "charge.refunded" => IntentStatus::Refunded,
+            other => return Err(format!("Unhandled event type {}", other)),
+        };
+        Ok(WebhookEvent { provider_ref: payload.provider_ref, status })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn release_builds_need_their_own_webhook_secret() {
+        assert_eq!(webhook_secret(None, true).as_deref(), Some(MOCK_DEV_WEBHOOK_SECRET));
+        assert_eq!(webhook_secret(None, false), None);
+        assert_eq!(webhook_secret(Some(String::new()), false), None);
+        assert_eq!(webhook_secret(Some("whsec_ours".to_string()), false).as_deref(), Some("whsec_ours"));
+        assert_eq!(webhook_secret(Some("whsec_ours".to_string()), true).as_deref(), Some("whsec_ours"));
+    }
+}
@@ -0,0 +1,412 @@
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::request::Parts;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Mutex, OnceLock};
+use crate::db::{self, Db};
+use crate::models::RateCounter;
+
+// === Rate limiting ===
+//
+// Keeps password guessing and spam in check. Events are counted per key, which names the
+// limit and who it applies to: "login-email:alice@example.com", "messages:<user id>".
+//
+// Failed logins are counted per IP and per email. The first few are free; after that each
+// one blocks the key for twice as long as the last, up to five minutes, and enough of them
+// lock it out for 15 minutes and write a row to `lockouts`. A blocked login is refused before
+// any password is hashed. Registrations, password resets, verification emails, messages and
+// offers have plain `Limit`s instead.
+//
+// Counters live in memory unless `FORGE_RATE_LIMIT_STORE=sqlite`, which keeps them in the
+// `rate_limits` table so they survive restarts and are shared by servers on one database.
+// The sweeper prunes stale counters from either.
+
+/// At most `max` events per `window_secs` for each key.
+pub struct Limit {
+    pub name: &'static str,
+    pub max: u32,
+    pub window_secs: i64,
+}
+
+pub const REGISTRATIONS_PER_IP: Limit = Limit { name: "register-ip", max: 5, window_secs: 60 * 60 };
+pub const MESSAGES_PER_USER: Limit = Limit { name: "messages", max: 20, window_secs: 60 };
+/// Offers and counter-offers
+pub const OFFERS_PER_USER: Limit = Limit { name: "offers", max: 10, window_secs: 10 * 60 };
+pub const VERIFICATION_EMAILS_PER_USER: Limit = Limit { name: "verify-email", max: 3, window_secs: 60 * 60 };
+pub const PASSWORD_RESETS_PER_IP: Limit = Limit { name: "reset-ip", max: 10, window_secs: 60 * 60 };
+/// Reset links mailed to one account, so the form can't be used to flood an inbox
+pub const PASSWORD_RESETS_PER_EMAIL: Limit = Limit { name: "reset-email", max: 3, window_secs: 60 * 60 };
+
+/// Failed logins allowed before the backoff starts
+const FREE_FAILURES: u32 = 3;
+const MAX_BACKOFF_SECS: i64 = 5 * 60;
+/// An IP is allowed more failures than an email, since it may be shared
+const EMAIL_LOCKOUT_FAILURES: u32 = 10;
+const IP_LOCKOUT_FAILURES: u32 = 30;
+const LOCKOUT_SECS: i64 = 15 * 60;
+/// Failures are forgotten after this long without another one
+const FAILURE_MEMORY_SECS: i64 = 60 * 60;
+
+pub trait LimitStore: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// The counter for `key`, zeroed if there isn't one.
+    fn get(&self, db: &Db, key: &str) -> RateCounter;
+
+    /// Replaces the counter for `key` with `f` of it, atomically, and returns the result. A
+    /// zeroed result is deleted.
+    fn update(&self, db: &Db, key: &str, f: &mut dyn FnMut(RateCounter) -> RateCounter) -> RateCounter;
+
+    /// Forgets counters that have reset and aren't blocking anything.
+    fn prune(&self, db: &Db, now: i64);
+}
+
+pub struct MemoryStore {
+    counters: Mutex<HashMap<String, RateCounter>>,
+}
+
+impl LimitStore for MemoryStore {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn get(&self, _db: &Db, key: &str) -> RateCounter {
+        self.counters.lock().unwrap().get(key).copied().unwrap_or_default()
+    }
+
+    fn update(&self, _db: &Db, key: &str, f: &mut dyn FnMut(RateCounter) -> RateCounter) -> RateCounter {
+        let mut counters = self.counters.lock().unwrap();
+        let updated = f(counters.get(key).copied().unwrap_or_default());
+        if updated.count == 0 && updated.blocked_until == 0 {
+            counters.remove(key);
+        } else {
+            counters.insert(key.to_string(), updated);
+        }
+        updated
+    }
+
+    fn prune(&self, _db: &Db, now: i64) {
+        self.counters.lock().unwrap().retain(|_, c| c.reset_at > now || c.blocked_until > now);
+    }
+}
+
+pub struct SqliteStore;
+
+impl LimitStore for SqliteStore {
+    fn name(&self) -> &'static str {
+        "sqlite"
+    }
+
+    fn get(&self, db: &Db, key: &str) -> RateCounter {
+        db::get_rate_limit(db, key)
+    }
+
+    fn update(&self, db: &Db, key: &str, f: &mut dyn FnMut(RateCounter) -> RateCounter) -> RateCounter {
+        db::update_rate_limit(db, key, f)
+    }
+
+    fn prune(&self, db: &Db, now: i64) {
+        db::prune_rate_limits(db, now);
+    }
+}
+
+static STORE: OnceLock<Box<dyn LimitStore>> = OnceLock::new();
+
+pub fn store() -> &'static dyn LimitStore {
+    STORE.get_or_init(|| {
+        match std::env::var("FORGE_RATE_LIMIT_STORE").as_deref() {
+            Ok("sqlite") => Box::new(SqliteStore),
+            _ => Box::new(MemoryStore { counters: Mutex::new(HashMap::new()) }),
+        }
+    }).as_ref()
+}
+
+fn now() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+/// Counts an event against `limit` for `who`. Over the limit, returns the seconds until the
+/// window starts over.
+pub fn hit(db: &Db, limit: &Limit, who: &str) -> Result<(), i64> {
+    hit_in(store(), db, limit, who)
+}
+
+// The functions here each have an `_in` twin taking the store, so tests can run them
+// against both
+fn hit_in(store: &dyn LimitStore, db: &Db, limit: &Limit, who: &str) -> Result<(), i64> {
+    let now = now();
+    let counter = store.update(db, &format!("{}:{}", limit.name, who), &mut |c| {
+        if c.reset_at <= now {
+            RateCounter { count: 1, reset_at: now + limit.window_secs, blocked_until: 0 }
+        } else {
+            RateCounter { count: c.count + 1, ..c }
+        }
+    });
+    if counter.count > limit.max {
+        Err(counter.reset_at - now)
+    } else {
+        Ok(())
+    }
+}
+
+fn login_keys(ip: &str, email: &str) -> [(String, u32); 2] {
+    [
+        (format!("login-ip:{}", ip), IP_LOCKOUT_FAILURES),
+        (format!("login-email:{}", email.trim().to_lowercase()), EMAIL_LOCKOUT_FAILURES),
+    ]
+}
+
+/// Whether a login from `ip` for `email` may be tried now. If not, returns the seconds to wait.
+pub fn check_login(db: &Db, ip: &str, email: &str) -> Result<(), i64> {
+    check_login_in(store(), db, ip, email)
+}
+
+fn check_login_in(store: &dyn LimitStore, db: &Db, ip: &str, email: &str) -> Result<(), i64> {
+    let now = now();
+    let wait = login_keys(ip, email).iter()
+        .map(|(key, _)| store.get(db, key).blocked_until - now)
+        .max()
+        .unwrap_or(0);
+    if wait > 0 {
+        Err(wait)
+    } else {
+        Ok(())
+    }
+}
+
+/// Counts a failed login, blocking the IP and email for a while once they've had their free
+/// attempts, and locking them out after too many.
+pub fn login_failed(db: &Db, ip: &str, email: &str) {
+    login_failed_in(store(), db, ip, email)
+}
+
+fn login_failed_in(store: &dyn LimitStore, db: &Db, ip: &str, email: &str) {
+    let now = now();
+    for (key, lockout_at) in login_keys(ip, email) {
+        let counter = store.update(db, &key, &mut |c| {
+            let count = if c.reset_at <= now { 1 } else { c.count + 1 };
+            let blocked_until = if count >= lockout_at {
+                now + LOCKOUT_SECS
+            } else if count > FREE_FAILURES {
+                now + (1i64 << (count - FREE_FAILURES - 1)).min(MAX_BACKOFF_SECS)
+            } else {
+                0
+            };
+            RateCounter { count, reset_at: now + FAILURE_MEMORY_SECS, blocked_until }
+        });
+        if counter.count >= lockout_at {
+            db::record_lockout(db, &key, ip, counter.count, counter.blocked_until);
+            println!("🔒 Locked out {} for {} minutes after {} failed logins", key, LOCKOUT_SECS / 60, counter.count);
+        }
+    }
+}
+
+/// Clears the email's failures. The IP's stay, so someone guessing at other accounts can't
+/// reset them by logging in to their own.
+pub fn login_succeeded(db: &Db, email: &str) {
+    login_succeeded_in(store(), db, email)
+}
+
+fn login_succeeded_in(store: &dyn LimitStore, db: &Db, email: &str) {
+    let key = format!("login-email:{}", email.trim().to_lowercase());
+    store.update(db, &key, &mut |_| RateCounter::default());
+}
+
+/// Drops counters nobody needs any more. Called by the sweeper.
+pub fn prune(db: &Db) {
+    store().prune(db, now());
+}
+
+/// "Try again in …" for a wait in seconds.
+pub fn try_again_in(secs: i64) -> String {
+    match secs {
+        ..=1 => "Try again in a second.".to_string(),
+        2..=59 => format!("Try again in {} seconds.", secs),
+        60..=119 => "Try again in a minute.".to_string(),
+        _ => format!("Try again in {} minutes.", (secs + 59) / 60),
+    }
+}
+
+/// The client's address, for per-IP limits: the connection's peer, or behind a reverse proxy
+/// (`FORGE_TRUST_PROXY=1`) the last `X-Forwarded-For` entry, which the proxy added itself.
+/// "unknown" when there's neither, e.g. in tests that call the router directly.
+pub struct ClientIp(pub String);
+
+static TRUST_PROXY: OnceLock<bool> = OnceLock::new();
+
+/// Whether `X-Forwarded-For` comes from our own proxy. Read from the environment once.
+fn trust_proxy() -> bool {
+    *TRUST_PROXY.get_or_init(|| std::env::var("FORGE_TRUST_PROXY").as_deref() == Ok("1"))
+}
+
+impl ClientIp {
+    fn from_parts(parts: &Parts, trust_proxy: bool) -> Self {
+        let forwarded = if trust_proxy {
+            parts.headers.get("x-forwarded-for")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.rsplit(',').next())
+                .map(|ip| ip.trim().to_string())
+                .filter(|ip| !ip.is_empty())
+        } else {
+            None
+        };
+        let peer = parts.extensions.get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(addr)| addr.ip().to_string());
+        ClientIp(forwarded.or(peer).unwrap_or_else(|| "unknown".to_string()))
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(ClientIp::from_parts(parts, trust_proxy()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Request;
+
+    /// Each store with a fresh database of its own.
+    fn stores() -> Vec<(Box<dyn LimitStore>, Db)> {
+        let memory: Box<dyn LimitStore> = Box::new(MemoryStore { counters: Mutex::new(HashMap::new()) });
+        let sqlite: Box<dyn LimitStore> = Box::new(SqliteStore);
+        [memory, sqlite].into_iter().map(|store| {
+            let path = std::env::temp_dir().join(format!("forge-ratelimit-{}.db", uuid::Uuid::new_v4()));
+            (store, db::init_db_with_path(path.to_str().unwrap()))
+        }).collect()
+    }
+
+    /// Seconds `key` is still blocked for.
+    fn blocked_for(store: &dyn LimitStore, db: &Db, key: &str) -> i64 {
+        store.get(db, key).blocked_until - now()
+    }
+
+    /// Whether `secs` is `expected`, allowing for the clock ticking over since it was set.
+    fn about(secs: i64, expected: i64) -> bool {
+        (expected - 1..=expected).contains(&secs)
+    }
+
+    #[test]
+    fn failed_logins_back_off_then_lock_out() {
+        for (store, db) in stores() {
+            let store = store.as_ref();
+            let name = store.name();
+            for _ in 0..FREE_FAILURES {
+                login_failed_in(store, &db, "10.0.0.1", "alice@example.com");
+            }
+            assert_eq!(check_login_in(store, &db, "10.0.0.1", "alice@example.com"), Ok(()), "{}", name);
+
+            // Each failure past the free ones doubles the block
+            let mut backoffs = Vec::new();
+            for _ in FREE_FAILURES..EMAIL_LOCKOUT_FAILURES - 1 {
+                login_failed_in(store, &db, "10.0.0.1", "alice@example.com");
+                backoffs.push(blocked_for(store, &db, "login-email:alice@example.com"));
+            }
+            assert!(backoffs.iter().zip([1, 2, 4, 8, 16, 32]).all(|(&b, e)| about(b, e)), "{}: {:?}", name, backoffs);
+            let wait = check_login_in(store, &db, "10.0.0.1", "alice@example.com").unwrap_err();
+            assert!(about(wait, 32), "{}: {}", name, wait);
+            // The IP is blocked too, whichever account it tries next
+            assert!(check_login_in(store, &db, "10.0.0.1", "bob@example.com").is_err(), "{}", name);
+
+            // The email's tenth failure locks it out, and says so in `lockouts`
+            login_failed_in(store, &db, "10.0.0.2", "Alice@Example.com ");
+            assert!(about(blocked_for(store, &db, "login-email:alice@example.com"), LOCKOUT_SECS), "{}", name);
+            let locked: i64 = db.lock().unwrap().query_row(
+                "SELECT COUNT(*) FROM lockouts WHERE key = 'login-email:alice@example.com'", [], |r| r.get(0),
+            ).unwrap();
+            assert_eq!(locked, 1, "{}", name);
+            assert!(check_login_in(store, &db, "10.0.0.3", "alice@example.com").is_err(), "{}", name);
+            // An IP is allowed more failures before its lockout
+            assert_eq!(store.get(&db, "login-ip:10.0.0.1").count, EMAIL_LOCKOUT_FAILURES - 1, "{}", name);
+            assert!(about(blocked_for(store, &db, "login-ip:10.0.0.1"), 32), "{}", name);
+
+            // The backoff tops out below the lockout
+            for _ in EMAIL_LOCKOUT_FAILURES - 1..IP_LOCKOUT_FAILURES - 1 {
+                login_failed_in(store, &db, "10.0.0.1", "bob@example.com");
+            }
+            assert!(about(blocked_for(store, &db, "login-ip:10.0.0.1"), MAX_BACKOFF_SECS), "{}", name);
+            login_failed_in(store, &db, "10.0.0.1", "carol@example.com");
+            assert!(about(blocked_for(store, &db, "login-ip:10.0.0.1"), LOCKOUT_SECS), "{}", name);
+        }
+    }
+
+    #[test]
+    fn a_successful_login_clears_the_email_but_not_the_ip() {
+        for (store, db) in stores() {
+            let store = store.as_ref();
+            let name = store.name();
+            for _ in 0..FREE_FAILURES + 2 {
+                login_failed_in(store, &db, "10.0.0.1", "alice@example.com");
+            }
+            assert!(check_login_in(store, &db, "10.0.0.2", "alice@example.com").is_err(), "{}", name);
+
+            login_succeeded_in(store, &db, " ALICE@example.com");
+            let email = store.get(&db, "login-email:alice@example.com");
+            assert_eq!((email.count, email.blocked_until), (0, 0), "{}", name);
+            assert_eq!(check_login_in(store, &db, "10.0.0.2", "alice@example.com"), Ok(()), "{}", name);
+
+            // Someone guessing from this IP can't reset it by logging in to their own account
+            assert_eq!(store.get(&db, "login-ip:10.0.0.1").count, FREE_FAILURES + 2, "{}", name);
+            assert!(check_login_in(store, &db, "10.0.0.1", "alice@example.com").is_err(), "{}", name);
+
+            // Counting starts again from the first free failure
+            login_failed_in(store, &db, "10.0.0.2", "alice@example.com");
+            assert_eq!(store.get(&db, "login-email:alice@example.com").count, 1, "{}", name);
+            assert_eq!(check_login_in(store, &db, "10.0.0.2", "alice@example.com"), Ok(()), "{}", name);
+        }
+    }
+
+    #[test]
+    fn limits_allow_max_events_per_window() {
+        let limit = Limit { name: "test", max: 3, window_secs: 60 };
+        for (store, db) in stores() {
+            let store = store.as_ref();
+            let name = store.name();
+            for _ in 0..limit.max {
+                assert_eq!(hit_in(store, &db, &limit, "alice"), Ok(()), "{}", name);
+            }
+            let wait = hit_in(store, &db, &limit, "alice").unwrap_err();
+            assert!(about(wait, 60), "{}: {}", name, wait);
+            assert_eq!(hit_in(store, &db, &limit, "bob"), Ok(()), "{}", name);
+
+            // Once the window has passed the count starts over
+            store.update(&db, "test:alice", &mut |c| RateCounter { reset_at: now() - 1, ..c });
+            assert_eq!(hit_in(store, &db, &limit, "alice"), Ok(()), "{}", name);
+            assert_eq!(store.get(&db, "test:alice").count, 1, "{}", name);
+
+            store.update(&db, "test:bob", &mut |c| RateCounter { reset_at: now() - 1, ..c });
+            store.prune(&db, now());
+            assert_eq!(store.get(&db, "test:bob").count, 0, "{}", name);
+            assert_eq!(store.get(&db, "test:alice").count, 1, "{}", name);
+        }
+    }
+
+    fn client_ip(request: Request<()>, trust_proxy: bool) -> String {
+        let (parts, ()) = request.into_parts();
+        ClientIp::from_parts(&parts, trust_proxy).0
+    }
+
+    #[test]
+    fn client_ip_is_the_peer_unless_a_trusted_proxy_says_otherwise() {
+        let peer = ConnectInfo(SocketAddr::from(([203, 0, 113, 7], 51234)));
+        let direct = || Request::builder().extension(peer).body(()).unwrap();
+        let proxied = || Request::builder()
+            .extension(peer)
+            .header("x-forwarded-for", "198.51.100.1, 192.0.2.44")
+            .body(()).unwrap();
+
+        assert_eq!(client_ip(Request::new(()), false), "unknown");
+        assert_eq!(client_ip(direct(), false), "203.0.113.7");
+        // Anyone can send the header, so it's ignored unless there's a proxy in front
+        assert_eq!(client_ip(proxied(), false), "203.0.113.7");
+
+        // The proxy appends the address it saw; earlier entries are the client's say-so
+        assert_eq!(client_ip(proxied(), true), "192.0.2.44");
+        assert_eq!(client_ip(direct(), true), "203.0.113.7");
+        let blank = Request::builder().extension(peer).header("x-forwarded-for", "198.51.100.1, ").body(()).unwrap();
+        assert_eq!(client_ip(blank, true), "203.0.113.7");
+    }
+}
@@ -0,0 +1,859 @@
+use axum::extract::rejection::{JsonRejection, QueryRejection};
+use axum::extract::{FromRequestParts, Path, Query, State};
+use axum::http::request::Parts;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{IntoParams, Modify, OpenApi, ToSchema};
+use crate::db::{self, Db};
+use crate::auth;
+use crate::models::{
+    Conversation, Listing, ListingError, ListingForm, ListingImage, Message, Offer, OfferError, OfferResponse,
+    ProfileForm, SearchQuery, User,
+};
+use crate::money::{Currency, Money};
+use crate::ratelimit::{self, ClientIp};
+use crate::routes::auth::send_verification_email;
+use crate::routes::messages::{new_offer_message, offer_answer_message};
+use tera::Tera;
+use std::sync::Arc;
+
+type AppState = (Db, Arc<Tera>);
+
+// === JSON API (/api/v1) ===
+//
+// Mirrors the HTML routes for the mobile app and scripts, on top of the same `db`
+// functions. Clients authenticate with `Authorization: Bearer <token>`: a session token
+// from `POST /api/v1/auth/login`, or a scoped personal token made on the profile page.
+// Every error is JSON: `{"error": {"code": "...", "message": "..."}}`.
+//
+// The OpenAPI document at `/api/openapi.json` is generated from the `#[utoipa::path]`
+// attribute on each handler, the same attribute `build_router` mounts it by, so a route
+// can't exist without its entry. `tests/openapi.rs` checks real responses against the
+// documented schemas.
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Forge Commerce API", description = "JSON API for the Forge Commerce marketplace."),
+    servers((url = "/api/v1")),
+    modifiers(&DocExtras),
+    tags(
+        (name = "auth", description = "Login sessions"),
+        (name = "profile", description = "The caller's account"),
+        (name = "listings", description = "Items for sale"),
+        (name = "conversations", description = "Buyer–seller conversations and messages"),
+        (name = "offers", description = "Price offers within a conversation"),
+    ),
+)]
+pub struct ApiDoc;
+
+/// Registers the bearer scheme named in each handler's `security(...)`, whose values are the
+/// scopes a personal token needs (session tokens have them all), and drops the empty license
+/// the derive copies from Cargo.toml.
+struct DocExtras;
+
+impl Modify for DocExtras {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        openapi.info.license = None;
+        let description = "A session token from `POST /auth/login`, or a personal token (`fpat_…`) from the profile page.";
+        openapi.components.get_or_insert_with(Default::default).add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).description(Some(description)).build()),
+        );
+    }
+}
+
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+    /// Seconds, sent as `Retry-After`
+    retry_after: Option<i64>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        ApiError { status, code, message: message.into(), retry_after: None }
+    }
+
+    pub fn unauthorized() -> Self {
+        ApiError::new(StatusCode::UNAUTHORIZED, "unauthorized", "A valid bearer token is required.")
+    }
+
+    pub fn forbidden() -> Self {
+        ApiError::new(StatusCode::FORBIDDEN, "forbidden", "You don't have access to this resource.")
+    }
+
+    pub fn not_found(what: &str) -> Self {
+        ApiError::new(StatusCode::NOT_FOUND, "not_found", format!("{} not found.", what))
+    }
+
+    pub fn invalid(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "invalid", message)
+    }
+
+    pub fn conflict(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::CONFLICT, "conflict", message)
+    }
+
+    /// The details go to the server log, not the client.
+    pub fn internal(detail: impl std::fmt::Display) -> Self {
+        eprintln!("❌ API request failed: {}", detail);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "internal", "Something went wrong on our side.")
+    }
+
+    pub fn rate_limited(message: &str, wait_secs: i64) -> Self {
+        let message = format!("{} {}", message, ratelimit::try_again_in(wait_secs));
+        ApiError { retry_after: Some(wait_secs), ..ApiError::new(StatusCode::TOO_MANY_REQUESTS, "rate_limited", message) }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = Json(ErrorBody { error: ErrorDetail { code: self.code.to_string(), message: self.message } });
+        if self.status == StatusCode::UNAUTHORIZED {
+            (self.status, [(header::WWW_AUTHENTICATE, "Bearer")], body).into_response()
+        } else if let Some(secs) = self.retry_after {
+            (self.status, [(header::RETRY_AFTER, secs.to_string())], body).into_response()
+        } else {
+            (self.status, body).into_response()
+        }
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        ApiError::new(StatusCode::BAD_REQUEST, "bad_request", rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        ApiError::new(StatusCode::BAD_REQUEST, "bad_request", rejection.body_text())
+    }
+}
+
+/// Body of every error response.
+#[derive(Serialize, ToSchema)]
+pub struct ErrorBody {
+    pub error: ErrorDetail,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ErrorDetail {
+    /// Stable machine-readable code, e.g. `not_found` or `insufficient_scope`
+    pub code: String,
+    pub message: String,
+}
+
+pub type ApiResult<T> = Result<T, ApiError>;
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers.get(header::AUTHORIZATION)?
+        .to_str().ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+/// The caller, resolved from `Authorization: Bearer <token>`. Either a session token from
+/// `/auth/login` or a personal token created on the profile page.
+pub struct ApiUser {
+    pub user: User,
+    scopes: Option<Vec<String>>,
+}
+
+impl ApiUser {
+    /// The caller's user, if their token was granted `scope`.
+    pub fn require(self, scope: &str) -> ApiResult<User> {
+        match &self.scopes {
+            Some(scopes) if !scopes.iter().any(|s| s == scope) => Err(ApiError::new(
+                StatusCode::FORBIDDEN,
+                "insufficient_scope",
+                format!("This token needs the {} scope.", scope),
+            )),
+            _ => Ok(self.user),
+        }
+    }
+}
+
+impl FromRequestParts<AppState> for ApiUser {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, (db, _tera): &AppState) -> Result<Self, Self::Rejection> {
+        let token = bearer_token(&parts.headers).ok_or_else(ApiError::unauthorized)?;
+        let (user, scopes) = auth::get_bearer_user(db, token).ok_or_else(ApiError::unauthorized)?;
+        Ok(ApiUser { user, scopes })
+    }
+}
+
+/// Listing, starting conversations and making offers need a confirmed email address.
+fn require_verified(user: &User) -> ApiResult<()> {
+    if user.is_verified() {
+        Ok(())
+    } else {
+        Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "email_unverified",
+            "Confirm your email address first. Follow the link we emailed you, or send a new one from your profile.",
+        ))
+    }
+}
+
+fn require_participant(db: &Db, convo_id: &str, user: &User) -> ApiResult<Conversation> {
+    match db::get_conversation(db, convo_id) {
+        Some(c) if c.buyer_id == user.id || c.seller_id == user.id => Ok(c),
+        // Don't reveal conversations the caller isn't part of
+        _ => Err(ApiError::not_found("Conversation")),
+    }
+}
+
+/// Parses an offer amount in the currency of the listing it's for.
+fn parse_amount(db: &Db, listing_id: &str, amount: &str) -> ApiResult<Money> {
+    let currency = db::get_listing_currency(db, listing_id).unwrap_or_default();
+    match Money::parse(amount, currency) {
+        Ok(a) if !a.is_zero() => Ok(a),
+        Ok(_) => Err(ApiError::invalid("Amount must be more than zero.")),
+        Err(e) => Err(ApiError::invalid(e)),
+    }
+}
+
+pub async fn not_found() -> ApiError {
+    ApiError::not_found("Endpoint")
+}
+
+// === Auth ===
+
+#[derive(Deserialize, ToSchema)]
+pub struct LoginRequest {
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct RegisterRequest {
+    pub name: String,
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TokenResponse {
+    pub token: String,
+    pub user: User,
+}
+
+#[utoipa::path(
+    post, path = "/auth/login", tag = "auth",
+    request_body = LoginRequest,
+    responses(
+        (status = 200, description = "Logged in; use `token` as the bearer token", body = TokenResponse),
+        (status = 400, description = "Malformed JSON body", body = ErrorBody),
+        (status = 401, description = "Wrong email or password", body = ErrorBody),
+        (status = 429, description = "Too many failed attempts for this email or address", body = ErrorBody),
+    ),
+)]
+pub async fn login(
+    State((db, _tera)): State<AppState>,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
+    payload: Result<Json<LoginRequest>, JsonRejection>,
+) -> ApiResult<Json<TokenResponse>> {
+    let Json(req) = payload?;
+    ratelimit::check_login(&db, &ip, &req.email).map_err(|wait| ApiError::rate_limited("Too many failed attempts.", wait))?;
+    match db::get_user_by_email(&db, &req.email) {
+        Some(user) if auth::verify_password(&req.password, &user.password_hash) => {
+            ratelimit::login_succeeded(&db, &req.email);
+            let token = db::create_session(&db, &user.id, &auth::user_agent(&headers), &ip);
+            Ok(Json(TokenResponse { token, user }))
+        }
+        _ => {
+            ratelimit::login_failed(&db, &ip, &req.email);
+            Err(ApiError::new(StatusCode::UNAUTHORIZED, "invalid_credentials", "Invalid email or password."))
+        }
+    }
+}
+
+#[utoipa::path(
+    post, path = "/auth/register", tag = "auth",
+    request_body = RegisterRequest,
+    responses(
+        (status = 201, description = "Account created and logged in. A link to confirm the email address is mailed to it", body = TokenResponse),
+        (status = 400, description = "Malformed JSON body", body = ErrorBody),
+        (status = 409, description = "Email already registered", body = ErrorBody),
+        (status = 422, description = "Invalid field values", body = ErrorBody),
+        (status = 429, description = "Too many new accounts from this address", body = ErrorBody),
+    ),
+)]
+pub async fn register(
+    State((db, _tera)): State<AppState>,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
+    payload: Result<Json<RegisterRequest>, JsonRejection>,
+) -> ApiResult<(StatusCode, Json<TokenResponse>)> {
+    let Json(req) = payload?;
+    if req.name.trim().is_empty() || req.email.trim().is_empty() {
+        return Err(ApiError::invalid("Name and email are required."));
+    }
+    if req.password.len() < 8 {
+        return Err(ApiError::invalid("Password must be at least 8 characters"));
+    }
+    if !auth::valid_email(req.email.trim()) {
+        return Err(ApiError::invalid("Enter a valid email address"));
+    }
+    ratelimit::hit(&db, &ratelimit::REGISTRATIONS_PER_IP, &ip)
+        .map_err(|wait| ApiError::rate_limited("Too many new accounts from your network.", wait))?;
+    let hash = auth::hash_password(&req.password);
+    let user_id = db::create_user(&db, req.name.trim(), req.email.trim(), &hash).map_err(ApiError::conflict)?;
+    let user = db::get_user_by_id(&db, &user_id).unwrap();
+    send_verification_email(&db, &user).await;
+    let token = db::create_session(&db, &user_id, &auth::user_agent(&headers), &ip);
+    Ok((StatusCode::CREATED, Json(TokenResponse { token, user })))
+}
+
+/// Ends a login session. Personal tokens are revoked from the profile page instead.
+#[utoipa::path(
+    post, path = "/auth/logout", tag = "auth",
+    responses(
+        (status = 204, description = "Session ended"),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn logout(
+    State((db, _tera)): State<AppState>,
+    _caller: ApiUser,
+    headers: HeaderMap,
+) -> ApiResult<StatusCode> {
+    if let Some(token) = bearer_token(&headers) {
+        db::delete_session(&db, token);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// === Profile ===
+
+#[utoipa::path(
+    get, path = "/me", tag = "profile",
+    responses(
+        (status = 200, description = "The caller", body = User),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+        (status = 403, description = "Token lacks the required scope", body = ErrorBody),
+    ),
+    security(("bearer" = ["profile:read"])),
+)]
+pub async fn me(caller: ApiUser) -> ApiResult<Json<User>> {
+    Ok(Json(caller.require("profile:read")?))
+}
+
+/// Fields left out keep their current value.
+#[derive(Deserialize, ToSchema)]
+pub struct ProfileUpdate {
+    pub name: Option<String>,
+    pub location: Option<String>,
+    pub bio: Option<String>,
+    pub payment_info: Option<String>,
+    pub offer_ttl_hours: Option<i64>,
+}
+
+#[utoipa::path(
+    patch, path = "/me", tag = "profile",
+    request_body = ProfileUpdate,
+    responses(
+        (status = 200, description = "The updated caller", body = User),
+        (status = 400, description = "Malformed JSON body", body = ErrorBody),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+        (status = 403, description = "Token lacks the required scope", body = ErrorBody),
+        (status = 422, description = "Invalid field values", body = ErrorBody),
+    ),
+    security(("bearer" = ["profile:write"])),
+)]
+pub async fn update_me(
+    State((db, _tera)): State<AppState>,
+    caller: ApiUser,
+    payload: Result<Json<ProfileUpdate>, JsonRejection>,
+) -> ApiResult<Json<User>> {
+    let user = caller.require("profile:write")?;
+    let Json(update) = payload?;
+    if update.name.as_deref().is_some_and(|n| n.trim().is_empty()) {
+        return Err(ApiError::invalid("Name can't be empty."));
+    }
+    let form = ProfileForm {
+        name: update.name.unwrap_or(user.name),
+        location: update.location.unwrap_or(user.location),
+        bio: update.bio.unwrap_or(user.bio),
+        payment_info: update.payment_info.unwrap_or(user.payment_info),
+        offer_ttl_hours: update.offer_ttl_hours,
+    };
+    db::update_user_profile(&db, &user.id, &form);
+    Ok(Json(db::get_user_by_id(&db, &user.id).unwrap()))
+}
+
+// === Listings ===
+
+#[derive(Serialize, ToSchema)]
+pub struct ListingDetail {
+    #[serde(flatten)]
+    pub listing: Listing,
+    pub images: Vec<ListingImage>,
+}
+
+fn listing_detail(db: &Db, id: &str) -> ApiResult<ListingDetail> {
+    let listing = db::get_listing(db, id).ok_or_else(|| ApiError::not_found("Listing"))?;
+    let images = db::get_listing_images(db, id);
+    Ok(ListingDetail { listing, images })
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ListingRequest {
+    pub title: String,
+    pub description: String,
+    /// Decimal amount in dollars, e.g. "12.50"
+    pub price: String,
+    pub category: String,
+    #[serde(default = "default_condition")]
+    pub condition: String,
+    #[serde(default)]
+    pub location: String,
+}
+
+fn default_condition() -> String {
+    "Good".to_string()
+}
+
+const CONDITIONS: &[&str] = &["New", "Like New", "Good", "Fair"];
+
+impl ListingRequest {
+    fn validate(self) -> ApiResult<(ListingForm, Money)> {
+        if self.title.trim().is_empty() || self.description.trim().is_empty() || self.category.trim().is_empty() {
+            return Err(ApiError::invalid("Title, description and category are required."));
+        }
+        if !CONDITIONS.contains(&self.condition.as_str()) {
+            return Err(ApiError::invalid(format!("Condition must be one of: {}.", CONDITIONS.join(", "))));
+        }
+        let price = Money::parse(&self.price, Currency::USD).map_err(ApiError::invalid)?;
+        let form = ListingForm {
+            title: self.title.trim().to_string(),
+            description: self.description.trim().to_string(),
+            price: self.price,
+            category: self.category.trim().to_string(),
+            condition: self.condition,
+            location: self.location.trim().to_string(),
+        };
+        Ok((form, price))
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ListingList {
+    pub listings: Vec<Listing>,
+}
+
+#[utoipa::path(
+    get, path = "/listings", tag = "listings",
+    params(SearchQuery),
+    responses(
+        (status = 200, description = "Active listings matching the query", body = ListingList),
+    ),
+)]
+pub async fn list_listings(
+    State((db, _tera)): State<AppState>,
+    Query(query): Query<SearchQuery>,
+) -> Json<ListingList> {
+    Json(ListingList { listings: db::get_listings(&db, &query) })
+}
+
+#[utoipa::path(
+    get, path = "/listings/{id}", tag = "listings",
+    params(("id" = String, Path, description = "Listing id")),
+    responses(
+        (status = 200, description = "The listing with its photos", body = ListingDetail),
+        (status = 404, description = "Listing not found", body = ErrorBody),
+    ),
+)]
+pub async fn get_listing(
+    State((db, _tera)): State<AppState>,
+    Path(id): Path<String>,
+) -> ApiResult<Json<ListingDetail>> {
+    Ok(Json(listing_detail(&db, &id)?))
+}
+
+#[utoipa::path(
+    post, path = "/listings", tag = "listings",
+    request_body = ListingRequest,
+    responses(
+        (status = 201, description = "The new listing", body = ListingDetail),
+        (status = 400, description = "Malformed JSON body", body = ErrorBody),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+        (status = 403, description = "Token lacks the required scope, or the email address isn't confirmed", body = ErrorBody),
+        (status = 422, description = "Invalid field values", body = ErrorBody),
+    ),
+    security(("bearer" = ["listings:write"])),
+)]
+pub async fn create_listing(
+    State((db, _tera)): State<AppState>,
+    caller: ApiUser,
+    payload: Result<Json<ListingRequest>, JsonRejection>,
+) -> ApiResult<(StatusCode, Json<ListingDetail>)> {
+    let user = caller.require("listings:write")?;
+    require_verified(&user)?;
+    let Json(req) = payload?;
+    let (form, price) = req.validate()?;
+    let id = db::create_listing(&db, &user.id, &form, price, &[]);
+    Ok((StatusCode::CREATED, Json(listing_detail(&db, &id)?)))
+}
+
+fn listing_error(e: ListingError) -> ApiError {
+    match e {
+        ListingError::NotFound => ApiError::not_found("Listing"),
+        ListingError::Conflict(_) => ApiError::conflict(e.to_string()),
+        ListingError::Failed(_) => ApiError::internal(e),
+    }
+}
+
+fn require_own_listing(db: &Db, id: &str, user: &User) -> ApiResult<Listing> {
+    let listing = db::get_listing(db, id).ok_or_else(|| ApiError::not_found("Listing"))?;
+    if listing.seller_id != user.id {
+        return Err(ApiError::forbidden());
+    }
+    Ok(listing)
+}
+
+#[utoipa::path(
+    put, path = "/listings/{id}", tag = "listings",
+    params(("id" = String, Path, description = "Listing id")),
+    request_body = ListingRequest,
+    responses(
+        (status = 200, description = "The updated listing", body = ListingDetail),
+        (status = 400, description = "Malformed JSON body", body = ErrorBody),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+        (status = 403, description = "Missing scope, or not your listing", body = ErrorBody),
+        (status = 404, description = "Listing not found", body = ErrorBody),
+        (status = 422, description = "Invalid field values", body = ErrorBody),
+        (status = 500, description = "The listing couldn't be saved", body = ErrorBody),
+    ),
+    security(("bearer" = ["listings:write"])),
+)]
+pub async fn update_listing(
+    State((db, _tera)): State<AppState>,
+    caller: ApiUser,
+    Path(id): Path<String>,
+    payload: Result<Json<ListingRequest>, JsonRejection>,
+) -> ApiResult<Json<ListingDetail>> {
+    let user = caller.require("listings:write")?;
+    require_own_listing(&db, &id, &user)?;
+    let Json(req) = payload?;
+    let (form, price) = req.validate()?;
+    db::update_listing(&db, &id, &user.id, &form, price, &[]).map_err(listing_error)?;
+    Ok(Json(listing_detail(&db, &id)?))
+}
+
+#[utoipa::path(
+    delete, path = "/listings/{id}", tag = "listings",
+    params(("id" = String, Path, description = "Listing id")),
+    responses(
+        (status = 204, description = "Listing deleted"),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+        (status = 403, description = "Missing scope, or not your listing", body = ErrorBody),
+        (status = 404, description = "Listing not found", body = ErrorBody),
+        (status = 409, description = "Buyers have messaged about the listing; mark it sold instead", body = ErrorBody),
+        (status = 500, description = "The listing couldn't be deleted", body = ErrorBody),
+    ),
+    security(("bearer" = ["listings:write"])),
+)]
+pub async fn delete_listing(
+    State((db, _tera)): State<AppState>,
+    caller: ApiUser,
+    Path(id): Path<String>,
+) -> ApiResult<StatusCode> {
+    let user = caller.require("listings:write")?;
+    require_own_listing(&db, &id, &user)?;
+    db::delete_listing(&db, &id, &user.id).map_err(listing_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// === Conversations & messages ===
+
+#[derive(Serialize, ToSchema)]
+pub struct ConversationList {
+    pub conversations: Vec<Conversation>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ConversationDetail {
+    pub conversation: Conversation,
+    /// The latest page of messages; page back with `GET /conversations/{id}/messages`
+    pub messages: Vec<Message>,
+    pub offers: Vec<Offer>,
+    pub pending_offer: Option<Offer>,
+}
+
+#[utoipa::path(
+    get, path = "/conversations", tag = "conversations",
+    responses(
+        (status = 200, description = "The caller's conversations, most recent first", body = ConversationList),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+        (status = 403, description = "Token lacks the required scope", body = ErrorBody),
+    ),
+    security(("bearer" = ["messages:read"])),
+)]
+pub async fn list_conversations(
+    State((db, _tera)): State<AppState>,
+    caller: ApiUser,
+) -> ApiResult<Json<ConversationList>> {
+    let user = caller.require("messages:read")?;
+    Ok(Json(ConversationList { conversations: db::get_user_conversations(&db, &user.id) }))
+}
+
+/// Opens (or returns the existing) conversation with the seller of a listing.
+#[utoipa::path(
+    post, path = "/listings/{id}/conversation", tag = "conversations",
+    params(("id" = String, Path, description = "Listing id")),
+    responses(
+        (status = 200, description = "The conversation", body = Conversation),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+        (status = 403, description = "Token lacks the required scope, or the email address isn't confirmed", body = ErrorBody),
+        (status = 404, description = "Listing not found", body = ErrorBody),
+        (status = 422, description = "The listing is your own", body = ErrorBody),
+    ),
+    security(("bearer" = ["messages:write"])),
+)]
+pub async fn start_conversation(
+    State((db, _tera)): State<AppState>,
+    caller: ApiUser,
+    Path(listing_id): Path<String>,
+) -> ApiResult<Json<Conversation>> {
+    let user = caller.require("messages:write")?;
+    let listing = db::get_listing(&db, &listing_id).ok_or_else(|| ApiError::not_found("Listing"))?;
+    if listing.seller_id == user.id {
+        return Err(ApiError::invalid("You can't message yourself about your own listing."));
+    }
+    require_verified(&user)?;
+    let convo_id = db::get_or_create_conversation(&db, &listing_id, &user.id, &listing.seller_id);
+    Ok(Json(db::get_conversation(&db, &convo_id).unwrap()))
+}
+
+#[utoipa::path(
+    get, path = "/conversations/{id}", tag = "conversations",
+    params(("id" = String, Path, description = "Conversation id")),
+    responses(
+        (status = 200, description = "The conversation with its latest messages and its offers", body = ConversationDetail),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+        (status = 403, description = "Token lacks the required scope", body = ErrorBody),
+        (status = 404, description = "Conversation not found", body = ErrorBody),
+    ),
+    security(("bearer" = ["messages:read"])),
+)]
+pub async fn get_conversation(
+    State((db, _tera)): State<AppState>,
+    caller: ApiUser,
+    Path(id): Path<String>,
+) -> ApiResult<Json<ConversationDetail>> {
+    let user = caller.require("messages:read")?;
+    let conversation = require_participant(&db, &id, &user)?;
+    let messages = db::get_messages_before(&db, &id, None);
+    db::mark_read_through(&db, &user.id, &id, messages.last().map(|m| m.seq).unwrap_or(0));
+    Ok(Json(ConversationDetail {
+        conversation,
+        messages,
+        offers: db::get_offer_thread(&db, &id),
+        pending_offer: db::get_pending_offer(&db, &id),
+    }))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct MessageList {
+    pub messages: Vec<Message>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct MessagesQuery {
+    /// Every message with a higher `seq`, for catching up
+    pub after: Option<i64>,
+    /// The page of messages just before this `seq`; without either, the latest page
+    pub before: Option<i64>,
+}
+
+#[utoipa::path(
+    get, path = "/conversations/{id}/messages", tag = "conversations",
+    params(("id" = String, Path, description = "Conversation id"), MessagesQuery),
+    responses(
+        (status = 200, description = "Messages oldest first, at most 50 unless `after` is given", body = MessageList),
+        (status = 400, description = "Malformed query", body = ErrorBody),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+        (status = 403, description = "Token lacks the required scope", body = ErrorBody),
+        (status = 404, description = "Conversation not found", body = ErrorBody),
+    ),
+    security(("bearer" = ["messages:read"])),
+)]
+pub async fn list_messages(
+    State((db, _tera)): State<AppState>,
+    caller: ApiUser,
+    Path(id): Path<String>,
+    query: Result<Query<MessagesQuery>, QueryRejection>,
+) -> ApiResult<Json<MessageList>> {
+    let user = caller.require("messages:read")?;
+    require_participant(&db, &id, &user)?;
+    let Query(query) = query?;
+    let messages = match query.after {
+        Some(seq) => db::get_messages_after(&db, &id, seq),
+        None => db::get_messages_before(&db, &id, query.before),
+    };
+    db::mark_messages_read(&db, &user.id, &messages);
+    Ok(Json(MessageList { messages }))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct MessageRequest {
+    pub content: String,
+}
+
+#[utoipa::path(
+    post, path = "/conversations/{id}/messages", tag = "conversations",
+    params(("id" = String, Path, description = "Conversation id")),
+    request_body = MessageRequest,
+    responses(
+        (status = 201, description = "The sent message", body = Message),
+        (status = 400, description = "Malformed JSON body", body = ErrorBody),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+        (status = 403, description = "Token lacks the required scope", body = ErrorBody),
+        (status = 404, description = "Conversation not found", body = ErrorBody),
+        (status = 422, description = "Empty message", body = ErrorBody),
+        (status = 429, description = "Sending messages too quickly", body = ErrorBody),
+    ),
+    security(("bearer" = ["messages:write"])),
+)]
+pub async fn send_message(
+    State((db, _tera)): State<AppState>,
+    caller: ApiUser,
+    Path(id): Path<String>,
+    payload: Result<Json<MessageRequest>, JsonRejection>,
+) -> ApiResult<(StatusCode, Json<Message>)> {
+    let user = caller.require("messages:write")?;
+    require_participant(&db, &id, &user)?;
+    let Json(req) = payload?;
+    let content = req.content.trim();
+    if content.is_empty() {
+        return Err(ApiError::invalid("Message can't be empty."));
+    }
+    ratelimit::hit(&db, &ratelimit::MESSAGES_PER_USER, &user.id)
+        .map_err(|wait| ApiError::rate_limited("You're sending messages too quickly.", wait))?;
+    let message_id = db::send_message(&db, &id, &user.id, content);
+    Ok((StatusCode::CREATED, Json(db::get_message(&db, &message_id).unwrap())))
+}
+
+// === Offers ===
+
+#[derive(Deserialize, ToSchema)]
+pub struct OfferRequest {
+    /// Decimal amount in dollars, e.g. "40" or "39.99"
+    pub amount: String,
+}
+
+#[utoipa::path(
+    post, path = "/conversations/{id}/offers", tag = "offers",
+    params(("id" = String, Path, description = "Conversation id")),
+    request_body = OfferRequest,
+    responses(
+        (status = 201, description = "The new offer", body = Offer),
+        (status = 400, description = "Malformed JSON body", body = ErrorBody),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+        (status = 403, description = "Missing scope, the caller is the seller, or their email address isn't confirmed", body = ErrorBody),
+        (status = 404, description = "Conversation not found", body = ErrorBody),
+        (status = 409, description = "The listing is reserved or sold", body = ErrorBody),
+        (status = 422, description = "Invalid amount", body = ErrorBody),
+        (status = 429, description = "Making offers too quickly", body = ErrorBody),
+    ),
+    security(("bearer" = ["offers:write"])),
+)]
+pub async fn make_offer(
+    State((db, _tera)): State<AppState>,
+    caller: ApiUser,
+    Path(convo_id): Path<String>,
+    payload: Result<Json<OfferRequest>, JsonRejection>,
+) -> ApiResult<(StatusCode, Json<Offer>)> {
+    let user = caller.require("offers:write")?;
+    let convo = require_participant(&db, &convo_id, &user)?;
+    if convo.buyer_id != user.id {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "forbidden", "Only the buyer can make an offer."));
+    }
+    require_verified(&user)?;
+    let Json(req) = payload?;
+    let amount = parse_amount(&db, &convo.listing_id, &req.amount)?;
+    ratelimit::hit(&db, &ratelimit::OFFERS_PER_USER, &user.id)
+        .map_err(|wait| ApiError::rate_limited("You're making offers too quickly.", wait))?;
+    let offer_id = db::create_offer(&db, &convo.listing_id, &convo_id, &user.id, amount).map_err(offer_error)?;
+    let offer = db::get_offer(&db, &offer_id).unwrap();
+    db::send_message(&db, &convo_id, &user.id, &new_offer_message(&offer));
+    Ok((StatusCode::CREATED, Json(offer)))
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum OfferAction {
+    Accept,
+    Reject,
+    Counter,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct OfferResponseRequest {
+    pub action: OfferAction,
+    /// Required for `counter`
+    pub amount: Option<String>,
+}
+
+/// Answers a pending offer. Returns the answered offer, or the new counter-offer.
+#[utoipa::path(
+    post, path = "/offers/{id}/respond", tag = "offers",
+    params(("id" = String, Path, description = "Offer id")),
+    request_body = OfferResponseRequest,
+    responses(
+        (status = 200, description = "The answered offer, or the counter-offer", body = Offer),
+        (status = 400, description = "Malformed JSON body", body = ErrorBody),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+        (status = 403, description = "Missing scope, the offer is your own, or countering with an unconfirmed email address", body = ErrorBody),
+        (status = 404, description = "Offer not found", body = ErrorBody),
+        (status = 409, description = "The offer is no longer pending", body = ErrorBody),
+        (status = 422, description = "Invalid or missing amount", body = ErrorBody),
+        (status = 429, description = "Countering too quickly", body = ErrorBody),
+    ),
+    security(("bearer" = ["offers:write"])),
+)]
+pub async fn respond_offer(
+    State((db, _tera)): State<AppState>,
+    caller: ApiUser,
+    Path(offer_id): Path<String>,
+    payload: Result<Json<OfferResponseRequest>, JsonRejection>,
+) -> ApiResult<Json<Offer>> {
+    let user = caller.require("offers:write")?;
+    let offer = db::get_offer(&db, &offer_id).ok_or_else(|| ApiError::not_found("Offer"))?;
+    require_participant(&db, &offer.conversation_id, &user).map_err(|_| ApiError::not_found("Offer"))?;
+    let Json(req) = payload?;
+    let response = match req.action {
+        OfferAction::Accept => OfferResponse::Accept,
+        OfferAction::Reject => OfferResponse::Reject,
+        OfferAction::Counter => {
+            require_verified(&user)?;
+            let amount = req.amount.as_deref().ok_or_else(|| ApiError::invalid("A counter-offer needs an amount."))?;
+            let amount = parse_amount(&db, &offer.listing_id, amount)?;
+            ratelimit::hit(&db, &ratelimit::OFFERS_PER_USER, &user.id)
+                .map_err(|wait| ApiError::rate_limited("You're making offers too quickly.", wait))?;
+            OfferResponse::Counter(amount)
+        }
+    };
+    let answered = db::respond_to_offer(&db, &offer_id, &user.id, response).map_err(offer_error)?;
+    db::send_message(&db, &offer.conversation_id, &user.id, &offer_answer_message(response, &answered));
+    Ok(Json(answered))
+}
+
+fn offer_error(e: OfferError) -> ApiError {
+    match e {
+        OfferError::NotFound => ApiError::not_found("Offer"),
+        OfferError::Forbidden => ApiError::new(StatusCode::FORBIDDEN, "forbidden", e.to_string()),
+        OfferError::Conflict(_) => ApiError::conflict(e.to_string()),
+    }
+}
@@ -1,21 +1,35 @@
-use axum::extract::State;
+use axum::extract::rejection::QueryRejection;
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
 use axum::response::{Html, Redirect, IntoResponse, Response};
 use axum::Form;
-use axum_extra::extract::cookie::{Cookie, CookieJar};
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
 use crate::db::{self, Db};
 use crate::auth as auth_service;
-use crate::models::{LoginForm, RegisterForm, ProfileForm};
+use crate::mailer::{self, Email};
+use crate::models::{CartMerge, ForgotPasswordForm, LoginForm, RegisterForm, ProfileForm, ResetPasswordForm, User};
+use crate::ratelimit::{self, ClientIp};
 use tera::Tera;
+use std::collections::HashMap;
 use std::sync::Arc;
 
 type AppState = (Db, Arc<Tera>);
 
+#[derive(serde::Deserialize)]
+pub struct LoginQuery {
+    /// Set after a password reset
+    pub reset: Option<String>,
+}
+
 pub async fn login_page(
     State((_db, tera)): State<AppState>,
-    jar: CookieJar,
+    Query(query): Query<LoginQuery>,
 ) -> Html<String> {
     let mut ctx = tera::Context::new();
     ctx.insert("error", &"");
+    if query.reset.is_some() {
+        ctx.insert("success", &"Your password has been changed and every device signed out. Log in with your new password.");
+    }
     ctx.insert("user", &None::<crate::models::User>);
     ctx.insert("unread_count", &0i64);
     Html(tera.render("login.html", &ctx).unwrap())
@@ -23,33 +37,58 @@ pub async fn login_page(
 
 pub async fn login(
     State((db, tera)): State<AppState>,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
     jar: CookieJar,
     Form(form): Form<LoginForm>,
 ) -> Response {
+    if let Err(wait) = ratelimit::check_login(&db, &ip, &form.email) {
+        let error = format!("Too many failed attempts. {}", ratelimit::try_again_in(wait));
+        return (StatusCode::TOO_MANY_REQUESTS, render_login(&tera, &error)).into_response();
+    }
     let user = db::get_user_by_email(&db, &form.email);
     match user {
         Some(u) if auth_service::verify_password(&form.password, &u.password_hash) => {
-            let session_id = db::create_session(&db, &u.id);
+            ratelimit::login_succeeded(&db, &form.email);
+            let session_id = db::create_session(&db, &u.id, &auth_service::user_agent(&headers), &ip);
             let cookie = Cookie::build((auth_service::SESSION_COOKIE, session_id))
                 .path("/")
                 .http_only(true)
+                .same_site(SameSite::Lax)
                 .max_age(time::Duration::days(7))
                 .build();
-            (jar.add(cookie), Redirect::to("/")).into_response()
+            let (jar, merge) = auth_service::claim_guest_cart(&db, jar, &u.id);
+            (jar.add(cookie), after_login_redirect(&merge)).into_response()
         }
         _ => {
-            let mut ctx = tera::Context::new();
-            ctx.insert("error", &"Invalid email or password");
-            ctx.insert("user", &None::<crate::models::User>);
-            ctx.insert("unread_count", &0i64);
-            Html(tera.render("login.html", &ctx).unwrap()).into_response()
+            ratelimit::login_failed(&db, &ip, &form.email);
+            render_login(&tera, "Invalid email or password").into_response()
         }
     }
 }
 
+fn render_login(tera: &Tera, error: &str) -> Html<String> {
+    let mut ctx = tera::Context::new();
+    ctx.insert("error", error);
+    ctx.insert("user", &None::<crate::models::User>);
+    ctx.insert("unread_count", &0i64);
+    Html(tera.render("login.html", &ctx).unwrap())
+}
+
+// Send people who shopped as a guest to their cart so they can see what carried over
+fn after_login_redirect(merge: &CartMerge) -> Redirect {
+    if merge.is_empty() {
+        Redirect::to("/")
+    } else {
+        Redirect::to(&format!(
+            "/cart?added={}&combined={}&dropped={}",
+            merge.added, merge.combined, merge.dropped
+        ))
+    }
+}
+
 pub async fn register_page(
     State((_db, tera)): State<AppState>,
-    jar: CookieJar,
 ) -> Html<String> {
     let mut ctx = tera::Context::new();
     ctx.insert("error", &"");
@@ -60,6 +99,8 @@ pub async fn register_page(
 
 pub async fn register(
     State((db, tera)): State<AppState>,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
     jar: CookieJar,
     Form(form): Form<RegisterForm>,
 ) -> Response {
@@ -77,17 +118,34 @@ pub async fn register(
         ctx.insert("unread_count", &0i64);
         return Html(tera.render("register.html", &ctx).unwrap()).into_response();
     }
+    if !auth_service::valid_email(form.email.trim()) {
+        let mut ctx = tera::Context::new();
+        ctx.insert("error", &"Enter a valid email address");
+        ctx.insert("user", &None::<crate::models::User>);
+        ctx.insert("unread_count", &0i64);
+        return Html(tera.render("register.html", &ctx).unwrap()).into_response();
+    }
+    if let Err(wait) = ratelimit::hit(&db, &ratelimit::REGISTRATIONS_PER_IP, &ip) {
+        let mut ctx = tera::Context::new();
+        ctx.insert("error", &format!("Too many new accounts from your network. {}", ratelimit::try_again_in(wait)));
+        ctx.insert("user", &None::<crate::models::User>);
+        ctx.insert("unread_count", &0i64);
+        return (StatusCode::TOO_MANY_REQUESTS, Html(tera.render("register.html", &ctx).unwrap())).into_response();
+    }
 
     let hash = auth_service::hash_password(&form.password);
-    match db::create_user(&db, &form.name, &form.email, &hash) {
+    match db::create_user(&db, &form.name, form.email.trim(), &hash) {
         Ok(user_id) => {
-            let session_id = db::create_session(&db, &user_id);
+            send_verification_email(&db, &db::get_user_by_id(&db, &user_id).unwrap()).await;
+            let session_id = db::create_session(&db, &user_id, &auth_service::user_agent(&headers), &ip);
             let cookie = Cookie::build((auth_service::SESSION_COOKIE, session_id))
                 .path("/")
                 .http_only(true)
+                .same_site(SameSite::Lax)
                 .max_age(time::Duration::days(7))
                 .build();
-            (jar.add(cookie), Redirect::to("/")).into_response()
+            let (jar, merge) = auth_service::claim_guest_cart(&db, jar, &user_id);
+            (jar.add(cookie), after_login_redirect(&merge)).into_response()
         }
         Err(e) => {
             let mut ctx = tera::Context::new();
@@ -99,7 +157,224 @@ pub async fn register(
     }
 }
 
-pub async fn logout(jar: CookieJar) -> (CookieJar, Redirect) {
+/// Mails the user a link that confirms their address. A failure is logged; they can ask for
+/// another link from their profile.
+pub async fn send_verification_email(db: &Db, user: &User) {
+    let email = Email {
+        to: user.email.clone(),
+        subject: "Confirm your Forge Market email address".to_string(),
+        body: format!(
+            "Hi {},\n\n\
+             Welcome to Forge Market! Confirm this is your email address to start selling, \
+             messaging sellers and making offers:\n\n\
+             {}{}\n\n\
+             The link works for {} hours. If you didn't sign up, ignore this email.\n",
+            user.name, mailer::base_url(), auth_service::email_verification_path(db, user), auth_service::VERIFY_LINK_HOURS,
+        ),
+    };
+    let db = db.clone();
+    if let Err(e) = tokio::task::spawn_blocking(move || mailer::send(&db, &email)).await.unwrap() {
+        eprintln!("❌ Couldn't send a verification link to {}: {}", user.email, e);
+    }
+}
+
+/// The signed parts of a verification link, from its query string or the confirm form.
+#[derive(serde::Deserialize, serde::Serialize, Default)]
+pub struct VerifyEmailQuery {
+    pub user: Option<String>,
+    pub expires: Option<i64>,
+    pub sig: Option<String>,
+}
+
+fn check_verification_link(db: &Db, link: &VerifyEmailQuery) -> Result<User, &'static str> {
+    match link {
+        VerifyEmailQuery { user: Some(user), expires: Some(expires), sig: Some(sig) } => {
+            auth_service::check_email_verification(db, user, *expires, sig)
+        }
+        _ => Err("This verification link isn't valid. Make sure you copied all of it."),
+    }
+}
+
+// Opening the link only asks for confirmation. Mail clients and link scanners fetch links
+// too, and that shouldn't confirm an address nobody has looked at.
+pub async fn verify_email_page(
+    State((db, tera)): State<AppState>,
+    jar: CookieJar,
+    query: Result<Query<VerifyEmailQuery>, QueryRejection>,
+) -> Html<String> {
+    let link = query.map(|Query(q)| q).unwrap_or_default();
+    let result = check_verification_link(&db, &link);
+    // Opened again after confirming: nothing left to ask
+    let confirm = match &result {
+        Ok(user) if !user.is_verified() => Some(&link),
+        _ => None,
+    };
+    render_verify_email(&db, &tera, &jar, &result, confirm)
+}
+
+pub async fn verify_email(
+    State((db, tera)): State<AppState>,
+    jar: CookieJar,
+    Form(link): Form<VerifyEmailQuery>,
+) -> Html<String> {
+    let result = check_verification_link(&db, &link);
+    if let Ok(user) = &result {
+        db::mark_email_verified(&db, &user.id);
+    }
+    render_verify_email(&db, &tera, &jar, &result, None)
+}
+
+/// With `confirm`, asks to confirm the link's address; without, says it's confirmed.
+fn render_verify_email(db: &Db, tera: &Tera, jar: &CookieJar, result: &Result<User, &str>, confirm: Option<&VerifyEmailQuery>) -> Html<String> {
+    let current = auth_service::get_current_user(db, jar);
+    let unread = current.as_ref().map(|u| db::get_unread_count(db, &u.id)).unwrap_or(0);
+    let mut ctx = tera::Context::new();
+    ctx.insert("user", &current);
+    ctx.insert("unread_count", &unread);
+    ctx.insert("email", &result.as_ref().ok().map(|u| &u.email));
+    ctx.insert("confirm", &confirm);
+    ctx.insert("error", result.as_ref().err().copied().unwrap_or(""));
+    Html(tera.render("verify_email.html", &ctx).unwrap())
+}
+
+pub async fn resend_verification(
+    State((db, tera)): State<AppState>,
+    jar: CookieJar,
+) -> Response {
+    let user = match auth_service::get_current_user(&db, &jar) {
+        Some(u) => u,
+        None => return Redirect::to("/login").into_response(),
+    };
+    if user.is_verified() {
+        return Redirect::to("/profile").into_response();
+    }
+    if let Err(wait) = ratelimit::hit(&db, &ratelimit::VERIFICATION_EMAILS_PER_USER, &user.id) {
+        let error = format!("We've already sent you several links. {}", ratelimit::try_again_in(wait));
+        return render_profile(&db, &tera, &jar, &user, &error, "", None);
+    }
+    send_verification_email(&db, &user).await;
+    let success = format!("We've sent a new link to {}.", user.email);
+    render_profile(&db, &tera, &jar, &user, "", &success, None)
+}
+
+pub async fn forgot_password_page(
+    State((_db, tera)): State<AppState>,
+) -> Html<String> {
+    render_forgot_password(&tera, "", "")
+}
+
+pub async fn forgot_password(
+    State((db, tera)): State<AppState>,
+    ClientIp(ip): ClientIp,
+    Form(form): Form<ForgotPasswordForm>,
+) -> Response {
+    if let Err(wait) = ratelimit::hit(&db, &ratelimit::PASSWORD_RESETS_PER_IP, &ip) {
+        let error = format!("Too many reset requests from your network. {}", ratelimit::try_again_in(wait));
+        return (StatusCode::TOO_MANY_REQUESTS, render_forgot_password(&tera, &error, "")).into_response();
+    }
+    if let Some(user) = db::get_user_by_email(&db, form.email.trim()) {
+        if ratelimit::hit(&db, &ratelimit::PASSWORD_RESETS_PER_EMAIL, &user.id).is_ok() {
+            let token = auth_service::random_token();
+            db::create_password_reset(&db, &user.id, &auth_service::hash_token(&token), auth_service::RESET_TOKEN_MINUTES);
+            let email = password_reset_email(&user, &token);
+            let db = db.clone();
+            if let Err(e) = tokio::task::spawn_blocking(move || mailer::send(&db, &email)).await.unwrap() {
+                eprintln!("❌ Couldn't send a password reset to {}: {}", user.email, e);
+            }
+        }
+    }
+    // The same answer whether or not there's an account, so the form can't be used to find
+    // out who has one
+    let success = format!(
+        "If there's an account for that email, we've sent it a link to choose a new password. The link works for {} minutes.",
+        auth_service::RESET_TOKEN_MINUTES,
+    );
+    render_forgot_password(&tera, "", &success).into_response()
+}
+
+fn password_reset_email(user: &User, token: &str) -> Email {
+    Email {
+        to: user.email.clone(),
+        subject: "Reset your Forge Market password".to_string(),
+        body: format!(
+            "Hi {},\n\n\
+             Someone asked to reset the password for your Forge Market account. If it was you, \
+             choose a new one here:\n\n\
+             {}/reset-password?token={}\n\n\
+             The link works once, for the next {} minutes. Setting a new password signs you out \
+             everywhere else.\n\n\
+             If you didn't ask for this, ignore this email and your password stays the same.\n",
+            user.name, mailer::base_url(), token, auth_service::RESET_TOKEN_MINUTES,
+        ),
+    }
+}
+
+fn render_forgot_password(tera: &Tera, error: &str, success: &str) -> Html<String> {
+    let mut ctx = tera::Context::new();
+    ctx.insert("error", error);
+    ctx.insert("success", success);
+    ctx.insert("user", &None::<crate::models::User>);
+    ctx.insert("unread_count", &0i64);
+    Html(tera.render("forgot_password.html", &ctx).unwrap())
+}
+
+#[derive(serde::Deserialize)]
+pub struct ResetPasswordQuery {
+    pub token: Option<String>,
+}
+
+pub async fn reset_password_page(
+    State((db, tera)): State<AppState>,
+    Query(query): Query<ResetPasswordQuery>,
+) -> Html<String> {
+    let token = query.token.unwrap_or_default();
+    // Checked up front so nobody types a new password into a dead link
+    let valid = db::get_password_reset_user(&db, &auth_service::hash_token(&token)).is_some();
+    render_reset_password(&tera, &token, valid, "")
+}
+
+pub async fn reset_password(
+    State((db, tera)): State<AppState>,
+    Form(form): Form<ResetPasswordForm>,
+) -> Response {
+    if form.password != form.password_confirm {
+        return render_reset_password(&tera, &form.token, true, "Passwords do not match").into_response();
+    }
+    if form.password.len() < 8 {
+        return render_reset_password(&tera, &form.token, true, "Password must be at least 8 characters").into_response();
+    }
+    let user_id = match db::redeem_password_reset(&db, &auth_service::hash_token(&form.token)) {
+        Some(id) => id,
+        None => return render_reset_password(&tera, "", false, "").into_response(),
+    };
+    let hash = auth_service::hash_password(&form.password);
+    db::update_user_password(&db, &user_id, &hash);
+    // Whoever had the old password is signed out too, including any API tokens they made
+    db::delete_user_sessions(&db, &user_id);
+    db::delete_user_api_tokens(&db, &user_id);
+    if let Some(user) = db::get_user_by_id(&db, &user_id) {
+        ratelimit::login_succeeded(&db, &user.email);
+    }
+    Redirect::to("/login?reset=1").into_response()
+}
+
+fn render_reset_password(tera: &Tera, token: &str, valid: bool, error: &str) -> Html<String> {
+    let mut ctx = tera::Context::new();
+    ctx.insert("token", token);
+    ctx.insert("valid", &valid);
+    ctx.insert("error", error);
+    ctx.insert("user", &None::<crate::models::User>);
+    ctx.insert("unread_count", &0i64);
+    Html(tera.render("reset_password.html", &ctx).unwrap())
+}
+
+pub async fn logout(
+    State((db, _tera)): State<AppState>,
+    jar: CookieJar,
+) -> (CookieJar, Redirect) {
+    if let Some(session) = jar.get(auth_service::SESSION_COOKIE) {
+        db::delete_session(&db, session.value());
+    }
     let jar = jar.remove(Cookie::from(auth_service::SESSION_COOKIE));
     (jar, Redirect::to("/"))
 }
@@ -112,15 +387,29 @@ pub async fn profile(
         Some(u) => u,
         None => return Redirect::to("/login").into_response(),
     };
-    let listings = db::get_user_listings(&db, &user.id);
-    let unread = db::get_unread_count(&db, &user.id);
+    render_profile(&db, &tera, &jar, &user, "", "", None)
+}
+
+fn render_profile(db: &Db, tera: &Tera, jar: &CookieJar, user: &User, error: &str, success: &str, new_token: Option<&str>) -> Response {
+    let listings = db::get_user_listings(db, &user.id);
+    let unread = db::get_unread_count(db, &user.id);
+    let api_tokens = db::get_api_tokens(db, &user.id);
+    let current_session = jar.get(auth_service::SESSION_COOKIE).map(|c| c.value());
+    let sessions = db::get_sessions(db, &user.id, current_session);
+    let api_scopes: Vec<serde_json::Value> = auth_service::API_SCOPES.iter()
+        .map(|(name, label)| serde_json::json!({ "name": name, "label": label }))
+        .collect();
 
     let mut ctx = tera::Context::new();
-    ctx.insert("user", &Some(&user));
+    ctx.insert("user", &Some(user));
     ctx.insert("listings", &listings);
     ctx.insert("unread_count", &unread);
-    ctx.insert("error", &"");
-    ctx.insert("success", &"");
+    ctx.insert("error", error);
+    ctx.insert("success", success);
+    ctx.insert("api_tokens", &api_tokens);
+    ctx.insert("api_scopes", &api_scopes);
+    ctx.insert("new_token", &new_token);
+    ctx.insert("sessions", &sessions);
     Html(tera.render("profile.html", &ctx).unwrap()).into_response()
 }
 
@@ -136,14 +425,69 @@ pub async fn update_profile(
     db::update_user_profile(&db, &user.id, &form);
     // Reload user
     let user = db::get_user_by_id(&db, &user.id).unwrap();
-    let listings = db::get_user_listings(&db, &user.id);
-    let unread = db::get_unread_count(&db, &user.id);
+    render_profile(&db, &tera, &jar, &user, "", "Profile updated!", None)
+}
 
-    let mut ctx = tera::Context::new();
-    ctx.insert("user", &Some(&user));
-    ctx.insert("listings", &listings);
-    ctx.insert("unread_count", &unread);
-    ctx.insert("error", &"");
-    ctx.insert("success", &"Profile updated!");
-    Html(tera.render("profile.html", &ctx).unwrap()).into_response()
+// Checkbox fields are named after the scopes they grant, e.g. `listings:write=on`
+pub async fn create_api_token(
+    State((db, tera)): State<AppState>,
+    jar: CookieJar,
+    Form(form): Form<HashMap<String, String>>,
+) -> Response {
+    let user = match auth_service::get_current_user(&db, &jar) {
+        Some(u) => u,
+        None => return Redirect::to("/login").into_response(),
+    };
+    let name = form.get("name").map(|n| n.trim()).unwrap_or("");
+    let scopes: Vec<&str> = auth_service::API_SCOPES.iter()
+        .map(|(scope, _)| *scope)
+        .filter(|scope| form.contains_key(*scope))
+        .collect();
+    if name.is_empty() || scopes.is_empty() {
+        return render_profile(&db, &tera, &jar, &user, "Give the token a name and at least one scope.", "", None);
+    }
+    let token = auth_service::generate_api_token();
+    let prefix = &token[..auth_service::API_TOKEN_PREFIX.len() + 6];
+    db::create_api_token(&db, &user.id, name, &auth_service::hash_token(&token), prefix, &scopes);
+    // The only time the full token is shown
+    render_profile(&db, &tera, &jar, &user, "", "", Some(&token))
+}
+
+pub async fn revoke_api_token(
+    State((db, _tera)): State<AppState>,
+    jar: CookieJar,
+    Path(id): Path<String>,
+) -> Response {
+    let user = match auth_service::get_current_user(&db, &jar) {
+        Some(u) => u,
+        None => return Redirect::to("/login").into_response(),
+    };
+    db::revoke_api_token(&db, &user.id, &id);
+    Redirect::to("/profile#api-tokens").into_response()
+}
+
+pub async fn revoke_session(
+    State((db, _tera)): State<AppState>,
+    jar: CookieJar,
+    Path(id): Path<String>,
+) -> Response {
+    let user = match auth_service::get_current_user(&db, &jar) {
+        Some(u) => u,
+        None => return Redirect::to("/login").into_response(),
+    };
+    db::revoke_session(&db, &user.id, &id);
+    Redirect::to("/profile#sessions").into_response()
+}
+
+pub async fn revoke_all_sessions(
+    State((db, _tera)): State<AppState>,
+    jar: CookieJar,
+) -> Response {
+    let user = match auth_service::get_current_user(&db, &jar) {
+        Some(u) => u,
+        None => return Redirect::to("/login").into_response(),
+    };
+    db::delete_user_sessions(&db, &user.id);
+    let jar = jar.remove(Cookie::from(auth_service::SESSION_COOKIE));
+    (jar, Redirect::to("/login")).into_response()
 }
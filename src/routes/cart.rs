@@ -0,0 +1,121 @@
+use axum::extract::{Path, Query, State};
+use axum::http::HeaderMap;
+use axum::response::{Html, Redirect, IntoResponse, Response};
+use axum_extra::extract::CookieJar;
+use crate::db::{self, Db};
+use crate::auth;
+use crate::models::{Cart, CartMerge, CartOwner};
+use tera::Tera;
+use std::sync::Arc;
+
+type AppState = (Db, Arc<Tera>);
+
+fn is_htmx(headers: &HeaderMap) -> bool {
+    headers.contains_key("hx-request")
+}
+
+fn load_cart(db: &Db, jar: &CookieJar) -> Cart {
+    auth::get_cart_owner(db, jar).map(|o| db::get_cart(db, &o)).unwrap_or_default()
+}
+
+#[derive(serde::Deserialize)]
+pub struct CartQuery {
+    pub error: Option<String>,
+}
+
+// Errors are passed back to the cart page as short codes in the query string
+fn cart_error(code: &str) -> &'static str {
+    match code {
+        "checkout" => "Nothing in your cart can be checked out right now.",
+        _ => "Something went wrong. Please try again.",
+    }
+}
+
+pub async fn cart_page(
+    State((db, tera)): State<AppState>,
+    jar: CookieJar,
+    Query(merged): Query<CartMerge>,
+    Query(query): Query<CartQuery>,
+) -> Html<String> {
+    let user = auth::get_current_user(&db, &jar);
+    let unread = user.as_ref().map(|u| db::get_unread_count(&db, &u.id)).unwrap_or(0);
+    let cart = load_cart(&db, &jar);
+
+    let mut ctx = tera::Context::new();
+    ctx.insert("user", &user);
+    ctx.insert("unread_count", &unread);
+    ctx.insert("cart", &cart);
+    ctx.insert("merged", &merged);
+    ctx.insert("error", &query.error.as_deref().map(cart_error).unwrap_or(""));
+    Html(tera.render("cart.html", &ctx).unwrap())
+}
+
+// HTMX partial for the navbar badge; refreshed on load and whenever a `cart-updated` event fires
+pub async fn cart_count(
+    State((db, _tera)): State<AppState>,
+    jar: CookieJar,
+) -> Html<String> {
+    let count = auth::get_cart_owner(&db, &jar).map(|o| db::get_cart_count(&db, &o)).unwrap_or(0);
+    if count > 0 {
+        Html(format!(r#"<span class="badge">{}</span>"#, count))
+    } else {
+        Html(String::new())
+    }
+}
+
+pub async fn add_to_cart(
+    State((db, _tera)): State<AppState>,
+    jar: CookieJar,
+    headers: HeaderMap,
+    Path(listing_id): Path<String>,
+) -> Response {
+    let user = auth::get_current_user(&db, &jar);
+    // Sellers can't buy their own items
+    if let (Some(user), Some(listing)) = (&user, db::get_listing(&db, &listing_id)) {
+        if listing.seller_id == user.id {
+            return Redirect::to(&format!("/listing/{}", listing_id)).into_response();
+        }
+    }
+
+    // Signed-in users shop with their own cart; guests get a cookie token on first add
+    let (owner, jar) = match user {
+        Some(u) => (CartOwner::User(u.id), jar),
+        None => {
+            let (token, jar) = auth::ensure_cart_token(jar);
+            (CartOwner::Guest(token), jar)
+        }
+    };
+    let result = db::add_to_cart(&db, &owner, &listing_id);
+    if is_htmx(&headers) {
+        let html = match result {
+            Ok(()) => r#"<a href="/cart" class="btn btn-secondary btn-block">✓ In your cart — view cart</a>"#.to_string(),
+            Err(e) => format!(r#"<p class="form-hint">{}</p>"#, tera::escape_html(&e)),
+        };
+        return (jar, [("HX-Trigger", "cart-updated")], Html(html)).into_response();
+    }
+    (jar, Redirect::to("/cart")).into_response()
+}
+
+pub async fn remove_item(
+    State((db, tera)): State<AppState>,
+    jar: CookieJar,
+    headers: HeaderMap,
+    Path(listing_id): Path<String>,
+) -> Response {
+    if let Some(owner) = auth::get_cart_owner(&db, &jar) {
+        db::remove_from_cart(&db, &owner, &listing_id);
+    }
+    cart_response(&db, &tera, &jar, &headers)
+}
+
+// HTMX callers get the refreshed cart body; plain form posts go back to the cart page
+fn cart_response(db: &Db, tera: &Tera, jar: &CookieJar, headers: &HeaderMap) -> Response {
+    if !is_htmx(headers) {
+        return Redirect::to("/cart").into_response();
+    }
+    let mut ctx = tera::Context::new();
+    ctx.insert("user", &auth::get_current_user(db, jar));
+    ctx.insert("cart", &load_cart(db, jar));
+    let html = tera.render("cart_contents.html", &ctx).unwrap();
+    ([("HX-Trigger", "cart-updated")], Html(html)).into_response()
+}
@@ -1,9 +1,12 @@
-use axum::extract::{Multipart, Path, Query, State};
+use axum::extract::{Form, Multipart, Path, Query, State};
+use axum::http::StatusCode;
 use axum::response::{Html, Redirect, IntoResponse, Response};
 use axum_extra::extract::CookieJar;
 use crate::db::{self, Db};
 use crate::auth;
-use crate::models::{SearchQuery, time_ago};
+use crate::images;
+use crate::models::{ImageAltForm, ImageMoveForm, ListingForm, ListingImage, SearchQuery, UploadedImage, User, time_ago};
+use crate::money::{Currency, Money};
 use tera::Tera;
 use std::sync::Arc;
 
@@ -38,11 +41,9 @@ pub async fn feed(
 
 pub async fn feed_partial(
     State((db, _tera)): State<AppState>,
-    jar: CookieJar,
     Query(query): Query<SearchQuery>,
 ) -> Html<String> {
     let listings = db::get_listings(&db, &query);
-    let user = auth::get_current_user(&db, &jar);
 
     let mut html = String::new();
     if listings.is_empty() {
@@ -54,7 +55,7 @@ pub async fn feed_partial(
             r##"<a href="/listing/{id}" class="listing-card">
                 <div class="listing-image"><img src="{img}" alt="{title}" loading="lazy"></div>
                 <div class="listing-info">
-                    <p class="listing-price">${price:.0}</p>
+                    <p class="listing-price">{price}</p>
                     <h3 class="listing-title">{title}</h3>
                     <div class="listing-meta">
                         <span class="listing-location">📍 {location}</span>
@@ -62,17 +63,31 @@ pub async fn feed_partial(
                     </div>
                 </div>
             </a>"##,
-            id = l.id, img = l.image_url, title = tera::escape_html(&l.title),
-            price = l.price, location = tera::escape_html(&l.location), ago = ago,
+            id = l.id, img = l.thumb_url, title = tera::escape_html(&l.title),
+            price = l.price.display_short(), location = tera::escape_html(&l.location), ago = ago,
         ));
     }
     Html(html)
 }
 
+#[derive(serde::Deserialize)]
+pub struct ListingQuery {
+    pub error: Option<String>,
+}
+
+// Errors are passed back to the listing page as short codes in the query string
+fn listing_error(code: &str) -> &'static str {
+    match code {
+        "unreserve" => "This item isn't reserved any more. The buyer may already have checked out.",
+        _ => "Something went wrong. Please try again.",
+    }
+}
+
 pub async fn listing_detail(
     State((db, tera)): State<AppState>,
     jar: CookieJar,
     Path(id): Path<String>,
+    Query(query): Query<ListingQuery>,
 ) -> Response {
     let user = auth::get_current_user(&db, &jar);
     let unread = user.as_ref().map(|u| db::get_unread_count(&db, &u.id)).unwrap_or(0);
@@ -81,6 +96,7 @@ pub async fn listing_detail(
         Some(listing) => {
             let seller = db::get_user_by_id(&db, &listing.seller_id);
             let seller_listings = db::get_seller_listings(&db, &listing.seller_id, &listing.id);
+            let images = db::get_listing_images(&db, &listing.id);
             let ago = time_ago(&listing.created_at);
             let is_owner = user.as_ref().map(|u| u.id == listing.seller_id).unwrap_or(false);
 
@@ -94,8 +110,14 @@ pub async fn listing_detail(
                 }
             });
 
+            let in_cart = auth::get_cart_owner(&db, &jar)
+                .map(|o| db::is_in_cart(&db, &o, &listing.id))
+                .unwrap_or(false);
+
             let mut ctx = tera::Context::new();
             ctx.insert("listing", &listing);
+            ctx.insert("images", &images);
+            ctx.insert("in_cart", &in_cart);
             ctx.insert("seller", &seller);
             ctx.insert("seller_listings", &seller_listings);
             ctx.insert("user", &user);
@@ -103,6 +125,7 @@ pub async fn listing_detail(
             ctx.insert("time_ago", &ago);
             ctx.insert("is_owner", &is_owner);
             ctx.insert("existing_convo", &existing_convo);
+            ctx.insert("error", &query.error.as_deref().map(listing_error).unwrap_or(""));
             Html(tera.render("listing_detail.html", &ctx).unwrap()).into_response()
         }
         None => Html("<h1>Listing not found</h1>".to_string()).into_response(),
@@ -117,18 +140,24 @@ pub async fn new_listing_page(
         Some(u) => u,
         None => return Redirect::to("/login").into_response(),
     };
+    if !user.is_verified() {
+        return Redirect::to("/profile#verify-email").into_response();
+    }
     let unread = db::get_unread_count(&db, &user.id);
     let mut ctx = tera::Context::new();
     ctx.insert("user", &Some(&user));
     ctx.insert("unread_count", &unread);
     ctx.insert("listing", &None::<crate::models::Listing>);
+    ctx.insert("images", &Vec::<ListingImage>::new());
+    ctx.insert("max_images", &db::MAX_LISTING_IMAGES);
+    ctx.insert("max_photo_mb", &(images::MAX_UPLOAD_BYTES / (1024 * 1024)));
     ctx.insert("editing", &false);
     ctx.insert("error", &"");
     Html(tera.render("listing_form.html", &ctx).unwrap()).into_response()
 }
 
 pub async fn create_listing(
-    State((db, _tera)): State<AppState>,
+    State((db, tera)): State<AppState>,
     jar: CookieJar,
     mut multipart: Multipart,
 ) -> Response {
@@ -136,45 +165,109 @@ pub async fn create_listing(
         Some(u) => u,
         None => return Redirect::to("/login").into_response(),
     };
+    if !user.is_verified() {
+        return Redirect::to("/profile#verify-email").into_response();
+    }
 
-    let mut title = String::new();
-    let mut description = String::new();
-    let mut price = String::new();
-    let mut category = String::new();
-    let mut condition = String::from("Good");
-    let mut location = user.location.clone();
-    let mut image_url = "/static/images/placeholder.svg".to_string();
+    let ListingUpload { form, uploads, upload_error } = read_listing_form(&mut multipart, user.location.clone()).await;
+    let price = match Money::parse(&form.price, Currency::USD) {
+        Ok(p) => p,
+        Err(e) => return render_form_error(&db, &tera, &user, None, &form, &e),
+    };
+    if let Some(e) = upload_error {
+        return render_form_error(&db, &tera, &user, None, &form, &e);
+    }
+    if uploads.len() > db::MAX_LISTING_IMAGES {
+        let e = format!("You can add up to {} photos.", db::MAX_LISTING_IMAGES);
+        return render_form_error(&db, &tera, &user, None, &form, &e);
+    }
+    let images = match store_uploads(&db, uploads).await {
+        Ok(images) => images,
+        Err(e) => return render_form_error(&db, &tera, &user, None, &form, &e),
+    };
+    let id = db::create_listing(&db, &user.id, &form, price, &images);
+    Redirect::to(&format!("/listing/{}", id)).into_response()
+}
+
+/// The listing form as posted: its text fields and the bytes of any photos chosen.
+struct ListingUpload {
+    form: ListingForm,
+    uploads: Vec<Vec<u8>>,
+    upload_error: Option<String>,
+}
+
+// Reads the multipart listing form shared by the new and edit pages
+async fn read_listing_form(multipart: &mut Multipart, location: String) -> ListingUpload {
+    let mut form = ListingForm {
+        title: String::new(), description: String::new(), price: String::new(),
+        category: String::new(), condition: String::from("Good"), location,
+    };
+    let mut uploads: Vec<Vec<u8>> = Vec::new();
+    let mut upload_error: Option<String> = None;
 
     while let Some(field) = multipart.next_field().await.unwrap_or(None) {
         let field_name = field.name().unwrap_or("").to_string();
         match field_name.as_str() {
-            "title" => title = field.text().await.unwrap_or_default(),
-            "description" => description = field.text().await.unwrap_or_default(),
-            "price" => price = field.text().await.unwrap_or_default(),
-            "category" => category = field.text().await.unwrap_or_default(),
-            "condition" => condition = field.text().await.unwrap_or_default(),
-            "location" => location = field.text().await.unwrap_or_default(),
+            "title" => form.title = field.text().await.unwrap_or_default(),
+            "description" => form.description = field.text().await.unwrap_or_default(),
+            "price" => form.price = field.text().await.unwrap_or_default(),
+            "category" => form.category = field.text().await.unwrap_or_default(),
+            "condition" => form.condition = field.text().await.unwrap_or_default(),
+            "location" => form.location = field.text().await.unwrap_or_default(),
             "image" => {
                 let filename = field.file_name().unwrap_or("").to_string();
                 if !filename.is_empty() {
-                    let data = field.bytes().await.unwrap_or_default();
-                    if !data.is_empty() {
-                        let _ = std::fs::create_dir_all("static/images");
-                        let ext = filename.rsplit('.').next().unwrap_or("jpg");
-                        let save_name = format!("{}.{}", uuid::Uuid::new_v4(), ext);
-                        let path = format!("static/images/{}", save_name);
-                        std::fs::write(&path, &data).ok();
-                        image_url = format!("/static/images/{}", save_name);
+                    match field.bytes().await {
+                        Ok(data) if !data.is_empty() => uploads.push(data.to_vec()),
+                        Ok(_) => {}
+                        Err(_) => upload_error = Some("Those photos are too large to upload together.".to_string()),
                     }
                 }
             }
             _ => {}
         }
     }
+    ListingUpload { form, uploads, upload_error }
+}
 
-    let form = crate::models::ListingForm { title, description, price, category, condition, location };
-    let id = db::create_listing(&db, &user.id, &form, &image_url);
-    Redirect::to(&format!("/listing/{}", id)).into_response()
+// Decoding and resizing is CPU-bound, so it runs off the async workers
+async fn store_uploads(db: &Db, uploads: Vec<Vec<u8>>) -> Result<Vec<UploadedImage>, String> {
+    if uploads.is_empty() { return Ok(Vec::new()); }
+    let db = db.clone();
+    tokio::task::spawn_blocking(move || images::store_uploads(&db, &uploads)).await.unwrap()
+}
+
+// Re-renders the listing form with the seller's input preserved
+fn render_form_error(
+    db: &Db,
+    tera: &Tera,
+    user: &User,
+    listing_id: Option<&str>,
+    form: &ListingForm,
+    error: &str,
+) -> Response {
+    let draft = serde_json::json!({
+        "id": listing_id.unwrap_or(""),
+        "title": form.title,
+        "description": form.description,
+        "price": { "amount": form.price },
+        "category": form.category,
+        "condition": form.condition,
+        "location": form.location,
+        "image_url": db::PLACEHOLDER_IMAGE,
+    });
+    let images = listing_id.map(|id| db::get_listing_images(db, id)).unwrap_or_default();
+    let unread = db::get_unread_count(db, &user.id);
+    let mut ctx = tera::Context::new();
+    ctx.insert("user", &Some(user));
+    ctx.insert("unread_count", &unread);
+    ctx.insert("listing", &Some(draft));
+    ctx.insert("images", &images);
+    ctx.insert("max_images", &db::MAX_LISTING_IMAGES);
+    ctx.insert("max_photo_mb", &(images::MAX_UPLOAD_BYTES / (1024 * 1024)));
+    ctx.insert("editing", &listing_id.is_some());
+    ctx.insert("error", error);
+    (StatusCode::UNPROCESSABLE_ENTITY, Html(tera.render("listing_form.html", &ctx).unwrap())).into_response()
 }
 
 pub async fn edit_listing_page(
@@ -190,18 +283,22 @@ pub async fn edit_listing_page(
         Some(l) if l.seller_id == user.id => l,
         _ => return Redirect::to("/").into_response(),
     };
+    let images = db::get_listing_images(&db, &listing.id);
     let unread = db::get_unread_count(&db, &user.id);
     let mut ctx = tera::Context::new();
     ctx.insert("user", &Some(&user));
     ctx.insert("unread_count", &unread);
     ctx.insert("listing", &Some(&listing));
+    ctx.insert("images", &images);
+    ctx.insert("max_images", &db::MAX_LISTING_IMAGES);
+    ctx.insert("max_photo_mb", &(images::MAX_UPLOAD_BYTES / (1024 * 1024)));
     ctx.insert("editing", &true);
     ctx.insert("error", &"");
     Html(tera.render("listing_form.html", &ctx).unwrap()).into_response()
 }
 
 pub async fn update_listing(
-    State((db, _tera)): State<AppState>,
+    State((db, tera)): State<AppState>,
     jar: CookieJar,
     Path(id): Path<String>,
     mut multipart: Multipart,
@@ -211,43 +308,32 @@ pub async fn update_listing(
         None => return Redirect::to("/login").into_response(),
     };
 
-    let mut title = String::new();
-    let mut description = String::new();
-    let mut price = String::new();
-    let mut category = String::new();
-    let mut condition = String::from("Good");
-    let mut location = String::new();
-    let mut image_url: Option<String> = None;
+    // Only the seller gets as far as reading the upload, so nobody else's photos are stored
+    let listing = match db::get_listing(&db, &id) {
+        Some(l) if l.seller_id == user.id => l,
+        _ => return Redirect::to("/").into_response(),
+    };
 
-    while let Some(field) = multipart.next_field().await.unwrap_or(None) {
-        let field_name = field.name().unwrap_or("").to_string();
-        match field_name.as_str() {
-            "title" => title = field.text().await.unwrap_or_default(),
-            "description" => description = field.text().await.unwrap_or_default(),
-            "price" => price = field.text().await.unwrap_or_default(),
-            "category" => category = field.text().await.unwrap_or_default(),
-            "condition" => condition = field.text().await.unwrap_or_default(),
-            "location" => location = field.text().await.unwrap_or_default(),
-            "image" => {
-                let filename = field.file_name().unwrap_or("").to_string();
-                if !filename.is_empty() {
-                    let data = field.bytes().await.unwrap_or_default();
-                    if !data.is_empty() {
-                        let _ = std::fs::create_dir_all("static/images");
-                        let ext = filename.rsplit('.').next().unwrap_or("jpg");
-                        let save_name = format!("{}.{}", uuid::Uuid::new_v4(), ext);
-                        let path = format!("static/images/{}", save_name);
-                        std::fs::write(&path, &data).ok();
-                        image_url = Some(format!("/static/images/{}", save_name));
-                    }
-                }
-            }
-            _ => {}
-        }
+    let ListingUpload { form, uploads, upload_error } = read_listing_form(&mut multipart, String::new()).await;
+    let price = match Money::parse(&form.price, Currency::USD) {
+        Ok(p) => p,
+        Err(e) => return render_form_error(&db, &tera, &user, Some(&id), &form, &e),
+    };
+    if let Some(e) = upload_error {
+        return render_form_error(&db, &tera, &user, Some(&id), &form, &e);
+    }
+    let existing = db::get_listing_images(&db, &listing.id).len();
+    if existing + uploads.len() > db::MAX_LISTING_IMAGES {
+        let e = format!("You can add up to {} photos. Remove some before adding more.", db::MAX_LISTING_IMAGES);
+        return render_form_error(&db, &tera, &user, Some(&id), &form, &e);
+    }
+    let images = match store_uploads(&db, uploads).await {
+        Ok(images) => images,
+        Err(e) => return render_form_error(&db, &tera, &user, Some(&id), &form, &e),
+    };
+    if let Err(e) = db::update_listing(&db, &id, &user.id, &form, price, &images) {
+        return render_form_error(&db, &tera, &user, Some(&id), &form, &e.to_string());
     }
-
-    let form = crate::models::ListingForm { title, description, price, category, condition, location };
-    db::update_listing(&db, &id, &user.id, &form, image_url.as_deref());
     Redirect::to(&format!("/listing/{}", id)).into_response()
 }
 
@@ -264,6 +350,62 @@ pub async fn mark_sold(
     Redirect::to(&format!("/listing/{}", id)).into_response()
 }
 
+pub async fn unreserve(
+    State((db, _tera)): State<AppState>,
+    jar: CookieJar,
+    Path(id): Path<String>,
+) -> Response {
+    let user = match auth::get_current_user(&db, &jar) {
+        Some(u) => u,
+        None => return Redirect::to("/login").into_response(),
+    };
+    match db::unreserve_listing(&db, &id, &user.id) {
+        Ok(()) => Redirect::to(&format!("/listing/{}", id)).into_response(),
+        Err(_) => Redirect::to(&format!("/listing/{}?error=unreserve", id)).into_response(),
+    }
+}
+
+pub async fn move_image(
+    State((db, _tera)): State<AppState>,
+    jar: CookieJar,
+    Path((id, image_id)): Path<(String, String)>,
+    Form(form): Form<ImageMoveForm>,
+) -> Response {
+    let user = match auth::get_current_user(&db, &jar) {
+        Some(u) => u,
+        None => return Redirect::to("/login").into_response(),
+    };
+    db::move_listing_image(&db, &id, &image_id, &user.id, &form.direction);
+    Redirect::to(&format!("/listing/{}/edit", id)).into_response()
+}
+
+pub async fn delete_image(
+    State((db, _tera)): State<AppState>,
+    jar: CookieJar,
+    Path((id, image_id)): Path<(String, String)>,
+) -> Response {
+    let user = match auth::get_current_user(&db, &jar) {
+        Some(u) => u,
+        None => return Redirect::to("/login").into_response(),
+    };
+    db::delete_listing_image(&db, &id, &image_id, &user.id);
+    Redirect::to(&format!("/listing/{}/edit", id)).into_response()
+}
+
+pub async fn update_image_alt(
+    State((db, _tera)): State<AppState>,
+    jar: CookieJar,
+    Path((id, image_id)): Path<(String, String)>,
+    Form(form): Form<ImageAltForm>,
+) -> Response {
+    let user = match auth::get_current_user(&db, &jar) {
+        Some(u) => u,
+        None => return Redirect::to("/login").into_response(),
+    };
+    db::set_listing_image_alt(&db, &id, &image_id, &user.id, &form.alt);
+    Redirect::to(&format!("/listing/{}/edit", id)).into_response()
+}
+
 pub async fn delete_listing(
     State((db, _tera)): State<AppState>,
     jar: CookieJar,
@@ -273,6 +415,9 @@ pub async fn delete_listing(
         Some(u) => u,
         None => return Redirect::to("/login").into_response(),
     };
-    db::delete_listing(&db, &id, &user.id);
-    Redirect::to("/profile").into_response()
+    match db::delete_listing(&db, &id, &user.id) {
+        Ok(()) => Redirect::to("/profile").into_response(),
+        // Still listed, so back to it
+        Err(_) => Redirect::to(&format!("/listing/{}", id)).into_response(),
+    }
 }
@@ -1,11 +1,20 @@
+use axum::extract::ws::{Message as WsMessage, WebSocket, WebSocketUpgrade};
 use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::response::{Html, Redirect, IntoResponse, Response};
 use axum::Form;
 use axum_extra::extract::CookieJar;
 use crate::db::{self, Db};
 use crate::auth;
-use crate::models::{SendMessageForm, MakeOfferForm, time_ago};
+use crate::csrf;
+use crate::events::{self, ConversationEvent};
+use crate::models::{SendMessageForm, MakeOfferForm, RespondOfferForm, Message, Offer, OfferError, OfferResponse, time_ago, time_until};
+use crate::money::Money;
+use crate::ratelimit;
 use tera::Tera;
+use tokio::sync::broadcast::error::RecvError;
+use std::convert::Infallible;
 use std::sync::Arc;
 
 type AppState = (Db, Arc<Tera>);
@@ -33,10 +42,29 @@ pub async fn inbox(
     Html(tera.render("messages.html", &ctx).unwrap()).into_response()
 }
 
+#[derive(serde::Deserialize)]
+pub struct ConversationQuery {
+    pub error: Option<String>,
+}
+
+// Errors are passed back to the conversation page as short codes in the query string
+fn conversation_error(code: &str) -> &'static str {
+    match code {
+        "offer_amount" => "Enter a valid offer amount, like 40 or 39.99.",
+        "checkout" => "This item can't be checked out — it may already have been sold.",
+        "offer_answered" => "That offer has already been answered.",
+        "unavailable" => "This item is no longer available, so it can't take offers.",
+        "slow_down" => "You're sending too much too quickly. Wait a moment and try again.",
+        "verify_email" => "Confirm your email address before making offers. The link is in your inbox, or send a new one from your profile.",
+        _ => "Something went wrong. Please try again.",
+    }
+}
+
 pub async fn conversation(
     State((db, tera)): State<AppState>,
     jar: CookieJar,
     Path(id): Path<String>,
+    Query(query): Query<ConversationQuery>,
 ) -> Response {
     let user = match auth::get_current_user(&db, &jar) {
         Some(u) => u,
@@ -47,27 +75,21 @@ pub async fn conversation(
         _ => return Redirect::to("/messages").into_response(),
     };
 
-    db::mark_conversation_read(&db, &user.id, &id);
-    let messages = db::get_messages(&db, &id);
+    // Only the latest page; older ones load on demand through `older_messages`
+    let messages = db::get_messages_before(&db, &id, None);
+    let last_seq = messages.last().map(|m| m.seq).unwrap_or(0);
+    db::mark_read_through(&db, &user.id, &id, last_seq);
     let listing = db::get_listing(&db, &convo.listing_id);
+    let offers = db::get_offer_thread(&db, &id);
     let pending_offer = db::get_pending_offer(&db, &id);
+    // Whoever didn't name the current amount gets to accept, decline or counter it
+    let can_respond = pending_offer.as_ref().map(|o| o.created_by != user.id).unwrap_or(false);
     let is_seller = user.id == convo.seller_id;
     let unread = db::get_unread_count(&db, &user.id);
 
-    // If there's an accepted offer, get the seller's payment info
-    let payment_info = if !is_seller {
-        // Check for any accepted offer
-        let conn_check = db.lock().unwrap();
-        let accepted: Option<String> = conn_check.query_row(
-            "SELECT u.payment_info FROM offers o JOIN listings l ON o.listing_id = l.id JOIN users u ON l.seller_id = u.id WHERE o.conversation_id = ?1 AND o.status = 'accepted' LIMIT 1",
-            rusqlite::params![id],
-            |row| row.get(0),
-        ).ok();
-        drop(conn_check);
-        accepted.filter(|s| !s.is_empty())
-    } else {
-        None
-    };
+    // An accepted offer can be checked out; once it is, both sides get a link to the order
+    let accepted_offer = db::get_accepted_offer(&db, &id);
+    let order_id = accepted_offer.as_ref().and_then(|o| db::get_order_for_offer(&db, &o.id));
 
     let other_name = if is_seller { &convo.buyer_name } else { &convo.seller_name };
 
@@ -76,18 +98,26 @@ pub async fn conversation(
     ctx.insert("conversation", &convo);
     ctx.insert("messages", &messages);
     ctx.insert("listing", &listing);
+    ctx.insert("offers", &offers);
     ctx.insert("pending_offer", &pending_offer);
+    ctx.insert("can_respond", &can_respond);
+    ctx.insert("offer_expires", &pending_offer.as_ref().and_then(|o| o.expires_at.as_deref()).map(time_until));
     ctx.insert("is_seller", &is_seller);
     ctx.insert("unread_count", &unread);
-    ctx.insert("payment_info", &payment_info);
+    ctx.insert("accepted_offer", &accepted_offer);
+    ctx.insert("order_id", &order_id);
     ctx.insert("other_name", other_name);
-    // Last message ID for polling
-    let last_id = messages.last().map(|m| m.id.as_str()).unwrap_or("");
-    ctx.insert("last_message_id", last_id);
+    ctx.insert("error", &query.error.as_deref().map(conversation_error).unwrap_or(""));
+    // "Seen" goes under the latest of the user's messages the other side has read
+    let last_seen_id = messages.iter().rev().find(|m| m.sender_id == user.id && m.seen).map(|m| m.id.as_str());
+    ctx.insert("last_seen_id", &last_seen_id.unwrap_or(""));
+    // Cursors for polling and for loading older messages
+    ctx.insert("last_message_seq", &last_seq);
+    ctx.insert("older_before", &messages.first().filter(|m| m.seq > 1).map(|m| m.seq));
     Html(tera.render("conversation.html", &ctx).unwrap()).into_response()
 }
 
-// Start a conversation from a listing page
+// Start a conversation from a listing page. A POST, since it creates one
 pub async fn start_conversation(
     State((db, _tera)): State<AppState>,
     jar: CookieJar,
@@ -104,6 +134,9 @@ pub async fn start_conversation(
     if listing.seller_id == user.id {
         return Redirect::to(&format!("/listing/{}", listing_id)).into_response();
     }
+    if !user.is_verified() {
+        return Redirect::to("/profile#verify-email").into_response();
+    }
     let convo_id = db::get_or_create_conversation(&db, &listing_id, &user.id, &listing.seller_id);
     Redirect::to(&format!("/messages/{}", convo_id)).into_response()
 }
@@ -118,11 +151,14 @@ pub async fn send_message(
         Some(u) => u,
         None => return Redirect::to("/login").into_response(),
     };
-    let convo = match db::get_conversation(&db, &id) {
-        Some(c) if c.buyer_id == user.id || c.seller_id == user.id => c,
+    match db::get_conversation(&db, &id) {
+        Some(c) if c.buyer_id == user.id || c.seller_id == user.id => {}
         _ => return Redirect::to("/messages").into_response(),
-    };
+    }
     if !form.content.trim().is_empty() {
+        if ratelimit::hit(&db, &ratelimit::MESSAGES_PER_USER, &user.id).is_err() {
+            return Redirect::to(&format!("/messages/{}?error=slow_down", id)).into_response();
+        }
         db::send_message(&db, &id, &user.id, form.content.trim());
     }
     Redirect::to(&format!("/messages/{}", id)).into_response()
@@ -142,12 +178,22 @@ pub async fn make_offer(
         Some(c) if c.buyer_id == user.id => c,
         _ => return Redirect::to("/messages").into_response(),
     };
-    let amount: f64 = form.amount.replace('$', "").replace(',', "").parse().unwrap_or(0.0);
-    if amount > 0.0 {
-        db::create_offer(&db, &convo.listing_id, &convo_id, &user.id, amount);
-        let msg = format!("💰 Offer: ${:.2}", amount);
-        db::send_message(&db, &convo_id, &user.id, &msg);
+    if !user.is_verified() {
+        return Redirect::to(&format!("/messages/{}?error=verify_email", convo_id)).into_response();
+    }
+    let currency = db::get_listing_currency(&db, &convo.listing_id).unwrap_or_default();
+    let amount = match Money::parse(&form.amount, currency) {
+        Ok(a) if !a.is_zero() => a,
+        _ => return Redirect::to(&format!("/messages/{}?error=offer_amount", convo_id)).into_response(),
+    };
+    if ratelimit::hit(&db, &ratelimit::OFFERS_PER_USER, &user.id).is_err() {
+        return Redirect::to(&format!("/messages/{}?error=slow_down", convo_id)).into_response();
     }
+    let offer = match db::create_offer(&db, &convo.listing_id, &convo_id, &user.id, amount) {
+        Ok(id) => db::get_offer(&db, &id).unwrap(),
+        Err(_) => return Redirect::to(&format!("/messages/{}?error=unavailable", convo_id)).into_response(),
+    };
+    db::send_message(&db, &convo_id, &user.id, &new_offer_message(&offer));
     Redirect::to(&format!("/messages/{}", convo_id)).into_response()
 }
 
@@ -155,28 +201,96 @@ pub async fn respond_offer(
     State((db, _tera)): State<AppState>,
     jar: CookieJar,
     Path((convo_id, offer_id)): Path<(String, String)>,
-    Query(params): Query<std::collections::HashMap<String, String>>,
+    Form(form): Form<RespondOfferForm>,
 ) -> Response {
     let user = match auth::get_current_user(&db, &jar) {
         Some(u) => u,
         None => return Redirect::to("/login").into_response(),
     };
-    let accept = params.get("accept").map(|v| v == "true").unwrap_or(false);
-    if db::respond_to_offer(&db, &offer_id, &user.id, accept) {
-        let msg = if accept {
-            "✅ Offer accepted! Check below for payment details."
-        } else {
-            "❌ Offer declined."
-        };
-        db::send_message(&db, &convo_id, &user.id, msg);
+    let response = if form.accept { OfferResponse::Accept } else { OfferResponse::Reject };
+    answer_offer(&db, &convo_id, &offer_id, &user.id, response)
+}
+
+pub async fn counter_offer(
+    State((db, _tera)): State<AppState>,
+    jar: CookieJar,
+    Path((convo_id, offer_id)): Path<(String, String)>,
+    Form(form): Form<MakeOfferForm>,
+) -> Response {
+    let user = match auth::get_current_user(&db, &jar) {
+        Some(u) => u,
+        None => return Redirect::to("/login").into_response(),
+    };
+    if !user.is_verified() {
+        return Redirect::to(&format!("/messages/{}?error=verify_email", convo_id)).into_response();
+    }
+    // Counters are in the currency of the listing being negotiated
+    let currency = db::get_offer(&db, &offer_id)
+        .and_then(|o| db::get_listing_currency(&db, &o.listing_id))
+        .unwrap_or_default();
+    let amount = match Money::parse(&form.amount, currency) {
+        Ok(a) if !a.is_zero() => a,
+        _ => return Redirect::to(&format!("/messages/{}?error=offer_amount", convo_id)).into_response(),
+    };
+    if ratelimit::hit(&db, &ratelimit::OFFERS_PER_USER, &user.id).is_err() {
+        return Redirect::to(&format!("/messages/{}?error=slow_down", convo_id)).into_response();
+    }
+    answer_offer(&db, &convo_id, &offer_id, &user.id, OfferResponse::Counter(amount))
+}
+
+fn answer_offer(db: &Db, convo_id: &str, offer_id: &str, user_id: &str, response: OfferResponse) -> Response {
+    match db::get_offer(db, offer_id) {
+        Some(o) if o.conversation_id == convo_id => {}
+        _ => return Redirect::to("/messages").into_response(),
+    }
+    match db::respond_to_offer(db, offer_id, user_id, response) {
+        Ok(offer) => {
+            db::send_message(db, convo_id, user_id, &offer_answer_message(response, &offer));
+            Redirect::to(&format!("/messages/{}", convo_id)).into_response()
+        }
+        Err(OfferError::NotFound) => Redirect::to("/messages").into_response(),
+        Err(OfferError::Forbidden | OfferError::Conflict(_)) => {
+            Redirect::to(&format!("/messages/{}?error=offer_answered", convo_id)).into_response()
+        }
     }
-    Redirect::to(&format!("/messages/{}", convo_id)).into_response()
 }
 
-// HTMX polling endpoint — returns new messages as HTML fragments
+/// Chat line posted when someone answers an offer. Shared with the JSON API.
+/// A buyer's offer, announced as a counter when it answers the seller's.
+pub fn new_offer_message(offer: &Offer) -> String {
+    match offer.parent_id {
+        Some(_) => format!("↩️ Counter-offer: {}", offer.amount),
+        None => format!("💰 Offer: {}", offer.amount),
+    }
+}
+
+pub fn offer_answer_message(response: OfferResponse, offer: &Offer) -> String {
+    match response {
+        OfferResponse::Accept if offer.parent_id.is_some() => "✅ Counter-offer accepted! You can now check out.".to_string(),
+        OfferResponse::Accept => "✅ Offer accepted! You can now check out.".to_string(),
+        OfferResponse::Reject => "❌ Offer declined.".to_string(),
+        OfferResponse::Counter(amount) => format!("↩️ Counter-offer: {}", amount),
+    }
+}
+
+fn message_bubble(msg: &Message, user_id: &str) -> String {
+    let cls = if msg.sender_id == user_id { "message-bubble mine" } else { "message-bubble theirs" };
+    let seen = if msg.seen { " data-seen" } else { "" };
+    format!(
+        r##"<div class="{cls}" data-message-id="{id}" data-seq="{seq}"{seen}>
+                <div class="message-content">{content}</div>
+                <span class="message-time">{time}</span>
+            </div>"##,
+        cls = cls, id = msg.id, seq = msg.seq, seen = seen, content = tera::escape_html(&msg.content), time = time_ago(&msg.created_at),
+    )
+}
+
+// HTMX polling endpoint — returns new messages as HTML fragments. The conversation page
+// switches to `conversation_events` once that connects; this stays as the fallback.
 #[derive(serde::Deserialize)]
 pub struct PollQuery {
-    pub after: Option<String>,
+    /// `seq` of the last message the page has
+    pub after: Option<i64>,
 }
 
 pub async fn poll_messages(
@@ -189,41 +303,323 @@ pub async fn poll_messages(
         Some(u) => u,
         None => return Html(String::new()).into_response(),
     };
-    let convo = match db::get_conversation(&db, &id) {
-        Some(c) if c.buyer_id == user.id || c.seller_id == user.id => c,
+    match db::get_conversation(&db, &id) {
+        Some(c) if c.buyer_id == user.id || c.seller_id == user.id => {}
         _ => return Html(String::new()).into_response(),
-    };
+    }
 
-    let after_id = query.after.unwrap_or_default();
-    if after_id.is_empty() {
+    let Some(after_seq) = query.after else {
         return Html(String::new()).into_response();
-    }
+    };
 
-    let new_msgs = db::get_messages_after(&db, &id, &after_id);
+    let new_msgs = db::get_messages_after(&db, &id, after_seq);
     if new_msgs.is_empty() {
         return Html(String::new()).into_response();
     }
 
-    db::mark_conversation_read(&db, &user.id, &id);
+    db::mark_messages_read(&db, &user.id, &new_msgs);
 
     let mut html = String::new();
-    let last_id = new_msgs.last().map(|m| m.id.clone()).unwrap_or(after_id);
+    let last_seq = new_msgs.last().map(|m| m.seq).unwrap_or(after_seq);
     for msg in &new_msgs {
-        let is_mine = msg.sender_id == user.id;
-        let cls = if is_mine { "message-bubble mine" } else { "message-bubble theirs" };
-        let time = time_ago(&msg.created_at);
-        html.push_str(&format!(
-            r##"<div class="{cls}">
-                <div class="message-content">{content}</div>
-                <span class="message-time">{time}</span>
-            </div>"##,
-            cls = cls, content = tera::escape_html(&msg.content), time = time,
-        ));
+        html.push_str(&message_bubble(msg, &user.id));
     }
-    // Update the polling URL with new last_id via OOB swap
+    // Update the polling URL with the new last seq via OOB swap
     html.push_str(&format!(
-        r##"<div id="message-poller" hx-get="/messages/{convo_id}/poll?after={last_id}" hx-trigger="every 2s" hx-target="#new-messages" hx-swap="beforeend" hx-swap-oob="true"></div>"##,
-        convo_id = id, last_id = last_id,
+        r##"<div id="message-poller" hx-get="/messages/{convo_id}/poll?after={last_seq}" hx-trigger="every 2s" hx-target="#new-messages" hx-swap="beforeend" hx-swap-oob="true"></div>"##,
+        convo_id = id, last_seq = last_seq,
     ));
     Html(html).into_response()
 }
+
+#[derive(serde::Deserialize)]
+pub struct OlderQuery {
+    /// `seq` of the oldest message the page has
+    pub before: i64,
+}
+
+// "Load older messages" — replaces the button with the previous page of messages, topped by
+// a new button if there are more
+pub async fn older_messages(
+    State((db, _tera)): State<AppState>,
+    jar: CookieJar,
+    Path(id): Path<String>,
+    Query(query): Query<OlderQuery>,
+) -> Response {
+    let user = match auth::get_current_user(&db, &jar) {
+        Some(u) => u,
+        None => return Html(String::new()).into_response(),
+    };
+    match db::get_conversation(&db, &id) {
+        Some(c) if c.buyer_id == user.id || c.seller_id == user.id => {}
+        _ => return Html(String::new()).into_response(),
+    }
+
+    let older = db::get_messages_before(&db, &id, Some(query.before));
+    let mut html = String::new();
+    if let Some(first) = older.first().filter(|m| m.seq > 1) {
+        html.push_str(&format!(
+            r##"<button type="button" class="btn btn-secondary btn-sm load-older" hx-get="/messages/{convo_id}/older?before={seq}" hx-target="this" hx-swap="outerHTML">Load older messages</button>"##,
+            convo_id = id, seq = first.seq,
+        ));
+    }
+    for msg in &older {
+        html.push_str(&message_bubble(msg, &user.id));
+    }
+    Html(html).into_response()
+}
+
+/// Where a live connection is in the conversation, so the database backlog and the live feed
+/// can be merged without duplicates or gaps.
+struct MessageFeed {
+    conversation_id: String,
+    /// `seq` of the last message sent, if any
+    last_seq: Option<i64>,
+}
+
+impl MessageFeed {
+    /// `after` is the `seq` of the last message the client already has, if any.
+    fn new(conversation_id: &str, after: Option<i64>) -> Self {
+        MessageFeed { conversation_id: conversation_id.to_string(), last_seq: after }
+    }
+
+    /// Messages after the last one sent, read from the database. A client that has nothing
+    /// yet starts with the latest page.
+    fn backlog(&self, db: &Db) -> Vec<Message> {
+        match self.last_seq {
+            Some(seq) => db::get_messages_after(db, &self.conversation_id, seq),
+            None => db::get_messages_before(db, &self.conversation_id, None),
+        }
+    }
+
+    /// A message from the live feed, plus any it skipped past that haven't been sent.
+    fn live(&self, db: &Db, message: Message) -> Vec<Message> {
+        match self.last_seq {
+            Some(seq) if message.seq > seq + 1 => self.backlog(db),
+            _ => vec![message],
+        }
+    }
+
+    /// Drops messages already sent and records the rest as sent.
+    fn unsent(&mut self, messages: Vec<Message>) -> Vec<Message> {
+        let last_seq = self.last_seq;
+        let fresh: Vec<Message> = messages.into_iter().filter(|m| last_seq.is_none_or(|seq| m.seq > seq)).collect();
+        if let Some(last) = fresh.last() {
+            self.last_seq = Some(last.seq);
+        }
+        fresh
+    }
+}
+
+/// Live messages for the conversation page over SSE. Starts with anything after the `seq` in
+/// `?after=` (or the browser's `Last-Event-ID` when it reconnects), then sends each message as
+/// it's posted. Event ids are message `seq`s.
+/// Receive-only; `conversation_socket` adds typing and presence.
+pub async fn conversation_events(
+    State((db, _tera)): State<AppState>,
+    jar: CookieJar,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Query(query): Query<PollQuery>,
+) -> Response {
+    let user = match auth::get_current_user(&db, &jar) {
+        Some(u) => u,
+        None => return StatusCode::UNAUTHORIZED.into_response(),
+    };
+    match db::get_conversation(&db, &id) {
+        Some(c) if c.buyer_id == user.id || c.seller_id == user.id => {}
+        _ => return StatusCode::NOT_FOUND.into_response(),
+    }
+    // Subscribe before reading the backlog so nothing posted in between is missed
+    let mut rx = events::hub().subscribe_conversation(&id);
+    let after = headers.get("last-event-id").and_then(|v| v.to_str().ok()).and_then(|v| v.parse().ok())
+        .or(query.after);
+    let mut feed = MessageFeed::new(&id, after);
+
+    let stream = async_stream::stream! {
+        // Counts as having the conversation open until the stream is dropped
+        let _presence = events::hub().join(&id, &user.id);
+        let mut pending = feed.backlog(&db);
+        loop {
+            let fresh = feed.unsent(pending);
+            db::mark_messages_read(&db, &user.id, &fresh);
+            for msg in &fresh {
+                yield Ok::<_, Infallible>(Event::default().id(msg.seq.to_string()).data(message_bubble(msg, &user.id)));
+            }
+            pending = match rx.recv().await {
+                Ok(ConversationEvent::Message(msg)) => feed.live(&db, msg),
+                // The other side read some of ours: a JSON array of message ids
+                Ok(ConversationEvent::Seen { user_id, message_ids }) if user_id != user.id => {
+                    yield Ok(Event::default().event("seen").data(serde_json::to_string(&message_ids).unwrap()));
+                    Vec::new()
+                }
+                Ok(_) => Vec::new(),
+                // Fell behind the channel: re-read what we missed
+                Err(RecvError::Lagged(_)) => feed.backlog(&db),
+                Err(RecvError::Closed) => break,
+            };
+        }
+    };
+    Sse::new(stream).keep_alive(KeepAlive::default()).into_response()
+}
+
+// === WebSocket chat ===
+//
+// Frames are JSON objects tagged by `type`. The client sends `{"type": "message", "content"}`
+// and `{"type": "typing", "typing": bool}`. The server sends `message` (the `Message` plus
+// its rendered `html`), `typing`, `presence` and `seen` (message ids they've read) for the
+// other participant, and `error`.
+
+/// Largest frame a client may send; chat messages are short.
+const MAX_SOCKET_FRAME_BYTES: usize = 16 * 1024;
+
+#[derive(serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientFrame {
+    Message { content: String },
+    Typing { typing: bool },
+}
+
+#[derive(serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerFrame {
+    Message { message: Message, html: String },
+    Typing { user_id: String, typing: bool },
+    Presence { user_id: String, online: bool },
+    Seen { user_id: String, message_ids: Vec<String> },
+    Error { message: String },
+}
+
+/// Two-way chat for the conversation page, authenticated by the session cookie. Takes
+/// `?after=` like `conversation_events`. Messages sent here go through `db::send_message`,
+/// the same as the form. Handshakes from other sites' pages are refused, since the cookie
+/// would otherwise let them chat as the visitor.
+pub async fn conversation_socket(
+    State((db, _tera)): State<AppState>,
+    jar: CookieJar,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Query(query): Query<PollQuery>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    if !csrf::same_origin(&headers) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let user = match auth::get_current_user(&db, &jar) {
+        Some(u) => u,
+        None => return StatusCode::UNAUTHORIZED.into_response(),
+    };
+    match db::get_conversation(&db, &id) {
+        Some(c) if c.buyer_id == user.id || c.seller_id == user.id => {}
+        _ => return StatusCode::NOT_FOUND.into_response(),
+    }
+    let after = query.after;
+    ws.max_message_size(MAX_SOCKET_FRAME_BYTES)
+        .on_upgrade(move |socket| chat_socket(socket, db, user.id, id, after))
+}
+
+async fn chat_socket(mut socket: WebSocket, db: Db, user_id: String, convo_id: String, after: Option<i64>) {
+    let hub = events::hub();
+    let mut rx = hub.subscribe_conversation(&convo_id);
+    let _presence = hub.join(&convo_id, &user_id);
+    let mut feed = MessageFeed::new(&convo_id, after);
+    let mut pending = feed.backlog(&db);
+    let mut frames: Vec<ServerFrame> = hub.present(&convo_id).into_iter()
+        .filter(|u| *u != user_id)
+        .map(|u| ServerFrame::Presence { user_id: u, online: true })
+        .collect();
+    let mut typing = false;
+
+    'conn: loop {
+        let fresh = feed.unsent(std::mem::take(&mut pending));
+        db::mark_messages_read(&db, &user_id, &fresh);
+        for message in fresh {
+            let html = message_bubble(&message, &user_id);
+            frames.push(ServerFrame::Message { message, html });
+        }
+        for frame in frames.drain(..) {
+            let text = serde_json::to_string(&frame).unwrap();
+            if socket.send(WsMessage::text(text)).await.is_err() {
+                break 'conn;
+            }
+        }
+
+        tokio::select! {
+            incoming = socket.recv() => match incoming {
+                Some(Ok(WsMessage::Text(text))) => match serde_json::from_str::<ClientFrame>(text.as_str()) {
+                    Ok(ClientFrame::Message { content }) => {
+                        let content = content.trim();
+                        if content.is_empty() {
+                            frames.push(ServerFrame::Error { message: "Message can't be empty.".to_string() });
+                            continue;
+                        }
+                        if let Err(wait) = ratelimit::hit(&db, &ratelimit::MESSAGES_PER_USER, &user_id) {
+                            let message = format!("You're sending messages too quickly. {}", ratelimit::try_again_in(wait));
+                            frames.push(ServerFrame::Error { message });
+                            continue;
+                        }
+                        // Comes back to us through the hub like everyone else's
+                        db::send_message(&db, &convo_id, &user_id, content);
+                        if typing {
+                            typing = false;
+                            hub.publish_typing(&convo_id, &user_id, false);
+                        }
+                    }
+                    // Relayed every time: clients repeat `true` while typing continues
+                    Ok(ClientFrame::Typing { typing: now }) => {
+                        typing = now;
+                        hub.publish_typing(&convo_id, &user_id, now);
+                    }
+                    Err(_) => frames.push(ServerFrame::Error { message: "Unrecognized frame.".to_string() }),
+                },
+                Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => break,
+                // Pings are answered by axum; binary frames aren't part of the protocol
+                Some(Ok(_)) => {}
+            },
+            event = rx.recv() => match event {
+                Ok(ConversationEvent::Message(message)) => pending = feed.live(&db, message),
+                Ok(ConversationEvent::Typing { user_id: other, typing }) if other != user_id => {
+                    frames.push(ServerFrame::Typing { user_id: other, typing });
+                }
+                Ok(ConversationEvent::Presence { user_id: other, online }) if other != user_id => {
+                    frames.push(ServerFrame::Presence { user_id: other, online });
+                }
+                Ok(ConversationEvent::Seen { user_id: other, message_ids }) if other != user_id => {
+                    frames.push(ServerFrame::Seen { user_id: other, message_ids });
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(_)) => pending = feed.backlog(&db),
+                Err(RecvError::Closed) => break,
+            },
+        }
+    }
+    if typing {
+        hub.publish_typing(&convo_id, &user_id, false);
+    }
+}
+
+/// The nav badge's unread count over SSE: sent on connect, then again whenever it changes.
+pub async fn unread_events(
+    State((db, _tera)): State<AppState>,
+    jar: CookieJar,
+) -> Response {
+    let user = match auth::get_current_user(&db, &jar) {
+        Some(u) => u,
+        None => return StatusCode::UNAUTHORIZED.into_response(),
+    };
+    let mut rx = events::hub().subscribe_user(&user.id);
+
+    let stream = async_stream::stream! {
+        let mut count = db::get_unread_count(&db, &user.id);
+        yield Ok::<_, Infallible>(Event::default().event("unread").data(count.to_string()));
+        // A lag only means we skipped some events; recounting covers them
+        while let Ok(_) | Err(RecvError::Lagged(_)) = rx.recv().await {
+            let current = db::get_unread_count(&db, &user.id);
+            if current != count {
+                count = current;
+                yield Ok(Event::default().event("unread").data(count.to_string()));
+            }
+        }
+    };
+    Sse::new(stream).keep_alive(KeepAlive::default()).into_response()
+}
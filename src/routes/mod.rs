@@ -1,3 +1,7 @@
 pub mod listings;
 pub mod messages;
 pub mod auth;
+pub mod cart;
+pub mod orders;
+pub mod payments;
+pub mod api;
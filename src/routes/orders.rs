@@ -0,0 +1,163 @@
+use axum::extract::{Path, Query, State};
+use axum::response::{Html, Redirect, IntoResponse, Response};
+use axum::Form;
+use axum_extra::extract::CookieJar;
+use crate::db::{self, Db};
+use crate::auth;
+use crate::models::{IntentStatus, OrderError, OrderStatus, OrderStatusForm, PaymentIntent};
+use crate::payments::{self, Confirmer};
+use tera::Tera;
+use std::sync::Arc;
+
+type AppState = (Db, Arc<Tera>);
+
+#[derive(serde::Deserialize)]
+pub struct OrderQuery {
+    pub error: Option<String>,
+}
+
+// Errors are passed back to the order page as short codes in the query string
+fn order_error(code: &str) -> &'static str {
+    match code {
+        "status" => "That change isn't possible for this order any more.",
+        "payment" => "That payment method isn't available. Please choose another.",
+        "card_invalid" => "That card number isn't valid.",
+        "card_declined" => "Your card was declined. Try another card.",
+        "refund" => "The payment couldn't be refunded, so the order was not cancelled.",
+        _ => "Something went wrong. Please try again.",
+    }
+}
+
+pub async fn checkout_offer(
+    State((db, _tera)): State<AppState>,
+    jar: CookieJar,
+    Path(offer_id): Path<String>,
+) -> Response {
+    let user = match auth::get_current_user(&db, &jar) {
+        Some(u) => u,
+        None => return Redirect::to("/login").into_response(),
+    };
+    let offer = match db::get_offer(&db, &offer_id) {
+        Some(o) if o.buyer_id == user.id => o,
+        _ => return Redirect::to("/messages").into_response(),
+    };
+    let is_new = db::get_order_for_offer(&db, &offer_id).is_none();
+    match db::create_order_from_offer(&db, &offer_id, &user.id) {
+        Ok(order_id) => {
+            if is_new {
+                let msg = format!("🧾 Order placed for {}.", offer.amount);
+                db::send_message(&db, &offer.conversation_id, &user.id, &msg);
+            }
+            Redirect::to(&format!("/orders/{}", order_id)).into_response()
+        }
+        Err(_) => Redirect::to(&format!("/messages/{}?error=checkout", offer.conversation_id)).into_response(),
+    }
+}
+
+pub async fn checkout_cart(
+    State((db, _tera)): State<AppState>,
+    jar: CookieJar,
+) -> Response {
+    let user = match auth::get_current_user(&db, &jar) {
+        Some(u) => u,
+        None => return Redirect::to("/login").into_response(),
+    };
+    match db::create_orders_from_cart(&db, &user.id) {
+        Ok(ids) if ids.len() == 1 => Redirect::to(&format!("/orders/{}", ids[0])).into_response(),
+        Ok(_) => Redirect::to("/orders").into_response(),
+        Err(_) => Redirect::to("/cart?error=checkout").into_response(),
+    }
+}
+
+pub async fn orders_page(
+    State((db, tera)): State<AppState>,
+    jar: CookieJar,
+) -> Response {
+    let user = match auth::get_current_user(&db, &jar) {
+        Some(u) => u,
+        None => return Redirect::to("/login").into_response(),
+    };
+    let purchases = db::get_user_orders(&db, &user.id, false);
+    let sales = db::get_user_orders(&db, &user.id, true);
+    let unread = db::get_unread_count(&db, &user.id);
+
+    let mut ctx = tera::Context::new();
+    ctx.insert("user", &Some(&user));
+    ctx.insert("unread_count", &unread);
+    ctx.insert("purchases", &purchases);
+    ctx.insert("sales", &sales);
+    Html(tera.render("orders.html", &ctx).unwrap()).into_response()
+}
+
+pub async fn order_detail(
+    State((db, tera)): State<AppState>,
+    jar: CookieJar,
+    Path(id): Path<String>,
+    Query(query): Query<OrderQuery>,
+) -> Response {
+    let user = match auth::get_current_user(&db, &jar) {
+        Some(u) => u,
+        None => return Redirect::to("/login").into_response(),
+    };
+    let order = match db::get_order(&db, &id) {
+        Some(o) if o.buyer_id == user.id || o.seller_id == user.id => o,
+        _ => return Redirect::to("/orders").into_response(),
+    };
+    let is_seller = order.seller_id == user.id;
+    let unread = db::get_unread_count(&db, &user.id);
+    let intents = db::get_payment_intents(&db, &order.id);
+    // The payment attempt waiting to be confirmed, if the buyer has picked a method
+    let open_intent = intents.iter().find(|i| i.status == IntentStatus::RequiresAction);
+    let payment_options: Vec<serde_json::Value> = payments::providers().iter()
+        .map(|p| serde_json::json!({
+            "name": p.name(),
+            "label": p.label(),
+            "buyer_confirms": p.confirmer() == Confirmer::Buyer,
+        }))
+        .collect();
+
+    let mut ctx = tera::Context::new();
+    ctx.insert("user", &Some(&user));
+    ctx.insert("unread_count", &unread);
+    ctx.insert("order", &order);
+    ctx.insert("is_seller", &is_seller);
+    ctx.insert("next_steps", order.status.next_steps(is_seller));
+    ctx.insert("intents", &intents);
+    ctx.insert("open_intent", &open_intent);
+    ctx.insert("payment_options", &payment_options);
+    ctx.insert("error", &query.error.as_deref().map(order_error).unwrap_or(""));
+    Html(tera.render("order_detail.html", &ctx).unwrap()).into_response()
+}
+
+pub async fn update_status(
+    State((db, _tera)): State<AppState>,
+    jar: CookieJar,
+    Path(id): Path<String>,
+    Form(form): Form<OrderStatusForm>,
+) -> Response {
+    let user = match auth::get_current_user(&db, &jar) {
+        Some(u) => u,
+        None => return Redirect::to("/login").into_response(),
+    };
+    let next = match OrderStatus::parse(&form.status) {
+        Some(s) => s,
+        None => return Redirect::to(&format!("/orders/{}?error=status", id)).into_response(),
+    };
+
+    // Cancelling a paid order gives the money back through the provider it was paid with
+    let result = if next == OrderStatus::Cancelled {
+        let refund = |intent: &PaymentIntent| {
+            payments::provider(&intent.provider)
+                .ok_or_else(|| "Unknown provider".to_string())
+                .and_then(|p| p.refund(intent))
+        };
+        db::cancel_order(&db, &id, &user.id, &refund)
+    } else {
+        db::update_order_status(&db, &id, &user.id, next)
+    };
+    match result {
+        Ok(()) => Redirect::to(&format!("/orders/{}", id)).into_response(),
+        Err(OrderError::RefundFailed(_)) => Redirect::to(&format!("/orders/{}?error=refund", id)).into_response(),
+        Err(_) => Redirect::to(&format!("/orders/{}?error=status", id)).into_response(),
+    }
+}
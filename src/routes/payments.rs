@@ -0,0 +1,154 @@
+use axum::body::Bytes;
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{Redirect, IntoResponse, Response};
+use axum::Form;
+use axum_extra::extract::CookieJar;
+use crate::db::{self, Db};
+use crate::auth;
+use crate::models::{IntentStatus, OrderStatus, PayForm, PaymentIntent, PaymentRecord};
+use crate::payments::{self, Confirmer, ManualProvider, PaymentProvider};
+use tera::Tera;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+type AppState = (Db, Arc<Tera>);
+
+// Records a provider's verdict, and gives back money that arrived for an order no longer
+// waiting for it, e.g. a card payment that went through after the order was cancelled
+fn settle(db: &Db, intent: &PaymentIntent, status: IntentStatus) -> PaymentRecord {
+    let record = db::record_payment(db, &intent.id, status);
+    if record == PaymentRecord::Unwanted {
+        let refunded = db::get_intent_by_ref(db, &intent.provider, &intent.provider_ref)
+            .ok_or_else(|| "Intent disappeared".to_string())
+            .and_then(|i| {
+                let provider = payments::provider(&i.provider).ok_or_else(|| "Unknown provider".to_string())?;
+                provider.refund(&i)
+            });
+        match refunded {
+            Ok(status) => {
+                db::record_payment(db, &intent.id, status);
+                println!("💳 Refunded {}: its order wasn't waiting for payment", intent.id);
+            }
+            Err(e) => eprintln!("❌ Couldn't refund {} for an order that wasn't waiting for payment: {}", intent.id, e),
+        }
+    }
+    record
+}
+
+// Buyer picks a payment method; reuses an open intent for the same provider if there is one
+pub async fn start_payment(
+    State((db, _tera)): State<AppState>,
+    jar: CookieJar,
+    Path(id): Path<String>,
+    Form(form): Form<PayForm>,
+) -> Response {
+    let user = match auth::get_current_user(&db, &jar) {
+        Some(u) => u,
+        None => return Redirect::to("/login").into_response(),
+    };
+    let order = match db::get_order(&db, &id) {
+        Some(o) if o.buyer_id == user.id => o,
+        _ => return Redirect::to("/orders").into_response(),
+    };
+    if order.status != OrderStatus::PendingPayment {
+        return Redirect::to(&format!("/orders/{}", id)).into_response();
+    }
+    let provider = match payments::provider(&form.provider) {
+        Some(p) => p,
+        None => return Redirect::to(&format!("/orders/{}?error=payment", id)).into_response(),
+    };
+    let has_open = db::get_payment_intents(&db, &id).iter()
+        .any(|i| i.provider == provider.name() && i.status == IntentStatus::RequiresAction);
+    if !has_open {
+        match provider.create_intent(&order) {
+            Ok(new) => {
+                db::create_payment_intent(&db, &order, provider.name(), &new.provider_ref, &new.instructions);
+            }
+            Err(_) => return Redirect::to(&format!("/orders/{}?error=payment", id)).into_response(),
+        }
+    }
+    Redirect::to(&format!("/orders/{}", id)).into_response()
+}
+
+// Buyers confirm with payment details (card providers); sellers confirm money they received
+// off-platform, which records a manual payment if the buyer never picked a method
+pub async fn confirm_payment(
+    State((db, _tera)): State<AppState>,
+    jar: CookieJar,
+    Path(id): Path<String>,
+    Form(fields): Form<HashMap<String, String>>,
+) -> Response {
+    let user = match auth::get_current_user(&db, &jar) {
+        Some(u) => u,
+        None => return Redirect::to("/login").into_response(),
+    };
+    let order = match db::get_order(&db, &id) {
+        Some(o) if o.buyer_id == user.id || o.seller_id == user.id => o,
+        _ => return Redirect::to("/orders").into_response(),
+    };
+    if order.status != OrderStatus::PendingPayment {
+        return Redirect::to(&format!("/orders/{}", id)).into_response();
+    }
+    let confirmer = if order.seller_id == user.id { Confirmer::Seller } else { Confirmer::Buyer };
+
+    let open = db::get_payment_intents(&db, &id).into_iter().find(|i| {
+        i.status == IntentStatus::RequiresAction
+            && payments::provider(&i.provider).map(|p| p.confirmer() == confirmer).unwrap_or(false)
+    });
+    let intent = match (open, confirmer) {
+        (Some(i), _) => i,
+        (None, Confirmer::Seller) => {
+            let new = match ManualProvider.create_intent(&order) {
+                Ok(n) => n,
+                Err(_) => return Redirect::to(&format!("/orders/{}?error=payment", id)).into_response(),
+            };
+            db::create_payment_intent(&db, &order, ManualProvider.name(), &new.provider_ref, &new.instructions)
+        }
+        (None, Confirmer::Buyer) => return Redirect::to(&format!("/orders/{}?error=payment", id)).into_response(),
+    };
+    let provider = match payments::provider(&intent.provider) {
+        Some(p) => p,
+        None => return Redirect::to(&format!("/orders/{}?error=payment", id)).into_response(),
+    };
+
+    match provider.confirm(&intent, &fields) {
+        Ok(IntentStatus::Failed) => {
+            settle(&db, &intent, IntentStatus::Failed);
+            Redirect::to(&format!("/orders/{}?error=card_declined", id)).into_response()
+        }
+        Ok(status) => {
+            settle(&db, &intent, status);
+            Redirect::to(&format!("/orders/{}", id)).into_response()
+        }
+        Err(_) => Redirect::to(&format!("/orders/{}?error=card_invalid", id)).into_response(),
+    }
+}
+
+// Provider callbacks. Unsigned or unknown events are rejected so the provider retries or alerts.
+// A verified event that no longer applies (a replay, or news older than what we know) is
+// acknowledged without changing anything, so the provider stops resending it.
+pub async fn webhook(
+    State((db, _tera)): State<AppState>,
+    Path(provider_name): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> StatusCode {
+    let provider = match payments::provider(&provider_name) {
+        Some(p) => p,
+        None => return StatusCode::NOT_FOUND,
+    };
+    let event = match provider.verify_webhook(&headers, &body) {
+        Ok(e) => e,
+        Err(_) => return StatusCode::BAD_REQUEST,
+    };
+    match db::get_intent_by_ref(&db, provider.name(), &event.provider_ref) {
+        Some(intent) => {
+            if settle(&db, &intent, event.status) == PaymentRecord::Stale {
+                println!("💳 Ignored {} webhook for {}: intent is already {}", event.status.as_str(), intent.id, intent.status.as_str());
+            }
+            StatusCode::OK
+        }
+        None => StatusCode::NOT_FOUND,
+    }
+}
@@ -0,0 +1,32 @@
+use std::time::Duration;
+use crate::db::{self, Db};
+
+// === Background sweeper ===
+//
+// Periodic housekeeping that would otherwise need a cron job: expiring offers, pruning
+// rate limit counters and deleting expired sessions and spent password reset links. Runs
+// once at startup and then every `FORGE_SWEEP_INTERVAL_SECS` seconds (default 60).
+
+pub fn spawn(db: Db) -> tokio::task::JoinHandle<()> {
+    let secs = std::env::var("FORGE_SWEEP_INTERVAL_SECS")
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .filter(|s| *s > 0)
+        .unwrap_or(60);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(secs));
+        loop {
+            interval.tick().await;
+            let db = db.clone();
+            // SQLite calls block; keep them off the async workers
+            let _ = tokio::task::spawn_blocking(move || sweep(&db)).await;
+        }
+    })
+}
+
+pub fn sweep(db: &Db) {
+    db::expire_offers(db);
+    crate::ratelimit::prune(db);
+    db::purge_expired_sessions(db);
+    db::purge_password_resets(db);
+}
@@ -0,0 +1,70 @@
+//! Personal API tokens made on the profile page: each is limited to its scopes and stops
+//! working once revoked.
+
+mod common;
+
+use axum::body::Body;
+use axum::http::{header, Request, StatusCode};
+use axum::Router;
+use common::{sign_up, Browser, Page};
+use forge_commerce::db;
+
+/// Creates a token with `scopes` as the signed-in user and returns it as revealed on the page.
+async fn create_token(browser: &mut Browser, name: &str, scopes: &[&str]) -> String {
+    let mut fields = vec![("name", name)];
+    fields.extend(scopes.iter().map(|s| (*s, "on")));
+    let page = browser.post("/profile/tokens", "/profile", &fields).await;
+    let start = page.body.find(forge_commerce::auth::API_TOKEN_PREFIX).expect("the new token is shown");
+    page.body[start..].split('<').next().unwrap().to_string()
+}
+
+/// Calls the API with only the bearer token, no cookies.
+async fn api(router: &Router, method: &str, path: &str, token: &str, body: Option<serde_json::Value>) -> (StatusCode, serde_json::Value) {
+    let request = Request::builder().method(method).uri(format!("/api/v1{}", path))
+        .header(header::AUTHORIZATION, format!("Bearer {}", token))
+        .header(header::CONTENT_TYPE, "application/json");
+    let body = body.map(|b| Body::from(b.to_string())).unwrap_or_else(Body::empty);
+    let Page { status, body, .. } = Browser::new(router).send(request, body).await;
+    (status, serde_json::from_str(&body).unwrap_or_default())
+}
+
+#[tokio::test]
+async fn a_token_can_only_do_what_its_scopes_allow() {
+    let (router, db) = common::app("api-tokens");
+    let alice = sign_up(&db, "Alice", "alice@example.com");
+    let mut browser = Browser::new(&router);
+    browser.log_in("alice@example.com", "password123").await;
+    let token = create_token(&mut browser, "Reader", &["profile:read"]).await;
+
+    let (status, body) = api(&router, "GET", "/me", &token, None).await;
+    assert_eq!((status, body["email"].as_str()), (StatusCode::OK, Some("alice@example.com")));
+
+    let (status, body) = api(&router, "PATCH", "/me", &token, Some(serde_json::json!({ "location": "Paris" }))).await;
+    assert_eq!((status, body["error"]["code"].as_str()), (StatusCode::FORBIDDEN, Some("insufficient_scope")));
+    assert_eq!(body["error"]["message"], "This token needs the profile:write scope.");
+    assert_ne!(db::get_user_by_id(&db, &alice).unwrap().location, "Paris");
+}
+
+#[tokio::test]
+async fn a_revoked_token_is_refused() {
+    let (router, db) = common::app("api-tokens");
+    let alice = sign_up(&db, "Alice", "alice@example.com");
+    sign_up(&db, "Mallory", "mallory@example.com");
+    let mut browser = Browser::new(&router);
+    browser.log_in("alice@example.com", "password123").await;
+    let token = create_token(&mut browser, "Script", &["profile:read"]).await;
+    let id = db::get_api_tokens(&db, &alice)[0].id.clone();
+    let revoke = format!("/profile/tokens/{}/revoke", id);
+
+    // Nobody else can revoke it
+    let mut mallory = Browser::new(&router);
+    mallory.log_in("mallory@example.com", "password123").await;
+    mallory.post(&revoke, "/profile", &[]).await;
+    assert_eq!(api(&router, "GET", "/me", &token, None).await.0, StatusCode::OK);
+
+    let revoked = browser.post(&revoke, "/profile", &[]).await;
+    assert_eq!(revoked.location.as_deref(), Some("/profile#api-tokens"));
+    assert!(db::get_api_tokens(&db, &alice).is_empty());
+    let (status, body) = api(&router, "GET", "/me", &token, None).await;
+    assert_eq!((status, body["error"]["code"].as_str()), (StatusCode::UNAUTHORIZED, Some("unauthorized")));
+}
@@ -0,0 +1,110 @@
+//! The cart from the browser's side: a guest cart kept by the `forge_cart` cookie, claimed at
+//! login, and one of each item. Plus the one-currency rule, checked against the database.
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{list_item, sign_up, Browser};
+use forge_commerce::auth::CART_COOKIE;
+use forge_commerce::db;
+use forge_commerce::models::CartOwner;
+use forge_commerce::money::{Currency, Money};
+
+fn eur(cents: i64) -> Money {
+    Money::new(cents, "EUR".parse::<Currency>().unwrap())
+}
+
+#[tokio::test]
+async fn guest_cart_is_claimed_at_login() {
+    let (router, db) = common::app("cart");
+    let alice = sign_up(&db, "Alice", "alice@example.com");
+    let bob = sign_up(&db, "Bob", "bob@example.com");
+    let lamp = list_item(&db, &alice, "Desk lamp", Money::usd(4000));
+    let rug = list_item(&db, &alice, "Wool rug", Money::usd(12000));
+    let chair = list_item(&db, &bob, "Chair", Money::usd(9000));
+    db::add_to_cart(&db, &CartOwner::User(bob.clone()), &lamp).unwrap();
+
+    let mut guest = Browser::new(&router);
+    for listing in [&lamp, &rug, &chair] {
+        let added = guest.post(&format!("/cart/add/{}", listing), "/login", &[]).await;
+        assert_eq!((added.status, added.location.as_deref()), (StatusCode::SEE_OTHER, Some("/cart")));
+    }
+    let token = guest.cookies.get(CART_COOKIE).expect("a guest gets a cart cookie").clone();
+    let cart = db::get_cart(&db, &CartOwner::Guest(token.clone()));
+    assert_eq!((cart.item_count, cart.total), (3, Some(Money::usd(25000))));
+    assert!(guest.get("/cart").await.body.contains("Wool rug"));
+
+    // The lamp was already in Bob's cart, and the chair is his own listing
+    let login = guest.log_in("bob@example.com", "password123").await;
+    assert_eq!(login.location.as_deref(), Some("/cart?added=1&combined=1&dropped=1"));
+    assert!(!guest.cookies.contains_key(CART_COOKIE));
+    assert_eq!(db::get_cart_count(&db, &CartOwner::Guest(token)), 0);
+
+    let cart = db::get_cart(&db, &CartOwner::User(bob));
+    let items: Vec<&str> = cart.groups.iter().flat_map(|g| &g.items).map(|i| i.title.as_str()).collect();
+    assert_eq!(items, ["Desk lamp", "Wool rug"]);
+    assert_eq!((cart.item_count, cart.total), (2, Some(Money::usd(16000))));
+}
+
+#[tokio::test]
+async fn a_listing_is_in_the_cart_once() {
+    let (router, db) = common::app("cart");
+    let alice = sign_up(&db, "Alice", "alice@example.com");
+    let bob = sign_up(&db, "Bob", "bob@example.com");
+    let lamp = list_item(&db, &alice, "Desk lamp", Money::usd(4000));
+
+    let mut browser = Browser::new(&router);
+    browser.log_in("bob@example.com", "password123").await;
+    let owner = CartOwner::User(bob);
+    // The lamp is one lamp, however often it's added
+    for _ in 0..2 {
+        browser.post(&format!("/cart/add/{}", lamp), "/cart", &[]).await;
+        let cart = db::get_cart(&db, &owner);
+        assert_eq!((cart.item_count, cart.total), (1, Some(Money::usd(4000))));
+    }
+    let page = browser.get("/cart").await;
+    assert!(page.body.contains("Desk lamp") && !page.body.contains("name=\"quantity\""));
+    let quantity = browser.post(&format!("/cart/{}/quantity", lamp), "/cart", &[("quantity", "4")]).await;
+    assert_eq!(quantity.status, StatusCode::NOT_FOUND);
+
+    let removed = browser.post(&format!("/cart/{}/remove", lamp), "/cart", &[]).await;
+    assert_eq!(removed.status, StatusCode::SEE_OTHER);
+    assert!(!db::is_in_cart(&db, &owner, &lamp));
+    assert!(db::get_cart(&db, &owner).groups.is_empty());
+}
+
+#[test]
+fn a_cart_holds_one_currency() {
+    let (_router, db) = common::app("cart");
+    let alice = sign_up(&db, "Alice", "alice@example.com");
+    let bob = sign_up(&db, "Bob", "bob@example.com");
+    let lamp = list_item(&db, &alice, "Desk lamp", Money::usd(4000));
+    let rug = list_item(&db, &alice, "Wool rug", eur(12000));
+    let owner = CartOwner::User(bob.clone());
+
+    db::add_to_cart(&db, &owner, &lamp).unwrap();
+    let refused = db::add_to_cart(&db, &owner, &rug).unwrap_err();
+    assert!(refused.contains("priced in USD"), "{}", refused);
+    assert!(!db::is_in_cart(&db, &owner, &rug));
+
+    // A guest cart in another currency loses those items when it's merged
+    let guest = CartOwner::Guest("guest-token".to_string());
+    db::add_to_cart(&db, &guest, &rug).unwrap();
+    let merge = db::merge_guest_cart(&db, "guest-token", &bob);
+    assert_eq!((merge.added, merge.dropped), (0, 1));
+    assert!(!db::is_in_cart(&db, &owner, &rug));
+
+    // A listing repriced in another currency after it was added stays out of the total
+    // and out of checkout
+    let vase = list_item(&db, &alice, "Vase", Money::usd(1500));
+    db::add_to_cart(&db, &owner, &vase).unwrap();
+    db.lock().unwrap().execute("UPDATE listings SET currency = 'EUR' WHERE id = ?1", [&vase]).unwrap();
+    let cart = db::get_cart(&db, &owner);
+    assert_eq!((cart.item_count, cart.total), (1, Some(Money::usd(4000))));
+    assert_eq!(cart.groups[0].subtotal, Money::usd(4000));
+
+    let orders = db::create_orders_from_cart(&db, &bob).unwrap();
+    assert_eq!(orders.len(), 1);
+    assert_eq!(db::get_order(&db, &orders[0]).unwrap().total, Money::usd(4000));
+    assert!(db::is_in_cart(&db, &owner, &vase));
+}
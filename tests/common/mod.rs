@@ -0,0 +1,151 @@
+//! Helpers shared by the integration tests: a fresh app on a temporary database, and a
+//! browser that keeps cookies and fills in CSRF tokens.
+
+#![allow(dead_code)]
+
+use axum::body::Body;
+use axum::http::{header, Request, StatusCode};
+use axum::Router;
+use forge_commerce::db::{self, Db};
+use forge_commerce::models::ListingForm;
+use forge_commerce::money::Money;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tera::Tera;
+use tower::ServiceExt;
+
+/// The router on a fresh database named after `label`, with no users yet.
+pub fn app(label: &str) -> (Router, Db) {
+    let path = std::env::temp_dir().join(format!("forge-{}-{}.db", label, uuid::Uuid::new_v4()));
+    let db = db::init_db_with_path(path.to_str().unwrap());
+    let mut tera = Tera::new(concat!(env!("CARGO_MANIFEST_DIR"), "/templates/**/*.html")).unwrap();
+    forge_commerce::csrf::register(&mut tera);
+    (forge_commerce::build_router((db.clone(), Arc::new(tera))), db)
+}
+
+/// A user with the password `password123` and a confirmed email address. Returns their id.
+pub fn sign_up(db: &Db, name: &str, email: &str) -> String {
+    let hash = forge_commerce::auth::hash_password("password123");
+    let id = db::create_user(db, name, email, &hash).unwrap();
+    db::mark_email_verified(db, &id);
+    id
+}
+
+/// An active listing by `seller_id` at `price`. Returns its id.
+pub fn list_item(db: &Db, seller_id: &str, title: &str, price: Money) -> String {
+    let form = ListingForm {
+        title: title.to_string(), description: "Works well".to_string(), price: price.amount(),
+        category: "Home".to_string(), condition: "Good".to_string(), location: "Leeds".to_string(),
+    };
+    db::create_listing(db, seller_id, &form, price, &[])
+}
+
+/// One visitor: the router plus the cookies it has been given.
+pub struct Browser {
+    router: Router,
+    pub cookies: HashMap<String, String>,
+}
+
+pub struct Page {
+    pub status: StatusCode,
+    pub location: Option<String>,
+    pub body: String,
+}
+
+impl Browser {
+    pub fn new(router: &Router) -> Self {
+        Browser { router: router.clone(), cookies: HashMap::new() }
+    }
+
+    pub async fn get(&mut self, uri: &str) -> Page {
+        self.send(Request::get(uri), Body::empty()).await
+    }
+
+    /// Posts a urlencoded form, adding the CSRF token from the page at `token_page`.
+    pub async fn post(&mut self, uri: &str, token_page: &str, fields: &[(&str, &str)]) -> Page {
+        let token = self.csrf_token(token_page).await;
+        let mut form = form_urlencoded::Serializer::new(String::new());
+        form.append_pair("csrf_token", &token);
+        for (name, value) in fields {
+            form.append_pair(name, value);
+        }
+        let request = Request::post(uri).header(header::CONTENT_TYPE, "application/x-www-form-urlencoded");
+        self.send(request, Body::from(form.finish())).await
+    }
+
+    /// Posts `multipart/form-data` with the CSRF token from `token_page` as the first field,
+    /// the way the listing form sends it.
+    pub async fn post_multipart(&mut self, uri: &str, token_page: &str, fields: &[(&str, &str)], files: &[(&str, &str, &[u8])]) -> Page {
+        let token = self.csrf_token(token_page).await;
+        let mut all = vec![("csrf_token", token.as_str())];
+        all.extend_from_slice(fields);
+        let (content_type, body) = multipart(&all, files);
+        self.send(Request::post(uri).header(header::CONTENT_TYPE, content_type), Body::from(body)).await
+    }
+
+    /// The CSRF token in the first form on the page at `uri`.
+    pub async fn csrf_token(&mut self, uri: &str) -> String {
+        csrf_token(&self.get(uri).await.body)
+    }
+
+    /// The `Cookie` header this browser would send.
+    pub fn cookie_header(&self) -> String {
+        let cookies: Vec<String> = self.cookies.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        cookies.join("; ")
+    }
+
+    pub async fn send(&mut self, mut request: axum::http::request::Builder, body: Body) -> Page {
+        if !self.cookies.is_empty() {
+            request = request.header(header::COOKIE, self.cookie_header());
+        }
+        let response = self.router.clone().oneshot(request.body(body).unwrap()).await.unwrap();
+        for set in response.headers().get_all(header::SET_COOKIE) {
+            let pair = set.to_str().unwrap().split(';').next().unwrap();
+            let (name, value) = pair.split_once('=').unwrap();
+            if value.is_empty() {
+                self.cookies.remove(name);
+            } else {
+                self.cookies.insert(name.to_string(), value.to_string());
+            }
+        }
+        let status = response.status();
+        let location = response.headers().get(header::LOCATION).map(|l| l.to_str().unwrap().to_string());
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        Page { status, location, body: String::from_utf8(bytes.to_vec()).unwrap() }
+    }
+
+    pub async fn get_with_bearer(&mut self, uri: &str, token: &str) -> Page {
+        let request = Request::get(uri).header(header::AUTHORIZATION, format!("Bearer {}", token));
+        self.send(request, Body::empty()).await
+    }
+
+    pub async fn log_in(&mut self, email: &str, password: &str) -> Page {
+        self.post("/login", "/login", &[("email", email), ("password", password)]).await
+    }
+}
+
+pub fn csrf_token(html: &str) -> String {
+    let start = html.find(r#"name="csrf_token" value=""#).expect("page has a form") + r#"name="csrf_token" value=""#.len();
+    html[start..start + 64].to_string()
+}
+
+pub const MULTIPART_BOUNDARY: &str = "forge-test-boundary";
+
+/// A `multipart/form-data` body with `fields` in order, then `files` as (name, filename,
+/// bytes). Returns the content type and the body.
+pub fn multipart(fields: &[(&str, &str)], files: &[(&str, &str, &[u8])]) -> (String, Vec<u8>) {
+    let mut body = Vec::new();
+    for (name, value) in fields {
+        body.extend_from_slice(format!("--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n", MULTIPART_BOUNDARY, name, value).as_bytes());
+    }
+    for (name, filename, data) in files {
+        body.extend_from_slice(format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+            MULTIPART_BOUNDARY, name, filename,
+        ).as_bytes());
+        body.extend_from_slice(data);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", MULTIPART_BOUNDARY).as_bytes());
+    (format!("multipart/form-data; boundary={}", MULTIPART_BOUNDARY), body)
+}
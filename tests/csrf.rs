@@ -0,0 +1,99 @@
+//! CSRF protection as the router applies it: where a token is accepted from, what happens
+//! without one, and which paths don't need one.
+
+mod common;
+
+use axum::body::Body;
+use axum::http::{header, Request, StatusCode};
+use common::{list_item, multipart, sign_up, Browser, Page};
+use forge_commerce::db;
+use forge_commerce::money::Money;
+
+struct Visit {
+    browser: Browser,
+    db: db::Db,
+    bob_id: String,
+    lamp: String,
+    listing_page: String,
+}
+
+/// Bob signed in and looking at Alice's lamp.
+async fn buyer_on_listing(label: &str) -> Visit {
+    let (router, db) = common::app(label);
+    let alice = sign_up(&db, "Alice", "alice@example.com");
+    let bob = sign_up(&db, "Bob", "bob@example.com");
+    let lamp = list_item(&db, &alice, "Desk lamp", Money::usd(4000));
+    let mut browser = Browser::new(&router);
+    browser.log_in("bob@example.com", "password123").await;
+    let listing_page = format!("/listing/{}", lamp);
+    Visit { browser, db, bob_id: bob, lamp, listing_page }
+}
+
+fn refused(page: &Page) -> bool {
+    page.status == StatusCode::FORBIDDEN && page.body.contains("This form has expired")
+}
+
+async fn post_urlencoded(browser: &mut Browser, uri: &str, body: &str) -> Page {
+    let request = Request::post(uri).header(header::CONTENT_TYPE, "application/x-www-form-urlencoded");
+    browser.send(request, Body::from(body.to_string())).await
+}
+
+#[tokio::test]
+async fn contacting_a_seller_is_a_post_with_a_token() {
+    let Visit { browser: mut bob, db, bob_id, lamp, listing_page } = buyer_on_listing("csrf-contact").await;
+    let contact = format!("{}/contact", listing_page);
+
+    let page = bob.get(&listing_page).await;
+    assert!(page.body.contains(&format!(r#"<form method="post" action="{}">"#, contact)));
+    // A link or image pointing here from another site starts nothing
+    assert_eq!(bob.get(&contact).await.status, StatusCode::METHOD_NOT_ALLOWED);
+    assert!(refused(&post_urlencoded(&mut bob, &contact, "").await));
+    assert!(refused(&post_urlencoded(&mut bob, &contact, "csrf_token=guessed").await));
+    assert!(db::get_user_conversations(&db, &bob_id).is_empty());
+
+    let started = bob.post(&contact, &listing_page, &[]).await;
+    assert_eq!(started.status, StatusCode::SEE_OTHER);
+    assert!(started.location.unwrap().starts_with("/messages/"));
+    assert_eq!(db::get_user_conversations(&db, &bob_id)[0].listing_id, lamp);
+}
+
+#[tokio::test]
+async fn tokens_are_read_from_the_header_form_or_first_multipart_field() {
+    let Visit { browser: mut bob, listing_page, .. } = buyer_on_listing("csrf-sources").await;
+    let token = bob.csrf_token(&listing_page).await;
+    let contact = format!("{}/contact", listing_page);
+
+    let request = Request::post(&contact).header("x-csrf-token", &token);
+    assert_eq!(bob.send(request, Body::empty()).await.status, StatusCode::SEE_OTHER);
+
+    let body = format!("note=hi&csrf_token={}", token);
+    assert_eq!(post_urlencoded(&mut bob, &contact, &body).await.status, StatusCode::SEE_OTHER);
+
+    let (content_type, body) = multipart(&[("csrf_token", &token), ("note", "hi")], &[]);
+    let request = Request::post(&contact).header(header::CONTENT_TYPE, content_type);
+    assert_eq!(bob.send(request, Body::from(body)).await.status, StatusCode::SEE_OTHER);
+
+    // Only the first multipart field is looked at, so the rest can stream to the handler
+    let (content_type, body) = multipart(&[("note", "hi"), ("csrf_token", &token)], &[]);
+    let request = Request::post(&contact).header(header::CONTENT_TYPE, content_type);
+    assert!(refused(&bob.send(request, Body::from(body)).await));
+    let (content_type, body) = multipart(&[("note", "hi")], &[("csrf_token", "token.txt", token.as_bytes())]);
+    let request = Request::post(&contact).header(header::CONTENT_TYPE, content_type);
+    assert!(refused(&bob.send(request, Body::from(body)).await));
+}
+
+#[tokio::test]
+async fn the_api_and_payment_webhooks_need_no_token() {
+    let (router, _db) = common::app("csrf-exempt");
+    let mut client = Browser::new(&router);
+
+    let request = Request::post("/api/v1/auth/login").header(header::CONTENT_TYPE, "application/json");
+    let page = client.send(request, Body::from(r#"{"email": "nobody@example.com", "password": "wrong"}"#)).await;
+    assert_eq!(page.status, StatusCode::UNAUTHORIZED);
+
+    // Turned away for its signature, not for a missing token
+    let request = Request::post("/payments/webhook/mock_card").header(header::CONTENT_TYPE, "application/json");
+    let page = client.send(request, Body::from(r#"{"type": "payment_intent.succeeded", "provider_ref": "x"}"#)).await;
+    assert_eq!(page.status, StatusCode::BAD_REQUEST);
+    assert!(!client.cookies.contains_key("forge_csrf"));
+}
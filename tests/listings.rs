@@ -0,0 +1,43 @@
+//! Editing a listing through the multipart form.
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{list_item, sign_up, Browser};
+use forge_commerce::db;
+use forge_commerce::money::Money;
+
+fn png() -> Vec<u8> {
+    let mut out = std::io::Cursor::new(Vec::new());
+    image::RgbImage::from_pixel(64, 48, image::Rgb([200, 120, 40])).write_to(&mut out, image::ImageFormat::Png).unwrap();
+    out.into_inner()
+}
+
+fn media_objects(db: &db::Db) -> i64 {
+    db.lock().unwrap().query_row("SELECT COUNT(*) FROM media_objects", [], |r| r.get(0)).unwrap()
+}
+
+#[tokio::test]
+async fn only_the_seller_can_edit_and_nobody_elses_upload_is_stored() {
+    let (router, db) = common::app("listings");
+    let alice = sign_up(&db, "Alice", "alice@example.com");
+    sign_up(&db, "Mallory", "mallory@example.com");
+    let lamp = list_item(&db, &alice, "Desk lamp", Money::usd(4000));
+    let edit = format!("/listing/{}/edit", lamp);
+    let fields = [("title", "Mine now"), ("description", "x"), ("price", "1"), ("category", "Home"), ("condition", "Good"), ("location", "Leeds")];
+
+    let mut mallory = Browser::new(&router);
+    mallory.log_in("mallory@example.com", "password123").await;
+    let refused = mallory.post_multipart(&edit, "/profile", &fields, &[("image", "lamp.png", &png())]).await;
+    assert_eq!((refused.status, refused.location.as_deref()), (StatusCode::SEE_OTHER, Some("/")));
+    assert_eq!(db::get_listing(&db, &lamp).unwrap().title, "Desk lamp");
+    assert!(db::get_listing_images(&db, &lamp).is_empty());
+    assert_eq!(media_objects(&db), 0);
+
+    let mut seller = Browser::new(&router);
+    seller.log_in("alice@example.com", "password123").await;
+    let saved = seller.post_multipart(&edit, &edit, &[("title", "Brass desk lamp"), ("price", "45")], &[]).await;
+    assert_eq!(saved.location, Some(format!("/listing/{}", lamp)));
+    let listing = db::get_listing(&db, &lamp).unwrap();
+    assert_eq!((listing.title.as_str(), listing.price), ("Brass desk lamp", Money::usd(4500)));
+}
@@ -0,0 +1,173 @@
+//! Live delivery on the conversation page: each new message reaches an open SSE stream or
+//! WebSocket exactly once, and other sites' pages can't open the socket.
+
+mod common;
+
+use axum::body::{Body, BodyDataStream};
+use axum::http::{header, Request, StatusCode};
+use common::{list_item, sign_up, Browser};
+use forge_commerce::db;
+use forge_commerce::money::Money;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::{self, Message as WsMessage};
+use tower::ServiceExt;
+
+/// How long to wait for something that should arrive, and for something that shouldn't.
+const ARRIVES: Duration = Duration::from_secs(5);
+const QUIET: Duration = Duration::from_millis(300);
+
+/// Alice's lamp with Bob's opening message, both signed in. Returns their browsers and the
+/// conversation id.
+async fn conversation(label: &str) -> (axum::Router, Browser, Browser, String) {
+    let (router, db) = common::app(label);
+    let alice = sign_up(&db, "Alice", "alice@example.com");
+    let bob = sign_up(&db, "Bob", "bob@example.com");
+    let lamp = list_item(&db, &alice, "Desk lamp", Money::usd(4000));
+    let convo = db::get_or_create_conversation(&db, &lamp, &bob, &alice);
+    db::send_message(&db, &convo, &bob, "Is it still available?");
+    let mut seller = Browser::new(&router);
+    seller.log_in("alice@example.com", "password123").await;
+    let mut buyer = Browser::new(&router);
+    buyer.log_in("bob@example.com", "password123").await;
+    (router, seller, buyer, convo)
+}
+
+/// Reads server-sent events off a response body.
+struct EventStream {
+    body: BodyDataStream,
+    buffer: String,
+}
+
+impl EventStream {
+    /// The next event's (`event` type, `id`), waiting at most `wait`. Keep-alive comments
+    /// are skipped.
+    async fn next(&mut self, wait: Duration) -> Option<(String, String)> {
+        tokio::time::timeout(wait, async {
+            loop {
+                if let Some(end) = self.buffer.find("\n\n") {
+                    let block: String = self.buffer.drain(..end + 2).collect();
+                    let field = |name: &str| block.lines().find_map(|l| l.strip_prefix(name)).map(|v| v.trim().to_string());
+                    if field("data:").is_some() {
+                        return (field("event:").unwrap_or_else(|| "message".to_string()), field("id:").unwrap_or_default());
+                    }
+                    continue;
+                }
+                let chunk = self.body.next().await.expect("stream stays open").unwrap();
+                self.buffer.push_str(std::str::from_utf8(&chunk).unwrap());
+            }
+        }).await.ok()
+    }
+
+    /// Message event ids until the stream goes quiet.
+    async fn messages(&mut self) -> Vec<String> {
+        let mut ids = Vec::new();
+        let mut wait = ARRIVES;
+        while let Some((event, id)) = self.next(wait).await {
+            if event == "message" {
+                ids.push(id);
+            }
+            wait = QUIET;
+        }
+        ids
+    }
+}
+
+async fn subscribe(router: &axum::Router, browser: &Browser, uri: &str, last_event_id: Option<&str>) -> EventStream {
+    let mut request = Request::get(uri).header(header::COOKIE, browser.cookie_header());
+    if let Some(id) = last_event_id {
+        request = request.header("last-event-id", id);
+    }
+    let response = router.clone().oneshot(request.body(Body::empty()).unwrap()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    EventStream { body: response.into_body().into_data_stream(), buffer: String::new() }
+}
+
+#[tokio::test]
+async fn sse_delivers_each_message_once() {
+    let (router, mut seller, mut buyer, convo) = conversation("live-sse").await;
+    let page = format!("/messages/{}", convo);
+
+    // Both pages already show message 1
+    let mut seller_events = subscribe(&router, &seller, &format!("{}/events?after=1", page), None).await;
+    let mut buyer_events = subscribe(&router, &buyer, &format!("{}/events?after=1", page), None).await;
+    assert_eq!(seller_events.next(QUIET).await, None);
+
+    seller.post(&format!("{}/send", page), &page, &[("content", "Yes, still here.")]).await;
+    assert_eq!(seller_events.messages().await, ["2"]);
+    assert_eq!(buyer_events.messages().await, ["2"]);
+
+    buyer.post(&format!("{}/send", page), &page, &[("content", "Great, I'll take it.")]).await;
+    seller.post(&format!("{}/send", page), &page, &[("content", "See you Saturday.")]).await;
+    assert_eq!(seller_events.messages().await, ["3", "4"]);
+    assert_eq!(buyer_events.messages().await, ["3", "4"]);
+
+    // A browser reconnecting after message 3 gets only what it missed
+    let mut reconnected = subscribe(&router, &buyer, &format!("{}/events?after=1", page), Some("3")).await;
+    assert_eq!(reconnected.messages().await, ["4"]);
+}
+
+/// Serves the router on a random local port for clients that need a real connection.
+async fn serve(router: axum::Router) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+    addr
+}
+
+type Socket = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+async fn open_socket(addr: &str, convo: &str, browser: &Browser, origin: Option<&str>) -> Result<Socket, tungstenite::Error> {
+    let mut request = format!("ws://{}/messages/{}/ws?after=1", addr, convo).into_client_request().unwrap();
+    request.headers_mut().insert(header::COOKIE, browser.cookie_header().parse().unwrap());
+    if let Some(origin) = origin {
+        request.headers_mut().insert(header::ORIGIN, origin.parse().unwrap());
+    }
+    tokio_tungstenite::connect_async(request).await.map(|(socket, _)| socket)
+}
+
+/// Contents of `message` frames until the socket goes quiet.
+async fn socket_messages(socket: &mut Socket) -> Vec<String> {
+    let mut contents = Vec::new();
+    let mut wait = ARRIVES;
+    while let Ok(frame) = tokio::time::timeout(wait, socket.next()).await {
+        let WsMessage::Text(text) = frame.expect("socket stays open").unwrap() else { continue };
+        let frame: Value = serde_json::from_str(text.as_str()).unwrap();
+        if frame["type"] == "message" {
+            contents.push(frame["message"]["content"].as_str().unwrap().to_string());
+        }
+        wait = QUIET;
+    }
+    contents
+}
+
+#[tokio::test]
+async fn websocket_delivers_each_message_once() {
+    let (router, seller, buyer, convo) = conversation("live-ws").await;
+    let addr = serve(router).await;
+    let origin = format!("http://{}", addr);
+    let mut seller_socket = open_socket(&addr, &convo, &seller, Some(&origin)).await.unwrap();
+    let mut buyer_socket = open_socket(&addr, &convo, &buyer, Some(&origin)).await.unwrap();
+
+    let sent = json!({ "type": "message", "content": "Yes, still here." }).to_string();
+    seller_socket.send(WsMessage::text(sent)).await.unwrap();
+    // The sender hears its own message back once, through the hub like the buyer
+    assert_eq!(socket_messages(&mut seller_socket).await, ["Yes, still here."]);
+    assert_eq!(socket_messages(&mut buyer_socket).await, ["Yes, still here."]);
+}
+
+#[tokio::test]
+async fn websocket_handshakes_from_other_origins_are_refused() {
+    let (router, _seller, buyer, convo) = conversation("live-origin").await;
+    let addr = serve(router).await;
+
+    let other_port = format!("http://{}:1", addr.split(':').next().unwrap());
+    for origin in [Some("https://evil.example"), Some(other_port.as_str()), None] {
+        match open_socket(&addr, &convo, &buyer, origin).await {
+            Err(tungstenite::Error::Http(response)) => assert_eq!(response.status(), StatusCode::FORBIDDEN, "{:?}", origin),
+            other => panic!("{:?} was let in: {:?}", origin, other.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,212 @@
+//! Media storage: the S3 backend against an in-process stand-in that checks request
+//! signatures, and the sweep deleting only objects nothing points at.
+
+mod common;
+
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{HeaderMap, Method, StatusCode, Uri};
+use axum::Router;
+use common::sign_up;
+use forge_commerce::db::{self, Db};
+use forge_commerce::media::{self, LocalStore, MediaStore, S3Store};
+use forge_commerce::models::{CartOwner, ListingForm, UploadedImage};
+use forge_commerce::money::Money;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+const BUCKET: &str = "forge-media";
+const REGION: &str = "eu-west-2";
+const ACCESS_KEY: &str = "AKIDFORGETEST";
+const SECRET_KEY: &str = "forge-test-secret";
+
+type Objects = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+/// Just enough of S3 for `S3Store`: signed PUT and DELETE, and public GET.
+async fn s3(State(objects): State<Objects>, method: Method, uri: Uri, headers: HeaderMap, body: Bytes) -> (StatusCode, Vec<u8>) {
+    let path = uri.path().to_string();
+    if method == Method::GET {
+        return match objects.lock().unwrap().get(&path) {
+            Some(data) => (StatusCode::OK, data.clone()),
+            None => (StatusCode::NOT_FOUND, b"NoSuchKey".to_vec()),
+        };
+    }
+    if let Err(reason) = check_signature(&method, &path, &headers, &body) {
+        return (StatusCode::FORBIDDEN, reason.into_bytes());
+    }
+    let mut objects = objects.lock().unwrap();
+    match method {
+        Method::PUT => {
+            objects.insert(path, body.to_vec());
+            (StatusCode::OK, Vec::new())
+        }
+        Method::DELETE => {
+            objects.remove(&path);
+            (StatusCode::NO_CONTENT, Vec::new())
+        }
+        _ => (StatusCode::METHOD_NOT_ALLOWED, Vec::new()),
+    }
+}
+
+/// Recomputes the signature from what actually arrived, with the stand-in's own copy of
+/// the secret.
+fn check_signature(method: &Method, path: &str, headers: &HeaderMap, body: &[u8]) -> Result<(), String> {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok()).ok_or(format!("MissingHeader {}", name));
+    let payload_hash = header("x-amz-content-sha256")?;
+    if payload_hash != hex::encode(Sha256::digest(body)) {
+        return Err("XAmzContentSHA256Mismatch".to_string());
+    }
+    let amz_date = header("x-amz-date")?;
+    let signed_at = chrono::NaiveDateTime::parse_from_str(amz_date, "%Y%m%dT%H%M%SZ").map_err(|_| "InvalidDate")?;
+    if (chrono::Utc::now().naive_utc() - signed_at).num_minutes().abs() > 15 {
+        return Err("RequestTimeTooSkewed".to_string());
+    }
+    let verifier = S3Store::new(&format!("http://{}", header("host")?), BUCKET, REGION, ACCESS_KEY, SECRET_KEY, None);
+    if header("authorization")? != verifier.authorization(method.as_str(), path, payload_hash, amz_date) {
+        return Err("SignatureDoesNotMatch".to_string());
+    }
+    Ok(())
+}
+
+/// Serves the stand-in on a random local port. Returns its endpoint and what it holds.
+async fn serve_s3() -> (String, Objects) {
+    let objects = Objects::default();
+    let app = Router::new().fallback(s3).with_state(objects.clone());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    (endpoint, objects)
+}
+
+/// `S3Store` blocks, so it runs off the runtime the stand-in is served from.
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    tokio::task::spawn_blocking(f).await.unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn s3_store_puts_serves_and_deletes_signed_objects() {
+    let (endpoint, objects) = serve_s3().await;
+    let store = Arc::new(S3Store::new(&endpoint, BUCKET, REGION, ACCESS_KEY, SECRET_KEY, None));
+    let photo = b"\xFF\xD8\xFF pretend jpeg".to_vec();
+    let key = media::content_key(&photo, "jpg");
+
+    let (s, k, p) = (store.clone(), key.clone(), photo.clone());
+    blocking(move || s.put(&k, &p, "image/jpeg")).await.unwrap();
+    assert_eq!(objects.lock().unwrap().get(&format!("/{}/{}", BUCKET, key)), Some(&photo));
+
+    // Readable by anyone at the URL the store hands out
+    let url = store.url(&key);
+    assert_eq!(url, format!("{}/{}/{}", endpoint, BUCKET, key));
+    let served = reqwest::get(&url).await.unwrap();
+    assert_eq!(served.status(), StatusCode::OK);
+    assert_eq!(served.bytes().await.unwrap().to_vec(), photo);
+
+    // Keys are percent-encoded in the path that is signed
+    let (s, odd) = (store.clone(), "ab/odd name+1 é.jpg");
+    blocking(move || s.put(odd, b"odd", "image/jpeg")).await.unwrap();
+    assert!(objects.lock().unwrap().contains_key(&format!("/{}/ab/odd%20name%2B1%20%C3%A9.jpg", BUCKET)));
+
+    let (s, k) = (store.clone(), key.clone());
+    blocking(move || s.delete(&k)).await.unwrap();
+    assert_eq!(reqwest::get(&url).await.unwrap().status(), StatusCode::NOT_FOUND);
+    // Already gone is fine
+    let (s, k) = (store.clone(), key.clone());
+    blocking(move || s.delete(&k)).await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn s3_store_reports_requests_the_service_refuses() {
+    let (endpoint, objects) = serve_s3().await;
+    let impostor = S3Store::new(&endpoint, BUCKET, REGION, ACCESS_KEY, "not-the-secret", None);
+    let err = blocking(move || impostor.put("ab/abc.jpg", b"data", "image/jpeg")).await.unwrap_err();
+    assert!(err.contains("returned 403") && err.contains("SignatureDoesNotMatch"), "{}", err);
+    assert!(objects.lock().unwrap().is_empty());
+}
+
+/// A local store in a fresh temporary directory.
+fn local_store() -> (LocalStore, PathBuf) {
+    let root = std::env::temp_dir().join(format!("forge-media-{}", uuid::Uuid::new_v4()));
+    (LocalStore::new(&root, "/media"), root)
+}
+
+/// Stores `name` as an object the way `media::save` does. Returns its key and URL.
+fn stored(db: &Db, store: &LocalStore, name: &str) -> (String, String) {
+    let key = media::content_key(name.as_bytes(), "jpg");
+    let url = store.url(&key);
+    store.put(&key, name.as_bytes(), "image/jpeg").unwrap();
+    db::insert_media_object(db, &key, &url, "image/jpeg", name.len() as i64);
+    (key, url)
+}
+
+/// Moves every object's last use back past the sweep's grace period.
+fn age(db: &Db) {
+    db.lock().unwrap().execute(
+        "UPDATE media_objects SET touched_at = datetime('now', ?1)",
+        [format!("-{} minutes", media::SWEEP_GRACE_MINUTES + 1)],
+    ).unwrap();
+}
+
+fn refcount(db: &Db, key: &str) -> Option<i64> {
+    db.lock().unwrap().query_row("SELECT refcount FROM media_objects WHERE key = ?1", [key], |r| r.get(0)).ok()
+}
+
+#[test]
+fn sweep_removes_only_objects_nothing_points_at() {
+    let (_router, db) = common::app("media");
+    let (store, root) = local_store();
+    let alice = sign_up(&db, "Alice", "alice@example.com");
+    let bob = sign_up(&db, "Bob", "bob@example.com");
+
+    let (cover, cover_url) = stored(&db, &store, "cover");
+    let (cover_thumb, cover_thumb_url) = stored(&db, &store, "cover thumb");
+    let (side, side_url) = stored(&db, &store, "side");
+    let (side_thumb, side_thumb_url) = stored(&db, &store, "side thumb");
+    let (abandoned, _) = stored(&db, &store, "upload whose form was never saved");
+
+    let form = ListingForm {
+        title: "Desk lamp".to_string(), description: "Works well".to_string(), price: "40".to_string(),
+        category: "Home".to_string(), condition: "Good".to_string(), location: "Leeds".to_string(),
+    };
+    let images = [
+        UploadedImage { url: cover_url, thumb_url: cover_thumb_url },
+        UploadedImage { url: side_url, thumb_url: side_thumb_url },
+    ];
+    let lamp = db::create_listing(&db, &alice, &form, Money::usd(4000), &images);
+    // Bob's order keeps its own pointer at the cover
+    db::add_to_cart(&db, &CartOwner::User(bob.clone()), &lamp).unwrap();
+    db::create_orders_from_cart(&db, &bob).unwrap();
+    assert_eq!(refcount(&db, &cover), Some(2));
+    assert_eq!(refcount(&db, &side_thumb), Some(1));
+    assert_eq!(refcount(&db, &abandoned), Some(0));
+
+    // Unreferenced but recent: kept in case its row is about to be written
+    assert_eq!(media::sweep(&db, &store), Ok(0));
+    age(&db);
+    assert_eq!(media::sweep(&db, &store), Ok(1));
+    assert_eq!(refcount(&db, &abandoned), None);
+    assert!(!root.join(&abandoned).exists());
+
+    // Removing the cover photo frees its thumbnail, but the order still shows the full size
+    let photo = db::get_listing_images(&db, &lamp).into_iter().next().unwrap();
+    assert!(db::delete_listing_image(&db, &lamp, &photo.id, &alice));
+    assert_eq!((refcount(&db, &cover), refcount(&db, &cover_thumb)), (Some(1), Some(0)));
+    assert_eq!(media::sweep(&db, &store), Ok(0));
+    age(&db);
+    assert_eq!(media::sweep(&db, &store), Ok(1));
+    assert!(!root.join(&cover_thumb).exists());
+    assert!(root.join(&cover).exists());
+
+    db.lock().unwrap().execute("DELETE FROM order_items WHERE listing_id = ?1", [&lamp]).unwrap();
+    age(&db);
+    assert_eq!(media::sweep(&db, &store), Ok(1));
+    assert!(!root.join(&cover).exists());
+
+    // The photo still on the listing survives every sweep
+    for key in [&side, &side_thumb] {
+        assert_eq!(refcount(&db, key), Some(1));
+        assert!(root.join(key).exists());
+    }
+    std::fs::remove_dir_all(root).unwrap();
+}
@@ -0,0 +1,67 @@
+//! Making and countering offers through the conversation page.
+
+mod common;
+
+use common::{list_item, sign_up, Browser};
+use forge_commerce::db;
+use forge_commerce::models::OfferResponse;
+use forge_commerce::money::{Currency, Money};
+
+#[tokio::test]
+async fn offers_and_counters_are_in_the_listings_currency() {
+    let (router, db) = common::app("offers");
+    let alice = sign_up(&db, "Alice", "alice@example.com");
+    let bob = sign_up(&db, "Bob", "bob@example.com");
+    let eur: Currency = "EUR".parse().unwrap();
+    let vase = list_item(&db, &alice, "Glass vase", Money::new(6000, eur));
+    let convo = db::get_or_create_conversation(&db, &vase, &bob, &alice);
+    let page = format!("/messages/{}", convo);
+
+    let mut buyer = Browser::new(&router);
+    buyer.log_in("bob@example.com", "password123").await;
+    let offered = buyer.post(&format!("{}/offer", page), &page, &[("amount", "€45")]).await;
+    assert_eq!(offered.location.as_deref(), Some(page.as_str()));
+    let offer = db::get_pending_offer(&db, &convo).unwrap();
+    assert_eq!(offer.amount, Money::new(4500, eur));
+
+    let mut seller = Browser::new(&router);
+    seller.log_in("alice@example.com", "password123").await;
+    let countered = seller.post(&format!("{}/offer/{}/counter", page, offer.id), &page, &[("amount", "52.50")]).await;
+    assert_eq!(countered.location.as_deref(), Some(page.as_str()));
+    let counter = db::get_pending_offer(&db, &convo).unwrap();
+    assert_eq!((counter.parent_id.as_deref(), counter.amount), (Some(offer.id.as_str()), Money::new(5250, eur)));
+    assert!(db::get_messages_after(&db, &convo, 0).iter().any(|m| m.content.contains("€52.50")));
+}
+
+#[tokio::test]
+async fn a_new_offer_while_a_counter_is_open_counters_it() {
+    let (router, db) = common::app("offers");
+    let alice = sign_up(&db, "Alice", "alice@example.com");
+    let bob = sign_up(&db, "Bob", "bob@example.com");
+    let lamp = list_item(&db, &alice, "Desk lamp", Money::usd(4000));
+    let convo = db::get_or_create_conversation(&db, &lamp, &bob, &alice);
+    let first = db::create_offer(&db, &lamp, &convo, &bob, Money::usd(3000)).unwrap();
+    let counter = db::respond_to_offer(&db, &first, &alice, OfferResponse::Counter(Money::usd(3800))).unwrap();
+
+    let mut buyer = Browser::new(&router);
+    buyer.log_in("bob@example.com", "password123").await;
+    let page = format!("/messages/{}", convo);
+    buyer.post(&format!("{}/offer", page), &page, &[("amount", "34")]).await;
+
+    // The seller's counter is answered, not dropped, and the thread keeps every round
+    assert_eq!(db::get_offer(&db, &counter.id).unwrap().status, "countered");
+    let second = db::get_pending_offer(&db, &convo).unwrap();
+    assert_eq!((second.parent_id.as_deref(), second.amount), (Some(counter.id.as_str()), Money::usd(3400)));
+    let rounds: Vec<_> = db::get_offer_thread(&db, &convo).into_iter().map(|o| (o.amount, o.status)).collect();
+    assert_eq!(rounds, [
+        (Money::usd(3000), "countered".to_string()),
+        (Money::usd(3800), "countered".to_string()),
+        (Money::usd(3400), "pending".to_string()),
+    ]);
+    assert!(db::get_messages_after(&db, &convo, 0).iter().any(|m| m.content == "↩️ Counter-offer: $34.00"));
+
+    // Offering again replaces the open offer, which still answers the counter
+    buyer.post(&format!("{}/offer", page), &page, &[("amount", "35")]).await;
+    assert_eq!(db::get_offer(&db, &second.id).unwrap().status, "cancelled");
+    assert_eq!(db::get_pending_offer(&db, &convo).unwrap().parent_id, Some(counter.id));
+}
@@ -0,0 +1,360 @@
+//! Keeps `/api/openapi.json` honest. Every documented operation is called against the real
+//! router, and each response must use a documented status and match that status's schema.
+//! Adding a handler, changing a response type or returning a new status without updating
+//! its `#[utoipa::path]` fails here.
+
+mod common;
+
+use axum::body::Body;
+use axum::http::{header, Method, Request, StatusCode};
+use axum::Router;
+use forge_commerce::db::Db;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use tera::Tera;
+use tower::ServiceExt;
+
+struct Api {
+    router: Router,
+    db: Db,
+    spec: Value,
+    base: String,
+    /// (METHOD, path template) of every operation that answered with a 2xx status
+    succeeded: BTreeSet<(String, String)>,
+}
+
+impl Api {
+    async fn new() -> Self {
+        let path = std::env::temp_dir().join(format!("forge-openapi-{}.db", uuid::Uuid::new_v4()));
+        let db = forge_commerce::db::init_db_with_path(path.to_str().unwrap());
+        let mut tera = Tera::new(concat!(env!("CARGO_MANIFEST_DIR"), "/templates/**/*.html")).unwrap();
+        forge_commerce::csrf::register(&mut tera);
+        let router = forge_commerce::build_router((db.clone(), Arc::new(tera)));
+
+        let response = router.clone().oneshot(Request::get("/api/openapi.json").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let spec: Value = serde_json::from_slice(&bytes).unwrap();
+        let base = spec["servers"][0]["url"].as_str().expect("spec has a server url").to_string();
+        Api { router, db, spec, base, succeeded: BTreeSet::new() }
+    }
+
+    /// Follows the verification link last mailed to `email` and confirms, as its owner would.
+    /// The default mailer keeps mail in the `outbox` table.
+    async fn confirm_email(&self, email: &str) {
+        let mail = forge_commerce::db::get_outbox(&self.db, email);
+        let body = &mail.first().expect("a verification email was sent").body;
+        let start = body.find("/verify-email?").expect("the email has a verification link");
+        let path = body[start..].split_whitespace().next().unwrap();
+        let fields: Vec<(String, String)> = form_urlencoded::parse(path.split_once('?').unwrap().1.as_bytes()).into_owned().collect();
+        let fields: Vec<(&str, &str)> = fields.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        let page = common::Browser::new(&self.router).post("/verify-email", path, &fields).await;
+        assert!(page.body.contains("Email confirmed"), "{}", page.body);
+    }
+
+    /// Every (METHOD, path template) in the document.
+    fn operations(&self) -> BTreeSet<(String, String)> {
+        let mut ops = BTreeSet::new();
+        for (path, item) in self.spec["paths"].as_object().unwrap() {
+            for method in item.as_object().unwrap().keys() {
+                ops.insert((method.to_uppercase(), path.clone()));
+            }
+        }
+        ops
+    }
+
+    /// Calls `method template` with `{…}` placeholders filled from `args` in order, and checks
+    /// the response against the document. The template may end in a `?query`. Returns the
+    /// status and JSON body.
+    async fn call(&mut self, method: &str, template: &str, args: &[&str], token: Option<&str>, body: Option<Value>) -> (StatusCode, Value) {
+        let (template, query) = template.split_once('?').unwrap_or((template, ""));
+        let operation = self.spec["paths"][template][method.to_lowercase()].clone();
+        assert!(operation.is_object(), "{} {} is not documented", method, template);
+
+        let mut path = template.to_string();
+        for arg in args {
+            let start = path.find('{').expect("more args than placeholders");
+            let end = path[start..].find('}').unwrap() + start;
+            path.replace_range(start..=end, arg);
+        }
+        if !query.is_empty() {
+            path = format!("{}?{}", path, query);
+        }
+        let mut request = Request::builder().method(Method::from_bytes(method.as_bytes()).unwrap()).uri(format!("{}{}", self.base, path));
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let request = match &body {
+            Some(b) => request.header(header::CONTENT_TYPE, "application/json").body(Body::from(b.to_string())),
+            None => request.body(Body::empty()),
+        }.unwrap();
+
+        let response = self.router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let context = format!("{} {} -> {}", method, path, status);
+
+        let documented = &operation["responses"][status.as_str()];
+        assert!(documented.is_object(), "{}: status not documented; body {}", context, String::from_utf8_lossy(&bytes));
+        let value = match documented["content"]["application/json"]["schema"].as_object() {
+            Some(schema) => {
+                let value: Value = serde_json::from_slice(&bytes).unwrap_or_else(|e| panic!("{}: body isn't JSON: {}", context, e));
+                if let Err(e) = self.check(&Value::Object(schema.clone()), &value, "body") {
+                    panic!("{}: response doesn't match the schema: {}\n{}", context, e, value);
+                }
+                value
+            }
+            None => {
+                assert!(bytes.is_empty(), "{}: undocumented body {}", context, String::from_utf8_lossy(&bytes));
+                Value::Null
+            }
+        };
+
+        if status.is_success() {
+            // Only requests the server accepted are expected to match the request schema
+            if let Some(body) = &body {
+                let schema = &operation["requestBody"]["content"]["application/json"]["schema"];
+                assert!(schema.is_object(), "{}: request body not documented", context);
+                if let Err(e) = self.check(schema, body, "request") {
+                    panic!("{}: request doesn't match the schema: {}", context, e);
+                }
+            }
+            self.succeeded.insert((method.to_string(), template.to_string()));
+        }
+        (status, value)
+    }
+
+    fn resolve<'a>(&'a self, schema: &'a Value) -> &'a Value {
+        match schema["$ref"].as_str() {
+            Some(r) => {
+                let name = r.strip_prefix("#/components/schemas/").expect("local schema ref");
+                let target = &self.spec["components"]["schemas"][name];
+                assert!(target.is_object(), "dangling ref {}", r);
+                self.resolve(target)
+            }
+            None => schema,
+        }
+    }
+
+    /// Properties and required names of an object schema, merged across `allOf`.
+    fn object_fields(&self, schema: &Value, properties: &mut Map<String, Value>, required: &mut BTreeSet<String>) {
+        let schema = self.resolve(schema);
+        if let Some(parts) = schema["allOf"].as_array() {
+            for part in parts {
+                self.object_fields(part, properties, required);
+            }
+        }
+        if let Some(props) = schema["properties"].as_object() {
+            properties.extend(props.clone());
+        }
+        if let Some(names) = schema["required"].as_array() {
+            required.extend(names.iter().map(|n| n.as_str().unwrap().to_string()));
+        }
+    }
+
+    /// A small validator for the subset of JSON Schema the derive produces. Stricter than
+    /// JSON Schema in one way: objects may not carry properties the schema doesn't list.
+    fn check(&self, schema: &Value, value: &Value, at: &str) -> Result<(), String> {
+        let schema = self.resolve(schema);
+        if let Some(options) = schema["oneOf"].as_array() {
+            return match options.iter().any(|o| self.check(o, value, at).is_ok()) {
+                true => Ok(()),
+                false => Err(format!("{}: {} matches none of {}", at, value, schema)),
+            };
+        }
+        if let Some(allowed) = schema["enum"].as_array() {
+            if !allowed.contains(value) {
+                return Err(format!("{}: {} is not one of {:?}", at, value, allowed));
+            }
+        }
+
+        let types: Vec<&str> = match &schema["type"] {
+            Value::String(t) => vec![t.as_str()],
+            Value::Array(ts) => ts.iter().filter_map(Value::as_str).collect(),
+            _ if schema["allOf"].is_array() => vec!["object"],
+            _ => return Ok(()),
+        };
+        let actual = match value {
+            Value::Null => "null",
+            Value::Bool(_) => "boolean",
+            Value::Number(n) if n.is_i64() || n.is_u64() => "integer",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Array(_) => "array",
+            Value::Object(_) => "object",
+        };
+        let integer_as_number = actual == "integer" && types.contains(&"number");
+        if !types.contains(&actual) && !integer_as_number {
+            return Err(format!("{}: expected {:?}, got {}", at, types, value));
+        }
+
+        match value {
+            Value::Array(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    self.check(&schema["items"], item, &format!("{}[{}]", at, i))?;
+                }
+            }
+            Value::Object(fields) => {
+                let mut properties = Map::new();
+                let mut required = BTreeSet::new();
+                self.object_fields(schema, &mut properties, &mut required);
+                for name in &required {
+                    if !fields.contains_key(name) {
+                        return Err(format!("{}: missing required field {}", at, name));
+                    }
+                }
+                for (name, field) in fields {
+                    let field_schema = properties.get(name).ok_or_else(|| format!("{}: undocumented field {}", at, name))?;
+                    self.check(field_schema, field, &format!("{}.{}", at, name))?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+fn token(body: &Value) -> String {
+    body["token"].as_str().unwrap().to_string()
+}
+
+fn id(body: &Value) -> String {
+    body["id"].as_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn every_operation_is_routed_and_answers_as_documented() {
+    let mut api = Api::new().await;
+    // Without credentials or a body every operation still has to answer with a documented
+    // status; the API's own 404 for unknown endpoints would mean the route isn't mounted.
+    for (method, template) in api.operations() {
+        let args = vec!["missing"; template.matches('{').count()];
+        let body = if method == "GET" || method == "DELETE" { None } else { Some(json!({})) };
+        let (status, body) = api.call(&method, &template, &args, None, body).await;
+        assert_ne!(body["error"]["message"], "Endpoint not found.", "{} {} isn't routed", method, template);
+        assert_ne!(status, StatusCode::METHOD_NOT_ALLOWED, "{} {} isn't routed", method, template);
+    }
+}
+
+#[tokio::test]
+async fn responses_match_documented_schemas() {
+    let mut api = Api::new().await;
+
+    // Auth
+    let seller = json!({ "name": "Sam Seller", "email": "sam@example.com", "password": "password123" });
+    let (status, body) = api.call("POST", "/auth/register", &[], None, Some(seller.clone())).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let seller_token = token(&body);
+    let (status, _) = api.call("POST", "/auth/register", &[], None, Some(seller)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let buyer = json!({ "name": "Bo Buyer", "email": "bo@example.com", "password": "password123" });
+    api.call("POST", "/auth/register", &[], None, Some(buyer)).await;
+    let (status, _) = api.call("POST", "/auth/login", &[], None, Some(json!({ "email": "bo@example.com", "password": "wrong" }))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, body) = api.call("POST", "/auth/login", &[], None, Some(json!({ "email": "bo@example.com", "password": "password123" }))).await;
+    assert_eq!(status, StatusCode::OK);
+    let buyer_token = token(&body);
+
+    // Profile
+    let (status, _) = api.call("GET", "/me", &[], Some(&buyer_token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, body) = api.call("PATCH", "/me", &[], Some(&buyer_token), Some(json!({ "location": "Portland, OR" }))).await;
+    assert_eq!((status, body["location"].as_str()), (StatusCode::OK, Some("Portland, OR")));
+    let (status, _) = api.call("PATCH", "/me", &[], Some(&buyer_token), Some(json!({ "name": " " }))).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+    // Listings
+    let listing = json!({
+        "title": "Road bike", "description": "56cm frame, new tyres", "price": "350",
+        "category": "Sports", "condition": "Like New", "location": "Portland, OR",
+    });
+    let (status, body) = api.call("POST", "/listings", &[], Some(&seller_token), Some(listing.clone())).await;
+    assert_eq!((status, body["error"]["code"].as_str()), (StatusCode::FORBIDDEN, Some("email_unverified")));
+    api.confirm_email("sam@example.com").await;
+    api.confirm_email("bo@example.com").await;
+    let (status, body) = api.call("POST", "/listings", &[], Some(&seller_token), Some(listing.clone())).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let listing_id = id(&body);
+    let (status, _) = api.call("POST", "/listings", &[], Some(&seller_token), Some(json!({ "title": "", "description": "", "price": "1", "category": "" }))).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let (status, body) = api.call("GET", "/listings", &[], None, None).await;
+    assert_eq!((status, body["listings"].as_array().map(Vec::len)), (StatusCode::OK, Some(1)));
+    let (status, _) = api.call("GET", "/listings/{id}", &[&listing_id], None, None).await;
+    assert_eq!(status, StatusCode::OK);
+    let mut update = listing.clone();
+    update["price"] = json!("325.50");
+    let (status, body) = api.call("PUT", "/listings/{id}", &[&listing_id], Some(&seller_token), Some(update.clone())).await;
+    assert_eq!((status, body["price"]["cents"].as_i64()), (StatusCode::OK, Some(32550)));
+    let (status, _) = api.call("PUT", "/listings/{id}", &[&listing_id], Some(&buyer_token), Some(update.clone())).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = api.call("PUT", "/listings/{id}", &["missing"], Some(&seller_token), Some(update)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // Conversations
+    let (status, _) = api.call("POST", "/listings/{id}/conversation", &[&listing_id], Some(&seller_token), None).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let (status, body) = api.call("POST", "/listings/{id}/conversation", &[&listing_id], Some(&buyer_token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let convo_id = id(&body);
+    let (status, _) = api.call("POST", "/conversations/{id}/messages", &[&convo_id], Some(&buyer_token), Some(json!({ "content": "Is it still available?" }))).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, _) = api.call("POST", "/conversations/{id}/messages", &[&convo_id], Some(&buyer_token), Some(json!({ "content": "  " }))).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let (status, _) = api.call("POST", "/conversations/{id}/messages", &[&convo_id], Some(&seller_token), Some(json!({ "content": "Yes it is." }))).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, body) = api.call("GET", "/conversations/{id}/messages", &[&convo_id], Some(&seller_token), None).await;
+    let seqs: Vec<_> = body["messages"].as_array().unwrap().iter().map(|m| m["seq"].as_i64().unwrap()).collect();
+    assert_eq!((status, seqs), (StatusCode::OK, vec![1, 2]));
+    let (status, body) = api.call("GET", "/conversations/{id}/messages?before=2", &[&convo_id], Some(&seller_token), None).await;
+    assert_eq!((status, body["messages"][0]["content"].as_str()), (StatusCode::OK, Some("Is it still available?")));
+    let (status, body) = api.call("GET", "/conversations/{id}/messages?after=1", &[&convo_id], Some(&buyer_token), None).await;
+    assert_eq!((status, body["messages"][0]["content"].as_str()), (StatusCode::OK, Some("Yes it is.")));
+    let (status, _) = api.call("GET", "/conversations/{id}/messages?after=latest", &[&convo_id], Some(&buyer_token), None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, body) = api.call("GET", "/conversations", &[], Some(&seller_token), None).await;
+    assert_eq!((status, body["conversations"].as_array().map(Vec::len)), (StatusCode::OK, Some(1)));
+
+    // Offers
+    let (status, _) = api.call("POST", "/conversations/{id}/offers", &[&convo_id], Some(&seller_token), Some(json!({ "amount": "300" }))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, body) = api.call("POST", "/conversations/{id}/offers", &[&convo_id], Some(&buyer_token), Some(json!({ "amount": "300" }))).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let offer_id = id(&body);
+    let (status, _) = api.call("POST", "/offers/{id}/respond", &[&offer_id], Some(&buyer_token), Some(json!({ "action": "accept" }))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, body) = api.call("POST", "/offers/{id}/respond", &[&offer_id], Some(&seller_token), Some(json!({ "action": "counter", "amount": "320" }))).await;
+    assert_eq!(status, StatusCode::OK);
+    let counter_id = id(&body);
+    let (status, _) = api.call("POST", "/offers/{id}/respond", &[&offer_id], Some(&seller_token), Some(json!({ "action": "reject" }))).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let (status, body) = api.call("POST", "/offers/{id}/respond", &[&counter_id], Some(&buyer_token), Some(json!({ "action": "accept" }))).await;
+    assert_eq!((status, body["status"].as_str()), (StatusCode::OK, Some("accepted")));
+    // Accepting reserved the listing, so it takes no more offers
+    let (status, _) = api.call("POST", "/conversations/{id}/offers", &[&convo_id], Some(&buyer_token), Some(json!({ "amount": "310" }))).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let (status, body) = api.call("GET", "/conversations/{id}", &[&convo_id], Some(&buyer_token), None).await;
+    assert_eq!((status, body["offers"].as_array().map(Vec::len)), (StatusCode::OK, Some(2)));
+
+    // Cleanup: a listing with conversations stays, an untouched one can go
+    let (status, _) = api.call("DELETE", "/listings/{id}", &[&listing_id], Some(&seller_token), None).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let (status, _) = api.call("GET", "/listings/{id}", &[&listing_id], None, None).await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, body) = api.call("POST", "/listings", &[], Some(&seller_token), Some(listing)).await;
+    let spare_id = id(&body);
+    let (status, _) = api.call("DELETE", "/listings/{id}", &[&spare_id], Some(&buyer_token), None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = api.call("DELETE", "/listings/{id}", &[&spare_id], Some(&seller_token), None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = api.call("DELETE", "/listings/{id}", &[&spare_id], Some(&seller_token), None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = api.call("POST", "/auth/logout", &[], Some(&buyer_token), None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = api.call("GET", "/me", &[], Some(&buyer_token), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // A new operation needs a successful call above, so its schema gets checked too
+    let missed: Vec<_> = api.operations().difference(&api.succeeded).cloned().collect();
+    assert!(missed.is_empty(), "operations never called successfully: {:?}", missed);
+}
@@ -0,0 +1,165 @@
+//! The order state machine, driven through `db::update_order_status` and `db::cancel_order`
+//! the way the order page drives it.
+
+mod common;
+
+use common::{list_item, sign_up};
+use forge_commerce::db::{self, Db};
+use forge_commerce::models::{CartOwner, IntentStatus, OfferResponse, OrderError, OrderStatus, PaymentIntent};
+use std::cell::Cell;
+use forge_commerce::money::Money;
+
+struct Sale {
+    db: Db,
+    seller: String,
+    buyer: String,
+    listing: String,
+    offer: String,
+    order: String,
+}
+
+/// Bob's accepted offer on Alice's lamp, checked out into an order awaiting payment.
+fn accepted_offer_order() -> Sale {
+    let (_router, db) = common::app("orders");
+    let seller = sign_up(&db, "Alice", "alice@example.com");
+    let buyer = sign_up(&db, "Bob", "bob@example.com");
+    let listing = list_item(&db, &seller, "Desk lamp", Money::usd(4000));
+    let convo = db::get_or_create_conversation(&db, &listing, &buyer, &seller);
+    let offer = db::create_offer(&db, &listing, &convo, &buyer, Money::usd(3500)).unwrap();
+    db::respond_to_offer(&db, &offer, &seller, OfferResponse::Accept).unwrap();
+    let order = db::create_order_from_offer(&db, &offer, &buyer).unwrap();
+    Sale { db, seller, buyer, listing, offer, order }
+}
+
+fn status(db: &Db, order: &str) -> OrderStatus {
+    db::get_order(db, order).unwrap().status
+}
+
+fn pay(db: &Db, order: &str) {
+    let order = db::get_order(db, order).unwrap();
+    let intent = db::create_payment_intent(db, &order, "manual", &uuid::Uuid::new_v4().to_string(), "");
+    db::record_payment(db, &intent.id, IntentStatus::Succeeded);
+}
+
+#[test]
+fn illegal_transitions_are_refused() {
+    let Sale { db, seller, buyer, order, .. } = accepted_offer_order();
+    let stranger = sign_up(&db, "Clara", "clara@example.com");
+    assert_eq!(status(&db, &order), OrderStatus::PendingPayment);
+
+    // Nobody marks an order paid by hand, and nothing ships before it's paid
+    assert!(db::update_order_status(&db, &order, &seller, OrderStatus::Paid).is_err());
+    assert!(db::update_order_status(&db, &order, &seller, OrderStatus::Shipped).is_err());
+    assert!(db::update_order_status(&db, &order, &buyer, OrderStatus::Completed).is_err());
+    assert_eq!(db::update_order_status(&db, &order, &stranger, OrderStatus::Cancelled), Err(OrderError::NotFound));
+    assert_eq!(status(&db, &order), OrderStatus::PendingPayment);
+
+    pay(&db, &order);
+    assert_eq!(status(&db, &order), OrderStatus::Paid);
+    // Only the seller hands over, and only the buyer confirms it arrived
+    assert!(db::update_order_status(&db, &order, &buyer, OrderStatus::Shipped).is_err());
+    assert!(db::update_order_status(&db, &order, &buyer, OrderStatus::Cancelled).is_err());
+    assert!(db::update_order_status(&db, &order, &seller, OrderStatus::Completed).is_err());
+    db::update_order_status(&db, &order, &seller, OrderStatus::Shipped).unwrap();
+    assert!(db::update_order_status(&db, &order, &seller, OrderStatus::Cancelled).is_err());
+    assert!(db::update_order_status(&db, &order, &seller, OrderStatus::Completed).is_err());
+    db::update_order_status(&db, &order, &buyer, OrderStatus::Completed).unwrap();
+
+    // Completed is final
+    for next in [OrderStatus::Cancelled, OrderStatus::Shipped, OrderStatus::PendingPayment] {
+        assert!(db::update_order_status(&db, &order, &seller, next).is_err());
+        assert!(db::update_order_status(&db, &order, &buyer, next).is_err());
+    }
+    assert_eq!(status(&db, &order), OrderStatus::Completed);
+}
+
+#[test]
+fn cancelling_releases_the_listing_and_the_offer() {
+    let Sale { db, seller, buyer, listing, offer, order } = accepted_offer_order();
+    assert_eq!(db::get_listing(&db, &listing).unwrap().status, "sold");
+
+    db::update_order_status(&db, &order, &buyer, OrderStatus::Cancelled).unwrap();
+    assert_eq!(status(&db, &order), OrderStatus::Cancelled);
+    assert_eq!(db::get_listing(&db, &listing).unwrap().status, "active");
+    let withdrawn = db::get_offer(&db, &offer).unwrap();
+    assert_eq!(withdrawn.status, "withdrawn");
+    let notes = db::get_messages_after(&db, &withdrawn.conversation_id, 0);
+    assert!(notes.iter().any(|m| m.content.contains("accepted offer was withdrawn")));
+
+    // The withdrawn offer can't be checked out again, and cancelled is final
+    assert!(db::create_order_from_offer(&db, &offer, &buyer).is_err());
+    assert!(db::update_order_status(&db, &order, &seller, OrderStatus::Cancelled).is_err());
+
+    // The listing is back on the market for everyone
+    let clara = sign_up(&db, "Clara", "clara@example.com");
+    let convo = db::get_or_create_conversation(&db, &listing, &clara, &seller);
+    let second = db::create_offer(&db, &listing, &convo, &clara, Money::usd(4000)).unwrap();
+    db::respond_to_offer(&db, &second, &seller, OfferResponse::Accept).unwrap();
+    assert!(db::create_order_from_offer(&db, &second, &clara).is_ok());
+}
+
+fn refunded(_: &PaymentIntent) -> Result<IntentStatus, String> {
+    Ok(IntentStatus::Refunded)
+}
+
+#[test]
+fn a_paid_order_cancelled_by_the_seller_is_refunded_and_releases_the_listing() {
+    let Sale { db, seller, listing, offer, order, .. } = accepted_offer_order();
+    pay(&db, &order);
+    // Not without giving the money back
+    assert!(matches!(db::update_order_status(&db, &order, &seller, OrderStatus::Cancelled), Err(OrderError::Illegal(_))));
+    assert_eq!(status(&db, &order), OrderStatus::Paid);
+
+    db::cancel_order(&db, &order, &seller, &refunded).unwrap();
+    assert_eq!(status(&db, &order), OrderStatus::Cancelled);
+    assert_eq!(db::get_payment_intents(&db, &order)[0].status, IntentStatus::Refunded);
+    assert_eq!(db::get_listing(&db, &listing).unwrap().status, "active");
+    assert_eq!(db::get_offer(&db, &offer).unwrap().status, "withdrawn");
+}
+
+#[test]
+fn refunds_happen_only_for_cancellations_that_go_through() {
+    let Sale { db, seller, buyer, order, .. } = accepted_offer_order();
+    pay(&db, &order);
+    let refunds = Cell::new(0);
+    let counted = |_: &PaymentIntent| {
+        refunds.set(refunds.get() + 1);
+        Ok(IntentStatus::Refunded)
+    };
+
+    // The buyer can't cancel once paid, so nothing is refunded
+    assert!(matches!(db::cancel_order(&db, &order, &buyer, &counted), Err(OrderError::Illegal(_))));
+    assert_eq!(refunds.get(), 0);
+
+    // A refund the provider refuses leaves the order paid and the payment as it was
+    let declined = |_: &PaymentIntent| Err("gateway unavailable".to_string());
+    assert_eq!(
+        db::cancel_order(&db, &order, &seller, &declined),
+        Err(OrderError::RefundFailed("gateway unavailable".to_string())),
+    );
+    assert_eq!(status(&db, &order), OrderStatus::Paid);
+    assert_eq!(db::get_payment_intents(&db, &order)[0].status, IntentStatus::Succeeded);
+
+    // A double submit refunds once
+    db::cancel_order(&db, &order, &seller, &counted).unwrap();
+    assert!(db::cancel_order(&db, &order, &seller, &counted).is_err());
+    assert_eq!(refunds.get(), 1);
+    assert_eq!(status(&db, &order), OrderStatus::Cancelled);
+}
+
+#[test]
+fn a_cart_line_is_checked_out_as_one_item() {
+    let (_router, db) = common::app("orders");
+    let alice = sign_up(&db, "Alice", "alice@example.com");
+    let bob = sign_up(&db, "Bob", "bob@example.com");
+    let lamp = list_item(&db, &alice, "Desk lamp", Money::usd(4000));
+    db::add_to_cart(&db, &CartOwner::User(bob.clone()), &lamp).unwrap();
+    // A line saved when the cart still had a quantity control
+    db.lock().unwrap().execute("UPDATE cart_items SET quantity = 4 WHERE listing_id = ?1", [&lamp]).unwrap();
+
+    let orders = db::create_orders_from_cart(&db, &bob).unwrap();
+    let order = db::get_order(&db, &orders[0]).unwrap();
+    assert_eq!(order.total, Money::usd(4000));
+    assert_eq!((order.items[0].quantity, order.items[0].line_total), (1, Money::usd(4000)));
+    assert_eq!(db::get_listing(&db, &lamp).unwrap().status, "sold");
+}
@@ -0,0 +1,98 @@
+//! The forgot-password flow from the browser's side, reading the mailed link back out of the
+//! `outbox` table the default mailer writes to.
+
+mod common;
+
+use axum::http::StatusCode;
+use axum::Router;
+use common::Browser;
+use forge_commerce::db::{self, Db};
+
+/// A fresh app with Alice and Bob signed up, both with the password `password123`.
+fn app() -> (Router, Db) {
+    let (router, db) = common::app("reset");
+    let hash = forge_commerce::auth::hash_password("password123");
+    db::create_user(&db, "Alice", "alice@example.com", &hash).unwrap();
+    db::create_user(&db, "Bob", "bob@example.com", &hash).unwrap();
+    (router, db)
+}
+
+/// The token in the newest reset link mailed to `to`.
+fn mailed_token(db: &Db, to: &str) -> String {
+    let mail = db::get_outbox(db, to);
+    let body = &mail.first().expect("a reset email was sent").body;
+    let start = body.find("/reset-password?token=").unwrap() + "/reset-password?token=".len();
+    body[start..].split_whitespace().next().unwrap().to_string()
+}
+
+/// A personal API token for `email` with every scope.
+fn api_token(db: &Db, email: &str) -> String {
+    let user = db::get_user_by_email(db, email).unwrap();
+    let token = forge_commerce::auth::generate_api_token();
+    let scopes: Vec<&str> = forge_commerce::auth::API_SCOPES.iter().map(|(scope, _)| *scope).collect();
+    db::create_api_token(db, &user.id, "script", &forge_commerce::auth::hash_token(&token), &token[..forge_commerce::auth::API_TOKEN_PREFIX.len() + 6], &scopes);
+    token
+}
+
+#[tokio::test]
+async fn reset_link_changes_the_password_once_and_signs_everyone_out() {
+    let (router, db) = app();
+
+    let mut laptop = Browser::new(&router);
+    assert_eq!(laptop.log_in("alice@example.com", "password123").await.status, StatusCode::SEE_OTHER);
+    assert_eq!(laptop.get("/profile").await.status, StatusCode::OK);
+    let token_before = api_token(&db, "alice@example.com");
+    assert_eq!(laptop.get_with_bearer("/api/v1/me", &token_before).await.status, StatusCode::OK);
+
+    // Asking for a reset looks the same whether or not the account exists
+    let mut phone = Browser::new(&router);
+    let known = phone.post("/forgot-password", "/forgot-password", &[("email", "alice@example.com")]).await;
+    let unknown = phone.post("/forgot-password", "/forgot-password", &[("email", "nobody@example.com")]).await;
+    assert_eq!((known.status, unknown.status), (StatusCode::OK, StatusCode::OK));
+    assert!(known.body.contains("If there&#x27;s an account for that email"));
+    assert_eq!(known.body, unknown.body);
+    assert!(db::get_outbox(&db, "nobody@example.com").is_empty());
+
+    let mail = db::get_outbox(&db, "alice@example.com");
+    assert_eq!(mail.len(), 1);
+    assert!(mail[0].message.contains("Subject: Reset your Forge Market password"));
+    let token = mailed_token(&db, "alice@example.com");
+    let link = format!("/reset-password?token={}", token);
+
+    let form = phone.get(&link).await;
+    assert!(form.body.contains(r#"name="token""#));
+    let mismatch = phone.post("/reset-password", &link, &[("token", &token), ("password", "new-password-1"), ("password_confirm", "new-password-2")]).await;
+    assert!(mismatch.body.contains("Passwords do not match"));
+
+    let done = phone.post("/reset-password", &link, &[("token", &token), ("password", "new-password-1"), ("password_confirm", "new-password-1")]).await;
+    assert_eq!((done.status, done.location.as_deref()), (StatusCode::SEE_OTHER, Some("/login?reset=1")));
+
+    // The laptop's session ended with the old password, and so did the API token
+    assert_eq!(laptop.get("/profile").await.location.as_deref(), Some("/login"));
+    assert_eq!(laptop.get_with_bearer("/api/v1/me", &token_before).await.status, StatusCode::UNAUTHORIZED);
+    assert!(db::get_api_tokens(&db, &db::get_user_by_email(&db, "alice@example.com").unwrap().id).is_empty());
+
+    // The link is spent
+    assert!(phone.get(&link).await.body.contains("expired or has already been used"));
+    let again = phone.post("/reset-password", "/forgot-password", &[("token", &token), ("password", "another-pass"), ("password_confirm", "another-pass")]).await;
+    assert!(again.body.contains("expired or has already been used"));
+
+    assert_eq!(laptop.log_in("alice@example.com", "password123").await.status, StatusCode::OK);
+    assert_eq!(laptop.log_in("alice@example.com", "new-password-1").await.status, StatusCode::SEE_OTHER);
+}
+
+#[tokio::test]
+async fn a_new_link_replaces_the_last_one() {
+    let (router, db) = app();
+    let mut browser = Browser::new(&router);
+
+    browser.post("/forgot-password", "/forgot-password", &[("email", "bob@example.com")]).await;
+    let first = mailed_token(&db, "bob@example.com");
+    browser.post("/forgot-password", "/forgot-password", &[("email", "bob@example.com")]).await;
+    let second = mailed_token(&db, "bob@example.com");
+    assert_ne!(first, second);
+
+    assert!(browser.get(&format!("/reset-password?token={}", first)).await.body.contains("expired or has already been used"));
+    assert!(browser.get(&format!("/reset-password?token={}", second)).await.body.contains(r#"name="token""#));
+    assert!(browser.get("/reset-password?token=not-a-token").await.body.contains("expired or has already been used"));
+}
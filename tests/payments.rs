@@ -0,0 +1,180 @@
+//! Paid checkout against the mock card gateway, from picking the card provider through its
+//! signed webhooks to the refund when a paid order is cancelled.
+
+mod common;
+
+use axum::body::Body;
+use axum::http::{header, Request, StatusCode};
+use axum::Router;
+use common::{list_item, sign_up, Browser};
+use forge_commerce::db::{self, Db};
+use forge_commerce::models::{CartOwner, IntentStatus, OrderStatus};
+use forge_commerce::money::Money;
+use forge_commerce::payments::{MockCardGateway, MOCK_SIGNATURE_HEADER};
+
+/// Bob's order for Alice's lamp, awaiting payment, with Bob signed in.
+async fn pending_order() -> (Router, Browser, Db, String) {
+    let (router, db) = common::app("payments");
+    let alice = sign_up(&db, "Alice", "alice@example.com");
+    let bob = sign_up(&db, "Bob", "bob@example.com");
+    let lamp = list_item(&db, &alice, "Desk lamp", Money::usd(4000));
+    db::add_to_cart(&db, &CartOwner::User(bob.clone()), &lamp).unwrap();
+    let order = db::create_orders_from_cart(&db, &bob).unwrap().remove(0);
+    let mut browser = Browser::new(&router);
+    browser.log_in("bob@example.com", "password123").await;
+    (router, browser, db, order)
+}
+
+/// Picks the mock card gateway on the order page and returns its reference for the intent.
+async fn start_card_payment(browser: &mut Browser, db: &Db, order: &str) -> String {
+    let page = format!("/orders/{}", order);
+    let started = browser.post(&format!("{}/pay", page), &page, &[("provider", "mock_card")]).await;
+    assert_eq!(started.location.as_deref(), Some(page.as_str()));
+    let intents = db::get_payment_intents(db, order);
+    assert_eq!(intents.len(), 1);
+    assert_eq!((intents[0].provider.as_str(), intents[0].status), ("mock_card", IntentStatus::RequiresAction));
+    intents[0].provider_ref.clone()
+}
+
+/// Posts a webhook event for `provider_ref`, signed with the gateway's secret at `timestamp`
+/// unless `signature` overrides it.
+async fn webhook(browser: &mut Browser, kind: &str, provider_ref: &str, timestamp: i64, signature: Option<&str>) -> StatusCode {
+    let body = serde_json::json!({ "type": kind, "provider_ref": provider_ref }).to_string();
+    let signature = signature.map(str::to_string)
+        .unwrap_or_else(|| MockCardGateway::from_env().unwrap().sign(body.as_bytes(), timestamp));
+    let request = Request::post("/payments/webhook/mock_card")
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(MOCK_SIGNATURE_HEADER, signature);
+    browser.send(request, Body::from(body)).await.status
+}
+
+fn now() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+fn intent_status(db: &Db, order: &str) -> IntentStatus {
+    db::get_payment_intents(db, order)[0].status
+}
+
+fn order_status(db: &Db, order: &str) -> OrderStatus {
+    db::get_order(db, order).unwrap().status
+}
+
+#[tokio::test]
+async fn card_payment_confirmed_by_the_buyer_pays_the_order() {
+    let (_router, mut browser, db, order) = pending_order().await;
+    let provider_ref = start_card_payment(&mut browser, &db, &order).await;
+    let page = format!("/orders/{}", order);
+
+    let invalid = browser.post(&format!("{}/pay/confirm", page), &page, &[("card_number", "4242 4242 4242 4241")]).await;
+    assert_eq!(invalid.location, Some(format!("{}?error=card_invalid", page)));
+    assert_eq!(order_status(&db, &order), OrderStatus::PendingPayment);
+
+    // Any Luhn-valid number other than the decline card goes through
+    let paid = browser.post(&format!("{}/pay/confirm", page), &page, &[("card_number", "4111-1111-1111-1111")]).await;
+    assert_eq!(paid.location.as_deref(), Some(page.as_str()));
+    assert_eq!(intent_status(&db, &order), IntentStatus::Succeeded);
+    assert_eq!(order_status(&db, &order), OrderStatus::Paid);
+
+    // The gateway's own news of the same payment is acknowledged and changes nothing
+    assert_eq!(webhook(&mut browser, "payment_intent.succeeded", &provider_ref, now(), None).await, StatusCode::OK);
+    assert_eq!(intent_status(&db, &order), IntentStatus::Succeeded);
+    // Nor can a late failure undo it
+    assert_eq!(webhook(&mut browser, "payment_intent.payment_failed", &provider_ref, now(), None).await, StatusCode::OK);
+    assert_eq!(intent_status(&db, &order), IntentStatus::Succeeded);
+    assert_eq!(order_status(&db, &order), OrderStatus::Paid);
+
+    // Once refunded, a replayed success within the tolerance leaves it refunded
+    assert_eq!(webhook(&mut browser, "charge.refunded", &provider_ref, now(), None).await, StatusCode::OK);
+    assert_eq!(intent_status(&db, &order), IntentStatus::Refunded);
+    assert_eq!(webhook(&mut browser, "payment_intent.succeeded", &provider_ref, now() - 60, None).await, StatusCode::OK);
+    assert_eq!(intent_status(&db, &order), IntentStatus::Refunded);
+}
+
+#[tokio::test]
+async fn signed_webhook_pays_the_order() {
+    let (_router, mut browser, db, order) = pending_order().await;
+    let provider_ref = start_card_payment(&mut browser, &db, &order).await;
+
+    assert_eq!(webhook(&mut browser, "payment_intent.succeeded", &provider_ref, now(), None).await, StatusCode::OK);
+    assert_eq!(intent_status(&db, &order), IntentStatus::Succeeded);
+    assert_eq!(order_status(&db, &order), OrderStatus::Paid);
+
+    assert_eq!(webhook(&mut browser, "payment_intent.succeeded", "mock_pi_unknown", now(), None).await, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn webhooks_with_bad_signatures_or_stale_timestamps_are_rejected() {
+    let (_router, mut browser, db, order) = pending_order().await;
+    let provider_ref = start_card_payment(&mut browser, &db, &order).await;
+
+    let body = serde_json::json!({ "type": "payment_intent.succeeded", "provider_ref": provider_ref }).to_string();
+    let wrong_secret = MockCardGateway::new("whsec_not_ours").sign(body.as_bytes(), now());
+    assert_eq!(webhook(&mut browser, "payment_intent.succeeded", &provider_ref, now(), Some(&wrong_secret)).await, StatusCode::BAD_REQUEST);
+    assert_eq!(webhook(&mut browser, "payment_intent.succeeded", &provider_ref, now(), Some("t=1,v1=zz")).await, StatusCode::BAD_REQUEST);
+    assert_eq!(webhook(&mut browser, "payment_intent.succeeded", &provider_ref, now(), Some("")).await, StatusCode::BAD_REQUEST);
+
+    // Correctly signed, but outside the five-minute tolerance either way
+    assert_eq!(webhook(&mut browser, "payment_intent.succeeded", &provider_ref, now() - 301, None).await, StatusCode::BAD_REQUEST);
+    assert_eq!(webhook(&mut browser, "payment_intent.succeeded", &provider_ref, now() + 301, None).await, StatusCode::BAD_REQUEST);
+
+    assert_eq!(intent_status(&db, &order), IntentStatus::RequiresAction);
+    assert_eq!(order_status(&db, &order), OrderStatus::PendingPayment);
+}
+
+#[tokio::test]
+async fn cancelling_a_paid_order_refunds_it_once() {
+    let (router, mut buyer, db, order) = pending_order().await;
+    start_card_payment(&mut buyer, &db, &order).await;
+    let page = format!("/orders/{}", order);
+    buyer.post(&format!("{}/pay/confirm", page), &page, &[("card_number", "4242 4242 4242 4242")]).await;
+    assert_eq!(order_status(&db, &order), OrderStatus::Paid);
+
+    let mut seller = Browser::new(&router);
+    seller.log_in("alice@example.com", "password123").await;
+    let status = format!("{}/status", page);
+    let cancelled = seller.post(&status, &page, &[("status", "cancelled")]).await;
+    assert_eq!(cancelled.location.as_deref(), Some(page.as_str()));
+    assert_eq!(order_status(&db, &order), OrderStatus::Cancelled);
+    assert_eq!(intent_status(&db, &order), IntentStatus::Refunded);
+
+    // Submitting again is refused before the gateway is asked for a second refund
+    let again = seller.post(&status, &page, &[("status", "cancelled")]).await;
+    assert_eq!(again.location, Some(format!("{}?error=status", page)));
+    assert_eq!(db::get_payment_intents(&db, &order).len(), 1);
+    assert_eq!(intent_status(&db, &order), IntentStatus::Refunded);
+}
+
+#[tokio::test]
+async fn a_card_payment_for_a_cancelled_order_is_refunded() {
+    let (_router, mut buyer, db, order) = pending_order().await;
+    let provider_ref = start_card_payment(&mut buyer, &db, &order).await;
+    let page = format!("/orders/{}", order);
+    buyer.post(&format!("{}/status", page), &page, &[("status", "cancelled")]).await;
+    assert_eq!(order_status(&db, &order), OrderStatus::Cancelled);
+    assert_eq!(intent_status(&db, &order), IntentStatus::Voided);
+
+    // The gateway charged the card anyway: the money goes back and the order stays cancelled
+    assert_eq!(webhook(&mut buyer, "payment_intent.succeeded", &provider_ref, now(), None).await, StatusCode::OK);
+    assert_eq!(intent_status(&db, &order), IntentStatus::Refunded);
+    assert_eq!(order_status(&db, &order), OrderStatus::Cancelled);
+}
+
+#[tokio::test]
+async fn a_second_payment_for_a_paid_order_is_refunded() {
+    let (router, mut buyer, db, order) = pending_order().await;
+    let provider_ref = start_card_payment(&mut buyer, &db, &order).await;
+
+    // Bob paid Alice directly as well, and she confirms it
+    let mut seller = Browser::new(&router);
+    seller.log_in("alice@example.com", "password123").await;
+    let page = format!("/orders/{}", order);
+    seller.post(&format!("{}/pay/confirm", page), &page, &[]).await;
+    assert_eq!(order_status(&db, &order), OrderStatus::Paid);
+    let statuses = |db: &Db| db::get_payment_intents(db, &order).iter().map(|i| (i.provider.clone(), i.status)).collect::<Vec<_>>();
+    assert_eq!(statuses(&db), [("manual".to_string(), IntentStatus::Succeeded), ("mock_card".to_string(), IntentStatus::Voided)]);
+
+    assert_eq!(webhook(&mut buyer, "payment_intent.succeeded", &provider_ref, now(), None).await, StatusCode::OK);
+    assert_eq!(statuses(&db), [("manual".to_string(), IntentStatus::Succeeded), ("mock_card".to_string(), IntentStatus::Refunded)]);
+    assert_eq!(order_status(&db, &order), OrderStatus::Paid);
+}
@@ -0,0 +1,45 @@
+//! Unread counts and "Seen" markers follow each message's position in the conversation,
+//! so messages sent within the same second are still told apart.
+
+mod common;
+
+use common::{list_item, sign_up, Browser};
+use forge_commerce::db;
+use forge_commerce::money::Money;
+
+#[tokio::test]
+async fn messages_sent_in_the_same_second_are_each_unread_until_read() {
+    let (router, db) = common::app("read-receipts");
+    let alice = sign_up(&db, "Alice", "alice@example.com");
+    let bob = sign_up(&db, "Bob", "bob@example.com");
+    let lamp = list_item(&db, &alice, "Desk lamp", Money::usd(4000));
+    let convo = db::get_or_create_conversation(&db, &lamp, &bob, &alice);
+    for text in ["Hi!", "Is it still available?", "I can collect today."] {
+        db::send_message(&db, &convo, &bob, text);
+    }
+    // Pin them to one timestamp, as a quick sender gets
+    db.lock().unwrap().execute("UPDATE messages SET created_at = '2026-01-01 12:00:00'", []).unwrap();
+    assert_eq!(db::get_unread_count(&db, &alice), 3);
+
+    // Reading through the second leaves only the third, despite the shared timestamp
+    db::mark_read_through(&db, &alice, &convo, 2);
+    assert_eq!(db::get_unread_count(&db, &alice), 1);
+    let seen: Vec<bool> = db::get_messages_after(&db, &convo, 0).iter().map(|m| m.seen).collect();
+    assert_eq!(seen, [true, true, false]);
+
+    // Opening the conversation reads the rest
+    let mut seller = Browser::new(&router);
+    seller.log_in("alice@example.com", "password123").await;
+    seller.get(&format!("/messages/{}", convo)).await;
+    assert_eq!(db::get_unread_count(&db, &alice), 0);
+    assert!(db::get_messages_after(&db, &convo, 0).iter().all(|m| m.seen));
+
+    // Bob sees "Seen" once, under the latest message
+    let mut buyer = Browser::new(&router);
+    buyer.log_in("bob@example.com", "password123").await;
+    let page = buyer.get(&format!("/messages/{}", convo)).await.body;
+    assert_eq!(page.matches(" data-seen>").count(), 3);
+    assert_eq!(page.matches(r#"id="seen-marker""#).count(), 1);
+    let marker = page.find(r#"id="seen-marker""#).unwrap();
+    assert!(page.find("I can collect today.").unwrap() < marker);
+}
@@ -0,0 +1,76 @@
+//! Accepting an offer reserves the listing for that buyer: competing offers are declined,
+//! new ones refused, and the seller can put it back on the market until the buyer checks out.
+
+mod common;
+
+use common::{list_item, sign_up, Browser};
+use forge_commerce::db;
+use forge_commerce::models::{OfferError, OfferResponse, SearchQuery};
+use forge_commerce::money::Money;
+
+fn everything() -> SearchQuery {
+    SearchQuery { q: None, category: None, condition: None, min_price: None, max_price: None, sort: None }
+}
+
+#[tokio::test]
+async fn accepting_one_buyers_offer_declines_the_others_and_reserves_the_listing() {
+    let (router, db) = common::app("reservations");
+    let alice = sign_up(&db, "Alice", "alice@example.com");
+    let bob = sign_up(&db, "Bob", "bob@example.com");
+    let clara = sign_up(&db, "Clara", "clara@example.com");
+    let lamp = list_item(&db, &alice, "Desk lamp", Money::usd(4000));
+    let bob_convo = db::get_or_create_conversation(&db, &lamp, &bob, &alice);
+    let clara_convo = db::get_or_create_conversation(&db, &lamp, &clara, &alice);
+    let bobs = db::create_offer(&db, &lamp, &bob_convo, &bob, Money::usd(3500)).unwrap();
+    let claras = db::create_offer(&db, &lamp, &clara_convo, &clara, Money::usd(3000)).unwrap();
+
+    db::respond_to_offer(&db, &bobs, &alice, OfferResponse::Accept).unwrap();
+    assert_eq!(db::get_listing(&db, &lamp).unwrap().status, "reserved");
+    assert_eq!(db::get_offer(&db, &claras).unwrap().status, "rejected");
+    let notes = db::get_messages_after(&db, &clara_convo, 0);
+    assert!(notes.iter().any(|m| m.content == "🔒 This item is now reserved for another buyer, so your offer was declined."));
+    assert!(db::get_listings(&db, &everything()).iter().all(|l| l.id != lamp));
+
+    // Clara can't make a fresh offer while it's reserved
+    assert!(matches!(db::create_offer(&db, &lamp, &clara_convo, &clara, Money::usd(3800)), Err(OfferError::Conflict(_))));
+    let mut browser = Browser::new(&router);
+    browser.log_in("clara@example.com", "password123").await;
+    let page = format!("/messages/{}", clara_convo);
+    let refused = browser.post(&format!("{}/offer", page), &page, &[("amount", "38")]).await;
+    assert_eq!(refused.location, Some(format!("{}?error=unavailable", page)));
+    assert!(browser.get(&refused.location.unwrap()).await.body.contains("no longer available"));
+
+    // Alice puts it back on the market: Bob's accepted offer is withdrawn and offers are open again
+    let mut seller = Browser::new(&router);
+    seller.log_in("alice@example.com", "password123").await;
+    let listing_page = format!("/listing/{}", lamp);
+    let released = seller.post(&format!("{}/unreserve", listing_page), &listing_page, &[]).await;
+    assert_eq!(released.location.as_deref(), Some(listing_page.as_str()));
+    assert_eq!(db::get_listing(&db, &lamp).unwrap().status, "active");
+    assert_eq!(db::get_offer(&db, &bobs).unwrap().status, "withdrawn");
+    assert!(db::get_listings(&db, &everything()).iter().any(|l| l.id == lamp));
+    assert!(db::create_offer(&db, &lamp, &clara_convo, &clara, Money::usd(3800)).is_ok());
+}
+
+#[tokio::test]
+async fn unreserving_a_listing_the_buyer_already_bought_says_why_it_failed() {
+    let (router, db) = common::app("reservations");
+    let alice = sign_up(&db, "Alice", "alice@example.com");
+    let bob = sign_up(&db, "Bob", "bob@example.com");
+    let lamp = list_item(&db, &alice, "Desk lamp", Money::usd(4000));
+    let convo = db::get_or_create_conversation(&db, &lamp, &bob, &alice);
+    let offer = db::create_offer(&db, &lamp, &convo, &bob, Money::usd(3500)).unwrap();
+    db::respond_to_offer(&db, &offer, &alice, OfferResponse::Accept).unwrap();
+    db::create_order_from_offer(&db, &offer, &bob).unwrap();
+    assert_eq!(db::get_listing(&db, &lamp).unwrap().status, "sold");
+
+    let mut seller = Browser::new(&router);
+    seller.log_in("alice@example.com", "password123").await;
+    let listing_page = format!("/listing/{}", lamp);
+    let refused = seller.post(&format!("{}/unreserve", listing_page), &listing_page, &[]).await;
+    assert_eq!(refused.location, Some(format!("{}?error=unreserve", listing_page)));
+    let page = seller.get(&refused.location.unwrap()).await;
+    assert!(page.body.contains("The buyer may already have checked out."));
+    assert_eq!(db::get_listing(&db, &lamp).unwrap().status, "sold");
+    assert_eq!(db::get_offer(&db, &offer).unwrap().status, "accepted");
+}
@@ -0,0 +1,56 @@
+//! Full-text search over listings, through `db::get_listings`.
+
+mod common;
+
+use common::sign_up;
+use forge_commerce::db::{self, Db};
+use forge_commerce::models::{ListingForm, SearchQuery};
+use forge_commerce::money::Money;
+
+fn list(db: &Db, seller: &str, title: &str, description: &str) -> String {
+    let form = ListingForm {
+        title: title.to_string(), description: description.to_string(), price: "40".to_string(),
+        category: "Home".to_string(), condition: "Good".to_string(), location: "Leeds".to_string(),
+    };
+    db::create_listing(db, seller, &form, Money::usd(4000), &[])
+}
+
+fn search(db: &Db, q: &str, sort: Option<&str>) -> Vec<String> {
+    let query = SearchQuery {
+        q: Some(q.to_string()), category: None, condition: None, min_price: None, max_price: None,
+        sort: sort.map(str::to_string),
+    };
+    db::get_listings(db, &query).into_iter().map(|l| l.title).collect()
+}
+
+/// Alice's shop: a brass lamp listed yesterday, a lamp with brass only in its description
+/// listed today, and a rug that mentions neither.
+fn shop() -> Db {
+    let (_router, db) = common::app("search");
+    let alice = sign_up(&db, "Alice", "alice@example.com");
+    let older = list(&db, &alice, "Brass desk lamp", "Works well, new bulb");
+    db.lock().unwrap().execute("UPDATE listings SET created_at = datetime('now', '-1 day') WHERE id = ?1", [&older]).unwrap();
+    list(&db, &alice, "Desk lamp", "Linen shade on a heavy brass base");
+    list(&db, &alice, "Wool rug", "Hand knotted, some wear at the fringe");
+    db
+}
+
+#[test]
+fn relevance_ranks_a_title_hit_above_a_description_hit() {
+    let db = shop();
+    assert_eq!(search(&db, "brass", None), ["Desk lamp", "Brass desk lamp"]);
+    assert_eq!(search(&db, "brass", Some("relevance")), ["Brass desk lamp", "Desk lamp"]);
+}
+
+#[test]
+fn search_input_matches_words_phrases_and_prefixes() {
+    let db = shop();
+    assert_eq!(search(&db, "desk lamp", Some("relevance")).len(), 2);
+    assert_eq!(search(&db, "lamp rug", None), Vec::<String>::new());
+    assert_eq!(search(&db, r#""brass base""#, None), ["Desk lamp"]);
+    assert_eq!(search(&db, "knot*", None), ["Wool rug"]);
+    // Typed operators and stray quotes are plain text, never an SQL error
+    assert_eq!(search(&db, r#"brass OR (rug"#, None), Vec::<String>::new());
+    assert_eq!(search(&db, r#"wool ""#, None), ["Wool rug"]);
+    assert_eq!(search(&db, "***", None).len(), 3);
+}
@@ -0,0 +1,101 @@
+//! Signing out: a session cookie stops working once its session is ended, whether by
+//! logging out, revoking it from another device, or signing out everywhere.
+
+mod common;
+
+use axum::Router;
+use common::{sign_up, Browser};
+use forge_commerce::db::{self, Db};
+use std::collections::HashMap;
+
+/// Whether a browser holding `cookies` is still signed in.
+async fn signed_in(router: &Router, cookies: &HashMap<String, String>) -> bool {
+    let mut browser = Browser::new(router);
+    browser.cookies = cookies.clone();
+    browser.get("/profile").await.location.as_deref() != Some("/login")
+}
+
+async fn logged_in(router: &Router, email: &str) -> Browser {
+    let mut browser = Browser::new(router);
+    browser.log_in(email, "password123").await;
+    browser
+}
+
+fn public_ids(db: &Db, user_id: &str) -> Vec<String> {
+    db::get_sessions(db, user_id, None).into_iter().map(|s| s.public_id).collect()
+}
+
+#[tokio::test]
+async fn logging_out_ends_the_session_not_just_the_cookie() {
+    let (router, db) = common::app("sessions");
+    let alice = sign_up(&db, "Alice", "alice@example.com");
+    let mut browser = logged_in(&router, "alice@example.com").await;
+    let cookies = browser.cookies.clone();
+    assert!(signed_in(&router, &cookies).await);
+
+    let out = browser.post("/logout", "/profile", &[]).await;
+    assert_eq!(out.location.as_deref(), Some("/"));
+    assert!(!signed_in(&router, &cookies).await);
+    assert!(public_ids(&db, &alice).is_empty());
+}
+
+#[tokio::test]
+async fn a_session_revoked_from_another_device_stops_working() {
+    let (router, db) = common::app("sessions");
+    let alice = sign_up(&db, "Alice", "alice@example.com");
+    let laptop = logged_in(&router, "alice@example.com").await;
+    let mut phone = logged_in(&router, "alice@example.com").await;
+    let laptop_id = db::get_sessions(&db, &alice, laptop.cookies.get(forge_commerce::auth::SESSION_COOKIE).map(String::as_str))
+        .into_iter().find(|s| s.current).unwrap().public_id;
+
+    let revoked = phone.post(&format!("/profile/sessions/{}/revoke", laptop_id), "/profile", &[]).await;
+    assert_eq!(revoked.location.as_deref(), Some("/profile#sessions"));
+    assert!(!signed_in(&router, &laptop.cookies).await);
+    assert!(signed_in(&router, &phone.cookies).await);
+    assert!(!public_ids(&db, &alice).contains(&laptop_id));
+}
+
+#[tokio::test]
+async fn nobody_can_revoke_someone_elses_session() {
+    let (router, db) = common::app("sessions");
+    let alice = sign_up(&db, "Alice", "alice@example.com");
+    sign_up(&db, "Mallory", "mallory@example.com");
+    let victim = logged_in(&router, "alice@example.com").await;
+    let victim_id = public_ids(&db, &alice).remove(0);
+
+    let mut mallory = logged_in(&router, "mallory@example.com").await;
+    mallory.post(&format!("/profile/sessions/{}/revoke", victim_id), "/profile", &[]).await;
+    assert!(signed_in(&router, &victim.cookies).await);
+    assert_eq!(public_ids(&db, &alice), [victim_id]);
+}
+
+#[tokio::test]
+async fn signing_out_everywhere_ends_every_session() {
+    let (router, db) = common::app("sessions");
+    let alice = sign_up(&db, "Alice", "alice@example.com");
+    sign_up(&db, "Bob", "bob@example.com");
+    let mut laptop = logged_in(&router, "alice@example.com").await;
+    let phone = logged_in(&router, "alice@example.com").await;
+    let laptop_cookies = laptop.cookies.clone();
+    let bob = logged_in(&router, "bob@example.com").await;
+
+    let out = laptop.post("/profile/sessions/revoke-all", "/profile", &[]).await;
+    assert_eq!(out.location.as_deref(), Some("/login"));
+    assert!(!signed_in(&router, &laptop_cookies).await);
+    assert!(!signed_in(&router, &phone.cookies).await);
+    assert!(public_ids(&db, &alice).is_empty());
+    // Other people stay signed in
+    assert!(signed_in(&router, &bob.cookies).await);
+}
+
+#[tokio::test]
+async fn expired_sessions_stop_working_and_are_purged() {
+    let (router, db) = common::app("sessions");
+    sign_up(&db, "Alice", "alice@example.com");
+    let browser = logged_in(&router, "alice@example.com").await;
+    db.lock().unwrap().execute("UPDATE sessions SET expires_at = datetime('now', '-1 minute')", []).unwrap();
+
+    assert!(!signed_in(&router, &browser.cookies).await);
+    assert_eq!(db::purge_expired_sessions(&db), 1);
+    assert_eq!(db::purge_expired_sessions(&db), 0);
+}
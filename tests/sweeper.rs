@@ -0,0 +1,66 @@
+//! The background sweeper, driven directly rather than waiting on its timer.
+
+use forge_commerce::db::{self, Db};
+use forge_commerce::models::ListingForm;
+use forge_commerce::money::Money;
+use forge_commerce::sweeper;
+
+/// A fresh database with Alice selling a lamp and Bob's pending offer on it. Returns the
+/// conversation and offer ids.
+fn pending_offer() -> (Db, String, String) {
+    let path = std::env::temp_dir().join(format!("forge-sweeper-{}.db", uuid::Uuid::new_v4()));
+    let db = db::init_db_with_path(path.to_str().unwrap());
+    let hash = forge_commerce::auth::hash_password("password123");
+    let alice = db::create_user(&db, "Alice", "alice@example.com", &hash).unwrap();
+    let bob = db::create_user(&db, "Bob", "bob@example.com", &hash).unwrap();
+    let form = ListingForm {
+        title: "Desk lamp".to_string(), description: "Brass, works".to_string(), price: "40".to_string(),
+        category: "Home".to_string(), condition: "Good".to_string(), location: "Leeds".to_string(),
+    };
+    let listing = db::create_listing(&db, &alice, &form, Money::usd(4000), &[]);
+    let convo = db::get_or_create_conversation(&db, &listing, &bob, &alice);
+    let offer = db::create_offer(&db, &listing, &convo, &bob, Money::usd(3250)).unwrap();
+    (db, convo, offer)
+}
+
+fn backdate_expiry(db: &Db, offer_id: &str) {
+    db.lock().unwrap().execute(
+        "UPDATE offers SET expires_at = datetime('now', '-1 minute') WHERE id = ?1",
+        [offer_id],
+    ).unwrap();
+}
+
+#[test]
+fn sweep_expires_lapsed_offers_and_says_so_in_the_conversation() {
+    let (db, convo, offer) = pending_offer();
+    assert_eq!(db::get_pending_offer(&db, &convo).map(|o| o.id), Some(offer.clone()));
+
+    // Past its expiry the offer is no longer answerable, even before the sweeper runs
+    backdate_expiry(&db, &offer);
+    assert!(db::get_pending_offer(&db, &convo).is_none());
+    assert_eq!(db::get_offer(&db, &offer).unwrap().status, "pending");
+
+    sweeper::sweep(&db);
+    let swept = db::get_offer(&db, &offer).unwrap();
+    assert_eq!(swept.status, "expired");
+    assert!(db::get_pending_offer(&db, &convo).is_none());
+
+    let messages = db::get_messages_after(&db, &convo, 0);
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].content, "⌛ Offer of $32.50 expired without an answer.");
+    assert_eq!(messages[0].sender_id, swept.created_by);
+    assert_eq!(messages[0].seq, 1);
+
+    // Nothing left to expire, so a second pass posts nothing
+    sweeper::sweep(&db);
+    assert_eq!(db::get_messages_after(&db, &convo, 0).len(), 1);
+}
+
+#[test]
+fn sweep_leaves_offers_that_have_not_expired() {
+    let (db, convo, offer) = pending_offer();
+    sweeper::sweep(&db);
+    assert_eq!(db::get_offer(&db, &offer).unwrap().status, "pending");
+    assert_eq!(db::get_pending_offer(&db, &convo).map(|o| o.id), Some(offer));
+    assert!(db::get_messages_after(&db, &convo, 0).is_empty());
+}
@@ -0,0 +1,87 @@
+//! Confirming an email address from the mailed link, and what an unconfirmed account can't
+//! do.
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{list_item, sign_up, Browser};
+use forge_commerce::auth::email_verification_path;
+use forge_commerce::db;
+use forge_commerce::money::Money;
+
+/// The link's query as form fields, the way the confirm page posts them back.
+fn link_fields(path: &str) -> Vec<(String, String)> {
+    let query = path.split_once('?').unwrap().1;
+    form_urlencoded::parse(query.as_bytes()).into_owned().collect()
+}
+
+#[tokio::test]
+async fn opening_the_link_asks_and_confirming_verifies() {
+    let (router, db) = common::app("verify");
+    let hash = forge_commerce::auth::hash_password("password123");
+    let id = db::create_user(&db, "Alice", "alice@example.com", &hash).unwrap();
+    let link = email_verification_path(&db, &db::get_user_by_id(&db, &id).unwrap());
+
+    // A mail scanner fetching the link confirms nothing
+    let mut visitor = Browser::new(&router);
+    let page = visitor.get(&link).await;
+    assert_eq!(page.status, StatusCode::OK);
+    assert!(page.body.contains("<h1>Confirm your email</h1>") && page.body.contains("alice@example.com"));
+    assert!(!db::get_user_by_id(&db, &id).unwrap().is_verified());
+
+    let fields = link_fields(&link);
+    let fields: Vec<(&str, &str)> = fields.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+    let page = visitor.post("/verify-email", &link, &fields).await;
+    assert!(page.body.contains("Email confirmed"), "{}", page.body);
+    assert!(db::get_user_by_id(&db, &id).unwrap().is_verified());
+
+    // Opening it again just says so
+    let page = visitor.get(&link).await;
+    assert!(page.body.contains("Email confirmed") && !page.body.contains("<form method=\"post\" action=\"/verify-email\">"));
+}
+
+#[tokio::test]
+async fn tampered_links_are_refused_either_way() {
+    let (router, db) = common::app("verify-tampered");
+    let hash = forge_commerce::auth::hash_password("password123");
+    let alice = db::create_user(&db, "Alice", "alice@example.com", &hash).unwrap();
+    let mallory = db::create_user(&db, "Mallory", "mallory@example.com", &hash).unwrap();
+    let link = email_verification_path(&db, &db::get_user_by_id(&db, &mallory).unwrap());
+    let forged = link.replace(&mallory, &alice);
+
+    let mut visitor = Browser::new(&router);
+    let page = visitor.get(&forged).await;
+    assert!(page.body.contains("This verification link") && !page.body.contains("<h1>Confirm your email</h1>"));
+    let mut fields = link_fields(&link);
+    fields[0].1 = alice.clone();
+    let fields: Vec<(&str, &str)> = fields.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+    let page = visitor.post("/verify-email", &link, &fields).await;
+    assert!(page.body.contains("This verification link"));
+    assert!(!db::get_user_by_id(&db, &alice).unwrap().is_verified());
+    assert!(visitor.get("/verify-email").await.body.contains("This verification link"));
+}
+
+#[tokio::test]
+async fn counter_offers_need_a_confirmed_email() {
+    let (router, db) = common::app("verify-counter");
+    let hash = forge_commerce::auth::hash_password("password123");
+    // A listing from before addresses had to be confirmed
+    let alice = db::create_user(&db, "Alice", "alice@example.com", &hash).unwrap();
+    let bob = sign_up(&db, "Bob", "bob@example.com");
+    let lamp = list_item(&db, &alice, "Desk lamp", Money::usd(4000));
+    let convo = db::get_or_create_conversation(&db, &lamp, &bob, &alice);
+    let offer = db::create_offer(&db, &lamp, &convo, &bob, Money::usd(3000)).unwrap();
+
+    let mut seller = Browser::new(&router);
+    seller.log_in("alice@example.com", "password123").await;
+    let page = format!("/messages/{}", convo);
+    let counter = format!("{}/offer/{}/counter", page, offer);
+    let refused = seller.post(&counter, &page, &[("amount", "35")]).await;
+    assert_eq!(refused.location, Some(format!("{}?error=verify_email", page)));
+    assert_eq!(db::get_offer(&db, &offer).unwrap().status, "pending");
+
+    db::mark_email_verified(&db, &alice);
+    let countered = seller.post(&counter, &page, &[("amount", "35")]).await;
+    assert_eq!(countered.location, Some(page));
+    assert_eq!(db::get_offer(&db, &offer).unwrap().status, "countered");
+}